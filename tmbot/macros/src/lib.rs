@@ -1,6 +1,6 @@
 use proc_macro::TokenStream;
 use quote::quote;
-use syn::{parse, DeriveInput};
+use syn::{parse, Data, DeriveInput, Fields, Field, Lit, Meta, NestedMeta};
 
 
 #[proc_macro_derive(Hello)]
@@ -17,6 +17,65 @@ pub fn hello_macro(input: TokenStream) -> TokenStream {
     }).into()
 }
 
+/// Implement tmbot's FromRow for a struct with named fields.  Each field is
+/// read from the SQL column of the same name.  Field attributes:
+///   #[row(skip)]           not a column, use Default::default()
+///   #[row(default)]        Default::default() when the column is NULL or missing
+///   #[row(default = 2)]    the literal when the column is NULL or missing
+#[proc_macro_derive(FromRow, attributes(row))]
+pub fn from_row_derive(input: TokenStream) -> TokenStream {
+    let ast = parse::<DeriveInput>(input).unwrap();
+    let name = ast.ident;
+    let fields = match ast.data {
+        Data::Struct(data) => match data.fields {
+            Fields::Named(fields) => fields.named,
+            _ => panic!("FromRow needs a struct with named fields")
+        },
+        _ => panic!("FromRow needs a struct")
+    };
+    let inits = fields.iter().map( |field| {
+        let ident = field.ident.as_ref().unwrap();
+        let column = ident.to_string();
+        match row_attr(field) {
+            RowAttr::Column => quote!{
+                #ident: crate::db::FromValue::from_value(row, #column)? },
+            RowAttr::Skip => quote!{
+                #ident: ::std::default::Default::default() },
+            RowAttr::Default(None) => quote!{
+                #ident: crate::db::FromValue::from_value(row, #column).unwrap_or_default() },
+            RowAttr::Default(Some(Lit::Str(lit))) => quote!{
+                #ident: crate::db::FromValue::from_value(row, #column).unwrap_or_else( |_| #lit.into() ) },
+            RowAttr::Default(Some(lit)) => quote!{
+                #ident: crate::db::FromValue::from_value(row, #column).unwrap_or( #lit ) }
+        }
+    });
+    (quote!{
+        impl crate::db::FromRow for #name {
+            fn from_row (row: &crate::db::Row) -> crate::util::Bresult<Self> {
+                Ok(#name { #(#inits),* })
+            }
+        }
+    }).into()
+}
+
+enum RowAttr { Column, Skip, Default(Option<Lit>) }
+
+fn row_attr (field: &Field) -> RowAttr {
+    for attr in field.attrs.iter().filter( |attr| attr.path.is_ident("row") ) {
+        if let Ok(Meta::List(list)) = attr.parse_meta() {
+            for nested in list.nested {
+                match nested {
+                    NestedMeta::Meta(Meta::Path(path)) if path.is_ident("skip") => return RowAttr::Skip,
+                    NestedMeta::Meta(Meta::Path(path)) if path.is_ident("default") => return RowAttr::Default(None),
+                    NestedMeta::Meta(Meta::NameValue(nv)) if nv.path.is_ident("default") => return RowAttr::Default(Some(nv.lit)),
+                    _ => panic!("unknown #[row(..)] attribute")
+                }
+            }
+        }
+    }
+    RowAttr::Column
+}
+
 /*
 use ::macros::*;
 use ::std::{
@@ -0,0 +1,122 @@
+//! # Price Alerts
+use crate::*;
+use ::std::fmt;
+
+#[derive(Debug, FromRow)]
+pub struct Alert {
+    pub rowid: i64,
+    pub id: i64,
+    pub at: i64,       // Chat to message
+    pub ticker: String,
+    pub kind: String,  // > < price at or past, >% <% change at or past, % change either way
+    pub price: Money,
+    pub percent: f64,  // Positive
+    #[row(default)]
+    pub name: String   // Name of the at entity, when joined
+}
+
+impl Alert {
+    pub fn triggered_p (&self, price:Money, percent:f64) -> bool {
+        match self.kind.as_str() {
+            ">"  => self.price <= price,
+            "<"  => price <= self.price,
+            ">%" => self.percent <= percent,
+            "<%" => percent <= -self.percent,
+            _    => self.percent <= percent.abs()
+        }
+    }
+}
+
+impl fmt::Display for Alert {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind.as_str() {
+            ">%" => write!(f, "{} +{}%", self.ticker, self.percent),
+            "<%" => write!(f, "{} -{}%", self.ticker, self.percent),
+            "%"  => write!(f, "{} ±{}%", self.ticker, self.percent),
+            kind => write!(f, "{} {}{}", self.ticker, kind, self.price)
+        }
+    }
+}
+
+pub fn alerts (dbconn:&Connection, id:i64) -> Bresult<Vec<Alert>> {
+    dbconn.query_as::<Alert>(
+        "SELECT alerts.rowid AS rowid, alerts.id, at, ticker, kind, price, percent, name FROM alerts
+         LEFT JOIN entitys ON alerts.at = entitys.id WHERE alerts.id=? ORDER BY ticker, time", &[&id])
+}
+
+/// Refresh the alerted tickers' quotes in one batch then message the alerts
+/// they trigger, deleting each once sent so a failed send is retried next
+/// time.  Run by the scheduler thread.
+pub async fn alerts_check (env:Env, now:i64) -> Bresult<()> {
+    let alerts = env.lock().unwrap().dbconn.query_as::<Alert>(
+        "SELECT rowid AS rowid, id, at, ticker, kind, price, percent FROM alerts ORDER BY ticker, time", &[])?;
+    let cmdstruct = CmdStruct::new_cmdstruct(env.clone(), now, 0, 0, 0, 0, "")?;
+    let mut tickers = alerts.iter().map( |alert| alert.ticker.clone() ).collect::<Vec<String>>();
+    tickers.dedup(); // Alerts are ordered by ticker
+    let quotes = Quote::get_market_quotes(&cmdstruct, &tickers).await?;
+    for alert in alerts {
+        let quote = match quotes.get(&alert.ticker) {
+            Some(quote) if alert.triggered_p(quote.price, quote.percent) => quote,
+            Some(_) => continue,
+            None => { warn!("alerts_check no quote for {}", alert.ticker); continue }
+        };
+
+        info!("\x1b[1malert triggered {} at {}", alert, quote.price);
+        let mut cmdstruct =
+            match CmdStruct::new_cmdstruct(env.clone(), now, alert.id, alert.at, alert.at, 0, "") {
+                Ok(cmdstruct) => cmdstruct,
+                e => { glog!(e); continue }
+            };
+        let msg = format!("*Alert:* `{}` {}", alert, quote.format_quote(alert.id)?);
+        let sent = cmdstruct.markdown().push_msg(&msg).send_msg().await;
+        glogd!("alerts_check send_msg =>", sent);
+        if sent.is_ok() {
+            getsql!(env.lock().unwrap().dbconn, "DELETE FROM alerts WHERE rowid=?", alert.rowid)?;
+        }
+    }
+    Ok(())
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn alert_triggers() {
+        let alert = |kind:&str, price:i64, percent:f64| Alert{ rowid: 0, id: 1, at: 1, ticker: "GME".into(), kind: kind.into(),
+            price: Money::from_int(price), percent, name: String::new() };
+        assert_eq!(
+            [alert(">", 200, 0.0), alert("<", 190, 0.0), alert(">%", 0, 5.0), alert("<%", 0, 5.0), alert("%", 0, 5.0), alert("%", 0, 6.0)]
+                .iter().map( |a| a.triggered_p(Money::from_int(190), -5.0) ).collect::<Vec<_>>(),
+            vec![false, true, false, true, true, false]);
+        assert_eq!(format!("{} {}", alert("<", 190, 0.0), alert("%", 0, 2.5)), "GME <190 GME ±2.5%");
+    }
+
+    // Env with GME>9 and GME>11 alerts set and GME at 10
+    fn alerts_env (chat:ChatPlatform) -> Env {
+        let mut envstruct = test_envstruct();
+        envstruct.chat = chat;
+        for (price, time) in &[(9, 1), (11, 2)] {
+            getsql!(envstruct.dbconn, "INSERT INTO alerts VALUES (1, 1, 'GME', '>', ?, 0, ?)", Money::from_int(*price), *time).unwrap();
+        }
+        envstruct.into()
+    }
+
+    #[test]
+    fn sent_alert_deleted() {
+        let env = alerts_env(ChatPlatform::Console);
+        futures::executor::block_on(alerts_check(env.clone(), Instant::now().seconds())).unwrap();
+        let alerts = alerts(&env.lock().unwrap().dbconn, 1).unwrap();
+        assert_eq!(alerts.iter().map( |alert| alert.to_string() ).collect::<Vec<_>>(), vec!["GME >11"]);
+    }
+
+    #[test]
+    fn unsent_alert_kept() {
+        let env = alerts_env(ChatPlatform::Telegram); // Sends fail without an api url
+        futures::executor::block_on(alerts_check(env.clone(), Instant::now().seconds())).unwrap();
+        let alerts = alerts(&env.lock().unwrap().dbconn, 1).unwrap();
+        assert_eq!(alerts.iter().map( |alert| alert.to_string() ).collect::<Vec<_>>(), vec!["GME >9", "GME >11"]);
+    }
+}
@@ -0,0 +1,373 @@
+//! # Portfolio Export and Import
+use crate::*;
+
+pub const EXPORT_VERSION :i64 = 1;
+
+/// One account as read back from an export
+#[derive(Debug, Default, PartialEq)]
+pub struct AccountExport {
+    pub id: i64,
+    pub name: String,
+    pub cash: Vec<(String, Money)>,               // currency, balance
+    pub positions: Vec<(String, Qty, Money)>,     // ticker, qty, price
+    pub orders: Vec<(String, Qty, Money, i64)>,   // ticker, qty, price, time
+    pub exchange: Vec<(String, Qty, Money, i64)>,
+    pub schedules: Vec<(i64, i64, String, String)> // at, time, days, cmd
+}
+
+fn export_account (dbconn:&Connection, id:i64, name:&str) -> Bresult<Value> {
+    let trades = |table:&str| -> Bresult<Vec<Value>> {
+        getsql!(dbconn, format!("SELECT ticker, qty, price, time FROM {} WHERE id=? ORDER BY rowid", table), id)?.iter()
+            .map( |row| Ok(serde_json::json!({
+                "ticker": row.get_string("ticker")?,
+                "qty":    row.get_qty("qty")?.to_string(),
+                "price":  row.get_money("price")?.to_string(),
+                "time":   row.get_i64("time")? })) )
+            .collect()
+    };
+    let mut cash = serde_json::Map::new();
+    for row in getsql!(dbconn, "SELECT 'USD' AS currency, balance FROM accounts WHERE id=? UNION ALL SELECT currency, balance FROM balances WHERE id=?", id, id)? {
+        cash.insert(row.get_string("currency")?, Value::String(row.get_money("balance")?.to_string()));
+    }
+    Ok(serde_json::json!({
+        "id": id,
+        "name": name,
+        "cash": cash,
+        "positions":
+            getsql!(dbconn, "SELECT ticker, qty, price FROM positions WHERE id=? ORDER BY ticker", id)?.iter()
+            .map( |row| Ok(serde_json::json!({
+                "ticker": row.get_string("ticker")?,
+                "qty":    row.get_qty("qty")?.to_string(),
+                "price":  row.get_money("price")?.to_string() })) )
+            .collect::<Bresult<Vec<Value>>>()?,
+        "orders": trades("orders")?,
+        "exchange": trades("exchange")?,
+        "schedules":
+            getsql!(dbconn, "SELECT at, time, days, cmd FROM schedules WHERE id=? ORDER BY rowid", id)?.iter()
+            .map( |row| Ok(serde_json::json!({
+                "at":   row.get_i64("at")?,
+                "time": row.get_i64("time")?,
+                "days": row.get_string("days")?,
+                "cmd":  row.get_string("cmd")? })) )
+            .collect::<Bresult<Vec<Value>>>()?
+    }))
+}
+
+/// Export one account, or every entity's when id is None
+pub fn export_document (dbconn:&Connection, id:Option<i64>, now:i64) -> Bresult<Value> {
+    let accounts =
+        getsql!(dbconn, "SELECT id, name FROM entitys WHERE ?=0 OR id=? ORDER BY id", id.map_or(0, |_| 1), id.unwrap_or(0))?.iter()
+        .map( |row| export_account(dbconn, row.get_i64("id")?, &row.get_string("name")?) )
+        .collect::<Bresult<Vec<Value>>>()?;
+    if id.is_some() && accounts.is_empty() { Err("nothing to export")? }
+    Ok(serde_json::json!({ "tmbot_export": EXPORT_VERSION, "time": now, "accounts": accounts }))
+}
+
+fn csv_field (field:&str) -> String {
+    if field.contains(&[',', '"', '\n'][..]) { format!("\"{}\"", field.replace('"', "\"\"")) } else { field.to_string() }
+}
+
+/// The same export flattened to one CSV row per item
+pub fn export_csv (doc:&Value) -> Bresult<String> {
+    let mut csv = String::from("id,section,key,qty,price,time,days,cmd\n");
+    for account in parse_document(doc)? {
+        let mut row = |fields:&[&str]| {
+            csv += &account.id.to_string();
+            for field in fields { csv.push(','); csv += &csv_field(field) }
+            csv.push('\n');
+        };
+        for (currency, balance) in &account.cash {
+            row(&["cash", currency, "", &balance.to_string(), "", "", ""]) }
+        for (ticker, qty, price) in &account.positions {
+            row(&["position", ticker, &qty.to_string(), &price.to_string(), "", "", ""]) }
+        for (section, trades) in &[("order", &account.orders), ("exchange", &account.exchange)] {
+            for (ticker, qty, price, time) in trades.iter() {
+                row(&[*section, ticker, &qty.to_string(), &price.to_string(), &time.to_string(), "", ""]) }
+        }
+        for (at, time, days, cmd) in &account.schedules {
+            row(&["schedule", &at.to_string(), "", "", &time.to_string(), days, cmd]) }
+    }
+    Ok(csv)
+}
+
+// Split CSV text into records of unquoted fields
+fn csv_records (text:&str) -> Bresult<Vec<Vec<String>>> {
+    let (mut records, mut record, mut field) = (vec![], vec![], String::new());
+    let (mut quoted, mut chars) = (false, text.chars().peekable());
+    while let Some(c) = chars.next() {
+        match (quoted, c) {
+            (true, '"') if Some(&'"') == chars.peek() => { field.push('"'); chars.next(); },
+            (true, '"') => quoted = false,
+            (true, c) => field.push(c),
+            (false, '"') if field.is_empty() => quoted = true,
+            (false, ',') => record.push(::std::mem::take(&mut field)),
+            (false, '\r') => (),
+            (false, '\n') => { record.push(::std::mem::take(&mut field)); records.push(::std::mem::take(&mut record)) },
+            (false, c) => field.push(c)
+        }
+    }
+    if quoted { Err("csv quote not closed")? }
+    if !field.is_empty() || !record.is_empty() { record.push(field); records.push(record) }
+    Ok(records)
+}
+
+/// The export document export_csv flattened, less account names
+pub fn csv_document (text:&str) -> Bresult<Value> {
+    let mut records = csv_records(text)?.into_iter();
+    if records.next().map_or(true, |header| header.join(",") != "id,section,key,qty,price,time,days,cmd") { Err("not a tmbot csv export")? }
+    let mut accounts :Vec<Value> = vec![];
+    for (i, record) in records.enumerate() {
+        let line = i + 2;
+        if 8 != record.len() { Err(format!("csv line {} has {} fields", line, record.len()))? }
+        let id = record[0].parse::<i64>().map_err( |_| format!("csv line {} bad id {:?}", line, record[0]) )?;
+        let time = || record[5].parse::<i64>().map_err( |_| format!("csv line {} bad time {:?}", line, record[5]) );
+        if accounts.last().map_or(true, |account| account["id"] != id) {
+            accounts.push(serde_json::json!({"id": id, "cash": {}, "positions": [], "orders": [], "exchange": [], "schedules": []}));
+        }
+        let account = accounts.last_mut().unwrap();
+        let (key, qty, price) = (&record[2], &record[3], &record[4]);
+        match record[1].as_str() {
+            "cash" => { account["cash"][key] = Value::from(price.as_str()) },
+            "position" => account["positions"].as_array_mut().unwrap().push(serde_json::json!({"ticker": key, "qty": qty, "price": price})),
+            "order" => account["orders"].as_array_mut().unwrap().push(serde_json::json!({"ticker": key, "qty": qty, "price": price, "time": time()?})),
+            "exchange" => account["exchange"].as_array_mut().unwrap().push(serde_json::json!({"ticker": key, "qty": qty, "price": price, "time": time()?})),
+            "schedule" => {
+                let at = key.parse::<i64>().map_err( |_| format!("csv line {} bad at {:?}", line, key) )?;
+                account["schedules"].as_array_mut().unwrap().push(serde_json::json!({"at": at, "time": time()?, "days": record[6], "cmd": record[7]}))
+            },
+            section => Err(format!("csv line {} bad section {:?}", line, section))?
+        }
+    }
+    Ok(serde_json::json!({ "tmbot_export": EXPORT_VERSION, "accounts": accounts }))
+}
+
+/// An export document from its JSON or CSV text
+pub fn read_document (text:&str) -> Bresult<Value> {
+    if text.trim_start().starts_with('{') { Ok(serde_json::from_str(text)?) } else { csv_document(text) }
+}
+
+// Field of an item, naming where it was on failure
+fn field<'a> (item:&'a Value, key:&str, path:&str) -> Bresult<&'a Value> {
+    let value = &item[key];
+    if value.is_null() { Err(format!("{} missing {}", path, key))? }
+    Ok(value)
+}
+
+fn field_i64 (item:&Value, key:&str, path:&str) -> Bresult<i64> {
+    Ok(field(item, key, path)?.as_i64().ok_or(format!("{}.{} not an integer", path, key))?)
+}
+
+fn field_str<'a> (item:&'a Value, key:&str, path:&str) -> Bresult<&'a str> {
+    Ok(field(item, key, path)?.as_str().ok_or(format!("{}.{} not a string", path, key))?)
+}
+
+fn field_ticker (item:&Value, path:&str) -> Bresult<String> {
+    let ticker = field_str(item, "ticker", path)?;
+    if !Regex::new(r"^[A-Za-z0-9^.=_-]{1,32}$")?.is_match(ticker) { Err(format!("{} bad ticker {:?}", path, ticker))? }
+    Ok(ticker.to_string())
+}
+
+fn items<'a> (account:&'a Value, key:&str, path:&str) -> Bresult<&'a [Value]> {
+    match &account[key] {
+        Value::Null => Ok(&[][..]),
+        Value::Array(items) => Ok(items),
+        _ => Err(format!("{}.{} not a list", path, key).into())
+    }
+}
+
+fn parse_trades (account:&Value, key:&str, path:&str) -> Bresult<Vec<(String, Qty, Money, i64)>> {
+    items(account, key, path)?.iter().enumerate()
+        .map( |(i, item)| {
+            let path = format!("{}.{}[{}]", path, key, i);
+            let qty = field_str(item, "qty", &path)?.parse::<Qty>()?;
+            if "exchange" == key && qty.is_zero() { Err(format!("{} zero qty", path))? }
+            Ok((field_ticker(item, &path)?, qty, field_str(item, "price", &path)?.parse::<Money>()?, field_i64(item, "time", &path)?))
+        })
+        .collect()
+}
+
+/// Validate a whole export document, answering its accounts
+pub fn parse_document (doc:&Value) -> Bresult<Vec<AccountExport>> {
+    match doc["tmbot_export"].as_i64() {
+        Some(EXPORT_VERSION) => (),
+        Some(version) => Err(format!("unsupported export version {}", version))?,
+        None => Err("not a tmbot export")?
+    }
+    let accounts = doc["accounts"].as_array().ok_or("accounts not a list")?;
+    let mut parsed :Vec<AccountExport> = vec![];
+    for (i, account) in accounts.iter().enumerate() {
+        let path = format!("accounts[{}]", i);
+        let id = field_i64(account, "id", &path)?;
+        if parsed.iter().any( |a| a.id == id ) { Err(format!("{} duplicate id {}", path, id))? }
+        let mut cash = vec![];
+        for (currency, balance) in account["cash"].as_object().map_or(vec![], |m| m.iter().collect()) {
+            if !Regex::new(r"^[A-Z]{3}$")?.is_match(currency) { Err(format!("{}.cash bad currency {:?}", path, currency))? }
+            let balance = balance.as_str().ok_or(format!("{}.cash.{} not a string", path, currency))?.parse::<Money>()?;
+            cash.push((currency.to_string(), balance));
+        }
+        let mut positions :Vec<(String, Qty, Money)> = vec![];
+        for (j, item) in items(account, "positions", &path)?.iter().enumerate() {
+            let path = format!("{}.positions[{}]", path, j);
+            let (ticker, qty, price) = (field_ticker(item, &path)?, field_str(item, "qty", &path)?.parse::<Qty>()?, field_str(item, "price", &path)?.parse::<Money>()?);
+            if qty.is_zero() { Err(format!("{} zero qty", path))? }
+            if price.is_negative() { Err(format!("{} negative price", path))? }
+            if positions.iter().any( |p| p.0 == ticker ) { Err(format!("{} duplicate ticker {}", path, ticker))? }
+            positions.push((ticker, qty, price));
+        }
+        let schedules =
+            items(account, "schedules", &path)?.iter().enumerate()
+            .map( |(j, item)| {
+                let path = format!("{}.schedules[{}]", path, j);
+                let days = field_str(item, "days", &path)?;
+                if !Regex::new(r"^[mtwhfsu]*$")?.is_match(days) { Err(format!("{} bad days {:?}", path, days))? }
+                let cmd = field_str(item, "cmd", &path)?;
+                if cmd.trim().is_empty() { Err(format!("{} empty cmd", path))? }
+                Ok((field_i64(item, "at", &path)?, field_i64(item, "time", &path)?, days.to_string(), cmd.to_string()))
+            })
+            .collect::<Bresult<Vec<_>>>()?;
+        parsed.push(AccountExport{
+            id,
+            name: account["name"].as_str().unwrap_or("").to_string(),
+            cash,
+            positions,
+            orders: parse_trades(account, "orders", &path)?,
+            exchange: parse_trades(account, "exchange", &path)?,
+            schedules
+        });
+    }
+    Ok(parsed)
+}
+
+/// Replay an account into id, which must hold no positions or orders yet.
+/// Cash is moved to the exported balances and positions opened as single
+/// lots at their basis, all through the ledger.  The account's own stonk
+/// and schedules in its private chat move to id.  Call in a transaction.
+pub fn import_account (dbconn:&Connection, origin:Origin, account:&AccountExport, id:i64) -> Bresult<()> {
+    let own = account.id.to_string();
+    let remap = |ticker:&String| IF!(*ticker == own, id.to_string(), ticker.to_string());
+    for table in &["positions", "orders", "exchange"] {
+        if !getsql!(dbconn, format!("SELECT id FROM {} WHERE id=? LIMIT 1", table), id)?.is_empty() {
+            Err(format!("{} already has {}", id, table))?
+        }
+    }
+    if !account.name.is_empty() {
+        getsql!(dbconn, "INSERT OR IGNORE INTO entitys VALUES (?, ?)", id, &*account.name)?;
+    }
+    getsql!(dbconn, "INSERT OR IGNORE INTO accounts VALUES (?, 0)", id)?;
+    for (currency, balance) in &account.cash {
+        let before =
+            if currency == USD { getsql!(dbconn, "SELECT balance FROM accounts WHERE id=?", id)? }
+            else { getsql!(dbconn, "SELECT balance FROM balances WHERE id=? AND currency=?", id, &**currency)? }
+            .first().map_or(Money::ZERO, |row| row.get_money_or(Money::ZERO, "balance"));
+        if before != *balance { cash_adjust(dbconn, origin, id, currency, *balance - before)?; }
+    }
+    for (ticker, qty, price) in &account.positions {
+        let ticker = &remap(ticker);
+        position_set(dbconn, origin, id, ticker, *qty, *price)?;
+        lots_apply(dbconn, id, ticker, *qty, *price, origin.time)?;
+        borrow_sync(dbconn, id, ticker, origin.time)?;
+    }
+    for (ticker, qty, price, time) in &account.orders {
+        getsql!(dbconn, "INSERT INTO orders VALUES (?, ?, ?, ?, ?)", id, &*remap(ticker), *qty, *price, *time)?;
+    }
+    for (ticker, qty, price, time) in &account.exchange {
+        getsql!(dbconn, "INSERT INTO exchange VALUES (?, ?, ?, ?, ?)", id, &*remap(ticker), *qty, *price, *time)?;
+    }
+    for (at, time, days, cmd) in &account.schedules {
+        getsql!(dbconn, "INSERT INTO schedules VALUES (?, ?, ?, ?, ?)", id, IF!(*at == account.id, id, *at), *time, &**days, &**cmd)?;
+    }
+    Ok(())
+}
+
+/// Import every account of a document under its own id, answering how many
+pub fn import_document (dbconn:&Connection, origin:Origin, doc:&Value) -> Bresult<usize> {
+    let accounts = parse_document(doc)?;
+    dbconn.transaction( |dbconn| {
+        for account in &accounts { import_account(dbconn, origin, account, account.id)? }
+        Ok(accounts.len())
+    })
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn account () -> AccountExport {
+        AccountExport{
+            id: 7,
+            name: "ann".into(),
+            cash: vec![("EUR".into(), Money::from_int(3)), (USD.into(), "1000.25".parse().unwrap())],
+            positions: vec![("GME".into(), "2.5".parse().unwrap(), Money::from_int(40))],
+            orders: vec![("GME".into(), "2.5".parse().unwrap(), Money::from_int(40), 90)],
+            exchange: vec![("7".into(), Qty::from_int(-1), Money::from_int(5), 95)],
+            schedules: vec![(7, 3600, "mf".into(), "/stonks".into())]
+        }
+    }
+
+    // Database holding account()
+    fn dbconn () -> Connection {
+        let dbconn = test_dbconn();
+        dbconn.transaction( |dbconn| import_account(dbconn, Origin::system(100), &account(), 7) ).unwrap();
+        dbconn
+    }
+
+    #[test]
+    fn import_goes_through_ledger() {
+        let dbconn = dbconn();
+        assert!(ledger_check(&dbconn).unwrap().is_empty());
+        assert_eq!(lots(&dbconn, 7, "GME").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn export_round_trips() {
+        let doc = export_document(&dbconn(), Some(7), 100).unwrap();
+        assert_eq!(parse_document(&doc).unwrap(), vec![account()]);
+        let other = test_dbconn();
+        assert_eq!(import_document(&other, Origin::system(100), &doc).unwrap(), 1);
+        assert_eq!(export_document(&other, None, 100).unwrap()["accounts"], doc["accounts"]);
+    }
+
+    #[test]
+    fn import_needs_empty_account() {
+        let dbconn = dbconn();
+        let doc = export_document(&dbconn, Some(7), 100).unwrap();
+        assert!(import_document(&dbconn, Origin::system(100), &doc).is_err());
+        assert_eq!(getsql!(dbconn, "SELECT * FROM schedules").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn csv_row_per_item() {
+        let csv = export_csv(&export_document(&dbconn(), Some(7), 100).unwrap()).unwrap();
+        assert_eq!(csv.lines().count(), 1 + 6);
+        assert!(csv.contains("\n7,schedule,7,,,3600,mf,/stonks\n"));
+    }
+
+    #[test]
+    fn import_moves_own_id() {
+        let dbconn = test_dbconn();
+        dbconn.transaction( |dbconn| import_account(dbconn, Origin::system(100), &account(), 8) ).unwrap();
+        let moved = &parse_document(&export_document(&dbconn, Some(8), 100).unwrap()).unwrap()[0];
+        assert_eq!((&moved.positions[0].0, &moved.exchange[0].0, moved.schedules[0].0), (&"GME".to_string(), &"8".to_string(), 8));
+    }
+
+    #[test]
+    fn csv_round_trips() {
+        let mut doc = export_document(&dbconn(), Some(7), 100).unwrap();
+        doc["accounts"][0]["schedules"][0]["cmd"] = Value::from("/say \"a, b\"\nc");
+        let csv = export_csv(&doc).unwrap();
+        doc["accounts"][0]["name"] = Value::Null;
+        assert_eq!(parse_document(&read_document(&csv).unwrap()).unwrap(), parse_document(&doc).unwrap());
+        assert!(read_document("id,section,key,qty,price,time,days,cmd\n7,stock,GME,1,1,,,\n").is_err());
+    }
+
+    #[test]
+    fn rejects_bad_documents() {
+        let mut doc = export_document(&dbconn(), Some(7), 100).unwrap();
+        doc["accounts"][0]["positions"][0]["qty"] = Value::String("lots".into());
+        assert!(parse_document(&doc).is_err());
+        doc["tmbot_export"] = Value::from(2);
+        assert!(parse_document(&doc).unwrap_err().to_string().contains("version"));
+    }
+}
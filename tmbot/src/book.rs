@@ -0,0 +1,258 @@
+//! # Self-Stonk Exchange Order Book
+use crate::*;
+use ::std::collections::VecDeque;
+
+const TAPE_LENGTH :usize = 32; // Fills remembered per ticker
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Side { Bid, Ask }
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Order {
+    pub id: i64,    // Entity that placed the order
+    pub qty: Qty,   // Remaining quantity, always positive
+    pub price: Money,
+    pub time: i64
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fill {
+    pub buyer: i64,
+    pub seller: i64,
+    pub qty: Qty,
+    pub price: Money, // Always the resting order's price
+    pub time: i64
+}
+
+/// Outcome of submitting an order to the book.
+#[derive(Debug, Default)]
+pub struct Execution {
+    pub fills: Vec<Fill>,
+    pub cancelled: Vec<Order>, // Own resting orders removed by self-trade prevention
+    pub rested: Option<Order>  // Unfilled remainder now in the book
+}
+
+#[derive(Debug)]
+pub struct Book {
+    pub ticker: String,
+    bids: Vec<Order>, // Best first: highest price then oldest
+    asks: Vec<Order>, // Best first: lowest price then oldest
+    tape: VecDeque<Fill>
+}
+
+impl Book {
+    pub fn new (ticker: &str) -> Self {
+        Book { ticker: ticker.to_string(), bids: Vec::new(), asks: Vec::new(), tape: VecDeque::new() }
+    }
+
+    pub fn load (dbconn: &Connection, ticker: &str) -> Bresult<Self> {
+        let mut book = Book::new(ticker);
+        for row in getsql!(dbconn, "SELECT id, qty, price, time FROM exchange WHERE ticker=? ORDER BY time, rowid", ticker)? {
+            let qty = row.get_qty("qty")?;
+            book.rest(
+                IF!(qty.is_negative(), Side::Ask, Side::Bid),
+                row.get_i64("id")?, qty.abs(), row.get_money("price")?, row.get_i64("time")?);
+        }
+        Ok(book)
+    }
+
+    // Replace the ticker's exchange rows with the book's resting orders
+    pub fn save (&self, dbconn: &Connection) -> Bresult<()> {
+        getsql!(dbconn, "DELETE FROM exchange WHERE ticker=?", &*self.ticker)?;
+        for (side, order) in self.bids.iter().map( |o| (Side::Bid, o) ).chain(self.asks.iter().map( |o| (Side::Ask, o) )) {
+            getsql!(dbconn, "INSERT INTO exchange VALUES (?, ?, ?, ?, ?)",
+                order.id, &*self.ticker, IF!(side == Side::Ask, -order.qty, order.qty), order.price, order.time)?;
+        }
+        Ok(())
+    }
+
+    fn side_mut (&mut self, side: Side) -> &mut Vec<Order> {
+        match side { Side::Bid => &mut self.bids, Side::Ask => &mut self.asks }
+    }
+
+    pub fn orders (&self, side: Side) -> &[Order] {
+        match side { Side::Bid => &self.bids, Side::Ask => &self.asks }
+    }
+
+    // Queue an order behind every order with a better or equal price so
+    // arrival order is the time priority
+    fn rest (&mut self, side: Side, id: i64, qty: Qty, price: Money, time: i64) -> Order {
+        let order = Order{ id, qty, price, time };
+        let orders = self.side_mut(side);
+        let idx = orders.iter()
+            .position( |o| IF!(side == Side::Bid, price > o.price, price < o.price) )
+            .unwrap_or(orders.len());
+        orders.insert(idx, order.clone());
+        order
+    }
+
+    /// Match an incoming order against the opposite side, best price first
+    /// then oldest, filling at the resting order's price.  Resting orders
+    /// owned by the same id are cancelled rather than traded against.  Any
+    /// remainder rests in the book.
+    pub fn submit (&mut self, id: i64, side: Side, mut qty: Qty, price: Money, time: i64) -> Execution {
+        let mut exec = Execution::default();
+        let contra = IF!(side == Side::Bid, Side::Ask, Side::Bid);
+        while qty.is_positive() {
+            let best = match self.side_mut(contra).first_mut() {
+                Some(best) => best,
+                None => break
+            };
+            let crosses = IF!(side == Side::Bid, best.price <= price, price <= best.price);
+            if !crosses { break }
+            if best.id == id { // Self-trade prevention
+                exec.cancelled.push(self.side_mut(contra).remove(0));
+                continue
+            }
+            let xqty = qty.min(best.qty);
+            let fill = Fill {
+                buyer:  IF!(side == Side::Bid, id, best.id),
+                seller: IF!(side == Side::Bid, best.id, id),
+                qty:    xqty,
+                price:  best.price,
+                time
+            };
+            best.qty -= xqty;
+            if !best.qty.is_positive() { self.side_mut(contra).remove(0); }
+            qty -= xqty;
+            self.tape.push_front(fill.clone());
+            self.tape.truncate(TAPE_LENGTH);
+            exec.fills.push(fill);
+        }
+        if qty.is_positive() {
+            exec.rested = Some(self.rest(side, id, qty, price, time));
+        }
+        exec
+    }
+
+    /// Remove id's resting orders, optionally only on one side and/or at one price.
+    pub fn cancel (&mut self, id: i64, side: Option<Side>, price: Option<Money>) -> Vec<Order> {
+        let mut cancelled = Vec::new();
+        for s in [Side::Bid, Side::Ask].iter() {
+            if side.map_or(false, |side| side != *s) { continue }
+            let (keep, gone) : (Vec<Order>, Vec<Order>) =
+                self.side_mut(*s).drain(..)
+                .partition( |o| o.id != id || price.map_or(false, |p| p != o.price) );
+            *self.side_mut(*s) = keep;
+            cancelled.extend(gone);
+        }
+        cancelled
+    }
+
+    // Quantity id has resting on a side
+    pub fn open_qty (&self, id: i64, side: Side) -> Qty {
+        self.orders(side).iter().filter( |o| o.id == id ).map( |o| o.qty ).sum()
+    }
+
+    // Cash id has committed to resting bids
+    pub fn open_bid_value (&self, id: i64) -> Money {
+        self.bids.iter().filter( |o| o.id == id ).map( |o| o.qty * o.price ).sum()
+    }
+
+    /// Aggregated (price, qty) levels, best first, for each side.
+    pub fn depth (&self, levels: usize) -> (Vec<(Money, Qty)>, Vec<(Money, Qty)>) {
+        let aggregate = |orders: &[Order]| {
+            let mut depth :Vec<(Money, Qty)> = Vec::new();
+            for o in orders {
+                if depth.last().map_or(false, |level| level.0 == o.price) {
+                    let level = depth.last_mut().unwrap();
+                    level.1 += o.qty;
+                } else if depth.len() < levels {
+                    depth.push((o.price, o.qty));
+                } else {
+                    break
+                }
+            }
+            depth
+        };
+        (aggregate(&self.bids), aggregate(&self.asks))
+    }
+
+    // Most recent fills first
+    pub fn tape (&self) -> impl Iterator<Item=&Fill> {
+        self.tape.iter()
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn q (f:f64) -> Qty { Qty::from_f64(f) }
+    fn m (f:f64) -> Money { Money::from_f64(f) }
+
+    fn book () -> Book {
+        let mut book = Book::new("1");
+        book.submit(10, Side::Ask, q(5.0), m(3.0), 1);
+        book.submit(11, Side::Ask, q(2.0), m(2.0), 2);
+        book.submit(12, Side::Ask, q(4.0), m(2.0), 3);
+        book.submit(13, Side::Bid, q(1.0), m(1.0), 4);
+        book
+    }
+
+    #[test]
+    fn no_cross_rests() {
+        let mut book = book();
+        let exec = book.submit(20, Side::Bid, q(1.0), m(1.5), 5);
+        assert!(exec.fills.is_empty());
+        assert_eq!(exec.rested.unwrap().qty, q(1.0));
+        assert_eq!(book.depth(5), (vec![(m(1.5), q(1.0)), (m(1.0), q(1.0))], vec![(m(2.0), q(6.0)), (m(3.0), q(5.0))]));
+    }
+
+    #[test]
+    fn crossing_price_time_priority() {
+        let mut book = book();
+        let exec = book.submit(20, Side::Bid, q(7.0), m(3.0), 5);
+        assert_eq!(exec.fills, vec![
+            Fill{buyer:20, seller:11, qty:q(2.0), price:m(2.0), time:5}, // Oldest at best price first
+            Fill{buyer:20, seller:12, qty:q(4.0), price:m(2.0), time:5},
+            Fill{buyer:20, seller:10, qty:q(1.0), price:m(3.0), time:5}]);
+        assert!(exec.rested.is_none());
+        assert_eq!(book.depth(5).1, vec![(m(3.0), q(4.0))]);
+        assert_eq!(book.tape().next().unwrap().seller, 10);
+    }
+
+    #[test]
+    fn partial_fill_rests_remainder() {
+        let mut book = book();
+        let exec = book.submit(20, Side::Ask, q(3.0), m(0.5), 5);
+        assert_eq!(exec.fills, vec![Fill{buyer:13, seller:20, qty:q(1.0), price:m(1.0), time:5}]);
+        let rested = exec.rested.unwrap();
+        assert_eq!((rested.qty, rested.price), (q(2.0), m(0.5)));
+        assert_eq!(book.depth(1).1, vec![(m(0.5), q(2.0))]);
+        assert!(book.orders(Side::Bid).is_empty());
+    }
+
+    #[test]
+    fn partial_fill_of_resting_order() {
+        let mut book = book();
+        let exec = book.submit(20, Side::Bid, q(0.5), m(2.0), 5);
+        assert_eq!(exec.fills, vec![Fill{buyer:20, seller:11, qty:q(0.5), price:m(2.0), time:5}]);
+        assert_eq!(book.orders(Side::Ask)[0].qty, q(1.5));
+        assert_eq!(book.orders(Side::Ask)[0].id, 11); // Keeps its time priority
+    }
+
+    #[test]
+    fn self_trade_prevention() {
+        let mut book = book();
+        let exec = book.submit(11, Side::Bid, q(3.0), m(2.0), 5);
+        assert_eq!(exec.cancelled.len(), 1);
+        assert_eq!(exec.cancelled[0].id, 11);
+        assert_eq!(exec.fills, vec![Fill{buyer:11, seller:12, qty:q(3.0), price:m(2.0), time:5}]);
+        assert_eq!(book.open_qty(11, Side::Ask), Qty::ZERO);
+        assert_eq!(book.open_qty(12, Side::Ask), q(1.0));
+    }
+
+    #[test]
+    fn cancel_orders() {
+        let mut book = book();
+        book.submit(10, Side::Ask, q(1.0), m(4.0), 5);
+        assert_eq!(book.cancel(10, None, Some(m(4.0))).len(), 1);
+        assert_eq!(book.open_qty(10, Side::Ask), q(5.0));
+        assert_eq!(book.cancel(10, Some(Side::Ask), None).len(), 1);
+        assert!(book.cancel(10, None, None).is_empty());
+        assert_eq!(book.depth(5).1, vec![(m(2.0), q(6.0))]);
+    }
+}
@@ -0,0 +1,282 @@
+//! # Market Calendar
+use crate::*;
+use ::chrono::{Datelike, NaiveDate, Offset, TimeZone, Timelike, Weekday as Wday};
+use ::chrono_tz::{Tz, America::{New_York, Toronto}, Europe::London};
+
+const DELAYED_ORDER_SECS :i64 = 300;
+
+pub const NYSE :&str = "NYQ";
+
+/// An exchange's hours, in minutes after local midnight
+#[derive(Debug)]
+pub struct ExchangeHours {
+    pub name: &'static str,
+    pub codes: &'static [&'static str], // Yahoo exchange codes
+    pub tz: Tz,
+    pub pre: u32,         // Pre-market open
+    pub open: u32,        // Regular open
+    pub close: u32,       // Regular close
+    pub early_close: u32, // Regular close on early close days
+    pub after_hours: u32, // Minutes of trading after the close
+    holidays: fn(i32) -> Vec<NaiveDate>,
+    early_close_p: fn(NaiveDate) -> bool
+}
+
+pub static EXCHANGES :[ExchangeHours; 3] = [
+    ExchangeHours{
+        name: "NYSE", codes: &["NYQ", "NYS", "NMS", "NGM", "NCM", "NAS", "NIM", "ASE", "PCX", "BTS", "PNK", "OPR"],
+        tz: New_York, pre: 4*60, open: 9*60+30, close: 16*60, early_close: 13*60, after_hours: 4*60,
+        holidays: us_holidays, early_close_p: us_early_close_p },
+    ExchangeHours{
+        name: "LSE", codes: &["LSE", "IOB"],
+        tz: London, pre: 8*60, open: 8*60, close: 16*60+30, early_close: 12*60+30, after_hours: 0,
+        holidays: uk_holidays, early_close_p: uk_early_close_p },
+    ExchangeHours{
+        name: "TSX", codes: &["TOR", "VAN", "CNQ", "NEO"],
+        tz: Toronto, pre: 9*60+30, open: 9*60+30, close: 16*60, early_close: 13*60, after_hours: 60,
+        holidays: ca_holidays, early_close_p: ca_early_close_p },
+];
+
+/// The exchange a Yahoo exchange code trades on, US hours if unknown
+pub fn exchange_hours (code:&str) -> &'static ExchangeHours {
+    EXCHANGES.iter().find( |e| e.codes.contains(&code) ).unwrap_or(&EXCHANGES[0])
+}
+
+/// One trading day on an exchange
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Session {
+    pub pre: i64,   // Pre-market open
+    pub open: i64,  // Regular open
+    pub close: i64, // Regular close
+    pub post: i64   // After hours close plus delayed orders
+}
+
+////////////////////////////////////////
+
+fn weekend_p (date:NaiveDate) -> bool {
+    matches!(date.weekday(), Wday::Sat | Wday::Sun)
+}
+
+fn nth_weekday (year:i32, month:u32, weekday:Wday, n:u32) -> Option<NaiveDate> {
+    let first = NaiveDate::from_ymd_opt(year, month, 1)?;
+    let offset = (7 + weekday.num_days_from_monday() - first.weekday().num_days_from_monday()) % 7;
+    NaiveDate::from_ymd_opt(year, month, 1 + offset + 7 * (n - 1))
+}
+
+fn last_weekday (year:i32, month:u32, weekday:Wday) -> Option<NaiveDate> {
+    (1..=5).rev().find_map( |n| nth_weekday(year, month, weekday, n) )
+}
+
+// Anonymous Gregorian algorithm
+fn easter (year:i32) -> Option<NaiveDate> {
+    let (a, b, c) = (year % 19, year / 100, year % 100);
+    let (d, e, f) = (b / 4, b % 4, (b + 8) / 25);
+    let g = (b - f + 1) / 3;
+    let h = (19*a + b - d - g + 15) % 30;
+    let (i, k) = (c / 4, c % 4);
+    let l = (32 + 2*e + 2*i - h - k) % 7;
+    let m = (a + 11*h + 22*l) / 451;
+    NaiveDate::from_ymd_opt(year, ((h + l - 7*m + 114) / 31) as u32, ((h + l - 7*m + 114) % 31 + 1) as u32)
+}
+
+fn good_friday (year:i32) -> Option<NaiveDate> {
+    easter(year)?.pred_opt()?.pred_opt()
+}
+
+// US:  Saturday holidays close Friday, Sunday holidays close Monday
+fn observed (date:NaiveDate) -> Option<NaiveDate> {
+    match date.weekday() {
+        Wday::Sat => date.pred_opt(),
+        Wday::Sun => date.succ_opt(),
+        _ => Some(date)
+    }
+}
+
+// UK and Canada:  weekend holidays move to the next weekday not already a holiday
+fn substitute (dates:Vec<Option<NaiveDate>>) -> Vec<NaiveDate> {
+    let mut days :Vec<NaiveDate> = Vec::new();
+    for mut date in dates.into_iter().flatten() {
+        while weekend_p(date) || days.contains(&date) {
+            match date.succ_opt() { Some(next) => date = next, None => break }
+        }
+        days.push(date);
+    }
+    days
+}
+
+fn closures (year:i32, dates:&[(i32, u32, u32)]) -> impl Iterator<Item=NaiveDate> + '_ {
+    dates.iter().filter( move |d| d.0 == year ).filter_map( |d| NaiveDate::from_ymd_opt(d.0, d.1, d.2) )
+}
+
+fn us_holidays (year:i32) -> Vec<NaiveDate> {
+    let ymd = |month, day| NaiveDate::from_ymd_opt(year, month, day);
+    vec![
+        ymd(1, 1).filter( |d| d.weekday() != Wday::Sat ).and_then(observed), // Not moved into the old year
+        nth_weekday(year, 1, Wday::Mon, 3),  // Martin Luther King Jr. Day
+        nth_weekday(year, 2, Wday::Mon, 3),  // Washington's Birthday
+        good_friday(year),
+        last_weekday(year, 5, Wday::Mon),    // Memorial Day
+        IF!(2022 <= year, ymd(6, 19).and_then(observed), None), // Juneteenth
+        ymd(7, 4).and_then(observed),        // Independence Day
+        nth_weekday(year, 9, Wday::Mon, 1),  // Labor Day
+        nth_weekday(year, 11, Wday::Thu, 4), // Thanksgiving
+        ymd(12, 25).and_then(observed)]      // Christmas
+        .into_iter()
+        .flatten()
+        .chain(closures(year, &[
+            (2012, 10, 29), (2012, 10, 30), // Hurricane Sandy
+            (2018, 12, 5),                  // President George H. W. Bush
+            (2025, 1, 9)]))                 // President Jimmy Carter
+        .collect()
+}
+
+// July 3rd, the day after Thanksgiving and Christmas Eve
+fn us_early_close_p (date:NaiveDate) -> bool {
+    (date.month(), date.day()) == (7, 3)
+        || (date.month(), date.day()) == (12, 24)
+        || nth_weekday(date.year(), 11, Wday::Thu, 4).and_then( |d| d.succ_opt() ) == Some(date)
+}
+
+fn uk_holidays (year:i32) -> Vec<NaiveDate> {
+    let ymd = |month, day| NaiveDate::from_ymd_opt(year, month, day);
+    let mut holidays = substitute(vec![ymd(1, 1), ymd(12, 25), ymd(12, 26)]); // New Year's, Christmas, Boxing Day
+    holidays.extend(vec![
+        good_friday(year),
+        easter(year).and_then( |d| d.succ_opt() ), // Easter Monday
+        IF!(year == 2020, ymd(5, 8), nth_weekday(year, 5, Wday::Mon, 1)), // Early May, VE Day in 2020
+        IF!(year == 2022, ymd(6, 2), last_weekday(year, 5, Wday::Mon)),   // Spring, the Jubilee in 2022
+        last_weekday(year, 8, Wday::Mon)]   // Summer
+        .into_iter()
+        .flatten()
+        .chain(closures(year, &[
+            (2022, 6, 3),  // Platinum Jubilee
+            (2022, 9, 19), // Queen Elizabeth II's funeral
+            (2023, 5, 8)])));  // Coronation of King Charles III
+    holidays
+}
+
+// Christmas Eve and New Year's Eve
+fn uk_early_close_p (date:NaiveDate) -> bool {
+    (date.month(), date.day()) == (12, 24) || (date.month(), date.day()) == (12, 31)
+}
+
+fn ca_holidays (year:i32) -> Vec<NaiveDate> {
+    let ymd = |month, day| NaiveDate::from_ymd_opt(year, month, day);
+    let mut holidays = substitute(vec![ymd(1, 1), ymd(7, 1), ymd(12, 25), ymd(12, 26)]); // New Year's, Canada Day, Christmas, Boxing Day
+    holidays.extend(vec![
+        nth_weekday(year, 2, Wday::Mon, 3),  // Family Day
+        good_friday(year),
+        (18..=24).rev().filter_map( |day| ymd(5, day) ).find( |d| d.weekday() == Wday::Mon ), // Victoria Day
+        nth_weekday(year, 8, Wday::Mon, 1),  // Civic Holiday
+        nth_weekday(year, 9, Wday::Mon, 1),  // Labour Day
+        nth_weekday(year, 10, Wday::Mon, 2)] // Thanksgiving
+        .into_iter()
+        .flatten());
+    holidays
+}
+
+// Christmas Eve
+fn ca_early_close_p (date:NaiveDate) -> bool {
+    (date.month(), date.day()) == (12, 24)
+}
+
+////////////////////////////////////////
+
+impl ExchangeHours {
+    /// Full day closures in a year, in order
+    pub fn holidays (&self, year:i32) -> Vec<NaiveDate> {
+        let mut holidays = (self.holidays)(year);
+        holidays.sort();
+        holidays
+    }
+
+    pub fn trading_day_p (&self, date:NaiveDate) -> bool {
+        !weekend_p(date) && !(self.holidays)(date.year()).contains(&date)
+    }
+
+    pub fn early_close_p (&self, date:NaiveDate) -> bool {
+        self.trading_day_p(date) && (self.early_close_p)(date)
+    }
+
+    fn local_time (&self, date:NaiveDate, minutes:u32) -> Bresult<i64> {
+        Ok(self.tz
+            .from_local_datetime(&date.and_hms_opt(minutes / 60, minutes % 60, 0).ok_or("bad time of day")?)
+            .single()
+            .ok_or_else( || format!("no single {} time {} {}", self.name, date, minutes) )?
+            .timestamp())
+    }
+
+    /// A trading day's session times
+    pub fn session (&self, date:NaiveDate) -> Bresult<Session> {
+        let close = IF!(self.early_close_p(date), self.early_close, self.close);
+        Ok(Session{
+            pre:   self.local_time(date, self.pre)?,
+            open:  self.local_time(date, self.open)?,
+            close: self.local_time(date, close)?,
+            post:  self.local_time(date, close + self.after_hours)? + DELAYED_ORDER_SECS })
+    }
+
+    /// The session trading now or the last one to have traded.  A trading
+    /// day starts at the pre-market open so before it the previous day's counts.
+    pub fn most_recent_session (&self, now:i64) -> Bresult<Session> {
+        let local = self.tz.timestamp_opt(now, 0).single().ok_or("bad time")?.naive_local();
+        let mut date = local.date();
+        if local.hour() * 60 + local.minute() < self.pre { date = date.pred_opt().ok_or("bad date")? }
+        while !self.trading_day_p(date) { date = date.pred_opt().ok_or("bad date")? }
+        let session = self.session(date)?;
+        info!("most_recent_session  {}  now:{}  date:{}  {:?}", self.name, now, date, session);
+        Ok(session)
+    }
+
+    /// Between the most recent session's pre-market open and after hours close
+    pub fn trading_p (&self, now:i64) -> Bresult<bool> {
+        let session = self.most_recent_session(now)?;
+        Ok(session.pre <= now && now <= session.post)
+    }
+}
+
+/// 1 when New York is on daylight saving time, else 0
+pub fn dst_hours_adjust (now:i64) -> i64 {
+    New_York.timestamp_opt(now, 0).single()
+        .map_or(0, |t| (t.offset().fix().local_minus_utc() as i64 + 5 * 3600) / 3600)
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn market_calendar() {
+        let ymd = |y, m, d| NaiveDate::from_ymd_opt(y, m, d).unwrap();
+        let (nyse, lse, tsx) = (exchange_hours("NMS"), exchange_hours("LSE"), exchange_hours("TOR"));
+        assert_eq!(exchange_hours("???").name, "NYSE");
+        assert_eq!(nyse.holidays(2024), vec![
+            ymd(2024, 1, 1), ymd(2024, 1, 15), ymd(2024, 2, 19), ymd(2024, 3, 29), ymd(2024, 5, 27), ymd(2024, 6, 19),
+            ymd(2024, 7, 4), ymd(2024, 9, 2), ymd(2024, 11, 28), ymd(2024, 12, 25)]);
+        assert!(!nyse.holidays(2022).contains(&ymd(2021, 12, 31)) && nyse.holidays(2022).contains(&ymd(2022, 12, 26)));
+        assert_eq!(lse.holidays(2022)[..3], [ymd(2022, 1, 3), ymd(2022, 4, 15), ymd(2022, 4, 18)]);
+        assert_eq!(lse.holidays(2022)[8..], [ymd(2022, 12, 26), ymd(2022, 12, 27)]);
+        assert_eq!(tsx.holidays(2024), vec![
+            ymd(2024, 1, 1), ymd(2024, 2, 19), ymd(2024, 3, 29), ymd(2024, 5, 20), ymd(2024, 7, 1),
+            ymd(2024, 8, 5), ymd(2024, 9, 2), ymd(2024, 10, 14), ymd(2024, 12, 25), ymd(2024, 12, 26)]);
+        assert!(nyse.early_close_p(ymd(2024, 11, 29)) && nyse.early_close_p(ymd(2024, 7, 3)) && !nyse.early_close_p(ymd(2023, 7, 5)));
+
+        // Pre-market opens 2024-07-01 0800Z EDT and 2024-01-02 0900Z EST
+        assert_eq!(nyse.session(ymd(2024, 7, 1)).unwrap().pre, 1719820800);
+        assert_eq!(nyse.session(ymd(2024, 7, 1)).unwrap().open, 1719840600);
+        assert_eq!(nyse.session(ymd(2024, 1, 2)).unwrap().pre, 1704186000);
+        assert_eq!(nyse.session(ymd(2024, 11, 29)).unwrap().close, 1732903200); // 1PM EST
+        assert_eq!(lse.session(ymd(2024, 7, 1)).unwrap().open, 1719817200);     // 8AM BST
+        assert_eq!(lse.session(ymd(2024, 12, 24)).unwrap().post, 1735043400 + DELAYED_ORDER_SECS);
+        assert_eq!(tsx.session(ymd(2024, 1, 2)).unwrap().open, 1704205800);
+        assert!(!lse.trading_p(1719817200 - 60).unwrap() && lse.trading_p(1719817200).unwrap() && !nyse.trading_p(1719817200).unwrap());
+        assert_eq!((dst_hours_adjust(1719820800), dst_hours_adjust(1704186000)), (1, 0));
+
+        // Good Friday 2024-03-29 noon EDT falls back to Thursday's session
+        let thursday = nyse.session(ymd(2024, 3, 28)).unwrap();
+        assert_eq!(nyse.most_recent_session(1711728000).unwrap(), thursday);
+        assert!(thursday.post < 1711728000);
+    }
+}
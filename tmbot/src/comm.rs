@@ -1,9 +1,9 @@
-//! # Telegram Communication
+//! # Chat Communication (Telegram and console)
 use crate::*;
 //use util::*;
-use ::std::{fmt, time::{Duration} };
-use ::openssl::ssl::{SslConnector, SslMethod, SslConnectorBuilder};
-use ::actix_web::{ client::{Client, Connector} };
+use ::std::{fmt, io::Write, sync::atomic::{AtomicI64, Ordering as AtomicOrdering} };
+use ::futures::future::{LocalBoxFuture, FutureExt};
+use ::openssl::ssl::{SslConnector, SslConnectorBuilder};
 
 const TELEGRAM_KEY_PEM: &str = "tmbot/key.pem";
 const TELEGRAM_CERT_PEM: &str = "tmbot/cert.pem";
@@ -18,10 +18,9 @@ pub fn new_ssl_acceptor_builder() -> Bresult<SslAcceptorBuilder> {
 
 pub fn new_ssl_connector_builder() -> Bresult<SslConnectorBuilder> {
     let mut ssl_connector_builder = SslConnector::builder(SslMethod::tls())?;
-    ssl_connector_builder.set_private_key_file(TELEGRAM_KEY_PEM, openssl::ssl::SslFiletype::PEM)?;
+    ssl_connector_builder.set_private_key_file(TELEGRAM_KEY_PEM, SslFiletype::PEM)?;
     ssl_connector_builder.set_certificate_chain_file(TELEGRAM_CERT_PEM)?;
     Ok(ssl_connector_builder)
-
 }
 
 pub trait MsgDetails {
@@ -39,40 +38,71 @@ pub struct MsgCmd<'a> {
     pub msg: &'a str
 }
 
+/// An incoming chat message, independent of the platform that delivered it.
+#[derive(Debug)]
+pub struct Update {
+    pub id: i64, // Entity who sent msg
+    pub at: i64, // Channel (group/entity) msg sent to
+    pub to: i64, // Entity replied to ('at' if non-reply)
+    pub message_id: i64,
+    pub message: String
+}
+
+/// A chat platform the bot can listen and talk on.
+pub trait ChatTransport: fmt::Debug {
+    // Send a new message returning its message id
+    fn send<'a> (&'a self, at: i64, markdown: bool, msg: &'a str) -> LocalBoxFuture<'a, Bresult<i64>>;
+    // Replace an existing message's text returning its message id
+    fn edit<'a> (&'a self, at: i64, markdown: bool, msg_id: i64, msg: &'a str) -> LocalBoxFuture<'a, Bresult<i64>>;
+    // Send a text file returning its message id
+    fn send_document<'a> (&'a self, at: i64, filename: &'a str, content: &'a str) -> LocalBoxFuture<'a, Bresult<i64>>;
+    // Parse the platform's inbound payload into an Update
+    fn parse_update (&self, body: &[u8]) -> Bresult<Update>;
+}
+
+// Send or edit (if it has a msg_id) a message over any transport.
+pub async fn send_msg<'a> (transport: &dyn ChatTransport, obj: &'a impl MsgDetails) -> Bresult<MsgCmd<'a>> {
+    let mut mc = MsgCmd {
+        at:       obj.at(),
+        markdown: obj.markdown(),
+        msg_id:   obj.msg_id(),
+        msg:      obj.msg()
+    };
+    info!("{:?} {:?}", transport, mc);
+    mc.msg_id = Some(match mc.msg_id {
+        Some(msg_id) => transport.edit(mc.at, mc.markdown, msg_id, mc.msg).await?,
+        None => transport.send(mc.at, mc.markdown, mc.msg).await?
+    });
+    Ok(mc)
+}
+
+/// Which ChatTransport new CmdStructs talk over.  Transports aren't shared
+/// between threads, so EnvStruct keeps this and builds one per CmdStruct.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChatPlatform {
+    Telegram,
+    Console
+}
+
 ////////////////////////////////////////
 
+#[derive(Debug)]
 pub struct Telegram {
-    client: Client,
+    http: Arc<HttpService>,
     url_api: String
 }
 
 impl Telegram {
-    pub fn new (url_api: String) -> Bresult<Self> {
-        let ssl_connector_builder = new_ssl_connector_builder()?;
-        let client =
-            Client::builder()
-                .connector(
-                    Connector::new()
-                        .ssl( ssl_connector_builder.build() )
-                        .timeout(Duration::new(90,0))
-                        .finish())
-                .finish();
-        Ok(Telegram { client, url_api })
+    pub fn new (http: Arc<HttpService>, url_api: String) -> Self {
+        Telegram { http, url_api }
     }
 }
 
 impl Telegram {
-    pub async fn send_msg<'a> (&self, obj: &'a impl MsgDetails) -> Bresult<MsgCmd<'a>> {
-        let mut mc = MsgCmd {
-            at:       obj.at(),
-            markdown: obj.markdown(),
-            msg_id:   obj.msg_id(),
-            msg:      obj.msg()
-        };
-        info!("Telegram {:?}", mc);
-        let chat_id = mc.at.to_string();
-        let text = if mc.markdown {
-            mc.msg // Quick and dirty uni/url decode
+    async fn request (&self, at: i64, markdown: bool, msg_id: Option<i64>, msg: &str) -> Bresult<i64> {
+        let chat_id = at.to_string();
+        let text = if markdown {
+            msg // Quick and dirty uni/url decode
             .replacen("%20", " ", 10000)
             .replacen("%27", "'", 10000)
             .replacen("%28", "(", 10000)
@@ -93,7 +123,7 @@ impl Telegram {
             .replacen("'", "\\'", 10000)
             .replacen("!", "\\!", 10000)
             .replacen("|", "\\|", 10000)
-        } else { mc.msg.to_string() };
+        } else { msg.to_string() };
 
         let mut query = vec![
             ["chat_id", &chat_id],
@@ -102,47 +132,130 @@ impl Telegram {
         ];
 
         let mut edit_msg_id_str = String::new(); // Str must exist as long as query has ref
-        if let Some(edit_msg_id) = mc.msg_id {
+        if let Some(edit_msg_id) = msg_id {
             edit_msg_id_str.push_str(&edit_msg_id.to_string());
             query.push( ["message_id", &edit_msg_id_str] )
         }
 
-        if mc.markdown { query.push(["parse_mode", "MarkdownV2"]) }
+        if markdown { query.push(["parse_mode", "MarkdownV2"]) }
 
         let theurl =
             format!("{}/{}",
                 self.url_api,
-                if mc.msg_id.is_some() { "editmessagetext" } else { "sendmessage"} );
+                if msg_id.is_some() { "editmessagetext" } else { "sendmessage"} );
 
         info!("Telegram <= \x1b[1;36m{:?} {:?}", theurl, query);
 
-        let mut send_client_request =
-            self.client
-                .get(theurl)
-                .header("User-Agent", "Actix-web TMBot/0.1.0")
-                .timeout(Duration::new(90,0))
-                .query(&query)
-                .unwrap()
-                .send().await?;
-
-        ginfod!("Telegram => ", &send_client_request);
-        let body = send_client_request.body().await;
+        let body = self.http.send(&theurl, &query, &[("User-Agent", "Actix-web TMBot/0.1.0")]).await;
         ginfod!("Telegram => \x1b[36m", body);
 
         // Return the new message's id
-        mc.msg_id = Some( getin_i64(
+        Ok(getin_i64(
             &bytes2json(&body?)?,
-            &["result", "message_id"])? );
-        Ok(mc) 
+            &["result", "message_id"])? )
+    }
+}
+
+impl Telegram {
+    // sendDocument only takes uploads as multipart/form-data
+    async fn upload (&self, at: i64, filename: &str, content: &str) -> Bresult<i64> {
+        let boundary = format!("tmbot{:016x}", ::rand::random::<u64>());
+        let body = format!(
+            "--{0}\r\nContent-Disposition: form-data; name=\"chat_id\"\r\n\r\n{1}\r\n\
+             --{0}\r\nContent-Disposition: form-data; name=\"disable_notification\"\r\n\r\ntrue\r\n\
+             --{0}\r\nContent-Disposition: form-data; name=\"document\"; filename=\"{2}\"\r\nContent-Type: application/octet-stream\r\n\r\n{3}\r\n\
+             --{0}--\r\n",
+            boundary, at, filename.replace('"', ""), content);
+        let content_type = format!("multipart/form-data; boundary={}", boundary);
+        let theurl = format!("{}/senddocument", self.url_api);
+        info!("Telegram <= \x1b[1;36m{:?} {:?} {} bytes", theurl, filename, content.len());
+        let body = self.http.post(&theurl, &[("User-Agent", "Actix-web TMBot/0.1.0"), ("Content-Type", content_type.as_str())], body.into()).await;
+        ginfod!("Telegram => \x1b[36m", body);
+        Ok(getin_i64(
+            &bytes2json(&body?)?,
+            &["result", "message_id"])? )
+    }
+}
+
+impl ChatTransport for Telegram {
+    fn send<'a> (&'a self, at: i64, markdown: bool, msg: &'a str) -> LocalBoxFuture<'a, Bresult<i64>> {
+        self.request(at, markdown, None, msg).boxed_local()
+    }
+    fn edit<'a> (&'a self, at: i64, markdown: bool, msg_id: i64, msg: &'a str) -> LocalBoxFuture<'a, Bresult<i64>> {
+        self.request(at, markdown, Some(msg_id), msg).boxed_local()
+    }
+    fn send_document<'a> (&'a self, at: i64, filename: &'a str, content: &'a str) -> LocalBoxFuture<'a, Bresult<i64>> {
+        self.upload(at, filename, content).boxed_local()
     }
+    // Telegram Bot API update JSON
+    fn parse_update (&self, body: &[u8]) -> Bresult<Update> {
+        let json: Value = bytes2json(body)?;
+        let inline_query = &json["inline_query"];
+        let edited_message = &json["edited_message"];
+        let message = if edited_message.is_object() { edited_message } else { &json["message"] };
+        let message_id = getin_i64(message, &["message_id"]).unwrap_or(0);
+        if inline_query.is_object() { // Inline queries are DMs so no other associated channels
+            let id = getin_i64(inline_query, &["from", "id"])?;
+            let message = getin_str(inline_query, &["query"])?.to_string();
+            Ok(Update{id, at:id, to:id, message_id, message})
+        } else if message.is_object() { // An incoming message could be a reply or normal.
+            let id = getin_i64(message, &["from", "id"])?;
+            let at = getin_i64(message, &["chat", "id"])?;
+            let to = getin_i64_or(at, &message, &["reply_to_message", "from", "id"]);
+            let message = getin_str(message, &["text"])?.to_string();
+            Ok(Update{id, at, to, message_id, message}) // Normal message
+        } else { Err("Nothing to do.")? }
+    }
+}
+
+////////////////////////////////////////
+
+static CONSOLE_MSG_ID: AtomicI64 = AtomicI64::new(1);
+
+/// Line based stdin/stdout transport for driving the bot from a terminal.
+/// Input lines are "text" sent by the default id (TMBOT_CONSOLE_ID or 0)
+/// or "id> text" and "id:at> text" to speak as someone else or in a channel.
+#[derive(Debug)]
+pub struct Console {
+    id: i64
 }
 
-impl fmt::Debug for Telegram {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
-    {
-        f.debug_struct("Telegram")
-         .field("client", &format!("{{Client Object}}"))
-         .field("url_api", &self.url_api)
-         .finish()
+impl Console {
+    pub fn new () -> Self {
+        Console {
+            id: env::var("TMBOT_CONSOLE_ID").ok()
+                .and_then( |id| id.parse::<i64>().ok() )
+                .unwrap_or(0)
+        }
+    }
+    fn print (&self, at: i64, msg_id: i64, edited: bool, msg: &str) -> Bresult<i64> {
+        let stdout = std::io::stdout();
+        let mut out = stdout.lock();
+        writeln!(out, "\x1b[1;32m[{}#{}{}]\x1b[0m {}", at, msg_id, IF!(edited, "*", ""), msg)?;
+        out.flush()?;
+        Ok(msg_id)
+    }
+}
+
+impl ChatTransport for Console {
+    fn send<'a> (&'a self, at: i64, _markdown: bool, msg: &'a str) -> LocalBoxFuture<'a, Bresult<i64>> {
+        let msg_id = CONSOLE_MSG_ID.fetch_add(1, AtomicOrdering::SeqCst);
+        futures::future::ready(self.print(at, msg_id, false, msg)).boxed_local()
     }
-}
\ No newline at end of file
+    fn edit<'a> (&'a self, at: i64, _markdown: bool, msg_id: i64, msg: &'a str) -> LocalBoxFuture<'a, Bresult<i64>> {
+        futures::future::ready(self.print(at, msg_id, true, msg)).boxed_local()
+    }
+    fn send_document<'a> (&'a self, at: i64, filename: &'a str, content: &'a str) -> LocalBoxFuture<'a, Bresult<i64>> {
+        let msg_id = CONSOLE_MSG_ID.fetch_add(1, AtomicOrdering::SeqCst);
+        futures::future::ready(self.print(at, msg_id, false, &format!("{}\n{}", filename, content))).boxed_local()
+    }
+    fn parse_update (&self, body: &[u8]) -> Bresult<Update> {
+        let line = from_utf8(body)?.trim_end_matches(&['\r', '\n'][..]);
+        let caps = regex_to_vec(r"(?s)^(?:(-?[0-9]+)(?::(-?[0-9]+))?> )?(.*)$", line)?;
+        let id = caps.as_i64(1).unwrap_or(self.id);
+        let at = caps.as_i64(2).unwrap_or(id);
+        let message = caps.as_string(3)?;
+        if message.is_empty() { Err("Nothing to do.")? }
+        Ok(Update{ id, at, to: at, message_id: CONSOLE_MSG_ID.fetch_add(1, AtomicOrdering::SeqCst), message })
+    }
+}
@@ -0,0 +1,190 @@
+//! # Command Registry
+use crate::*;
+use ::std::fmt;
+use ::futures::future::LocalBoxFuture;
+
+pub trait Command: Send + Sync {
+    fn name (&self) -> &'static str;
+    // Regex a message must match for the command to run, its captures are the arguments
+    fn trigger (&self) -> &'static str;
+    // (usage, description) lines for /help
+    fn help (&self) -> &'static [(&'static str, &'static str)];
+    fn role (&self) -> Role { DEFAULT_ROLE }
+    // Completing ends the message's handling and failures are sent to the sender
+    fn exclusive (&self) -> bool { false }
+    // Handle a message given its trigger's captures
+    fn run<'a> (&'a self, cmdstruct: &'a mut CmdStruct, caps: Vec<Option<String>>) -> LocalBoxFuture<'a, Bresult<Outcome>>;
+}
+
+/// How a command left a message
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Outcome {
+    Done, // Handled
+    Skip  // Not for this command after all
+}
+
+pub type CommandFn = for<'a> fn(&'a mut CmdStruct, Vec<Option<String>>) -> LocalBoxFuture<'a, Bresult<Outcome>>;
+
+/// A command backed by a do_* handler function.
+pub struct Builtin {
+    pub name: &'static str,
+    pub trigger: &'static str,
+    pub help: &'static [(&'static str, &'static str)],
+    pub role: Role,
+    pub exclusive: bool,
+    pub handler: CommandFn
+}
+
+impl Command for Builtin {
+    fn name (&self) -> &'static str { self.name }
+    fn trigger (&self) -> &'static str { self.trigger }
+    fn help (&self) -> &'static [(&'static str, &'static str)] { self.help }
+    fn role (&self) -> Role { self.role }
+    fn exclusive (&self) -> bool { self.exclusive }
+    fn run<'a> (&'a self, cmdstruct: &'a mut CmdStruct, caps: Vec<Option<String>>) -> LocalBoxFuture<'a, Bresult<Outcome>> {
+        (self.handler)(cmdstruct, caps)
+    }
+}
+
+////////////////////////////////////////
+
+#[derive(Default)]
+pub struct CommandRegistry {
+    commands: Vec<(Regex, Box<dyn Command>)>
+}
+
+impl CommandRegistry {
+    pub fn register (&mut self, command: impl Command + 'static) -> Bresult<&mut Self> {
+        if self.get(command.name()).is_some() { Err(format!("command {} already registered", command.name()))? }
+        self.commands.push((Regex::new(command.trigger())?, Box::new(command)));
+        Ok(self)
+    }
+
+    pub fn get (&self, name:&str) -> Option<&dyn Command> {
+        self.commands.iter().find( |c| c.1.name() == name ).map( |c| &*c.1 )
+    }
+
+    pub fn iter (&self) -> impl Iterator<Item=&dyn Command> {
+        self.commands.iter().map( |c| &*c.1 )
+    }
+
+    /// Commands chat at hasn't switched off that id's role there may run
+    pub fn available (&self, dbconn:&Connection, id:i64, at:i64) -> Bresult<Vec<&dyn Command>> {
+        let disabled = commands_disabled(dbconn, at)?;
+        let role = entity_role(dbconn, id, at)?;
+        let overrides = command_roles(dbconn)?;
+        Ok(self.iter()
+            .filter( |c| !disabled.contains(c.name()) && overrides.get(c.name()).map_or(c.role(), |r| *r) <= role )
+            .collect())
+    }
+
+    /// Run, in order, each available command triggered by the message with
+    /// its trigger's captures.
+    pub async fn dispatch (&self, cmdstruct: &mut CmdStruct) -> Bresult<()> {
+        let available = self.available(&getenvstruct!(cmdstruct).dbconn, cmdstruct.id, cmdstruct.at)?;
+        for (trigger, command) in &self.commands {
+            let name = command.name();
+            let caps = match trigger.captures(&cmdstruct.message) {
+                Some(captures) => captures_to_vec(&captures),
+                None => continue
+            };
+            if !available.iter().any( |c| c.name() == name ) { warn!("command {} unavailable to {} in {}", name, cmdstruct.id, cmdstruct.at); continue }
+            let res = command.run(cmdstruct, caps).await;
+            glogd!(format!("{} =>", name), res);
+            if command.exclusive() {
+                match res {
+                    Err(e) => { cmdstruct.push_msg(&format!("{} {}", name, e)).send_msg_id().await?; },
+                    Ok(Outcome::Done) => return Ok(()),
+                    _ => ()
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for CommandRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.iter().map( |c| c.name() )).finish()
+    }
+}
+
+/// Commands switched off in a chat
+pub fn commands_disabled (dbconn:&Connection, at:i64) -> Bresult<HashSet<String>> {
+    getsql!(dbconn, "SELECT name FROM commands_disabled WHERE at=?", at)?
+        .iter().map( |row| row.get_string("name") ).collect()
+}
+
+pub fn command_enable (dbconn:&Connection, at:i64, name:&str, enable:bool) -> Bresult<()> {
+    if enable {
+        getsql!(dbconn, "DELETE FROM commands_disabled WHERE at=? AND name=?", at, name)?;
+    } else {
+        getsql!(dbconn, "INSERT OR IGNORE INTO commands_disabled VALUES (?, ?)", at, name)?;
+    }
+    Ok(())
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::futures::future::FutureExt;
+
+    fn registry () -> CommandRegistry {
+        let mut registry = CommandRegistry::default();
+        registry.register(command("say", r"^/say ")).unwrap().register(command("yolo", r"/yolo")).unwrap();
+        registry
+    }
+
+    fn command (name: &'static str, trigger: &'static str) -> Builtin {
+        Builtin{ name, trigger, help: &[], role: Role::Admin, exclusive: false,
+            handler: |_, _| async { Bresult::Ok(Outcome::Done) }.boxed_local() }
+    }
+
+    #[test]
+    fn registry_rejects_duplicates_and_bad_triggers() {
+        let mut registry = registry();
+        assert!(registry.register(command("say", r"^/say")).is_err());
+        assert!(registry.register(command("bad", r"(")).is_err());
+        assert_eq!(registry.iter().map( |c| c.name() ).collect::<Vec<_>>(), ["say", "yolo"]);
+        assert!(registry.get("yolo").map_or(false, |c| Role::Admin == c.role()));
+    }
+
+    #[test]
+    fn available_follows_roles() {
+        let (registry, dbconn) = (registry(), test_dbconn());
+        let names = |id, at| registry.available(&dbconn, id, at).unwrap().iter().map( |c| c.name() ).collect::<Vec<_>>();
+        assert!(names(1, 5).is_empty());
+        role_grant(&dbconn, 1, 5, Role::Admin).unwrap();
+        assert_eq!(names(1, 5), ["say", "yolo"]);
+        command_role_set(&dbconn, "yolo", Some(Role::Owner)).unwrap();
+        assert_eq!(names(1, 5), ["say"]);
+    }
+
+    #[test]
+    fn dispatch_passes_captures_until_done() {
+        let mut registry = CommandRegistry::default();
+        registry
+        .register(Builtin{ name: "skip", trigger: r"^/(\w+) (\w+)$", help: &[], role: Role::Viewer, exclusive: true,
+            handler: |_, _| async { Bresult::Ok(Outcome::Skip) }.boxed_local() }).unwrap()
+        .register(Builtin{ name: "args", trigger: r"^/(\w+) (\w+)$", help: &[], role: Role::Viewer, exclusive: true,
+            handler: |c, caps| async move { c.message = caps.as_string(2)?; Bresult::Ok(Outcome::Done) }.boxed_local() }).unwrap()
+        .register(Builtin{ name: "after", trigger: r"^", help: &[], role: Role::Viewer, exclusive: true,
+            handler: |c, _| async move { c.message = "after".to_string(); Bresult::Ok(Outcome::Done) }.boxed_local() }).unwrap();
+        let mut cmdstruct = CmdStruct::new_cmdstruct(test_envstruct().into(), 0, 1, 1, 1, 1, "/say hi").unwrap();
+        futures::executor::block_on(registry.dispatch(&mut cmdstruct)).unwrap();
+        assert_eq!(cmdstruct.message, "hi");
+    }
+
+    #[test]
+    fn switches_per_chat() {
+        let dbconn = test_dbconn();
+        command_enable(&dbconn, 5, "yolo", false).unwrap();
+        command_enable(&dbconn, 5, "yolo", false).unwrap();
+        command_enable(&dbconn, 5, "say", false).unwrap();
+        command_enable(&dbconn, 5, "say", true).unwrap();
+        assert_eq!(commands_disabled(&dbconn, 5).unwrap().into_iter().collect::<Vec<_>>(), ["yolo"]);
+        assert!(commands_disabled(&dbconn, 6).unwrap().is_empty());
+    }
+}
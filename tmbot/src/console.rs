@@ -0,0 +1,173 @@
+//! # SQL Console
+use crate::*;
+use ::std::{fmt, os::raw::{c_int, c_void}, ptr, time::{Duration, Instant as Clock}};
+
+pub const SQL_ROW_LIMIT :usize = 50;
+pub const SQL_TIMEOUT :Duration = Duration::from_secs(2);
+pub const SQL_CONFIRM_SECS :i64 = 300; // How long a staged write waits for /sql confirm
+const SQL_CELL_WIDTH :usize = 24;
+const SQL_PROGRESS_OPS :c_int = 1000; // Virtual machine steps between deadline checks
+
+#[derive(Debug, Default)]
+pub struct SqlTable {
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<String>>,
+    pub truncated: bool // More rows than the limit
+}
+
+// A cell as text, clipped and free of characters that would close the code block
+fn sql_cell (value:&::sqlite::Value) -> String {
+    let cell = match value {
+        ::sqlite::Value::Null => "NULL".to_string(),
+        ::sqlite::Value::Integer(i) => i.to_string(),
+        ::sqlite::Value::Float(f) => f.to_string(),
+        ::sqlite::Value::String(s) => s.replace('`', "'").replace('\\', "/").replace('\n', " "),
+        ::sqlite::Value::Binary(b) => format!("<{} bytes>", b.len())
+    };
+    if SQL_CELL_WIDTH < cell.chars().count() {
+        cell.chars().take(SQL_CELL_WIDTH-1).collect::<String>() + "…"
+    } else {
+        cell
+    }
+}
+
+// SQLite's progress callback, a non-zero answer interrupts the statement
+extern "C" fn sql_deadline_passed (deadline:*mut c_void) -> c_int {
+    IF!(unsafe { *(deadline as *const Clock) } <= Clock::now(), 1, 0)
+}
+
+fn sql_rows (dbconn:&Connection, sql:&str, limit:usize) -> Bresult<SqlTable> {
+    let statement = dbconn.conn.prepare(sql)?;
+    let mut table = SqlTable{
+        columns: Statement::column_names(&statement).into_iter().map( String::from ).collect(),
+        ..SqlTable::default()
+    };
+    let mut cursor = Statement::into_cursor(statement);
+    while let Some(vals) = cursor.next()? {
+        if limit <= table.rows.len() { table.truncated = true; break }
+        table.rows.push(vals.iter().map( sql_cell ).collect());
+    }
+    Ok(table)
+}
+
+/// Run one statement on a read-only connection answering at most limit
+/// rows.  SQLite abandons it once the timeout passes, even mid-step.
+pub fn sql_query (dbconn:&Connection, sql:&str, limit:usize, timeout:Duration) -> Bresult<SqlTable> {
+    info!("SQLite console <= \x1b[1;36m{}", sql);
+    let deadline = Clock::now() + timeout;
+    let raw = dbconn.conn.as_raw();
+    unsafe { ::sqlite::ffi::sqlite3_progress_handler(raw, SQL_PROGRESS_OPS, Some(sql_deadline_passed), &deadline as *const Clock as *mut c_void) };
+    let res = sql_rows(dbconn, sql, limit);
+    unsafe { ::sqlite::ffi::sqlite3_progress_handler(raw, 0, None, ptr::null_mut()) };
+    match res {
+        Err(_) if deadline <= Clock::now() => Err(format!("query timed out after {:?}", timeout))?,
+        res => res
+    }
+}
+
+impl fmt::Display for SqlTable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let widths =
+            self.columns.iter().enumerate()
+            .map( |(i, column)|
+                self.rows.iter().map( |row| row[i].chars().count() ).fold(column.chars().count(), usize::max) )
+            .collect::<Vec<usize>>();
+        let line = |cells:&[String]|
+            cells.iter().zip(&widths)
+            .map( |(cell, width)| format!("{:1$}", cell, width) )
+            .collect::<Vec<String>>().join(" | ").trim_end().to_string();
+        writeln!(f, "{}", line(&self.columns))?;
+        writeln!(f, "{}", widths.iter().map( |w| "-".repeat(*w) ).collect::<Vec<String>>().join("-+-"))?;
+        for row in &self.rows { writeln!(f, "{}", line(row))? }
+        if self.truncated { writeln!(f, "… first {} rows", self.rows.len())? }
+        Ok(())
+    }
+}
+
+fn total_changes (dbconn:&Connection) -> Bresult<i64> {
+    getsql!(dbconn, "SELECT total_changes() AS changes")?[0].get_i64("changes")
+}
+
+/// Run a confirmed write in a transaction answering the rows it changed.
+/// The attempt and its outcome are recorded in sql_audit either way.
+pub fn sql_write (dbconn:&Connection, id:i64, at:i64, sql:&str, now:i64) -> Bresult<i64> {
+    let before = total_changes(dbconn)?;
+    let res = dbconn.transaction( |tx| {
+        tx.conn.execute(sql)?;
+        total_changes(tx).map( |after| after - before )
+    });
+    let result = match &res {
+        Ok(changes) => format!("{} changes", changes),
+        Err(e) => format!("error {}", e)
+    };
+    warn!("sql audit id:{} at:{} {:?} => {}", id, at, sql, result);
+    getsql!(dbconn, "INSERT INTO sql_audit VALUES (?, ?, ?, ?, ?)", now, id, at, sql, &*result)?;
+    res
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A file database, the read-only connection needs a second handle on it
+    struct TestDb { dbconn: Connection, readonly: Connection }
+
+    impl TestDb {
+        fn new (name:&str) -> Self {
+            let filename = env::temp_dir().join(format!("tmbot-console-{}-{}.db", name, ::std::process::id())).to_string_lossy().to_string();
+            ::std::fs::remove_file(&filename).ok();
+            let dbconn = Connection::new(filename).unwrap();
+            migrate(&dbconn).unwrap();
+            getsql!(dbconn, "INSERT INTO settings VALUES ('motd', 'a `long` line that will not fit in its cell'), ('z', 'z')").unwrap();
+            let readonly = dbconn.open_readonly().unwrap();
+            TestDb{ dbconn, readonly }
+        }
+    }
+
+    impl Drop for TestDb {
+        fn drop (&mut self) { ::std::fs::remove_file(&self.dbconn.filename).ok(); }
+    }
+
+    #[test]
+    fn query_row_limit() {
+        let db = TestDb::new("limit");
+        let table = sql_query(&db.readonly, "SELECT key, value FROM settings ORDER BY key", 1, SQL_TIMEOUT).unwrap();
+        assert_eq!((table.columns.join(","), table.rows.len(), table.truncated), ("key,value".to_string(), 1, true));
+    }
+
+    #[test]
+    fn cells_clipped_and_escaped() {
+        let db = TestDb::new("cells");
+        let table = sql_query(&db.readonly, "SELECT value FROM settings WHERE key='motd'", SQL_ROW_LIMIT, SQL_TIMEOUT).unwrap();
+        assert_eq!(table.to_string(), "value\n------------------------\na 'long' line that will…\n");
+    }
+
+    #[test]
+    fn query_is_read_only() {
+        let db = TestDb::new("readonly");
+        assert!(sql_query(&db.readonly, "DELETE FROM settings", SQL_ROW_LIMIT, SQL_TIMEOUT).is_err());
+    }
+
+    #[test]
+    fn query_times_out_before_first_row() {
+        let db = TestDb::new("timeout");
+        let start = Clock::now();
+        let endless = "WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i+1 FROM n) SELECT COUNT(*) FROM n";
+        let err = sql_query(&db.readonly, endless, SQL_ROW_LIMIT, Duration::from_millis(100)).unwrap_err();
+        assert!(err.to_string().contains("timed out") && start.elapsed() < Duration::from_secs(5));
+        assert!(sql_query(&db.readonly, "SELECT * FROM settings", SQL_ROW_LIMIT, SQL_TIMEOUT).is_ok()); // Handler removed
+    }
+
+    #[test]
+    fn writes_audited() {
+        let db = TestDb::new("write");
+        assert_eq!(sql_write(&db.dbconn, 1, 5, "UPDATE settings SET value='x' WHERE key='motd'", 100).unwrap(), 1);
+        assert!(sql_write(&db.dbconn, 1, 5, "UPDATE settings SET value='y'; DROP TABLE nope", 101).is_err());
+        assert_eq!(getsql!(db.dbconn, "SELECT value FROM settings WHERE key='motd'").unwrap()[0].get_string("value").unwrap(), "x");
+        let audit = getsql!(db.dbconn, "SELECT * FROM sql_audit ORDER BY time").unwrap();
+        assert_eq!(audit[0].get_string("result").unwrap(), "1 changes");
+        assert!(audit[1].get_string("result").unwrap().starts_with("error"));
+    }
+}
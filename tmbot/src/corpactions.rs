@@ -0,0 +1,313 @@
+//! # Corporate Actions
+use crate::*;
+use ::std::fmt;
+use ::futures::future::{LocalBoxFuture, FutureExt};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ActionKind {
+    Split(f64),   // New shares per old share
+    Dividend(Money) // Cash per share
+}
+
+impl ActionKind {
+    pub fn name (&self) -> &'static str {
+        match self { ActionKind::Split(_) => "split", ActionKind::Dividend(_) => "dividend" }
+    }
+    pub fn value (&self) -> f64 {
+        match self { ActionKind::Split(v) => *v, ActionKind::Dividend(v) => v.to_f64() }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CorporateAction {
+    pub ticker: String,
+    pub time: i64, // Effective or ex-dividend date
+    pub kind: ActionKind
+}
+
+impl fmt::Display for CorporateAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            ActionKind::Split(ratio) => write!(f, "{} {}:1 split", self.ticker, ratio),
+            ActionKind::Dividend(amount) => write!(f, "{} ${} dividend", self.ticker, amount)
+        }
+    }
+}
+
+/// A source of a ticker's splits and dividends since a time.
+pub trait CorporateActionProvider: fmt::Debug + Send + Sync {
+    fn get_actions<'a> (&'a self, ticker: &'a str, since: i64) -> LocalBoxFuture<'a, Bresult<Vec<CorporateAction>>>;
+}
+
+////////////////////////////////////////
+
+/// Yahoo's chart endpoint events:
+///   {"chart":{"result":[{"events":{"splits":{"1598832000":{"date":1598832000,"numerator":4,"denominator":1}},
+///                                 "dividends":{"1604673000":{"date":1604673000,"amount":0.205}}}}]}}
+pub fn parse_yahoo_events (ticker: &str, json: &Value) -> Vec<CorporateAction> {
+    let events = &json["chart"]["result"][0]["events"];
+    let mut actions :Vec<CorporateAction> = Vec::new();
+    if let Some(splits) = events["splits"].as_object() {
+        for split in splits.values() {
+            let (time, numerator, denominator) = (
+                getin_i64_or(0, split, &["date"]),
+                getin_f64(split, &["numerator"]).unwrap_or(0.0),
+                getin_f64(split, &["denominator"]).unwrap_or(0.0));
+            if 0 < time && 0.0 < numerator && 0.0 < denominator {
+                actions.push(CorporateAction{ ticker: ticker.to_string(), time, kind: ActionKind::Split(numerator / denominator) });
+            }
+        }
+    }
+    if let Some(dividends) = events["dividends"].as_object() {
+        for dividend in dividends.values() {
+            let (time, amount) = (getin_i64_or(0, dividend, &["date"]), getin_f64(dividend, &["amount"]).unwrap_or(0.0));
+            if 0 < time && 0.0 < amount {
+                actions.push(CorporateAction{ ticker: ticker.to_string(), time, kind: ActionKind::Dividend(Money::from_f64(amount)) });
+            }
+        }
+    }
+    actions.sort_by_key( |a| a.time );
+    actions
+}
+
+/// The live Yahoo Finance corporate actions.
+#[derive(Debug)]
+pub struct YahooActions {
+    http: Arc<HttpService>
+}
+
+impl CorporateActionProvider for YahooActions {
+    fn get_actions<'a> (&'a self, ticker: &'a str, since: i64) -> LocalBoxFuture<'a, Bresult<Vec<CorporateAction>>> {
+        async move {
+            Ok(parse_yahoo_events(ticker, &srvs::get_ticker_events_raw(&self.http, ticker, since).await?))
+        }.boxed_local()
+    }
+}
+
+////////////////////////////////////////
+
+/// Offline actions from a JSON fixture keyed by ticker symbol, read like
+/// FixtureQuotes:
+///   {"AAPL": [{"date":1598832000, "split":4}, {"date":1604673000, "dividend":0.205}]}
+pub struct FixtureActions {
+    actions: FixtureFile<Vec<CorporateAction>>
+}
+
+impl FixtureActions {
+    fn parse (json: Value) -> Bresult<Vec<CorporateAction>> {
+        let mut actions = Vec::new();
+        for (ticker, events) in json.as_object().ok_or("fixture actions must be a JSON object")? {
+            for event in events.as_array().ok_or("fixture actions must be JSON arrays")? {
+                let time = getin_i64(event, &["date"])?;
+                let kind =
+                    if let Some(ratio) = event["split"].as_f64() { ActionKind::Split(ratio) }
+                    else if let Some(amount) = event["dividend"].as_f64() { ActionKind::Dividend(Money::from_f64(amount)) }
+                    else { Err(format!("fixture action needs a split or dividend {:?}", event))? };
+                actions.push(CorporateAction{ ticker: ticker.to_uppercase(), time, kind });
+            }
+        }
+        Ok(actions)
+    }
+
+    pub fn new (filename: &str) -> Bresult<Self> {
+        Ok(FixtureActions{ actions: FixtureFile::new(filename, FixtureActions::parse)? })
+    }
+
+    pub fn from_value (json: Value) -> Bresult<Self> {
+        Ok(FixtureActions{ actions: FixtureFile::from_value(json, FixtureActions::parse)? })
+    }
+
+    pub fn add_action (&self, action: CorporateAction) {
+        self.actions.cached().push(action);
+    }
+
+    fn lookup (&self, ticker: &str, since: i64) -> Bresult<Vec<CorporateAction>> {
+        let ticker = ticker.to_uppercase();
+        let mut actions :Vec<CorporateAction> =
+            self.actions.fresh()
+            .iter()
+            .filter( |a| a.ticker == ticker && since <= a.time )
+            .cloned()
+            .collect();
+        actions.sort_by_key( |a| a.time );
+        Ok(actions)
+    }
+}
+
+impl CorporateActionProvider for FixtureActions {
+    fn get_actions<'a> (&'a self, ticker: &'a str, since: i64) -> LocalBoxFuture<'a, Bresult<Vec<CorporateAction>>> {
+        info!("FixtureActions <- {} {}", ticker, since);
+        futures::future::ready(self.lookup(ticker, since)).boxed_local()
+    }
+}
+
+impl fmt::Debug for FixtureActions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FixtureActions")
+         .field("filename", &self.actions.filename())
+         .field("actions", &self.actions.cached().len())
+         .finish()
+    }
+}
+
+////////////////////////////////////////
+
+pub fn new_corporate_action_provider (http: &Arc<HttpService>) -> Bresult<Arc<dyn CorporateActionProvider>> {
+    Ok(match fixture_filename("TMBOT_ACTIONS_FIXTURE") {
+        Some(filename) => Arc::new(FixtureActions::new(&filename)?),
+        None => Arc::new(YahooActions{ http: http.clone() })
+    })
+}
+
+////////////////////////////////////////
+
+/// Apply an action to every holder of its ticker inside the caller's
+/// transaction.  Returns the number of holders or None if it was already
+/// applied.
+pub fn corporate_action_apply (envstruct:&mut EnvStruct, action:&CorporateAction, origin:Origin) -> Bresult<Option<usize>> {
+    let (ticker, now) = (action.ticker.as_str(), origin.time);
+    let (kind, value) = (action.kind.name(), action.kind.value());
+    if !getsql!(envstruct.dbconn, "SELECT time FROM corpactions WHERE ticker=? AND time=? AND kind=?", ticker, action.time, kind)?.is_empty() {
+        return Ok(None)
+    }
+    if value <= 0.0 { Err(format!("bad corporate action {:?}", action))? }
+
+    let holders = getsql!(envstruct.dbconn, "SELECT id, qty, price FROM positions WHERE ticker=?", ticker)?;
+    let currency = // Dividends pay in the stonk's currency
+        getsql!(envstruct.dbconn, "SELECT currency FROM stonks WHERE ticker=?", ticker)?
+        .first().map_or(USD.to_string(), |row| row.get_string_or(USD, "currency"));
+    for row in &holders {
+        let (id, qty, price) = (row.get_i64("id")?, row.get_qty("qty")?, row.get_money("price")?);
+        match action.kind {
+            ActionKind::Split(ratio) => {
+                let new_qty = qty.scale(ratio).tradeable();
+                position_set(&envstruct.dbconn, origin, id, ticker, new_qty, price.scale(1.0 / ratio))?;
+                lots_split(&envstruct.dbconn, id, ticker, ratio, new_qty)?;
+                getsql!(envstruct.dbconn, "UPDATE borrows SET qty=CAST(ROUND(qty*?) AS INTEGER) WHERE id=? AND ticker=?", ratio, id, ticker)?;
+                getsql!(envstruct.dbconn, "INSERT INTO orders VALUES (?, ?, ?, ?, ?)", id, ticker, new_qty - qty, Money::ZERO, now)?;
+            },
+            ActionKind::Dividend(amount) => {
+                let cash = (qty * amount).cents(); // Shorts pay the dividend
+                cash_inc(envstruct, origin, id, &currency, cash)?;
+                getsql!(envstruct.dbconn, "INSERT INTO orders VALUES (?, ?, ?, ?, ?)", id, ticker, Qty::ZERO, cash, now)?;
+            }
+        }
+    }
+    if let ActionKind::Split(ratio) = action.kind {
+        for limit in getsql!(envstruct.dbconn, "SELECT rowid AS rowid, qty, price FROM limits WHERE ticker=?", ticker)? {
+            let (qty, price) = (limit.get_qty("qty")?.scale(ratio).tradeable(), limit.get_money("price")?.scale(1.0 / ratio));
+            getsql!(envstruct.dbconn, "UPDATE limits SET qty=?, price=? WHERE rowid=?", qty, price, limit.get_i64("rowid")?)?;
+        }
+    }
+    crash_point("corporate_action_apply")?;
+    getsql!(envstruct.dbconn, "INSERT INTO corpactions VALUES (?, ?, ?, ?, ?)", ticker, action.time, kind, value, now)?;
+    Ok(Some(holders.len()))
+}
+
+/// Fetch and apply the last two weeks of actions for one ticker or every
+/// held market ticker.  Returns the actions newly applied.
+pub async fn corporate_actions_run (env:Env, ticker:Option<&str>, origin:Origin) -> Bresult<Vec<CorporateAction>> {
+    let now = origin.time;
+    let (provider, tickers) = {
+        let envstruct = env.lock().unwrap();
+        let tickers :Vec<String> = match ticker {
+            Some(ticker) => vec![ticker.to_uppercase()],
+            None => getsql!(envstruct.dbconn, "SELECT DISTINCT ticker FROM positions")?
+                .iter()
+                .filter_map( |row| row.get_string("ticker").ok() )
+                .filter( |ticker| market_ticker_p(ticker) && !is_option(ticker) )
+                .collect()
+        };
+        (envstruct.actions.clone(), tickers)
+    };
+    let mut applied = Vec::new();
+    for ticker in tickers {
+        let actions = match provider.get_actions(&ticker, now - 14*86400).await {
+            Ok(actions) => actions,
+            e => { glogd!("corporate_actions_run get_actions =>", e); continue }
+        };
+        for action in actions.into_iter().filter( |a| a.time <= now ) {
+            let mut envstruct = env.lock().unwrap();
+            if envstruct.transaction( |envstruct| corporate_action_apply(envstruct, &action, origin) )?.is_some() {
+                warn!("corporate action applied {}", action);
+                applied.push(action);
+            }
+        }
+    }
+    Ok(applied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Env holding AAPL 3@400 for id 1 and -1@100 for id 2 with corporate actions
+    fn corporate_actions_env (actions:Value) -> Env {
+        let mut envstruct = test_envstruct();
+        getsql!(envstruct.dbconn, "INSERT INTO positions VALUES (1, 'AAPL', 3e6, 400e6), (2, 'AAPL', -1e6, 100e6)").unwrap();
+        lots_apply(&envstruct.dbconn, 1, "AAPL", Qty::from_int(3), Money::from_int(400), 0).unwrap();
+        envstruct.actions = Arc::new(FixtureActions::from_value(serde_json::json!({"aapl": actions})).unwrap());
+        envstruct.into()
+    }
+
+    fn corporate_actions_run_at (env:&Env, now:i64) -> usize {
+        futures::executor::block_on(corporate_actions_run(env.clone(), Some("AAPL"), Origin::system(now))).unwrap().len()
+    }
+
+    #[test]
+    fn corporate_actions_apply_once() {
+        let env = corporate_actions_env(serde_json::json!([{"date": 10, "split": 4}, {"date": 99, "dividend": 1}]));
+        assert_eq!(corporate_actions_run_at(&env, 30), 1); // The future dividend waits
+        assert_eq!(corporate_actions_run_at(&env, 30), 0); // Already applied
+    }
+
+    #[test]
+    fn split_scales_positions_and_lots() {
+        let (q, m) = (Qty::from_int, Money::from_int);
+        let env = corporate_actions_env(serde_json::json!([{"date": 10, "split": 4}]));
+        corporate_actions_run_at(&env, 30);
+        let envstruct = env.lock().unwrap();
+        let positions = getsql!(envstruct.dbconn, "SELECT qty, price FROM positions WHERE ticker='AAPL' ORDER BY id").unwrap();
+        assert_eq!(positions.iter().map( |r| (r.get_qty("qty").unwrap(), r.get_money("price").unwrap()) ).collect::<Vec<_>>(),
+            vec![(q(12), m(100)), (q(-4), m(25))]);
+        let lot = &lots(&envstruct.dbconn, 1, "AAPL").unwrap()[0];
+        assert_eq!((lot.qty, lot.price), (q(12), m(100)));
+        assert_eq!(test_ledger(&envstruct).3, 2); // Audit row per holder
+    }
+
+    #[test]
+    fn reverse_split_keeps_limits_tradeable() {
+        let env = corporate_actions_env(serde_json::json!([{"date": 10, "split": 1.0 / 3.0}]));
+        getsql!(env.lock().unwrap().dbconn, "INSERT INTO limits VALUES (1, 1, 'AAPL', 1e6, 30e6, '@', 0)").unwrap();
+        corporate_actions_run_at(&env, 30);
+        let limits = getsql!(env.lock().unwrap().dbconn, "SELECT qty, price FROM limits").unwrap();
+        assert_eq!((limits[0].get_qty("qty").unwrap(), limits[0].get_money("price").unwrap()), (Qty::from_f64(0.3333), Money::from_int(90)));
+    }
+
+    #[test]
+    fn split_keeps_lots_summing_to_position() {
+        let env = corporate_actions_env(serde_json::json!([{"date": 10, "split": 1.5}]));
+        {
+            let envstruct = env.lock().unwrap();
+            getsql!(envstruct.dbconn, "UPDATE positions SET qty=? WHERE id=2", Qty(-300)).unwrap();
+            for time in 1..=3 { lots_apply(&envstruct.dbconn, 2, "AAPL", Qty(-100), Money::from_int(100), time).unwrap(); }
+        }
+        corporate_actions_run_at(&env, 30);
+        let envstruct = env.lock().unwrap();
+        for (id, qty) in [(1, Qty::from_f64(4.5)), (2, Qty(-300).scale(1.5).tradeable())].iter() {
+            let position = getsql!(envstruct.dbconn, "SELECT qty FROM positions WHERE id=? AND ticker='AAPL'", *id).unwrap()[0].get_qty("qty").unwrap();
+            let lots = lots(&envstruct.dbconn, *id, "AAPL").unwrap();
+            assert_eq!((position, lots.iter().fold(Qty::ZERO, |sum, lot| sum + lot.qty)), (*qty, *qty));
+        }
+    }
+
+    #[test]
+    fn dividend_pays_longs_charges_shorts() {
+        let m = Money::from_int;
+        let env = corporate_actions_env(serde_json::json!([{"date": 20, "dividend": 0.25}]));
+        corporate_actions_run_at(&env, 30);
+        let envstruct = env.lock().unwrap();
+        assert_eq!(test_ledger(&envstruct).0, vec![m(1000) + Money::from_f64(0.75), m(1000) - Money::from_f64(0.25)]);
+        assert_eq!(test_ledger(&envstruct).0, test_ledger(&envstruct).1);
+    }
+}
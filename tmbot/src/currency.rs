@@ -0,0 +1,126 @@
+//! # Currencies
+use crate::*;
+
+pub const USD :&str = "USD";
+
+// Multiply a stonks.price by this to convert it to USD micro-units.  Needs
+//   LEFT JOIN stonks AS fx ON fx.ticker = stonks.currency || 'USD=X'
+pub const SQL_FX_RATE :&str = "IFNULL(fx.price, 1000000)/1e6";
+
+pub fn fx_ticker (currency:&str) -> String {
+    format!("{}USD=X", currency)
+}
+
+/// Yahoo quotes London in pence, GBp.  Returns the major currency and
+/// the price in it.
+pub fn currency_normalize (currency:&str, price:Money) -> (String, Money) {
+    match currency {
+        "GBp" | "GBX" => ("GBP".to_string(), price.scale(0.01)),
+        "ZAc" => ("ZAR".to_string(), price.scale(0.01)),
+        "ILA" => ("ILS".to_string(), price.scale(0.01)),
+        "" => (USD.to_string(), price),
+        c => (c.to_uppercase(), price)
+    }
+}
+
+/// USD per unit of currency, refreshing the pair's quote if stale
+pub async fn fx_rate (cmdstruct:&CmdStruct, currency:&str) -> Bresult<f64> {
+    if currency == USD { return Ok(1.0) }
+    let rate = Quote::get_market_quote(cmdstruct, &fx_ticker(currency)).await?.price;
+    if !rate.is_positive() { Err(format!("no {} exchange rate", currency))? }
+    Ok(rate.to_f64())
+}
+
+/// Pairs needed to value every position and foreign balance
+pub fn fx_tickers (dbconn:&Connection) -> Bresult<Vec<String>> {
+    getsql!(dbconn,
+        "SELECT DISTINCT currency FROM stonks WHERE ticker IN (SELECT ticker FROM positions)
+         UNION SELECT currency FROM balances ORDER BY currency")?
+    .iter()
+    .filter_map( |row| row.get_string("currency").ok() )
+    .filter( |currency| currency != USD )
+    .map( |currency| Ok(fx_ticker(&currency)) )
+    .collect()
+}
+
+/// id's non USD cash balances
+pub fn balances (dbconn:&Connection, id:i64) -> Bresult<Vec<(String, Money)>> {
+    getsql!(dbconn, "SELECT currency, balance FROM balances WHERE id=? AND balance!=0 ORDER BY currency", id)?
+    .iter()
+    .map( |row| Ok((row.get_string("currency")?, row.get_money("balance")?)) )
+    .collect()
+}
+
+/// id's non USD cash valued in USD at the cached rates
+pub fn balances_usd (dbconn:&Connection, id:i64) -> Bresult<Money> {
+    Ok(getsql!(dbconn,
+        &format!("SELECT SUM(balance*{}) AS value FROM balances LEFT JOIN stonks AS fx ON fx.ticker = balances.currency || 'USD=X' WHERE id=?", SQL_FX_RATE),
+        id)?[0]
+        .get_money_or(Money::ZERO, "value"))
+}
+
+/// Pay cost in a currency from id's balance in it, and whatever that won't
+/// cover from USD at rate, so only USD is ever borrowed.
+pub fn cash_spend (envstruct:&mut EnvStruct, origin:Origin, id:i64, currency:&str, cost:Money, rate:f64) -> Bresult<()> {
+    if currency == USD { return cash_inc(envstruct, origin, id, USD, -cost) }
+    let held =
+        getsql!(envstruct.dbconn, "SELECT balance FROM balances WHERE id=? AND currency=?", id, currency)?
+        .first().map_or(Money::ZERO, |row| row.get_money_or(Money::ZERO, "balance"));
+    let spent = cost.min(held.max(Money::ZERO));
+    if !spent.is_zero() { cash_inc(envstruct, origin, id, currency, -spent)? }
+    if spent < cost { cash_inc(envstruct, origin, id, USD, -(cost - spent).scale(rate))? }
+    Ok(())
+}
+
+pub fn base_currency (dbconn:&Connection, id:i64) -> Bresult<String> {
+    Ok(getsql!(dbconn, "SELECT currency FROM basecurrencies WHERE id=?", id)?
+        .first().map_or(USD.to_string(), |row| row.get_string_or(USD, "currency")))
+}
+
+pub fn base_currency_set (dbconn:&Connection, id:i64, currency:&str) -> Bresult<()> {
+    getsql!(dbconn, "INSERT OR REPLACE INTO basecurrencies VALUES (?, ?)", id, currency)?;
+    Ok(())
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dbconn () -> Connection {
+        let dbconn = test_dbconn();
+        getsql!(dbconn, "INSERT INTO stonks VALUES ('EURUSD=X', 1.1e6, 1e6, 'r', 24, 'CCY', 0, 'EUR/USD', 'USD'), ('SAP.DE', 100e6, 90e6, 'r', 16, 'GER', 0, 'SAP', 'EUR')").unwrap();
+        getsql!(dbconn, "INSERT INTO positions VALUES (1, 'SAP.DE', 1e6, 90e6)").unwrap();
+        getsql!(dbconn, "INSERT INTO balances VALUES (1, 'EUR', 200e6), (1, 'GBP', 0)").unwrap();
+        dbconn
+    }
+
+    #[test]
+    fn foreign_balances_in_usd() {
+        let dbconn = dbconn();
+        assert_eq!(balances(&dbconn, 1).unwrap(), vec![("EUR".to_string(), Money::from_int(200))]);
+        assert_eq!(balances_usd(&dbconn, 1).unwrap(), Money::from_int(220));
+    }
+
+    #[test]
+    fn fx_tickers_cover_positions_and_balances() {
+        assert_eq!(fx_tickers(&dbconn()).unwrap(), vec!["EURUSD=X".to_string(), "GBPUSD=X".to_string()]);
+    }
+
+    #[test]
+    fn pence_normalized_to_pounds() {
+        assert_eq!(currency_normalize("GBp", Money::from_int(250)), ("GBP".to_string(), Money::from_f64(2.5)));
+    }
+
+    #[test]
+    fn foreign_cost_past_balance_paid_in_usd() {
+        let m = Money::from_int;
+        let mut envstruct = test_envstruct();
+        cash_inc(&mut envstruct, Origin::system(0), 1, "EUR", m(100)).unwrap();
+        cash_spend(&mut envstruct, Origin::system(0), 1, "EUR", m(60), 1.5).unwrap();
+        assert_eq!((balances(&envstruct.dbconn, 1).unwrap(), envstruct.entity_balance(1).unwrap()), (vec![("EUR".to_string(), m(40))], m(1000)));
+        cash_spend(&mut envstruct, Origin::system(0), 1, "EUR", m(60), 1.5).unwrap();
+        assert_eq!((balances(&envstruct.dbconn, 1).unwrap(), envstruct.entity_balance(1).unwrap()), (vec![], m(970)));
+    }
+}
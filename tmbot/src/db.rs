@@ -1,22 +1,89 @@
 use ::std::{
     fmt,
+    cell::Cell,
     collections::{HashMap}};
 pub use sqlite::{Statement};
 //use crate::*;
 use crate::util::Bresult;
+use crate::money::{Money, Qty};
 
 ////////////////////////////////////////////////////////////////////////////////
 pub struct Connection {
     pub conn: ::sqlite::Connection,
-    pub filename: String
+    pub filename: String,
+    depth: Cell<usize> // Open transactions, nested ones are savepoints
 }
 
 impl Connection {
     pub fn new (filename:String) -> Bresult<Self> {
         ::sqlite::open(&filename)
-        .map( |conn| Connection{conn, filename} )
+        .map( |conn| Connection{conn, filename, depth: Cell::new(0)} )
         .map_err( Box::from )
     }
+
+    /// Another connection to the same database that refuses writes
+    pub fn open_readonly (&self) -> Bresult<Self> {
+        let readonly = Connection::new(self.filename.clone())?;
+        readonly.conn.execute("PRAGMA query_only = ON")?;
+        Ok(readonly)
+    }
+
+    pub fn begin (&self) -> Bresult<()> {
+        let depth = self.depth.get();
+        self.conn.execute(
+            if 0 == depth { "BEGIN IMMEDIATE".to_string() } else { format!("SAVEPOINT tx{}", depth) })?;
+        self.depth.set(depth + 1);
+        Ok(())
+    }
+
+    // Close the innermost transaction, committing it or rolling it back
+    pub fn end (&self, commit: bool) -> Bresult<()> {
+        let depth = self.depth.get().checked_sub(1).ok_or("no transaction to end")?;
+        let res =
+            match (0 == depth, commit) {
+                (true,  true)  => self.conn.execute("COMMIT"),
+                (true,  false) => self.conn.execute("ROLLBACK"),
+                (false, true)  => self.conn.execute(format!("RELEASE tx{}", depth)),
+                (false, false) => self.conn.execute(format!("ROLLBACK TO tx{0}; RELEASE tx{0}", depth))
+            };
+        if res.is_err() && commit { // A failed commit still has to unwind
+            self.conn.execute(
+                if 0 == depth { "ROLLBACK".to_string() } else { format!("ROLLBACK TO tx{0}; RELEASE tx{0}", depth) }).ok();
+        }
+        self.depth.set(depth);
+        Ok(res?)
+    }
+
+    /// Run a query and map each row into T.  Arguments bind to the ?
+    /// placeholders in order.
+    ///   dbconn.query_as::<Position>("SELECT * FROM positions WHERE id=?", &[&id])
+    pub fn query_as<T: FromRow> (&self, sql:&str, args:&[&dyn ToValue]) -> Bresult<Vec<T>> {
+        let mut statement = self.conn.prepare(sql)?;
+        for (i, arg) in args.iter().enumerate() {
+            statement.bind(i+1, &arg.to_value())?;
+        }
+        let col_names :Vec<String> =
+            Statement::column_names(&statement).into_iter().map( String::from ).collect();
+        let mut cursor = Statement::into_cursor(statement);
+        let mut rows = Vec::new();
+        while let Some(vals) = cursor.next()? {
+            let row :Row = vals.iter().enumerate()
+                .map( |(i, v)| (col_names[i].clone(), v.clone()) )
+                .collect();
+            rows.push(T::from_row(&row)?);
+        }
+        Ok(rows)
+    }
+
+    /// Run body as one atomic unit: committed when it returns Ok, rolled back
+    /// when it returns Err.  Nesting is allowed, an inner failure only
+    /// unwinds the inner body.
+    pub fn transaction<T> (&self, body: impl FnOnce(&Connection) -> Bresult<T>) -> Bresult<T> {
+        self.begin()?;
+        let res = body(self);
+        self.end(res.is_ok())?;
+        res
+    }
 }
 
 impl fmt::Debug for Connection {
@@ -24,6 +91,86 @@ impl fmt::Debug for Connection {
         f.write_fmt(format_args!("Connection{{{:?}}}", self.filename))
     }
 }
+// Settlement code calls this between its statements.  Tests arm a point by
+// name to make it fail there, simulating a crash halfway through.
+#[cfg(test)]
+thread_local! {
+    pub static CRASH_POINT: Cell<Option<&'static str>> = Cell::new(None);
+}
+
+#[cfg(test)]
+pub fn crash_point (name:&'static str) -> Bresult<()> {
+    if CRASH_POINT.with( |point| point.get() == Some(name) ) { Err(format!("crash injected at {}", name))? }
+    Ok(())
+}
+
+#[cfg(not(test))]
+pub fn crash_point (_name:&'static str) -> Bresult<()> { Ok(()) }
+
+// Run f with the named crash point armed
+#[cfg(test)]
+pub fn crashing_at<T> (name:&'static str, f:impl FnOnce() -> T) -> T {
+    CRASH_POINT.with( |point| point.set(Some(name)) );
+    let res = f();
+    CRASH_POINT.with( |point| point.set(None) );
+    res
+}
+
+// An empty in-memory database at the latest schema
+#[cfg(test)]
+pub fn test_dbconn () -> Connection {
+    let dbconn = Connection::new(":memory:".into()).unwrap();
+    crate::migrate::migrate(&dbconn).unwrap();
+    dbconn
+}
+
+// Entitys 1 and 2 with 1000 cash each, 2 holding 10 of its own stonk, GME
+// quoted at 10
+#[cfg(test)]
+pub fn test_envstruct () -> crate::EnvStruct {
+    use crate::*;
+    let dbconn = test_dbconn();
+    let mut entitys = HashMap::new();
+    for id in 1..=2 {
+        crate::getsql!(dbconn, "INSERT INTO accounts VALUES (?, 1000e6)", id).unwrap();
+        entitys.insert(id, Entity{ id, name: id.to_string(), balance: Money::from_int(1000), echo: 2, likes: 0,
+            quote: String::new(), position: String::new(), uuid: String::new() });
+    }
+    crate::getsql!(dbconn, "INSERT INTO positions VALUES (2, '2', 10e6, 5e6)").unwrap();
+    EnvStruct {
+        url_api: String::new(), dbconn,
+        http: Arc::new(HttpService::new(HttpConfig::default())),
+        quote_delay_secs: QUOTE_DELAY_SECS, time_scheduler: 0,
+        entitys,
+        quotes: Arc::new(FixtureQuotes::from_value(serde_json::json!(
+            {"gme": {"regularMarketPrice": 10.0, "regularMarketPreviousClose": 9.0, "volume24Hr": 1, "currency": "USD"}})).unwrap()),
+        actions: Arc::new(FixtureActions::from_value(serde_json::json!({})).unwrap()),
+        chat: ChatPlatform::Console,
+        books: HashMap::new(),
+        commands: Arc::new(builtin_commands().unwrap()),
+        sql_pending: HashMap::new()
+    }
+}
+
+// An entity's positions by ticker
+#[cfg(test)]
+pub fn test_positions (dbconn:&Connection, id:i64) -> Vec<(String, Qty)> {
+    crate::getsql!(dbconn, "SELECT ticker, qty FROM positions WHERE id=? ORDER BY ticker", id).unwrap().iter()
+        .map( |r| (r.get_string("ticker").unwrap(), r.get_qty("qty").unwrap()) ).collect()
+}
+
+// Balances cached and stored, positions and order count
+#[cfg(test)]
+pub fn test_ledger (envstruct: &crate::EnvStruct) -> (Vec<Money>, Vec<Money>, Vec<Qty>, usize) {
+    let dbconn = &envstruct.dbconn;
+    (
+        [1, 2].iter().map( |id| envstruct.entity_balance(*id).unwrap() ).collect(),
+        crate::getsql!(dbconn, "SELECT balance FROM accounts ORDER BY id").unwrap().iter().map( |r| r.get_money("balance").unwrap() ).collect(),
+        crate::getsql!(dbconn, "SELECT qty FROM positions ORDER BY id").unwrap().iter().map( |r| r.get_qty("qty").unwrap() ).collect(),
+        crate::getsql!(dbconn, "SELECT * FROM orders").unwrap().len()
+    )
+}
+
 ////////////////////////////////////////////////////////////////////////////////
 // SQLite macros that facilitate placeholders
 ////////////////////////////////////////////////////////////////////////////////
@@ -136,6 +283,27 @@ impl GetString for HashMap<String, ::sqlite::Value> {
     }
 }
 
+// Money and quantity columns hold INTEGER micro-units.  SQL arithmetic on
+// them can come back REAL so that's rounded to the nearest micro-unit.
+fn get_micros (row:&Row, key:&str) -> Bresult<i64> {
+    match row.get(key).ok_or(format!("Can't find key '{}'", key))? {
+        ::sqlite::Value::Integer(i) => Ok(*i),
+        ::sqlite::Value::Float(f) => Ok(f.round() as i64),
+        _ => Err(format!("Not a number '{}'", key).into())
+    }
+}
+
+pub trait GetMoney { fn get_money (&self, key:&str) -> Bresult<Money>; }
+pub trait GetQty   { fn get_qty   (&self, key:&str) -> Bresult<Qty>; }
+
+impl GetMoney for HashMap<String, ::sqlite::Value> {
+    fn get_money (&self, key:&str) -> Bresult<Money> { get_micros(self, key).map( Money ) }
+}
+
+impl GetQty for HashMap<String, ::sqlite::Value> {
+    fn get_qty (&self, key:&str) -> Bresult<Qty> { get_micros(self, key).map( Qty ) }
+}
+
 ////////////////////////////////////////
 
 pub trait GetI64Or    { fn get_i64_or    (&self, default:i64,  key:&str) -> i64; }
@@ -160,6 +328,77 @@ impl GetStringOr for HashMap<String, ::sqlite::Value> {
     }
 }
 
+pub trait GetMoneyOr { fn get_money_or (&self, default:Money, key:&str) -> Money; }
+pub trait GetQtyOr   { fn get_qty_or   (&self, default:Qty,   key:&str) -> Qty; }
+
+impl GetMoneyOr for HashMap<String, ::sqlite::Value> {
+    fn get_money_or (&self, default:Money, key:&str) -> Money { self.get_money(key).unwrap_or(default) }
+}
+
+impl GetQtyOr for HashMap<String, ::sqlite::Value> {
+    fn get_qty_or (&self, default:Qty, key:&str) -> Qty { self.get_qty(key).unwrap_or(default) }
+}
+
+////////////////////////////////////////
+// Typed rows
+
+pub type Row = HashMap<String, ::sqlite::Value>;
+
+/// A struct built from one result row, see #[derive(FromRow)] in macros.
+pub trait FromRow: Sized { fn from_row (row:&Row) -> Bresult<Self>; }
+
+/// Column types a FromRow field can have.
+pub trait FromValue: Sized { fn from_value (row:&Row, key:&str) -> Bresult<Self>; }
+
+impl FromValue for i64 {
+    fn from_value (row:&Row, key:&str) -> Bresult<Self> { row.get_i64(key) }
+}
+
+impl FromValue for f64 {
+    fn from_value (row:&Row, key:&str) -> Bresult<Self> { row.get_f64(key) }
+}
+
+impl FromValue for String {
+    fn from_value (row:&Row, key:&str) -> Bresult<Self> { row.get_string(key) }
+}
+
+impl FromValue for Money {
+    fn from_value (row:&Row, key:&str) -> Bresult<Self> { row.get_money(key) }
+}
+
+impl FromValue for Qty {
+    fn from_value (row:&Row, key:&str) -> Bresult<Self> { row.get_qty(key) }
+}
+
+impl<T: FromValue> FromValue for Option<T> { // NULL or missing columns are None
+    fn from_value (row:&Row, key:&str) -> Bresult<Self> {
+        match row.get(key) {
+            None | Some(::sqlite::Value::Null) => Ok(None),
+            _ => T::from_value(row, key).map(Some)
+        }
+    }
+}
+
+/// Values query_as can bind to placeholders.
+pub trait ToValue { fn to_value (&self) -> ::sqlite::Value; }
+
+impl ToValue for i64 { fn to_value (&self) -> ::sqlite::Value { ::sqlite::Value::Integer(*self) } }
+impl ToValue for f64 { fn to_value (&self) -> ::sqlite::Value { ::sqlite::Value::Float(*self) } }
+impl ToValue for str { fn to_value (&self) -> ::sqlite::Value { ::sqlite::Value::String(String::from(self)) } }
+impl ToValue for String { fn to_value (&self) -> ::sqlite::Value { ::sqlite::Value::String(self.clone()) } }
+impl ToValue for Money { fn to_value (&self) -> ::sqlite::Value { ::sqlite::Value::Integer(self.0) } }
+impl ToValue for Qty { fn to_value (&self) -> ::sqlite::Value { ::sqlite::Value::Integer(self.0) } }
+impl<T: ToValue + ?Sized> ToValue for &T { fn to_value (&self) -> ::sqlite::Value { (**self).to_value() } }
+
+// So getsql! placeholders take them directly
+impl ::sqlite::Bindable for Money {
+    fn bind (self, statement:&mut Statement, i:usize) -> ::sqlite::Result<()> { self.0.bind(statement, i) }
+}
+
+impl ::sqlite::Bindable for Qty {
+    fn bind (self, statement:&mut Statement, i:usize) -> ::sqlite::Result<()> { self.0.bind(statement, i) }
+}
+
 ////////////////////////////////////////
 
 pub trait ToString { fn to_string (&self, key:&str) -> Bresult<String>; }
@@ -0,0 +1,172 @@
+//! # Portfolio History
+use crate::*;
+use ::datetime::Month;
+
+#[derive(Debug, Clone, FromRow)]
+pub struct Snapshot {
+    pub time:  i64,
+    pub cash:  Money,
+    pub long:  Money,
+    pub short: Money, // Negative
+    pub yolo:  Money
+}
+
+#[derive(Debug)]
+pub struct Performance {
+    pub start: Snapshot,
+    pub end: Snapshot,
+    pub drawdown: f64, // Largest peak to trough YOLO drop, percent
+    pub positions: Vec<(String, Money)> // Gain per ticker over the period, best first
+}
+
+impl Performance {
+    pub fn gain (&self) -> Money { self.end.yolo - self.start.yolo }
+    pub fn percent (&self) -> f64 { percentify(self.start.yolo, self.end.yolo) }
+}
+
+// Self-stonks and @ references have no market value
+pub fn market_ticker_p (ticker:&str) -> bool {
+    !is_self_stonk(ticker) && !ticker.starts_with('@')
+}
+
+/// Start of a /perf period: 1w, 1m or ytd
+pub fn history_period_start (period:&str, now:i64) -> Bresult<i64> {
+    Ok(match period {
+        "1w" => now - 7*86400,
+        "1m" => now - 30*86400,
+        "ytd" => {
+            let date = LocalDate::ymd(LocalDateTime::at(now).year(), Month::January, 1)?;
+            LocalDateTime::new(date, LocalTime::midnight()).to_instant().seconds()
+        },
+        _ => Err(format!("unknown history period {:?}", period))?
+    })
+}
+
+// One snapshot per trading day, on the first scheduler tick after the close
+pub fn history_snapshot_due (envstruct:&EnvStruct, now:i64) -> Bresult<bool> {
+    let close = exchange_hours(NYSE).most_recent_session(now)?.close;
+    if now < close { return Ok(false) }
+    Ok(getsql!(envstruct.dbconn, "SELECT time FROM history WHERE ?<=time LIMIT 1", close)?.is_empty())
+}
+
+/// Refresh every positioned market ticker then record each entity's values.
+pub async fn history_snapshot (env:Env, now:i64) -> Bresult<()> {
+    let tickers = {
+        let envstruct = env.lock().unwrap();
+        getsql!(envstruct.dbconn, "SELECT DISTINCT ticker FROM positions")?
+    };
+    let cmdstruct = CmdStruct::new_cmdstruct(env.clone(), now, 0, 0, 0, 0, "")?;
+    for row in tickers {
+        let ticker = row.get_string("ticker")?;
+        if market_ticker_p(&ticker) {
+            glogd!("history_snapshot quote =>", Quote::get_market_quote(&cmdstruct, &ticker).await.map( |q| q.price ));
+        }
+    }
+    for ticker in fx_tickers(&env.lock().unwrap().dbconn)? {
+        glogd!("history_snapshot fx =>", Quote::get_market_quote(&cmdstruct, &ticker).await.map( |q| q.price ));
+    }
+
+    let envstruct = env.lock().unwrap();
+    envstruct.dbconn.transaction( |tx| {
+        let mut snapshots :HashMap<i64, Snapshot> =
+            getsql!(tx, "SELECT id, balance FROM accounts")?
+            .iter()
+            .map( |row| {
+                let id = row.get_i64_or(0, "id");
+                let cash = row.get_money_or(Money::ZERO, "balance") + balances_usd(tx, id)?;
+                Ok((id, Snapshot{ time: now, cash, long: Money::ZERO, short: Money::ZERO, yolo: cash }))
+            } )
+            .collect::<Bresult<_>>()?;
+        for row in getsql!(tx, &format!(
+            "SELECT positions.id, positions.ticker, qty, stonks.price*{} AS price FROM positions
+             LEFT JOIN stonks ON stonks.ticker = positions.ticker
+             LEFT JOIN stonks AS fx ON fx.ticker = stonks.currency || 'USD=X'", SQL_FX_RATE))? {
+            let (id, ticker, qty) = (row.get_i64("id")?, row.get_string("ticker")?, row.get_qty("qty")?);
+            let snapshot = match snapshots.get_mut(&id) { Some(s) => s, None => continue };
+            if !market_ticker_p(&ticker) { continue }
+            let price = row.get_money_or(Money::ZERO, "price");
+            let value = qty * price;
+            if value.is_negative() { snapshot.short += value } else { snapshot.long += value }
+            snapshot.yolo += value;
+            getsql!(tx, "INSERT INTO history_positions VALUES (?, ?, ?, ?, ?)", id, now, &*ticker, qty, price)?;
+        }
+        for (id, s) in snapshots {
+            getsql!(tx, "INSERT INTO history VALUES (?, ?, ?, ?, ?, ?)",
+                id, now, s.cash.cents(), s.long.cents(), s.short.cents(), s.yolo.cents())?;
+        }
+        Ok(())
+    })
+}
+
+pub fn history (dbconn:&Connection, id:i64, since:i64) -> Bresult<Vec<Snapshot>> {
+    dbconn.query_as::<Snapshot>(
+        "SELECT time, cash, long, short, yolo FROM history WHERE id=? AND ?<=time ORDER BY time",
+        &[&id, &since])
+}
+
+/// Return, drawdown and per position gains since a time.  A position's gain
+/// is its value change less the cash spent trading it in the period.
+pub fn performance (dbconn:&Connection, id:i64, since:i64) -> Bresult<Option<Performance>> {
+    let snapshots = history(dbconn, id, since)?;
+    if snapshots.len() < 2 { return Ok(None) }
+    let start = snapshots[0].clone();
+    let end = snapshots[snapshots.len()-1].clone();
+
+    let mut peak = start.yolo;
+    let mut drawdown = 0.0;
+    for s in &snapshots {
+        peak = peak.max(s.yolo);
+        if peak.is_positive() { drawdown = f64::max(drawdown, (peak - s.yolo).to_f64() / peak.to_f64() * 100.0) }
+    }
+
+    let mut gains :HashMap<String, Money> = HashMap::new();
+    for (time, sign) in vec![(start.time, -1), (end.time, 1)] {
+        for row in getsql!(dbconn, "SELECT ticker, qty*price/1e6 AS value FROM history_positions WHERE id=? AND time=?", id, time)? {
+            *gains.entry(row.get_string("ticker")?).or_insert(Money::ZERO) += row.get_money("value")? * sign;
+        }
+    }
+    for row in getsql!(dbconn,
+        "SELECT ticker, SUM(qty*price/1e6) AS value FROM orders WHERE id=? AND ?<time AND time<=? GROUP BY ticker",
+        id, start.time, end.time)?
+    {
+        let ticker = row.get_string("ticker")?;
+        if market_ticker_p(&ticker) {
+            *gains.entry(ticker).or_insert(Money::ZERO) -= row.get_money("value")?;
+        }
+    }
+    let mut positions :Vec<(String, Money)> = gains.into_iter().map( |(t, g)| (t, g.cents()) ).collect();
+    positions.sort_by( |a, b| b.1.cmp(&a.1) );
+
+    Ok(Some(Performance{ start, end, drawdown, positions }))
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // id 1 holding GME 100@10, peaking at 14 then bought AMC 50@10 which
+    // fell to 8 while GME fell to 12
+    fn dbconn () -> Connection {
+        let dbconn = test_dbconn();
+        let m = Money::from_int;
+        for (time, cash, long, yolo) in &[(50, 900, 1000, 1900), (100, 1000, 1000, 2000), (200, 1000, 1400, 2400), (300, 500, 1600, 2100)] {
+            getsql!(dbconn, "INSERT INTO history VALUES (1, ?, ?, ?, 0, ?)", *time, m(*cash), m(*long), m(*yolo)).unwrap();
+        }
+        for (time, ticker, qty, price) in &[(100, "GME", 100, 10), (200, "GME", 100, 14), (300, "GME", 100, 12), (300, "AMC", 50, 8)] {
+            getsql!(dbconn, "INSERT INTO history_positions VALUES (1, ?, ?, ?, ?)", *time, *ticker, Qty::from_int(*qty), m(*price)).unwrap();
+        }
+        getsql!(dbconn, "INSERT INTO orders VALUES (1, 'AMC', ?, ?, 250)", Qty::from_int(50), m(10)).unwrap();
+        dbconn
+    }
+
+    #[test]
+    fn performance_since() {
+        let m = Money::from_int;
+        let perf = performance(&dbconn(), 1, 100).unwrap().unwrap();
+        assert_eq!((perf.start.time, perf.end.time, perf.gain(), perf.percent(), perf.drawdown), (100, 300, m(100), 5.0, 12.5));
+        assert_eq!(perf.positions, vec![("GME".to_string(), m(200)), ("AMC".to_string(), m(-100))]);
+        assert!(performance(&dbconn(), 1, 300).unwrap().is_none());
+    }
+}
@@ -0,0 +1,270 @@
+//! # Outbound HTTP
+use crate::*;
+use ::std::{cell::RefCell, time::{Duration, Instant as Clock}};
+use ::actix_web::{rt::System, web::Bytes, http::Method, client::{Client, Connector}};
+use ::openssl::ssl::SslConnector;
+
+const HTTP_CONNECT_SECS :u64 = 90;
+const HTTP_BODY_LIMIT :usize = 10_000_000;
+
+#[derive(Debug, Clone)]
+pub struct HttpConfig {
+    pub base_url: Option<String>,  // Replaces each url's scheme and host, which is then sent as X-Forwarded-Host
+    pub timeout: Duration,         // Per attempt
+    pub retries: u32,              // Attempts after the first
+    pub backoff: Duration,         // Delay before the first retry, doubled for each one after
+    pub host_interval: Duration    // Minimum time between requests to one host
+}
+
+impl Default for HttpConfig {
+    fn default () -> Self {
+        HttpConfig {
+            base_url: None,
+            timeout: Duration::from_secs(90),
+            retries: 2,
+            backoff: Duration::from_millis(500),
+            host_interval: Duration::from_millis(100)
+        }
+    }
+}
+
+impl HttpConfig {
+    /// Defaults overridden by TMBOT_HTTP_BASE_URL, TMBOT_HTTP_RETRIES,
+    /// TMBOT_HTTP_BACKOFF_MS and TMBOT_HTTP_HOST_INTERVAL_MS.
+    pub fn from_env () -> Bresult<Self> {
+        let mut config = HttpConfig::default();
+        if let Ok(base_url) = env::var("TMBOT_HTTP_BASE_URL") {
+            config.base_url = Some(base_url.trim_end_matches('/').to_string());
+        }
+        if let Ok(retries) = env::var("TMBOT_HTTP_RETRIES") {
+            config.retries = retries.parse()?;
+        }
+        if let Ok(ms) = env::var("TMBOT_HTTP_BACKOFF_MS") {
+            config.backoff = Duration::from_millis(ms.parse()?);
+        }
+        if let Ok(ms) = env::var("TMBOT_HTTP_HOST_INTERVAL_MS") {
+            config.host_interval = Duration::from_millis(ms.parse()?);
+        }
+        Ok(config)
+    }
+}
+
+thread_local! {
+    // awc clients aren't Send and their pools live on the actix System that
+    // made them, so each thread keeps one per System (and TLS setup)
+    static CLIENT: RefCell<Option<(usize, bool, Client)>> = RefCell::new(None);
+}
+
+fn client (ssl:&Option<SslConnector>) -> Client {
+    let system = if System::is_set() { System::current().id() } else { 0 };
+    CLIENT.with( |cell| {
+        let mut cell = cell.borrow_mut();
+        match &*cell {
+            Some((id, has_ssl, client)) if *id == system && *has_ssl == ssl.is_some() => client.clone(),
+            _ => {
+                let connector = Connector::new().timeout( Duration::from_secs(HTTP_CONNECT_SECS) );
+                let connector = match ssl {
+                    Some(ssl) => connector.ssl(ssl.clone()).finish(),
+                    None => connector.finish()
+                };
+                let client = Client::builder().connector(connector).finish();
+                *cell = Some((system, ssl.is_some(), client.clone()));
+                client
+            }
+        }
+    })
+}
+
+// May a failed attempt be sent again?  A 429 was refused so always, but a
+// request whose connection failed or that got a 5xx may have taken effect.
+fn retryable (status:Option<u16>, idempotent:bool) -> bool {
+    match status {
+        Some(429) => true,
+        Some(status) => idempotent && 500 <= status,
+        None => idempotent
+    }
+}
+
+// Split "scheme://host/path?query" into its host and "/path?query"
+fn split_url (url:&str) -> Bresult<(&str, &str)> {
+    let rest = url.splitn(2, "://").nth(1).ok_or_else( || format!("no scheme in url {:?}", url) )?;
+    Ok(match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/")
+    })
+}
+
+#[derive(Debug)]
+pub struct HttpService {
+    config: HttpConfig,
+    ssl: Option<SslConnector>,           // Client certificate setup, otherwise awc's default
+    slots: Mutex<HashMap<String, Clock>> // Each host's next free request time
+}
+
+impl HttpService {
+    pub fn new (config: HttpConfig) -> Self {
+        HttpService{ config, ssl: None, slots: Mutex::new(HashMap::new()) }
+    }
+
+    pub fn ssl (mut self, ssl: SslConnector) -> Self {
+        self.ssl = Some(ssl);
+        self
+    }
+
+    // The url to request and the original host when base_url overrides it
+    fn route (&self, url:&str) -> Bresult<(String, Option<String>)> {
+        let (host, path) = split_url(url)?;
+        Ok(match &self.config.base_url {
+            Some(base_url) => (format!("{}{}", base_url, path), Some(host.to_string())),
+            None => (url.to_string(), None)
+        })
+    }
+
+    // Reserve host's next request slot answering how long until it opens
+    fn reserve (&self, host:&str, now:Clock) -> Duration {
+        let mut slots = self.slots.lock().unwrap();
+        let slot = slots.get(host).map_or(now, |next| now.max(*next));
+        slots.insert(host.to_string(), slot + self.config.host_interval);
+        slot - now
+    }
+
+    /// GET url with query pairs and headers answering the response body.
+    /// Connection failures, 429 and 5xx responses are retried, any other
+    /// response's body is returned whatever its status.
+    pub async fn get (&self, url:&str, query:&[[&str; 2]], headers:&[(&str, &str)]) -> Bresult<Bytes> {
+        self.request(Method::GET, url, query, headers, None, true).await
+    }
+
+    /// GET a url that does something, like Telegram's sendmessage.  Only
+    /// 429 responses are retried so it's never done twice.
+    pub async fn send (&self, url:&str, query:&[[&str; 2]], headers:&[(&str, &str)]) -> Bresult<Bytes> {
+        self.request(Method::GET, url, query, headers, None, false).await
+    }
+
+    /// POST a body to url, retried like send
+    pub async fn post (&self, url:&str, headers:&[(&str, &str)], body:Bytes) -> Bresult<Bytes> {
+        self.request(Method::POST, url, &[], headers, Some(body), false).await
+    }
+
+    async fn request (&self, method:Method, url:&str, query:&[[&str; 2]], headers:&[(&str, &str)], body:Option<Bytes>, idempotent:bool) -> Bresult<Bytes> {
+        let (host, _) = split_url(url)?;
+        let (target, forwarded) = self.route(url)?;
+        let client = client(&self.ssl);
+        let mut backoff = self.config.backoff;
+        let mut attempt = 0;
+        loop {
+            let wait = self.reserve(host, Clock::now());
+            if Duration::from_secs(0) < wait { ::actix::clock::delay_for(wait).await }
+
+            let mut request = client.request(method.clone(), target.as_str()).timeout(self.config.timeout);
+            for (key, value) in headers { request = request.header(*key, *value) }
+            if let Some(forwarded) = &forwarded { request = request.header("X-Forwarded-Host", forwarded.as_str()) }
+
+            if !query.is_empty() { request = request.query(query)? }
+            let sent = match &body {
+                Some(body) => request.send_body(body.clone()),
+                None => request.send()
+            };
+            let (status, failure) =
+                match sent.await {
+                    Ok(mut response) => {
+                        let status = response.status();
+                        if !(status.is_server_error() || 429 == status.as_u16()) {
+                            return Ok(response.body().limit(HTTP_BODY_LIMIT).await?)
+                        }
+                        (Some(status.as_u16()), format!("status {}", status))
+                    },
+                    Err(e) => (None, e.to_string())
+                };
+
+            if self.config.retries <= attempt || !retryable(status, idempotent) {
+                Err(format!("http {} {} failed after {} attempts: {}", method, url, attempt+1, failure))?
+            }
+            attempt += 1;
+            warn!("http {} {} {}, retry {} in {:?}", method, url, failure, attempt, backoff);
+            ::actix::clock::delay_for(backoff).await;
+            backoff *= 2;
+        }
+    }
+
+    /// GET a JSON document
+    pub async fn get_json (&self, url:&str, query:&[[&str; 2]], headers:&[(&str, &str)]) -> Bresult<Value> {
+        let body = self.get(url, query, headers).await?;
+        let jsonstr = from_utf8(&body).or_else( |r| Err(format!("http get {} body2str {:?}", url, r)) )?;
+        bytes2json(jsonstr.as_bytes())
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::std::{io::{Read, Write}, net::TcpListener, sync::atomic::{AtomicUsize, Ordering as AtomicOrdering}};
+
+    #[test]
+    fn routes_and_throttles() {
+        let http = HttpService::new(HttpConfig{ base_url: Some("http://127.0.0.1:8888".into()), ..HttpConfig::default() });
+        assert_eq!(
+            http.route("https://query1.finance.yahoo.com/v7/finance/quote").unwrap(),
+            ("http://127.0.0.1:8888/v7/finance/quote".to_string(), Some("query1.finance.yahoo.com".to_string())));
+        assert_eq!(http.route("https://onelook.com").unwrap().0, "http://127.0.0.1:8888/");
+        assert!(http.route("onelook.com").is_err());
+
+        let now = Clock::now();
+        let interval = http.config.host_interval;
+        assert_eq!(
+            [http.reserve("a", now), http.reserve("a", now), http.reserve("b", now), http.reserve("a", now + 3*interval)],
+            [Duration::from_secs(0), interval, Duration::from_secs(0), Duration::from_secs(0)]);
+    }
+
+    // Answer each connection to the returned url with the next status,
+    // counting connections
+    fn server (statuses:&[u16]) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let hits = Arc::new(AtomicUsize::new(0));
+        let (statuses, served) = (statuses.to_vec(), hits.clone());
+        ::std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let n = stream.read(&mut buf).unwrap();
+                    if 0 == n { break }
+                    request.extend_from_slice(&buf[..n]);
+                }
+                let status = statuses[served.fetch_add(1, AtomicOrdering::SeqCst).min(statuses.len()-1)];
+                write!(stream, "HTTP/1.1 {} X\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok", status).unwrap();
+            }
+        });
+        (url, hits)
+    }
+
+    // The outcome and number of attempts of a get, or send, against statuses
+    fn attempts (statuses:&[u16], idempotent:bool) -> (bool, usize) {
+        let (url, hits) = server(statuses);
+        let http = HttpService::new(HttpConfig{ backoff: Duration::from_millis(1), host_interval: Duration::from_millis(0), ..HttpConfig::default() });
+        let res = System::new("test").block_on(async move {
+            if idempotent { http.get(&url, &[], &[]).await } else { http.send(&url, &[], &[]).await }
+        });
+        (res.is_ok(), hits.load(AtomicOrdering::SeqCst))
+    }
+
+    #[test]
+    fn gets_retried() {
+        assert_eq!(attempts(&[503, 502, 200], true), (true, 3));
+        assert_eq!(attempts(&[503], true), (false, 3));
+        assert_eq!(attempts(&[404], true), (true, 1));
+    }
+
+    #[test]
+    fn sends_not_retried() {
+        assert_eq!(attempts(&[503, 200], false), (false, 1));
+        assert_eq!(attempts(&[429, 200], false), (true, 2));
+        assert_eq!(
+            [retryable(None, true), retryable(None, false), retryable(Some(500), false), retryable(Some(429), false)],
+            [true, false, false, true]);
+    }
+}
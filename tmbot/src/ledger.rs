@@ -0,0 +1,269 @@
+//! # Ledger
+use crate::*;
+
+/// Where a ledger change came from.  The scheduler and other bot initiated
+/// changes have actor 0.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Origin {
+    pub time: i64,
+    pub actor: i64,
+    pub at: i64,
+    pub message_id: i64
+}
+
+impl Origin {
+    pub fn system (time:i64) -> Self { Origin{ time, actor: 0, at: 0, message_id: 0 } }
+}
+
+#[derive(Debug, Clone, FromRow)]
+pub struct LedgerEvent {
+    pub rowid: i64,
+    pub time: i64,
+    pub id: i64,              // Account changed
+    pub kind: String,         // "cash" or "position"
+    pub key: String,          // Currency or ticker
+    pub before: i64,          // Cash or quantity micro-units
+    pub after: i64,
+    pub basis_before: Money,  // Positions' average price
+    pub basis_after: Money,
+    pub actor: i64,
+    pub at: i64,
+    pub message_id: i64
+}
+
+impl LedgerEvent {
+    pub fn describe (&self) -> String {
+        if "cash" == self.kind {
+            format!("{} {:+.2} to {:.2}", self.key, Money(self.after - self.before), Money(self.after))
+        } else {
+            format!("{} {:+} to {}@{:.2}", self.key, Qty(self.after - self.before), Qty(self.after), self.basis_after)
+        }
+    }
+}
+
+fn ledger_event_insert (dbconn:&Connection, origin:Origin, id:i64, kind:&str, key:&str, before:i64, after:i64, basis_before:Money, basis_after:Money) -> Bresult<()> {
+    getsql!(dbconn, "INSERT INTO ledger_events VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        origin.time, id, kind, key, before, after, basis_before, basis_after, origin.actor, origin.at, origin.message_id)?;
+    Ok(())
+}
+
+/// Adjust id's cash in a currency answering the new balance.  USD is kept
+/// in accounts, so ids without one are left alone and answer None, every
+/// other currency in balances.
+pub fn cash_adjust (dbconn:&Connection, origin:Origin, id:i64, currency:&str, amount:Money) -> Bresult<Option<Money>> {
+    let before =
+        if currency == USD {
+            match getsql!(dbconn, "SELECT balance FROM accounts WHERE id=?", id)?.first() {
+                Some(row) => row.get_money("balance")?,
+                None => return Ok(None)
+            }
+        } else {
+            getsql!(dbconn, "SELECT balance FROM balances WHERE id=? AND currency=?", id, currency)?
+                .first().map_or(Money::ZERO, |row| row.get_money_or(Money::ZERO, "balance"))
+        };
+    let after = before + amount;
+    if currency == USD {
+        getsql!(dbconn, "UPDATE accounts SET balance=? WHERE id=?", after, id)?;
+    } else {
+        getsql!(dbconn, "INSERT OR REPLACE INTO balances VALUES (?, ?, ?)", id, currency, after)?;
+    }
+    ledger_event_insert(dbconn, origin, id, "cash", currency, before.0, after.0, Money::ZERO, Money::ZERO)?;
+    Ok(Some(after))
+}
+
+/// cash_adjust keeping the entity's cached USD balance in step
+pub fn cash_inc (envstruct:&mut EnvStruct, origin:Origin, id:i64, currency:&str, amount:Money) -> Bresult<()> {
+    if let Some(after) = cash_adjust(&envstruct.dbconn, origin, id, currency, amount)? {
+        if currency == USD {
+            if let Some(entity) = envstruct.entitys.get_mut(&id) { entity.balance = after }
+        }
+    }
+    Ok(())
+}
+
+fn position_get (dbconn:&Connection, id:i64, ticker:&str) -> Bresult<(Qty, Money)> {
+    Ok(match getsql!(dbconn, "SELECT qty, price FROM positions WHERE id=? AND ticker=?", id, ticker)?.first() {
+        Some(row) => (row.get_qty("qty")?, row.get_money("price")?),
+        None => (Qty::ZERO, Money::ZERO)
+    })
+}
+
+/// Replace id's position, a zero quantity closes it.
+pub fn position_set (dbconn:&Connection, origin:Origin, id:i64, ticker:&str, qty:Qty, price:Money) -> Bresult<()> {
+    let (oldqty, oldprice) = position_get(dbconn, id, ticker)?;
+    let price = IF!(qty.is_zero(), Money::ZERO, price);
+    if qty.is_zero() {
+        getsql!(dbconn, "DELETE FROM positions WHERE id=? AND ticker=?", id, ticker)?;
+    } else if oldqty.is_zero() {
+        getsql!(dbconn, "INSERT INTO positions VALUES (?, ?, ?, ?)", id, ticker, qty, price)?;
+    } else {
+        getsql!(dbconn, "UPDATE positions SET qty=?, price=? WHERE id=? AND ticker=?", qty, price, id, ticker)?;
+    }
+    ledger_event_insert(dbconn, origin, id, "position", ticker, oldqty.0, qty.0, oldprice, price)
+}
+
+// Apply a signed quantity traded at price to id's position.  Adding to a
+// position averages the cost basis, reducing keeps it, flipping resets it.
+pub fn position_adjust (dbconn:&Connection, origin:Origin, id:i64, ticker:&str, qty:Qty, price:Money) -> Bresult<()> {
+    let (oldqty, oldprice) = position_get(dbconn, id, ticker)?;
+    let newqty = oldqty + qty;
+    let newprice =
+        if oldqty.is_zero() { price }
+        else if oldqty.is_positive() == qty.is_positive() { (oldqty*oldprice + qty*price) / newqty }
+        else if oldqty.is_positive() == newqty.is_positive() { oldprice }
+        else { price };
+    position_set(dbconn, origin, id, ticker, newqty, newprice)
+}
+
+/// id's latest n events, newest first
+pub fn ledger_events (dbconn:&Connection, id:i64, n:i64) -> Bresult<Vec<LedgerEvent>> {
+    dbconn.query_as::<LedgerEvent>(
+        "SELECT rowid AS rowid, * FROM ledger_events WHERE id=? ORDER BY rowid DESC LIMIT ?", &[&id, &n])
+}
+
+type LedgerKey = (i64, String, String); // id, kind, key
+
+// Cash and positions as the events left them: each account's summed
+// changes and the last basis.  Zeroed ones are dropped.
+fn ledger_replay (dbconn:&Connection) -> Bresult<HashMap<LedgerKey, (i64, Money)>> {
+    let mut state :HashMap<LedgerKey, (i64, Money)> = HashMap::new();
+    for event in dbconn.query_as::<LedgerEvent>("SELECT rowid AS rowid, * FROM ledger_events ORDER BY rowid", &[])? {
+        let entry = state.entry((event.id, event.kind.clone(), event.key.clone())).or_insert((0, Money::ZERO));
+        *entry = (entry.0 + event.after - event.before, event.basis_after);
+    }
+    state.retain( |_, value| 0 != value.0 );
+    Ok(state)
+}
+
+// Cash and positions as the tables hold them
+fn ledger_tables (dbconn:&Connection) -> Bresult<HashMap<LedgerKey, (i64, Money)>> {
+    let mut state = HashMap::new();
+    for row in getsql!(dbconn, "SELECT id, 'USD' AS currency, balance FROM accounts UNION ALL SELECT id, currency, balance FROM balances")? {
+        state.insert((row.get_i64("id")?, "cash".to_string(), row.get_string("currency")?), (row.get_money("balance")?.0, Money::ZERO));
+    }
+    for row in getsql!(dbconn, "SELECT id, ticker, qty, price FROM positions")? {
+        state.insert((row.get_i64("id")?, "position".to_string(), row.get_string("ticker")?), (row.get_qty("qty")?.0, row.get_money("price")?));
+    }
+    state.retain( |_, value| 0 != value.0 );
+    Ok(state)
+}
+
+/// Where the tables differ from the replayed events, as (id, kind, key,
+/// replayed, table) sorted by account.  Only cash and positions are
+/// covered: resting orders in the exchange table hold neither, and their
+/// fills are recorded by exchange_fill_settle.
+pub fn ledger_check (dbconn:&Connection) -> Bresult<Vec<(LedgerKey, (i64, Money), (i64, Money))>> {
+    let (replayed, tables) = (ledger_replay(dbconn)?, ledger_tables(dbconn)?);
+    let zero = (0, Money::ZERO);
+    let mut diffs =
+        replayed.keys().chain(tables.keys().filter( |k| !replayed.contains_key(*k) ))
+        .map( |k| (k.clone(), *replayed.get(k).unwrap_or(&zero), *tables.get(k).unwrap_or(&zero)) )
+        .filter( |(k, events, table)| events.0 != table.0 || ("position" == k.1 && events.1 != table.1) )
+        .collect::<Vec<_>>();
+    diffs.sort_by( |a, b| a.0.cmp(&b.0) );
+    Ok(diffs)
+}
+
+/// Put the tables back to what the events say, answering how many cash
+/// balances and positions changed.
+pub fn ledger_rebuild (envstruct:&mut EnvStruct) -> Bresult<usize> {
+    let diffs = ledger_check(&envstruct.dbconn)?;
+    envstruct.transaction( |envstruct| {
+        for ((id, kind, key), (qty, basis), _) in &diffs {
+            let dbconn = &envstruct.dbconn;
+            match (kind.as_str(), key.as_str()) {
+                ("cash", USD) => {
+                    getsql!(dbconn, "INSERT OR IGNORE INTO accounts VALUES (?, 0)", *id)?;
+                    getsql!(dbconn, "UPDATE accounts SET balance=? WHERE id=?", Money(*qty), *id)?;
+                    if let Some(entity) = envstruct.entitys.get_mut(id) { entity.balance = Money(*qty) }
+                },
+                ("cash", currency) =>
+                    { getsql!(dbconn, "INSERT OR REPLACE INTO balances VALUES (?, ?, ?)", *id, currency, Money(*qty))?; },
+                (_, ticker) => {
+                    getsql!(dbconn, "DELETE FROM positions WHERE id=? AND ticker=?", *id, ticker)?;
+                    if 0 != *qty { getsql!(dbconn, "INSERT INTO positions VALUES (?, ?, ?, ?)", *id, ticker, Qty(*qty), *basis)?; }
+                }
+            }
+        }
+        Ok(diffs.len())
+    })
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ORIGIN :Origin = Origin{ time: 10, actor: 1, at: 5, message_id: 77 };
+
+    // Bought 2@10 and 2@20 then sold 1@30
+    fn dbconn () -> Connection {
+        let dbconn = test_dbconn();
+        for (qty, price) in &[(2, 10), (2, 20), (-1, 30)] {
+            position_adjust(&dbconn, ORIGIN, 1, "GME", Qty::from_int(*qty), Money::from_int(*price)).unwrap();
+        }
+        dbconn
+    }
+
+    #[test]
+    fn adjust_averages_basis() {
+        let dbconn = dbconn();
+        assert_eq!(position_get(&dbconn, 1, "GME").unwrap(), (Qty::from_int(3), Money::from_int(15)));
+        assert!(ledger_check(&dbconn).unwrap().is_empty());
+    }
+
+    #[test]
+    fn events_newest_first() {
+        let events = ledger_events(&dbconn(), 1, 2).unwrap();
+        assert_eq!(events.iter().map( |e| (e.before, e.after, e.message_id) ).collect::<Vec<_>>(),
+            [(4_000_000, 3_000_000, 77), (2_000_000, 4_000_000, 77)]);
+    }
+
+    #[test]
+    fn events_append_only() {
+        let dbconn = dbconn();
+        assert!(getsql!(dbconn, "DELETE FROM ledger_events").is_err());
+        assert!(getsql!(dbconn, "UPDATE ledger_events SET after=0").is_err());
+    }
+
+    #[test]
+    fn check_finds_table_edits() {
+        let dbconn = dbconn();
+        getsql!(dbconn, "UPDATE positions SET qty=5e6").unwrap();
+        getsql!(dbconn, "INSERT INTO positions VALUES (2, 'AMC', 1e6, 1e6)").unwrap();
+        let diffs = ledger_check(&dbconn).unwrap();
+        assert_eq!(diffs.iter().map( |d| ((d.0).0, (d.1).0, (d.2).0) ).collect::<Vec<_>>(), [(1, 3_000_000, 5_000_000), (2, 0, 1_000_000)]);
+    }
+
+    #[test]
+    fn ledger_rebuild_restores_tables() {
+        let (q, m) = (Qty::from_int, Money::from_int);
+        let mut envstruct = test_envstruct();
+        getsql!(envstruct.dbconn, "UPDATE accounts SET balance=0").unwrap();
+        getsql!(envstruct.dbconn, "DELETE FROM positions").unwrap();
+        envstruct.entitys.values_mut().for_each( |e| e.balance = Money::ZERO );
+        let origin = Origin::system(0);
+        for id in 1..=2 { cash_inc(&mut envstruct, origin, id, USD, m(1000)).unwrap() }
+        cash_inc(&mut envstruct, origin, 1, "EUR", m(7)).unwrap();
+        position_set(&envstruct.dbconn, origin, 2, "2", q(10), m(5)).unwrap();
+        position_adjust(&envstruct.dbconn, origin, 1, "GME", q(4), m(10)).unwrap();
+        let before = test_ledger(&envstruct);
+
+        getsql!(envstruct.dbconn, "UPDATE accounts SET balance=5e6 WHERE id=1").unwrap();
+        envstruct.entitys.get_mut(&1).unwrap().balance = m(5);
+        getsql!(envstruct.dbconn, "DELETE FROM balances").unwrap();
+        getsql!(envstruct.dbconn, "UPDATE positions SET qty=99e6, price=1e6 WHERE id=1").unwrap();
+        getsql!(envstruct.dbconn, "DELETE FROM positions WHERE id=2").unwrap();
+        getsql!(envstruct.dbconn, "INSERT INTO positions VALUES (2, 'AMC', 1e6, 1e6)").unwrap();
+        assert_eq!(ledger_check(&envstruct.dbconn).unwrap().len(), 5);
+
+        assert_eq!(ledger_rebuild(&mut envstruct).unwrap(), 5);
+        assert_eq!(test_ledger(&envstruct), before);
+        assert_eq!(
+            (getsql!(envstruct.dbconn, "SELECT price FROM positions WHERE id=1").unwrap()[0].get_money("price").unwrap(),
+             getsql!(envstruct.dbconn, "SELECT balance FROM balances WHERE id=1").unwrap()[0].get_money("balance").unwrap()),
+            (m(10), m(7)));
+        assert!(ledger_check(&envstruct.dbconn).unwrap().is_empty());
+    }
+}
@@ -1,29 +1,48 @@
 //! # External Chat Service Robot
 mod util;  pub use crate::util::*;
+mod money; pub use crate::money::*;
 mod comm;  use crate::comm::*;
+mod http;  pub use crate::http::*;
 mod srvs;  use crate::srvs::*;
 mod db;    use crate::db::*;
+mod quotes; pub use crate::quotes::*;
+mod book;   use crate::book::*;
+mod migrate; use crate::migrate::*;
+mod history; use crate::history::*;
+mod lots;    use crate::lots::*;
+mod corpactions; use crate::corpactions::*;
+mod margin;  use crate::margin::*;
+mod shorts;  use crate::shorts::*;
+mod options; use crate::options::*;
+mod currency; use crate::currency::*;
+mod calendar; pub use crate::calendar::*;
+mod alerts;  use crate::alerts::*;
+mod commands; pub use crate::commands::*;
+mod roles;   pub use crate::roles::*;
+mod console; use crate::console::*;
+mod ledger;  use crate::ledger::*;
+mod backup;  use crate::backup::*;
 use ::std::{
     env,
-    cmp::Ordering,
-    mem::transmute,
     collections::{HashMap, HashSet},
     str::{from_utf8, FromStr},
     sync::{Arc, Mutex},
  };
 use ::log::*;
 use ::regex::Regex;
-use ::datetime::{ Instant, Duration, LocalDate, LocalTime, LocalDateTime, DatePiece, TimePiece, Weekday::* };
-use ::openssl::ssl::{SslConnector, SslAcceptor, SslFiletype, SslMethod, SslAcceptorBuilder};
+use ::datetime::{ Instant, LocalDate, LocalTime, LocalDateTime, DatePiece, TimePiece, Weekday::* };
+use ::openssl::ssl::{SslAcceptor, SslFiletype, SslMethod, SslAcceptorBuilder};
 use ::actix::{ prelude::*, Actor, StreamHandler };
-use ::actix_web::{ web, App, HttpRequest, HttpServer, HttpResponse, Route, client::{Client, Connector} };
+use ::actix_web::{ web, App, HttpRequest, HttpServer, HttpResponse, Route };
 use ::actix_web_actors::ws;
+use ::futures::future::FutureExt;
+use ::macros::FromRow;
 
 ////////////////////////////////////////////////////////////////////////////////
 
 const QUOTE_DELAY_SECS :i64 = 30;
 const FORMAT_STRING_QUOTE    :&str = "%q%A%B %C%% %D%E@%F %G%H%I%q";
-const FORMAT_STRING_POSITION :&str = "%n%q%A %C%B %D%%%q %q%E%F@%G%q %q%H@%I%q";
+const FORMAT_STRING_POSITION :&str = "%n%q%A %C%B %D%%%q %q%E%F@%G%q %q%H@%I%q%R";
 const WEB_KEY_PEM: &str = "key.pem";
 const WEB_CERT_PEM: &str = "cert.pem";
 
@@ -40,106 +59,52 @@ pub fn new_ssl_acceptor_builder() -> Bresult<SslAcceptorBuilder> {
     Ok(ssl_acceptor_builder)
 }
 
-/// Decide if a ticker's price should be refreshed given its last lookup time.
-///  PacificTZ  PreMarket  Regular  AfterHours  Closed
-///  PST        0900z      1430z    2100z       0100z..9000z
-///  PDT        0800z      1330z    2000z       0000z..0800z
-///  Duration   5.5h       6.5h     4h          8h
-fn most_recent_trading_hours (
-    dst_hours_adjust: i8,
-    now: LocalDateTime,
-) -> Bresult<(LocalDateTime, LocalDateTime)> {
-    // Absolute time (UTC) when US pre-markets open 1AMPT / 0900Z|0800Z
-    let start_time = LocalTime::hm(9 - dst_hours_adjust, 0)?;  // TODO create function describing the hour maths
-    let start_duration = Duration::of(start_time.to_seconds());
-    let market_hours_duration = Duration::of( LocalTime::hm(16, 5)?.to_seconds() ); // Add 5 minutes for delayed orders
-
-    // Consider the current trading date relative to now. Since pre-markets
-    // start at 0900Z (0800Z during daylight savings) treat that as the
-    // start of the trading day so subtract that many hours (shift to midnight)
-    // to shift the yesterday/today cutoff.
-    let now_norm_date :LocalDate = (now - start_duration).date();
-
-    // How many days ago (in seconds) were the markets open?
-    let since_last_market_duration =
-        Duration::of( LocalTime::hm(24, 0)?.to_seconds() )
-        * match now_norm_date.weekday() { Sunday=>2, Saturday=>1, _=>0 };
-
-    // Absolute time the markets opened/closed last. Could be future time.
-    let time_open = LocalDateTime::new(now_norm_date, start_time) - since_last_market_duration;
-    let time_close = time_open + market_hours_duration; // Add extra 30m for delayed market orders.
-
-    info!("most_recent_trading_hours  now:{:?}  now_norm_date:{:?}  since_last_market_duration:{:?}  time_open:{:?}  time_close:{:?}",
-        now, now_norm_date, since_last_market_duration, time_open, time_close);
-
-    Ok((time_open, time_close))
-}
-
-fn trading_hours_p (dst_hours_adjust:i8, now:i64) -> Bresult<bool>{
-    let now = LocalDateTime::at(now);
-    let (time_open, time_close) = most_recent_trading_hours(dst_hours_adjust, now)?;
-    Ok(time_open <= now && now <= time_close)
+fn trading_hours_p (exchange:&str, now:i64) -> Bresult<bool>{
+    exchange_hours(exchange).trading_p(now)
 }
 
+/// Decide if a ticker's price should be refreshed given its last lookup time.
+/// Refresh during its exchange's most recent session, throttled, and once
+/// more after it ends.  Weekends and market holidays have no session of their own.
 fn update_ticker_p (
     envstruct: &EnvStruct,
     cached: i64,
     now:    i64,
-    traded_all_day: bool
+    traded_all_day: bool,
+    exchange: &str
 ) -> Bresult<bool> {
-    info!("update_ticker_p  cached:{}  now:{}  traded_all_day:{}", cached, now, traded_all_day);
+    info!("update_ticker_p  cached:{}  now:{}  traded_all_day:{}  exchange:{}", cached, now, traded_all_day, exchange);
 
     if traded_all_day { return Ok(cached+(envstruct.quote_delay_secs) < now) }
 
-    let lookup_throttle_duration = Duration::of(envstruct.quote_delay_secs); // Lookup tickers every 2 minutes
-
-    // Do everything in DateTime
-    let cached = LocalDateTime::at(cached);
-    let now = LocalDateTime::at(now);
-
-    let (time_open, time_close) = most_recent_trading_hours(envstruct.dst_hours_adjust, now)?;
+    let session = exchange_hours(exchange).most_recent_session(now)?;
 
-    info!("update_ticker_p  cached:{:?}  now:{:?}  time_open:{:?}  time_close:{:?}",
-        cached, now, time_open, time_close);
-
-    Ok(cached <= time_close
-        && time_open <= now
-        && (cached + lookup_throttle_duration < now  ||  time_close <= now))
-}
-
-/// Round a float at the specified decimal offset
-///  println!("{:?}", num::Float::integer_decode(n) );
-fn roundfloat (num:f64, dec:i32) -> f64 {
-    let fac = 10f64.powi(dec);
-    let num_incremented = unsafe { transmute::<u64, f64>(transmute::<f64, u64>(num) + 1) };
-    (num_incremented * fac).round() / fac
+    Ok(cached <= session.post
+        && session.pre <= now
+        && (cached + envstruct.quote_delay_secs < now  ||  session.post <= now))
 }
 
-// Round for numbers that were serialized funny: -0.00999999 => -0.01, -1.3400116 => -1.34
-fn roundqty (num:f64) -> f64 { roundfloat(num, 4) }
-fn roundcents (num:f64) -> f64 { roundfloat(num, 2) }
-
-fn round (f:f64) -> String {
-    if 0.0 == f {
+fn round (m:Money) -> String {
+    if m.is_zero() {
         format!(".00")
-    } else if f < 1.0 {
-        format!("{:.4}", roundqty(f))
+    } else if m < Money::from_int(1) {
+        format!("{:.4}", m)
             .trim_start_matches('0').to_string()
     } else {
-        format!("{:.2}", roundcents(f))
+        format!("{:.2}", m)
     }
 }
 // number to -> .1234 1.12 999.99 1.99k
-fn roundkilofy (f:f64) -> String {
-    if 0.0 == f {
+fn roundkilofy (m:Money) -> String {
+    if m.is_zero() {
         format!(".00")
-    } else if 1000.0 <= f {
-        format!("{:.2}k", roundcents(f/1000.0))
-    } else if f < 1.0 {
-        format!("{:.4}", roundqty(f))
+    } else if Money::from_int(1000) <= m {
+        format!("{:.2}k", m.scale(0.001))
+    } else if m < Money::from_int(1) {
+        format!("{:.4}", m)
             .trim_start_matches('0').to_string()
     } else {
-        format!("{:.2}", roundcents(f))
+        format!("{:.2}", m)
             .trim_start_matches('0').to_string()
     }
 }
@@ -164,10 +129,10 @@ fn percent_squish (num:f64) -> String {
 
 // Stringify float to two decimal places unless under 10
 // where it's 4 decimal places with trailing 0s truncate.
-fn money_pretty (n:f64) -> String {
-    if n < 0.0001 {
+fn money_pretty (n:Money) -> String {
+    if n < Money(100) {
         format!("{}", n)
-    } else if n < 1.0 {
+    } else if n < Money::from_int(1) {
         let mut np = format!("{:.4}", n);
         np = regex_to_hashmap(r"^0(.*)$", &np).map_or(np, |c| c["1"].to_string() ); // strip leading 0
         np = regex_to_hashmap(r"^(.*)0$", &np).map_or(np, |c| c["1"].to_string() ); // strip trailing 0
@@ -177,8 +142,8 @@ fn money_pretty (n:f64) -> String {
     }
 }
 
-fn percentify (a: f64, b: f64) -> f64 {
-    (b-a)/a*100.0
+fn percentify (a: Money, b: Money) -> f64 {
+    (b-a).to_f64()/a.to_f64()*100.0
 }
 
 // (5,"a","bb") => "a..bb"
@@ -230,107 +195,57 @@ pub fn time2timestr (time:i64) -> String {
 ////////////////////////////////////////////////////////////////////////////////
 /// Helpers on complex types
 
-pub fn sql_table_order_insert (dbconn:&Connection, id:i64, ticker:&str, qty:f64, price:f64, time:i64) -> Bresult<()> {
+pub fn sql_table_order_insert (dbconn:&Connection, id:i64, ticker:&str, qty:Qty, price:Money, time:i64) -> Bresult<()> {
     getsql!(dbconn,
         "INSERT INTO orders VALUES (?, ?, ?, ?, ?)",
         id, ticker, qty, price, time)?;
+    lots_apply(dbconn, id, ticker, qty, price, time)?;
+    borrow_sync(dbconn, id, ticker, time)?;
     Ok(())
 }
 
-fn create_schema() -> Bresult<()> {
-    let conn = Connection::new("tmbot.sqlite".into())?;
-
-    getsql!(&conn,"
-        CREATE TABLE IF NOT EXISTS entitys (
-            id INTEGER NOT NULL UNIQUE,
-            name  TEXT NOT NULL);")?;
-
-    getsql!(&conn,"
-        CREATE TABLE IF NOT EXISTS modes (
-            id   INTEGER NOT NULL UNIQUE,
-            echo INTEGER NOT NULL);")?;
-
-    getsql!(&conn,"
-        CREATE TABLE IF NOT EXISTS formats (
-            id    INTEGER NOT NULL UNIQUE,
-            quote    TEXT NOT NULL,
-            position TEXT NOT NULL);
-    ")?;
-
-    getsql!(&conn,"
-        CREATE TABLE IF NOT EXISTS accounts (
-            id    INTEGER  NOT NULL UNIQUE,
-            balance FLOAT  NOT NULL);
-    ")?;
-
-    getsql!(&conn,"
-        CREATE TABLE IF NOT EXISTS stonks (
-            ticker   TEXT NOT NULL UNIQUE,
-            price   FLOAT NOT NULL,
-            last    FLOAT NOT NULL,
-            market   TEXT NOT NULL,
-            hours INTEGER NOT NULL,
-            exchange TEXT NOT NULL,
-            time  INTEGER NOT NULL,
-            title    TEXT NOT NULL);
-    ")?;
-
-    getsql!(&conn,"
-        CREATE TABLE IF NOT EXISTS orders (
-            id   INTEGER  NOT NULL,
-            ticker  TEXT  NOT NULL,
-            qty    FLOAT  NOT NULL,
-            price  FLOAT  NOT NULL,
-            time INTEGER  NOT NULL);
-    ")?;
-
-    getsql!(&conn,"
-        CREATE TABLE IF NOT EXISTS positions (
-            id   INTEGER NOT NULL,
-            ticker  TEXT NOT NULL,
-            qty    FLOAT NOT NULL,
-            price  FLOAT NOT NULL);
-    ")?;
-
-    getsql!(&conn,"
-        CREATE TABLE IF NOT EXISTS exchange (
-            id   INTEGER NOT NULL,
-            ticker  TEXT NOT NULL,
-            qty    FLOAT NOT NULL,
-            price  FLOAT NOT NULL,
-            time INTEGER NOT NULL);
-    ")?;
-
-    getsql!(&conn,"
-        CREATE TABLE IF NOT EXISTS schedules (
-            id   INTEGER NOT NULL,
-            at   INTEGER NOT NULL,
-            time INTEGER NOT NULL,
-            days    TEXT NOT NULL,
-            cmd     TEXT NOT NULL);")?;
-
-    getsql!(&conn,"
-        CREATE TABLE IF NOT EXISTS likes (
-            id    INTEGER NOT NULL UNIQUE,
-            likes INTEGER NOT NULL);")?;
+// Move cash and shares between both sides of an exchange fill
+fn exchange_fill_settle (envstruct:&mut EnvStruct, origin:Origin, ticker:&str, fill:&Fill) -> Bresult<()> {
+    let value = fill.qty * fill.price;
+    sql_table_order_insert(&envstruct.dbconn, fill.buyer, ticker, fill.qty, fill.price, fill.time)?;
+    sql_table_order_insert(&envstruct.dbconn, fill.seller, ticker, -fill.qty, fill.price, fill.time)?;
+    position_adjust(&envstruct.dbconn, origin, fill.buyer, ticker, fill.qty, fill.price)?;
+    position_adjust(&envstruct.dbconn, origin, fill.seller, ticker, -fill.qty, fill.price)?;
+    crash_point("exchange_fill_settle")?;
+    cash_inc(envstruct, origin, fill.buyer, USD, -value)?;
+    cash_inc(envstruct, origin, fill.seller, USD, value)?;
+    Ok(())
+}
 
+// Self-stonk quotes are the last exchange trade price
+fn stonk_trade_price_set (dbconn:&Connection, ticker:&str, price:Money, time:i64) -> Bresult<()> {
+    let rows = getsql!(dbconn, "SELECT price FROM stonks WHERE ticker=?", ticker)?;
+    let last = if rows.is_empty() { price } else { rows[0].get_money("price")? };
+    getsql!(dbconn, "INSERT OR REPLACE INTO stonks VALUES (?, ?, ?, 'r', 24, '™BOT', ?, 'FNFT', 'USD')",
+        ticker, price, last, time)?;
     Ok(())
-} // create_schema
+}
 
 ////////////////////////////////////////////////////////////////////////////////
 /// Blobs
 
-#[derive(Debug)]
+#[derive(Debug, FromRow)]
 pub struct Entity {
     id: i64,
     name: String,
-    balance: f64, // Available cash.  Could be negative as long as -2*balance < portfolioValue
+    #[row(default)]
+    balance: Money, // Available cash.  Negative is borrowed, see margin.rs
+    #[row(default = 2)]
     echo: i64,
+    #[row(default)]
     likes: i64,
     // format strings
+    #[row(default)]
     quote: String,
+    #[row(default)]
     position: String,
     // web portal
+    #[row(skip)]
     uuid: String
 }
 
@@ -338,10 +253,16 @@ pub struct Entity {
 pub struct EnvStruct {
     url_api:          String, // Telgram API URL
     dbconn:           Connection, // SQLite connection
+    http:             Arc<HttpService>, // Outbound requests
     quote_delay_secs: i64,    // Delay between remote stock quote queries
-    dst_hours_adjust: i8,     // 1 = DST, 0 = ST
     time_scheduler:   i64,    // Time the scheduler last ran
     entitys:          HashMap<i64, Entity>,
+    quotes:           Arc<dyn QuoteProvider>, // Market quote source
+    actions:          Arc<dyn CorporateActionProvider>, // Split and dividend source
+    chat:             ChatPlatform, // Transport each CmdStruct talks over
+    books:            HashMap<String, Book>, // Self-stonk order books, loaded on first use
+    commands:         Arc<CommandRegistry>, // Chat commands in dispatch order
+    sql_pending:      HashMap<(i64, i64), (String, i64)>, // Staged /sql write and its time by (id, at)
 }
 
 type Env = Arc<Mutex<EnvStruct>>;
@@ -361,7 +282,7 @@ impl EnvStruct {
         let s = &self.entitys.get(&id).unwrap().position;
         if s.is_empty() { FORMAT_STRING_POSITION } else { s }.to_string()
     }
-    fn entity_balance (&self, id:i64) -> Bresult<f64> {
+    fn entity_balance (&self, id:i64) -> Bresult<Money> {
         Ok(self.entitys.get(&id).ok_or(format!("entity_balance() no id {}", id))?.balance)
     }
     fn entity_id2name (&self, id:i64) -> Bresult<&str> {
@@ -370,21 +291,52 @@ impl EnvStruct {
     fn entity_ticker2name (&self, tkr:&str) -> Bresult<&str> {
         Ok(&self.entitys.get(&tkr.parse::<i64>()?).ok_or(format!("entity_name() no id {}", tkr))?.name)
     }
-    fn entity_balance_set (&mut self, id:i64, new_balance:f64) -> Bresult<()> {
-        getsql!(self.dbconn, "UPDATE accounts SET balance=? WHERE id=?", new_balance, id)?;
-        self.entitys.get_mut(&id).unwrap().balance = new_balance;
-        Ok(())
-    }
-    fn entity_balance_inc (&mut self, id:i64, balance_adjust:f64) -> Bresult<()> {
-        getsql!(self.dbconn, "UPDATE accounts SET balance=balance+? WHERE id=?", balance_adjust, id)?;
-        self.entitys.get_mut(&id).unwrap().balance += balance_adjust;
-        Ok(())
-    }
     fn entity_likes_inc (&mut self, at: i64, adj: i64) -> Bresult<i64> {
         let likes = self.entitys.get_mut(&at).map_or(1, |obj| { obj.likes+=adj; obj.likes });
         getsql!(self.dbconn, "INSERT OR REPLACE INTO likes VALUES (?, ?)", at, likes)?;
         Ok(likes)
     }
+    pub fn quote_provider_set (&mut self, provider: Arc<dyn QuoteProvider>) {
+        self.quotes = provider;
+    }
+    fn new_transport (&self) -> Bresult<Box<dyn ChatTransport>> {
+        Ok(match self.chat {
+            ChatPlatform::Telegram => Box::new(Telegram::new(self.http.clone(), self.url_api.to_string())),
+            ChatPlatform::Console => Box::new(Console::new())
+        })
+    }
+    // Run body as one database transaction.  On failure the cached balances
+    // and order books are put back to match the rolled back tables.
+    fn transaction<T> (&mut self, body: impl FnOnce(&mut EnvStruct) -> Bresult<T>) -> Bresult<T> {
+        let balances :Vec<(i64, Money)> = self.entitys.iter().map( |(id, e)| (*id, e.balance) ).collect();
+        self.dbconn.begin()?;
+        let res = body(self);
+        let ended = self.dbconn.end(res.is_ok());
+        if res.is_err() || ended.is_err() {
+            for (id, balance) in balances {
+                if let Some(entity) = self.entitys.get_mut(&id) { entity.balance = balance }
+            }
+            self.books.clear();
+        }
+        ended?;
+        res
+    }
+    fn book (&mut self, ticker:&str) -> Bresult<&mut Book> {
+        if !self.books.contains_key(ticker) {
+            let book = Book::load(&self.dbconn, ticker)?;
+            self.books.insert(ticker.to_string(), book);
+        }
+        Ok(self.books.get_mut(ticker).unwrap())
+    }
+    fn book_save (&self, ticker:&str) -> Bresult<()> {
+        self.books.get(ticker).map_or(Ok(()), |book| book.save(&self.dbconn))
+    }
+    fn setting (&self, key:&str) -> Bresult<Option<String>> {
+        Ok(match getsql!(self.dbconn, "SELECT value FROM settings WHERE key=?", key)?.first() {
+            Some(row) => Some(row.get_string("value")?),
+            None => None
+        })
+    }
     fn entity_uuid_set (&mut self, id: i64, pw: usize) -> Bresult<()> {
         self.entitys
             .get_mut(&id)
@@ -401,56 +353,56 @@ fn new(mut argv: std::env::Args) -> Bresult<Env> {
             .to_str()
             .unwrap());
     let dbconn = Connection::new(argv.next().ok_or("args[2] missing")?)?;
+    info!("schema version {}", migrate(&dbconn)?);
+    match owner_bootstrap(&dbconn)? {
+        Some(owner) => info!("owner {}", owner),
+        None => if grants(&dbconn, EVERY_CHAT)?.iter().all( |g| g.role != "owner" ) { warn!("no owner, set TMBOT_OWNER_ID") }
+    }
     let mut entitys = HashMap::new();
-    for hm in
-        getsql!(dbconn,
+    for entity in
+        dbconn.query_as::<Entity>(
             r"SELECT entitys.id, entitys.name, accounts.balance, modes.echo, likes.likes, formats.quote, formats.position
             FROM entitys
             LEFT JOIN accounts ON entitys.id = accounts.id
             LEFT JOIN modes    ON entitys.id = modes.id
             LEFT JOIN likes    ON entitys.id = likes.id
-            LEFT JOIN formats  ON entitys.id = formats.id")?
+            LEFT JOIN formats  ON entitys.id = formats.id", &[])?
     {
-        //warn!("{:#?}", hm);
-        let id = hm.get_i64("id")?;
-        entitys.insert(id,
-            Entity{
-                id,
-                name:    hm.get_string("name")?,
-                balance: hm.get_f64_or(0.0, "balance"),
-                echo:    hm.get_i64_or(2, "echo"),
-                likes:   hm.get_i64_or(0, "likes"),
-                quote:   hm.get_string_or("", "quote"),
-                position:hm.get_string_or("", "position"),
-                uuid:    String::new()
-            });
+        entitys.insert(entity.id, entity);
     }
     entitys.insert(0, Entity{
                 id:       0,
                 name:     "nil".to_string(),
-                balance:  0.0,
+                balance:  Money::ZERO,
                 echo:     2,
                 likes:    0,
                 quote:    String::new(),
                 position: String::new(),
                 uuid:     String::new()
     });
+    let http = Arc::new(HttpService::new(HttpConfig::from_env()?).ssl(comm::new_ssl_connector_builder()?.build()));
     Ok(EnvStruct{
         url_api, dbconn,
-        dst_hours_adjust:   argv.next().ok_or("args[3] missing")?.parse::<i8>()?,
         quote_delay_secs:   QUOTE_DELAY_SECS,
         time_scheduler:     Instant::now().seconds(),
         entitys,
+        quotes:             new_quote_provider(&http)?,
+        actions:            new_corporate_action_provider(&http)?,
+        http,
+        chat:               ChatPlatform::Telegram,
+        books:              HashMap::new(),
+        commands:           Arc::new(builtin_commands()?),
+        sql_pending:        HashMap::new(),
     }.into())
 } }
 
 ////////////////////////////////////////
 
 #[derive(Debug)]
-pub struct CmdStruct { // Represents in incoming Telegram message and session/service state.
+pub struct CmdStruct { // Represents in incoming chat message and session/service state.
     env: Env,
-    telegram: Telegram, // Not in Env since threads can't share this due to an internal Rc
-    // Telegram incoming message details
+    transport: Box<dyn ChatTransport>, // Not in Env since threads can't share Telegram due to an internal Rc
+    // Incoming message details
     now: i64,
     id: i64, // Entity who sent msg                    message.from.id
     at: i64, // Channel (group/entity) msg sent to     message.chat.id
@@ -459,11 +411,32 @@ pub struct CmdStruct { // Represents in incoming Telegram message and session/se
     message: String, // The incoming message
     id_level: i64, // Echo level [0,1,2] for each id.  Higher can't write to lower.
     at_level: i64,
-    // Telegram outgoing/response message details
+    // Outgoing/response message details
     markdown: bool,
     dm: Option<i64>, // direct message non-overrideable (normally sent to at if levels concur, othrwise id)
     msg_id: Option<i64>, // existing/previous message_id to overwrite
-    msg: String
+    msg: String,
+    fills: Option<Fills> // Order a trade fills, closed in the fill's transaction
+}
+
+/// Orders an automatic trade carries out
+#[derive(Debug, Clone)]
+pub enum Fills {
+    Limit(i64),           // limits table rowid
+    Exercise(String, Qty) // Option contracts
+}
+
+// Close the order a trade fills in the fill's transaction
+fn fills_close (dbconn:&Connection, origin:Origin, id:i64, now:i64, fills:&Option<Fills>) -> Bresult<()> {
+    match fills {
+        Some(Fills::Limit(rowid)) => { getsql!(dbconn, "DELETE FROM limits WHERE rowid=?", *rowid)?; },
+        Some(Fills::Exercise(ticker, qty)) => {
+            sql_table_order_insert(dbconn, id, ticker, -*qty, Money::ZERO, now)?;
+            position_set(dbconn, origin, id, ticker, Qty::ZERO, Money::ZERO)?;
+        },
+        None => ()
+    }
+    Ok(())
 }
 
 impl MsgDetails for CmdStruct {
@@ -486,48 +459,42 @@ impl MsgDetails for CmdStruct {
 }
 
 impl CmdStruct {
-    // Creates a Cmd object from Env and Telegram message body.
-    fn newcmdstruct(env: Env, body: &web::Bytes) -> Bresult<CmdStruct> {
-        let json: Value = bytes2json(&body)?;
-        let inline_query = &json["inline_query"];
-        let edited_message = &json["edited_message"];
-        let message = if edited_message.is_object() { edited_message } else { &json["message"] };
-        let message_id = getin_i64(message, &["message_id"]).unwrap_or(0);
-        let (id, at, to, message) =
-            if inline_query.is_object() { // Inline queries are DMs so no other associated channels
-                let id = getin_i64(inline_query, &["from", "id"])?;
-                let message = getin_str(inline_query, &["query"])?.to_string();
-                (id, id, id, message)
-            } else if message.is_object() { // An incoming message could be a reply or normal.
-                let id = getin_i64(message, &["from", "id"])?;
-                let at = getin_i64(message, &["chat", "id"])?;
-                let to = getin_i64_or(at, &message, &["reply_to_message", "from", "id"]);
-                let message = getin_str(message, &["text"])?.to_string();
-                (id, at, to, message) // Normal message
-            } else { Err("Nothing to do.")? };
-
-        CmdStruct::new_cmdstruct(env, Instant::now().seconds(), id, at, to, message_id, &message)
+    // Creates a Cmd object from Env and the chat platform's message body.
+    fn newcmdstruct(env: Env, body: &[u8]) -> Bresult<CmdStruct> {
+        let transport = env.lock().unwrap().new_transport()?;
+        let update = transport.parse_update(body)?;
+        CmdStruct::new_cmdstruct_transport(env, transport, Instant::now().seconds(),
+            update.id, update.at, update.to, update.message_id, &update.message)
     }
 
     // create a basic CmdStruct
     fn new_cmdstruct(env: Env, now:i64, id:i64, at:i64, to:i64, message_id:i64, message:&str) -> Bresult<CmdStruct> {
-        let (telegram, id_level, at_level) =  {
+        let transport = env.lock().unwrap().new_transport()?;
+        CmdStruct::new_cmdstruct_transport(env, transport, now, id, at, to, message_id, message)
+    }
+
+    fn new_cmdstruct_transport(env: Env, transport: Box<dyn ChatTransport>, now:i64, id:i64, at:i64, to:i64, message_id:i64, message:&str) -> Bresult<CmdStruct> {
+        let (id_level, at_level) =  {
             let envstruct = env.lock().unwrap();
-            (   Telegram::new(envstruct.url_api.to_string())?,
-                envstruct.entitys.get(&id).ok_or(format!("id {} missing from entitys", id))?.echo,
+            (   envstruct.entitys.get(&id).ok_or(format!("id {} missing from entitys", id))?.echo,
                 envstruct.entitys.get(&at).ok_or(format!("at {} missing from entitys", at))?.echo )
         };
         Ok(CmdStruct{
-            env, telegram, now, id, at, to, message_id,
+            env, transport, now, id, at, to, message_id,
             message: message.to_string(),
             id_level, at_level,
             markdown: false,
             dm: None,
             msg_id: None,
-            msg: String::new()
+            msg: String::new(),
+            fills: None
         })
     }
 
+    // Ledger origin of changes this message makes
+    fn origin (&self) -> Origin {
+        Origin{ time: self.now, actor: self.id, at: self.at, message_id: self.message_id }
+    }
     // Switch to markdown mode
     fn markdown (&mut self) -> &mut Self {
         self.markdown = true;
@@ -547,44 +514,54 @@ impl CmdStruct {
     // Send new message
     async fn send_msg (&mut self) -> Bresult<()> {
         self.msg_id = None;
-        self.msg_id = self.telegram.send_msg(self).await?.msg_id;
+        self.msg_id = send_msg(&*self.transport, self).await?.msg_id;
         Ok(())
     }
     // Edit last message (last msg_id is always cached)
     async fn edit_msg (&mut self) -> Bresult<()> {
-        self.msg_id = self.telegram.send_msg(self).await?.msg_id;
+        self.msg_id = send_msg(&*self.transport, self).await?.msg_id;
         Ok(())
     }
     // Send new message to self (TODO: implement edit message to self?)
     async fn send_msg_id (&mut self) -> Bresult<()> {
         self.msg_id = None;
         self.dm = Some(self.id);
-        self.msg_id = self.telegram.send_msg(self).await?.msg_id;
+        self.msg_id = send_msg(&*self.transport, self).await?.msg_id;
         self.dm = None;
         Ok(())
     }
+    // Send a file to self
+    async fn send_document (&mut self, filename:&str, content:&str) -> Bresult<()> {
+        self.transport.send_document(self.id, filename, content).await?;
+        Ok(())
+    }
     // Buying power is summed long positions (positive) plus double the bank
     // balance (pos or neg) plus 3 times summed short positions (negative).
     // Buying long decreased cash while selling short increases cash.
     // Thus the invariant/buying-power is (otherwise the margin is exceeded/called):
     // 0 < 3*ShortPositions + LongPositions + 2*CashBalance
-    async fn buying_power (&mut self) -> Bresult<f64> {
+    async fn buying_power (&mut self) -> Bresult<Money> {
         let positions = Position::get_users_positions(self)?;
-        let mut long = 0.0;
-        let mut short = 0.0;
+        let mut long = Money::ZERO;
+        let mut short = Money::ZERO;
         for mut pos in positions {
             if !is_self_stonk(&pos.ticker) {
                 pos.update_quote(&self).await?;
                 let qty= pos.qty;
-                if qty < 0.0 {
-                    short += qty * pos.quote.unwrap().price
+                let quote = pos.quote.unwrap();
+                let value = (qty * quote.price).scale(fx_rate(self, &quote.currency).await?);
+                if qty.is_negative() {
+                    short += value
                 } else {
-                    long += qty * pos.quote.unwrap().price
+                    long += value
                 }
             }
         }
-        let cash = getenvstruct!(self).entity_balance(self.id)?;
-        let bp = 2.0*cash + 3.0*short + long;
+        let cash = {
+            let envstruct = getenvstruct!(self);
+            envstruct.entity_balance(self.id)? + balances_usd(&envstruct.dbconn, self.id)?
+        };
+        let bp = cash*2 + short*3 + long;
         info!("buying_power => cash {:.2}*2 + short {:.2}*3 + long {:.2} = BP {:.2}", cash, short, long, bp);
         Ok(bp)
     }
@@ -611,25 +588,35 @@ macro_rules! getenvstruct {
 pub struct Quote {
     pub env: Env,
     pub ticker: String,
-    pub price: f64, // Current known price
-    pub last: f64,  // Previous day's closing regular market price
-    pub amount: f64, // Delta change since last
+    pub price: Money, // Current known price
+    pub last: Money,  // Previous day's closing regular market price
+    pub amount: Money, // Delta change since last
     pub percent: f64, // Delta % change since last
     pub market: String, // 'p're 'r'egular 'p'ost
     pub hours: i64, // 16 or 24 (hours per day market trades)
     pub exchange: String,// Keep track of this to filter "PNK" exchanged securities.
     pub title: String, // Full title/name of security
+    pub currency: String, // Prices are in this currency
     pub updated: bool // Was this generated or pulled from cache
 }
 
 
-impl Quote { // Query internet for ticker details
+impl Quote { // Query the quote provider (internet or fixture) for ticker details
     async fn new_market_quote (env:Env, ticker: &str) -> Bresult<Self> {
-        let json = srvs::get_ticker_raw(ticker).await?;
+        let provider = env.lock().unwrap().quotes.clone();
+        let json = match OptionContract::parse(ticker) {
+            Some(contract) => option_quote_raw(&provider.get_option_chain_raw(&contract.underlying, contract.expiry).await?, ticker)?,
+            None => provider.get_ticker_raw(ticker).await?
+        };
         let details =
             getin(&json, &["quoteResponse", "result"])
             .get(0)
             .ok_or_else( || format!("quoteResponse.Result.0 failed on json response: {}", json) )?;
+        Quote::from_details(env, ticker, details)
+    }
+
+    // Parse one of Yahoo's v7 quoteResponse results
+    fn from_details (env:Env, ticker: &str, details: &Value) -> Bresult<Self> {
         info!("{}", details);
 
         let title_raw =
@@ -654,6 +641,7 @@ impl Quote { // Query internet for ticker details
         info!("cleane title: '{}' => '{}'", title_raw, &title);
 
         let exchange = getin_str(&details, &["exchange"])?;
+        let currency = getin_str(&details, &["currency"]).unwrap_or_default();
 
         let hours = getin(&details, &["volume24Hr"]);
         let hours :i64 = if !hours.is_null() { 24 } else { 16 };
@@ -683,21 +671,22 @@ impl Quote { // Query internet for ticker details
 
         details.sort_by( |a,b| b.3.cmp(&a.3) ); // Find latest quote details
 
-        let price = details[0].0;
-        let last = details[0].1;
+        let (currency, price) = currency_normalize(&currency, Money::from_f64(details[0].0));
+        let last = currency_normalize(&currency, Money::from_f64(details[0].1)).1;
         Ok(Quote{
             env,
             ticker:  ticker.to_string(),
             price, last,
-            amount:  roundqty(price-last),
+            amount:  price-last,
             percent: percentify(last,price),
             market:  details[0].2.to_string(),
-            hours, exchange, title,
+            hours, exchange, title, currency,
             updated: true})
-    } // Quote::new_market_quote
+    } // Quote::from_details
 
     async fn _new_market_quote_1 (env:Env, ticker: &str) -> Bresult<Self> {
-        let json = srvs::_get_ticker_raw_1(ticker).await?;
+        let http = env.lock().unwrap().http.clone();
+        let json = srvs::_get_ticker_raw_1(&http, ticker).await?;
 
         let details = getin(&json, &["context", "dispatcher", "stores", "QuoteSummaryStore", "price"]);
         if details.is_null() { Err("Unable to find quote data in json key 'QuoteSummaryStore'")? }
@@ -754,16 +743,17 @@ impl Quote { // Query internet for ticker details
 
         details.sort_by( |a,b| b.3.cmp(&a.3) ); // Find latest quote details
 
-        let price = details[0].0;
-        let last = details[0].1;
+        let price = Money::from_f64(details[0].0);
+        let last = Money::from_f64(details[0].1);
         Ok(Quote{
             env,
             ticker:  ticker.to_string(),
             price, last,
-            amount:  roundqty(price-last),
+            amount:  price-last,
             percent: percentify(last,price),
             market:  details[0].2.to_string(),
             hours, exchange, title,
+            currency: USD.to_string(),
             updated: true})
     } // Quote::_new_market_quote_1
 }
@@ -778,7 +768,7 @@ impl Quote {// Query local cache or internet for ticker details
             let envstruct = getenvstruct!(cmdstruct);
             let res = getsql!(
                 envstruct.dbconn,
-                "SELECT ticker, price, last, market, hours, exchange, time, title FROM stonks WHERE ticker=?",
+                "SELECT ticker, price, last, market, hours, exchange, time, title, currency FROM stonks WHERE ticker=?",
                 ticker)?;
             let is_in_table = !res.is_empty();
             let is_cache_valid =
@@ -786,7 +776,7 @@ impl Quote {// Query local cache or internet for ticker details
                     let hm = &res[0];
                     let timesecs       = hm.get_i64("time")?;
                     let traded_all_day = 24 == hm.get_i64("hours")?;
-                    !update_ticker_p(&envstruct, timesecs, cmdstruct.now, traded_all_day)?
+                    !update_ticker_p(&envstruct, timesecs, cmdstruct.now, traded_all_day, &hm.get_string("exchange")?)?
                 });
             (res, is_in_table, is_cache_valid)
         };
@@ -794,31 +784,33 @@ impl Quote {// Query local cache or internet for ticker details
         let quote =
             if is_cache_valid { // Is in cache so use it
                 let hm = &res[0];
-                let price = hm.get_f64("price")?;
-                let last = hm.get_f64("last")?;
+                let price = hm.get_money("price")?;
+                let last = hm.get_money("last")?;
                 Quote{
                     env: cmdstruct.env.clone(),
                     ticker: hm.get_string("ticker")?,
                     price, last,
-                    amount:   roundqty(price-last),
+                    amount:   price-last,
                     percent:  percentify(last,price),
                     market:   hm.get_string("market")?,
                     hours:    hm.get_i64("hours")?,
                     exchange: hm.get_string("exchange")?,
                     title:    hm.get_string("title")?,
+                    currency: hm.get_string("currency")?,
                     updated:  false}
             } else if is_self_stonk { // FNFT is not in cache so create
                 Quote {
                     env: cmdstruct.env.clone(),
                     ticker: ticker.to_string(),
-                    price:   0.0,
-                    last:    0.0,
-                    amount:  0.0,
+                    price:   Money::ZERO,
+                    last:    Money::ZERO,
+                    amount:  Money::ZERO,
                     percent: 0.0,
                     market:  "r".to_string(),
                     hours:   24,
                     exchange:"™BOT".to_string(),
                     title:   "FNFT".to_string(),
+                    currency: USD.to_string(),
                     updated: true
                 }
             } else { // Quote not in cache so query internet
@@ -829,8 +821,8 @@ impl Quote {// Query local cache or internet for ticker details
         if !is_self_stonk { // Cached FNFTs are only updated during trading/settling.
             let dbconn = &getenvstruct!(cmdstruct).dbconn;
             if !is_in_table {
-                getsql!(dbconn, "INSERT INTO stonks VALUES(?,?,?,?,?,?,?,?)",
-                    &*quote.ticker, quote.price, quote.last, &*quote.market, quote.hours, &*quote.exchange, cmdstruct.now, &*quote.title)?;
+                getsql!(dbconn, "INSERT INTO stonks VALUES(?,?,?,?,?,?,?,?,?)",
+                    &*quote.ticker, quote.price, quote.last, &*quote.market, quote.hours, &*quote.exchange, cmdstruct.now, &*quote.title, &*quote.currency)?;
             } else if !is_cache_valid {
                 getsql!(dbconn, "UPDATE stonks SET price=?, last=?, market=?, time=? WHERE ticker=?",
                     quote.price, quote.last, &*quote.market, cmdstruct.now, quote.ticker.as_str())?;
@@ -839,6 +831,66 @@ impl Quote {// Query local cache or internet for ticker details
 
         Ok(quote)
     } // Quote::get_market_quote
+
+    /// Quotes for many tickers.  Market tickers missing from the cache or
+    /// stale are fetched together and cached in one transaction, the rest,
+    /// and all of them if the batch fails, come from get_market_quote.
+    /// Tickers without a quote are left out.
+    async fn get_market_quotes (cmdstruct: &CmdStruct, tickers: &[String]) -> Bresult<HashMap<String, Self>> {
+        let mut stale :Vec<(String, bool)> = Vec::new(); // Ticker and if it's in the cache
+        {
+            let envstruct = getenvstruct!(cmdstruct);
+            for ticker in tickers {
+                if !market_ticker_p(ticker) || is_option(ticker) || stale.iter().any( |s| &s.0 == ticker ) { continue }
+                match getsql!(envstruct.dbconn, "SELECT time, hours, exchange FROM stonks WHERE ticker=?", &**ticker)?.first() {
+                    None => stale.push((ticker.to_string(), false)),
+                    Some(row) =>
+                        if update_ticker_p(&envstruct, row.get_i64("time")?, cmdstruct.now, 24 == row.get_i64("hours")?, &row.get_string("exchange")?)? {
+                            stale.push((ticker.to_string(), true))
+                        }
+                }
+            }
+        }
+
+        let mut quotes = HashMap::new();
+        if !stale.is_empty() {
+            let provider = getenvstruct!(cmdstruct).quotes.clone();
+            let symbols = stale.iter().map( |s| s.0.clone() ).collect::<Vec<String>>();
+            let json = match provider.get_tickers_raw(&symbols).await {
+                Ok(json) => json,
+                e => { glogd!("get_market_quotes get_tickers_raw =>", e); Value::Null } // Fetched one at a time below
+            };
+            for details in getin(&json, &["quoteResponse", "result"]).as_array().map_or(&[][..], |a| &a[..]) {
+                let symbol = getin_str(details, &["symbol"]).unwrap_or_default().to_uppercase();
+                let ticker = match symbols.iter().find( |t| t.to_uppercase() == symbol ) { Some(t) => t, None => continue };
+                match Quote::from_details(cmdstruct.env.clone(), ticker, details) {
+                    Ok(quote) => { quotes.insert(ticker.to_string(), quote); },
+                    e => glogd!("get_market_quotes from_details =>", e)
+                }
+            }
+            getenvstruct!(cmdstruct).dbconn.transaction( |tx| {
+                for quote in quotes.values() {
+                    if stale.iter().any( |s| s.0 == quote.ticker && s.1 ) {
+                        getsql!(tx, "UPDATE stonks SET price=?, last=?, market=?, time=? WHERE ticker=?",
+                            quote.price, quote.last, &*quote.market, cmdstruct.now, quote.ticker.as_str())?;
+                    } else {
+                        getsql!(tx, "INSERT INTO stonks VALUES(?,?,?,?,?,?,?,?,?)",
+                            &*quote.ticker, quote.price, quote.last, &*quote.market, quote.hours, &*quote.exchange, cmdstruct.now, &*quote.title, &*quote.currency)?;
+                    }
+                }
+                Ok(())
+            })?;
+        }
+
+        for ticker in tickers { // Cached, self-stonks, options and any the batch missed
+            if quotes.contains_key(ticker) { continue }
+            match Quote::get_market_quote(cmdstruct, ticker).await {
+                Ok(quote) => { quotes.insert(ticker.to_string(), quote); },
+                e => glogd!("get_market_quotes get_market_quote =>", e)
+            }
+        }
+        Ok(quotes)
+    } // Quote::get_market_quotes
 }
 
 fn fmt_decode_to (c: &str, s: &mut String) {
@@ -854,10 +906,10 @@ fn fmt_decode_to (c: &str, s: &mut String) {
     }
 }
 
-fn amt_as_glyph (qty: f64, amt: f64) -> (&'static str, &'static str) {
-    if 0.0 == amt {
+fn amt_as_glyph (qty: Qty, amt: Money) -> (&'static str, &'static str) {
+    if amt.is_zero() {
         (from_utf8(b"\xF0\x9F\x94\xB7").unwrap(), " ") // Blue diamond, nothing
-    } else if (0.0 < amt) ^ (qty < 0.0) {
+    } else if amt.is_positive() ^ qty.is_negative() {
         (from_utf8(b"\xF0\x9F\x9F\xA2").unwrap(), from_utf8(b"\xE2\x86\x91").unwrap()) // Green circle, Arrow up
     } else {
         (from_utf8(b"\xF0\x9F\x9F\xA5").unwrap(), from_utf8(b"\xE2\x86\x93").unwrap()) // Red square, Arrow down
@@ -867,7 +919,7 @@ fn amt_as_glyph (qty: f64, amt: f64) -> (&'static str, &'static str) {
 impl Quote { // Format the quote/ticker using its format string IE: 🟢ETH-USD@2087.83! ↑48.49 2.38% Ethereum USD CCC
     fn format_quote (&self, id:i64) -> Bresult<String> {
         let envstruct = &self.env.lock().unwrap();
-        let gain_glyphs = amt_as_glyph(0.0, self.amount);
+        let gain_glyphs = amt_as_glyph(Qty::ZERO, self.amount);
         Ok(Regex::new("(?s)(%([A-Za-z%])|.)").unwrap() // (?s) dot accepts newline
             .captures_iter(&envstruct.fmt_str_quote(id))
             .fold(String::new(), |mut s, cap| {
@@ -898,11 +950,12 @@ impl Quote { // Format the quote/ticker using its format string IE: 🟢ETH-USD@
 ////////////////////////////////////////
 
 
-#[derive(Debug)]
+#[derive(Debug, FromRow)]
 struct Position { // Represent a ledgered position, and optional quote
     ticker:String,
-    qty:   f64,
-    price: f64,
+    qty:   Qty,
+    price: Money,
+    #[row(skip)]
     quote: Option<Quote>
 }
 
@@ -922,17 +975,7 @@ impl Position {
 
     fn get_user_positions_env (envstruct: &mut EnvStruct, id: i64) -> Bresult<Vec<Position>> {
         let dbconn = &envstruct.dbconn;
-        Ok(getsql!(dbconn, "SELECT ticker, qty, price FROM positions WHERE id=?", id)?
-        .iter()
-        .map( |pos|
-            Position {
-                //id,
-                ticker: pos.get_string("ticker").unwrap(),
-                qty:    pos.get_f64("qty").unwrap(),
-                price:  pos.get_f64("price").unwrap(),
-                quote:  None
-            } )
-        .collect())
+        dbconn.query_as::<Position>("SELECT ticker, qty, price FROM positions WHERE id=?", &[&id])
     }
 }
 
@@ -940,22 +983,13 @@ impl Position {
     // Return vector instead of option (maybe support more of the same position?)
     fn cached_position (cmdstruct:&CmdStruct, id: i64, ticker: &str) -> Bresult<Vec<Position>> {
         let dbconn = &getenvstruct!(cmdstruct).dbconn;
-        Ok(getsql!(dbconn, "SELECT qty,price FROM positions WHERE id=? AND ticker=?", id, ticker )?
-        .iter()
-        .map( |pos|
-            Position {
-                ticker: ticker.to_string(),
-                qty:    pos.get_f64("qty").unwrap(),
-                price:  pos.get_f64("price").unwrap(),
-                quote:  None
-            } )
-        .collect())
+        dbconn.query_as::<Position>("SELECT ticker, qty, price FROM positions WHERE id=? AND ticker=?", &[&id, &ticker])
     }
     async fn query_position(cmdstruct:&CmdStruct, id:i64, ticker:&str) -> Bresult<Position> {
         let mut vec = Position::cached_position(cmdstruct, id, ticker)?;
         let mut pos = // Consider the quote or a 0 quantity quote
             match vec.len() {
-                0 => Position { ticker: ticker.to_string(), qty: 0.0, price: 0.0, quote: None },
+                0 => Position { ticker: ticker.to_string(), qty: Qty::ZERO, price: Money::ZERO, quote: None },
                 1 => vec.pop().unwrap(),
                 _ => Err(format!("For {} ticker {} has {} positions, expect 0 or 1", id, ticker, vec.len()))?
             };
@@ -965,7 +999,7 @@ impl Position {
 }
 
 impl Position { // Format the position using its format string.
-    fn format_position (&self, envstruct:&EnvStruct, id: i64) -> Bresult<String> {
+    fn format_position (&self, envstruct:&EnvStruct, id: i64, now: i64) -> Bresult<String> {
         let qty = self.qty;
         let cost = self.price;
         let price = self.quote.as_ref().unwrap().price;
@@ -978,17 +1012,30 @@ impl Position { // Format the position using its format string.
         let gain = value - basis;
         let day_gain = value - last_value;
 
-        let gain_percent = if qty<0.0 { percentify(cost, price).abs() } else { percentify(cost, price).abs() };
+        let gain_percent = percentify(cost, price).abs();
         let day_gain_percent = percentify(last, price).abs();
 
         let gain_glyphs = amt_as_glyph(qty, price-cost);
         let day_gain_glyphs = amt_as_glyph(qty, price-last);
 
+        let fmt_str = envstruct.fmt_str_position(id);
+        let contract = OptionContract::parse(&self.ticker);
+        let realized = // Only query when the format uses it
+            if fmt_str.contains("%N") || fmt_str.contains("%O") {
+                realized_gain(&envstruct.dbconn, id, Some(&self.ticker))?
+            } else { Money::ZERO };
+        let realized_glyphs = amt_as_glyph(Qty::from_int(1), realized);
+        let (fees, rate) = // Borrow details for shorts using them
+            if qty.is_negative() && (fmt_str.contains("%P") || fmt_str.contains("%Q")) {
+                (borrow_fees(&envstruct.dbconn, id, &self.ticker)?,
+                 borrow(&envstruct.dbconn, id, &self.ticker)?.map_or(0.0, |b| b.rate))
+            } else { (Money::ZERO, 0.0) };
+
         Ok(Regex::new("(?s)(%([A-Za-z%])|.)").unwrap()
-        .captures_iter(&envstruct.fmt_str_position(id) )
+        .captures_iter(&fmt_str)
         .fold( String::new(), |mut s, cap| {
             if let Some(m) = cap.get(2) { match m.as_str() {
-                "A" => s.push_str( &format!("{:.2}", roundcents(value)) ), // value
+                "A" => s.push_str( &format!("{:.2}", value) ), // value
                 "B" => s.push_str( &round(gain.abs())), // gain
                 "C" => s.push_str( gain_glyphs.1), // Arrow
                 "D" => s.push_str( &percent_squish(gain_percent)), // gain%
@@ -996,13 +1043,22 @@ impl Position { // Format the position using its format string.
                 "F" => s.push_str( &reference_ticker(envstruct, &self.ticker).replacen("_", "\\_", 10000) ), // Ticker
                 "G" => s.push_str( &money_pretty(price) ), // latet stonks value
                 "H" =>
-                    if qty == 0.0 { s.push_str("0") }
+                    if qty.is_zero() { s.push_str("0") }
                     else { s.push_str( qty.to_string().trim_start_matches('0') ) },
                 "I" => s.push_str( &roundkilofy(cost) ), // cost
                 "J" => s.push_str( day_gain_glyphs.0 ), // day color
                 "K" => s.push_str( &money_pretty(day_gain) ), // inter-day delta
                 "L" => s.push_str( day_gain_glyphs.1 ), // day arrow
                 "M" => s.push_str( &percent_squish(day_gain_percent) ), // inter-day percent
+                "N" => s.push_str( &format!("{:.2}", realized) ), // realized gain
+                "O" => s.push_str( realized_glyphs.0 ), // realized color
+                "P" => s.push_str( &format!("{:.2}", fees) ), // borrow fees paid
+                "Q" => s.push_str( &percent_squish(rate * 100.0) ), // borrow rate
+                "R" => if let Some(c) = &contract { // option summary
+                    s.push_str( &format!(" {}{}/{}", IF!(c.kind == OptionKind::Call, "C", "P"), c.strike, c.expiry_str()) ) },
+                "S" => if let Some(c) = &contract { s.push_str( &c.strike.to_string() ) }, // option strike
+                "T" => if let Some(c) = &contract { // option days to expiry
+                    s.push_str( &((c.expiry - now).max(0) / 86400).to_string() ) },
                 c => fmt_decode_to(c, &mut s) }
             } else {
                 s.push_str(&cap[1]);
@@ -1014,34 +1070,31 @@ impl Position { // Format the position using its format string.
 
 ////////////////////////////////////////
 
+#[derive(Debug, Clone, Copy)]
+enum Amount { Dollars(Money), Shares(Qty) }
+
 #[derive(Debug)]
 struct Trade<'a> {
     cmdstruct: &'a mut CmdStruct,
     ticker: String,
     action: char, // '+':buy '-':sell
-    is_dollars: bool, // amt is dollars or quantity
-    amt: Option<f64>
+    amt: Option<Amount>
 }
 
 impl<'a> Trade<'a> {
-    fn new_trade(cmdstruct: &'a mut CmdStruct) -> Bresult<Option<Trade>> {
-        //                           _____ticker____  _+-_  _$_   ____________amt_______________
-        let caps = regex_to_vec(
-            r"(?xi)^
-                ([A-Za-z0-9^.-]+)         ### ticker symbol
-                ([+-])                    ### + buy, - sell
-                ([$])?                    ### amount is dollars
-                (\d+\.?|(\d*\.\d{1,4}))?  ### float amount
-                $",
-            &cmdstruct.message)?;
-        if caps.is_empty() { return Ok(None) }
-        Ok(Some(Trade{
+    // From the captures of a BUY_TRIGGER or SELL_TRIGGER match
+    fn new_trade(cmdstruct: &'a mut CmdStruct, caps: Vec<Option<String>>) -> Bresult<Trade<'a>> {
+        let amt = match caps.as_str(4) {
+            Ok(amt) if caps.as_str(3).is_ok() => Some(Amount::Dollars(amt.parse::<Money>()?)),
+            Ok(amt) => Some(Amount::Shares(amt.parse::<Qty>()?)),
+            Err(_) => None
+        };
+        Ok(Trade{
             cmdstruct,
-            ticker:     caps.as_str(1)?.to_uppercase(),
-            action:     caps.as_str(2)?.chars().nth(0).unwrap(),
-            is_dollars: caps.as_str(3).is_ok(),
-            amt:        caps.as_f64(4).ok()
-        }))
+            ticker: caps.as_str(1)?.to_uppercase(),
+            action: caps.as_str(2)?.chars().nth(0).unwrap(),
+            amt
+        })
     }
 }
 
@@ -1049,10 +1102,7 @@ impl<'a> Trade<'a> {
 // Bot's Do Handlers -- The Meat And Potatos.  The Bread N Butter.  The Works.
 ////////////////////////////////////////////////////////////////////////////////
 
-async fn do_echo_lvl (cmdstruct: &mut CmdStruct) -> Bresult<&'static str> {
-    let caps = regex_to_vec("^/echo ?([0-9]+)?", &cmdstruct.message)?;
-    if caps.is_empty() { return Ok("SKIP") }
-
+async fn do_echo_lvl (cmdstruct: &mut CmdStruct, caps:Vec<Option<String>>) -> Bresult<Outcome> {
     let msg = match caps.as_i64(1) {
         Ok(echo) => { // Update existing echo level
             if 2 < echo {
@@ -1077,68 +1127,232 @@ async fn do_echo_lvl (cmdstruct: &mut CmdStruct) -> Bresult<&'static str> {
         }
     };
     cmdstruct.push_msg(&msg).send_msg().await?;
-    Ok("COMPLETED.")
-}
-
-
-pub async fn do_help (cmdstruct:&mut CmdStruct) -> Bresult<&'static str> {
-    //let cmdl = cmd.lock().unwrap();
-    //let cmdstruct = cmdl.deref();
-    if regex_to_vec(r"/help", &cmdstruct.message)?.is_empty() { return Ok("SKIP") }
-    let delay = getenvstruct!(cmdstruct).quote_delay_secs;
-    let msg = format!(
-r#"`          ™Bot Commands          `
-`/echo 2` `Echo level (verbose 2…0 quiet)`
-`/say hi` `™Bot will say "hi"`
-`word: ` `Definition lookup`
-`+1    ` `Like someone's post (via reply)`
-`+?    ` `Like leaderboard`
-`/stonks` `Your Stonkfolio`
-`/orders` `Your @shares and bid/ask orders`
-`/yolo  ` `Stonks leaderboard`
-`gme$   ` `Quote ({}min delay)`
-`gme+   ` `Buy max GME shares`
-`gme-   ` `Sell all GME shares`
-`gme+3  ` `Buy 3 shares (min qty 0.0001)`
-`gme-5  ` `Sell 5 share`
-`gme+$18` `Buy $18 worth (min $0.01)`
-`gme-$.9` `Sell 90¢ worth`
-`@usr+2@3` `Bid/buy 2sh of '@usr' at $3`
-`@usr-5@4` `Ask/sell 5sh of '@usr' at $4`
-`/rebalance -9.99 AMC 40 QQQ 60 ...`
-   `rebalances AMC/40% QQQ/60%, opt adj -$9.99`
-`/fmt [?]     ` `Show format strings, help`
-`/fmt [qp] ...` `Set quote/position fmt str`
-`/schedule [time]` `List jobs, delete job at time`
-`/schedule [ISO-8601] | [1h][2m][3][mtwhfsu*] CMD` `schedule CMD now or ISO-8601 GMT o'clock, offset 1h 2m 3s, repeat on day(s)`"#, delay);
+    Ok(Outcome::Done)
+}
+
+
+pub async fn do_help (cmdstruct:&mut CmdStruct) -> Bresult<Outcome> {
+    let msg = {
+        let envstruct = getenvstruct!(cmdstruct);
+        let delay = envstruct.quote_delay_secs.to_string();
+        let mut msg = String::from("`          ™Bot Commands          `");
+        for command in envstruct.commands.available(&envstruct.dbconn, cmdstruct.id, cmdstruct.at)? {
+            for (usage, description) in command.help() {
+                msg.push_str(&format!("\n`{}` `{}`", usage, description.replace("{delay}", &delay)));
+            }
+        }
+        msg
+    };
+    cmdstruct.markdown().push_msg(&msg).send_msg().await?;
+    Ok(Outcome::Done)
+}
+
+// Handle: /commands [name on|off]
+// List this chat's commands or switch one on or off in it.
+async fn do_commands (cmdstruct:&mut CmdStruct, caps:Vec<Option<String>>) -> Bresult<Outcome> {
+    let msg = {
+        let envstruct = getenvstruct!(cmdstruct);
+        match (caps.as_str(1).map( str::to_lowercase ), caps.as_str(2).map( str::to_lowercase )) {
+            (Ok(name), Ok(state)) =>
+                if envstruct.commands.get(&name).is_none() {
+                    format!("`no command {}`", name)
+                } else if "commands" == name {
+                    "`commands can't be switched off`".to_string()
+                } else {
+                    command_enable(&envstruct.dbconn, cmdstruct.at, &name, "on" == state)?;
+                    format!("`{} {}`", name, state)
+                },
+            _ => {
+                let disabled = commands_disabled(&envstruct.dbconn, cmdstruct.at)?;
+                envstruct.commands.iter()
+                    .map( |c| IF!(disabled.contains(c.name()), format!("~{}~", c.name()), c.name().to_string()) )
+                    .fold("*Commands*".to_string(), |msg, name| msg + " " + &name.replacen("_", "\\_", 10))
+            }
+        }
+    };
+    cmdstruct.markdown().push_msg(&msg).send_msg().await?;
+    Ok(Outcome::Done)
+}
+
+// Handle: /roles
+// List the grants in this chat and every chat (*).
+async fn do_roles (cmdstruct:&mut CmdStruct) -> Bresult<Outcome> {
+    let msg = {
+        let dbconn = &getenvstruct!(cmdstruct).dbconn;
+        grants(dbconn, cmdstruct.at)?.iter()
+            .map( |g| format!("\n`{}{} {}`", g.name, IF!(EVERY_CHAT == g.at, "*", ""), g.role) )
+            .fold(format!("*Roles* `you {}, default {}`", entity_role(dbconn, cmdstruct.id, cmdstruct.at)?, DEFAULT_ROLE), |msg, line| msg + &line)
+    };
+    cmdstruct.markdown().push_msg(&msg).send_msg().await?;
+    Ok(Outcome::Done)
+}
+
+// Handle: /grant @usr role [*]   /revoke @usr [*]
+// Give or take a role in this chat, or every chat with *.
+async fn do_grant (cmdstruct:&mut CmdStruct, caps:Vec<Option<String>>) -> Bresult<Outcome> {
+    let grant = "grant" == caps.as_str(1)?.to_lowercase();
+    let name = caps.as_str(2)?;
+    let at = IF!(caps.as_str(4).is_ok(), EVERY_CHAT, cmdstruct.at);
+    let msg = {
+        let dbconn = &getenvstruct!(cmdstruct).dbconn;
+        let id = deref_ticker(dbconn, name)?.parse::<i64>()?;
+        let granter = entity_role(dbconn, cmdstruct.id, at)?;
+        let target = entity_role(dbconn, id, at)?;
+        match (grant, caps.as_str(3).map( |role| role.parse::<Role>() )) {
+            (true, Ok(Ok(role))) if role_assignable(granter, target, role, EVERY_CHAT == at) => {
+                role_grant(dbconn, id, at, role)?;
+                format!("`{} {}{}`", name, role, IF!(EVERY_CHAT == at, " everywhere", ""))
+            },
+            (false, Err(_)) if role_assignable(granter, target, target, EVERY_CHAT == at) =>
+                match role_revoke(dbconn, id, at)? {
+                    Some(role) => format!("`{} no longer {}`", name, role),
+                    None => format!("`{} had no role to revoke`", name)
+                },
+            (true, Err(_)) => "`grant needs a role`".to_string(),
+            _ => format!("`{} can't change {}'s role`", granter, name)
+        }
+    };
+    cmdstruct.markdown().push_msg(&msg).send_msg().await?;
+    Ok(Outcome::Done)
+}
+
+// Handle: /role cmd [viewer|trader|admin|owner|default]
+// Show or change the lowest role that may run a command.
+async fn do_role (cmdstruct:&mut CmdStruct, caps:Vec<Option<String>>) -> Bresult<Outcome> {
+    let name = caps.as_str(1)?.to_lowercase();
+    let msg = {
+        let envstruct = getenvstruct!(cmdstruct);
+        let declared = envstruct.commands.get(&name).ok_or(format!("no command {}", name))?.role();
+        if let Ok(role) = caps.as_str(2) {
+            let role = IF!("default" == role.to_lowercase(), None, Some(role.parse::<Role>()?));
+            command_role_set(&envstruct.dbconn, &name, role)?;
+        }
+        let role = command_roles(&envstruct.dbconn)?.get(&name).copied();
+        format!("`{} needs {}{}`", name, role.unwrap_or(declared), IF!(role.is_some(), format!(" (declared {})", declared), String::new()))
+    };
     cmdstruct.markdown().push_msg(&msg).send_msg().await?;
-    Ok("COMPLETED.")
+    Ok(Outcome::Done)
 }
 
-async fn do_curse (cmdstruct:&mut CmdStruct) -> Bresult<&'static str> {
-    if Regex::new(r"/curse").unwrap().find(&cmdstruct.message).is_none() { return Ok("SKIP") }
+// Handle: /balance @usr [+-]amount
+// Adjust someone's cash.
+async fn do_balance (cmdstruct:&mut CmdStruct, caps:Vec<Option<String>>) -> Bresult<Outcome> {
+    let name = caps.as_str(1)?;
+    let amount = caps.as_str(2)?.parse::<Money>()?;
+    let origin = cmdstruct.origin();
+    let msg = {
+        let envstruct = getenvstruct!(cmdstruct);
+        let id = deref_ticker(&envstruct.dbconn, name)?.parse::<i64>()?;
+        if getsql!(envstruct.dbconn, "SELECT id FROM accounts WHERE id=?", id)?.is_empty() { Err(format!("no account for {}", name))? }
+        envstruct.transaction( |envstruct| cash_inc(envstruct, origin, id, USD, amount) )?;
+        format!("`{} cash {:+.2} to {:.2}`", name, amount, envstruct.entity_balance(id)?)
+    };
+    cmdstruct.markdown().push_msg(&msg).send_msg().await?;
+    Ok(Outcome::Done)
+}
+
+// Handle: /history [n]
+// Your last n cash and position changes.
+async fn do_history (cmdstruct:&mut CmdStruct, caps:Vec<Option<String>>) -> Bresult<Outcome> {
+    let n = caps.as_i64(1).unwrap_or(10).min(50);
+    let id = cmdstruct.id;
+    let msg = {
+        let envstruct = getenvstruct!(cmdstruct);
+        ledger_events(&envstruct.dbconn, id, n)?.iter()
+            .map( |e| format!("\n`{}Z {}{}`", time2datetimestr(e.time), e.describe(),
+                match e.actor {
+                    0 => " (bot)".to_string(),
+                    actor if actor == id => String::new(),
+                    actor => format!(" by {}", envstruct.entity_id2name(actor).unwrap_or("?"))
+                }) )
+            .fold("*History*".to_string(), |msg, line| msg + &line)
+    };
+    cmdstruct.markdown().push_msg(&msg).send_msg().await?;
+    Ok(Outcome::Done)
+}
+
+// Handle: /ledger [check|rebuild]
+// Compare balances and positions with the ledger events, or rebuild them from it.
+async fn do_ledger (cmdstruct:&mut CmdStruct, caps:Vec<Option<String>>) -> Bresult<Outcome> {
+    let rebuild = caps.as_str(1).map_or(false, |c| "rebuild" == c.to_lowercase());
+    let msg = {
+        let envstruct = getenvstruct!(cmdstruct);
+        let diffs = ledger_check(&envstruct.dbconn)?;
+        if diffs.is_empty() {
+            "*Ledger* `balances and positions match the events`".to_string()
+        } else if rebuild {
+            format!("*Ledger* `rebuilt {} balances and positions from the events`", ledger_rebuild(envstruct)?)
+        } else {
+            diffs.iter()
+                .map( |((id, kind, key), (events, basis), (table, price))|
+                    if "cash" == kind.as_str() {
+                        format!("\n`{} {} events {:.2} table {:.2}`", envstruct.entity_id2name(*id).unwrap_or("?"), key, Money(*events), Money(*table))
+                    } else {
+                        format!("\n`{} {} events {}@{:.2} table {}@{:.2}`", envstruct.entity_id2name(*id).unwrap_or("?"), key, Qty(*events), basis, Qty(*table), price)
+                    } )
+                .fold("*Ledger*".to_string(), |msg, line| msg + &line)
+        }
+    };
+    cmdstruct.markdown().push_msg(&msg).send_msg().await?;
+    Ok(Outcome::Done)
+}
+
+// Handle: /export [json|csv]
+// Your cash, positions, orders, exchange orders and schedules as a file.
+async fn do_export (cmdstruct:&mut CmdStruct, caps:Vec<Option<String>>) -> Bresult<Outcome> {
+    let csv = caps.as_str(1).map_or(false, |format| "csv" == format.to_lowercase());
+    let (id, now) = (cmdstruct.id, cmdstruct.now);
+    let doc = export_document(&getenvstruct!(cmdstruct).dbconn, Some(id), now)?;
+    let (filename, content) =
+        if csv { (format!("tmbot-{}.csv", id), export_csv(&doc)?) }
+        else { (format!("tmbot-{}.json", id), serde_json::to_string_pretty(&doc)?) };
+    cmdstruct.send_document(&filename, &content).await?;
+    Ok(Outcome::Done)
+}
+
+// Handle: /import [@usr] {json}|csv
+// Replay one exported account into yours or someone's empty account.
+async fn do_import (cmdstruct:&mut CmdStruct, caps:Vec<Option<String>>) -> Bresult<Outcome> {
+    let origin = cmdstruct.origin();
+    let msg = {
+        let envstruct = getenvstruct!(cmdstruct);
+        let id = match caps.as_str(1) {
+            Ok(name) => deref_ticker(&envstruct.dbconn, name)?.parse::<i64>()?,
+            Err(_) => origin.actor
+        };
+        if !envstruct.entitys.contains_key(&id) { Err(format!("no entity {}", id))? }
+        let mut accounts = parse_document(&read_document(caps.as_str(2)?)?)?;
+        if 1 != accounts.len() { Err("import one account at a time")? }
+        let mut account = accounts.pop().unwrap();
+        account.name.clear(); // Keep the entity's own name
+        envstruct.transaction( |envstruct| import_account(&envstruct.dbconn, origin, &account, id) )?;
+        let balance = getsql!(envstruct.dbconn, "SELECT balance FROM accounts WHERE id=?", id)?[0].get_money("balance")?;
+        if let Some(entity) = envstruct.entitys.get_mut(&id) { entity.balance = balance }
+        envstruct.books.clear(); // Reload with the imported exchange orders
+        format!("`Imported {} cash, {} positions, {} orders, {} exchange orders, {} schedules into {}`",
+            account.cash.len(), account.positions.len(), account.orders.len(), account.exchange.len(), account.schedules.len(),
+            envstruct.entity_id2name(id)?)
+    };
+    cmdstruct.markdown().push_msg(&msg).send_msg().await?;
+    Ok(Outcome::Done)
+}
+
+async fn do_curse (cmdstruct:&mut CmdStruct) -> Bresult<Outcome> {
     cmdstruct
         .push_msg(["shit", "piss", "fuck", "cunt", "cocksucker", "motherfucker", "tits"][::rand::random::<usize>()%7])
         .send_msg()
         .await?;
-    Ok("COMPLETED.")
+    Ok(Outcome::Done)
 }
 
-async fn do_say (cmdstruct: &mut CmdStruct) -> Bresult<&'static str> {
-    let rev = regex_to_vec(r"^/say (.*)$", &cmdstruct.message)?;
-    if rev.is_empty() { return Ok("SKIP".into()) }
-    cmdstruct.push_msg(rev.as_str(1)?).send_msg().await?;
-    Ok("COMPLETED.")
+async fn do_say (cmdstruct: &mut CmdStruct, caps:Vec<Option<String>>) -> Bresult<Outcome> {
+    cmdstruct.push_msg(caps.as_str(1)?).send_msg().await?;
+    Ok(Outcome::Done)
 }
 
-async fn do_like (cmdstruct: &mut CmdStruct) -> Bresult<String> {
-    let adj =
-        match Regex::new(r"^([+-])1")?.captures(&cmdstruct.message) {
-            None => return Ok("SKIP".into()),
-            Some(cap) =>
-                if cmdstruct.id == cmdstruct.to { return Ok("SKIP self plussed".into()); }
-                else if &cap[1] == "+" { 1 } else { -1 }
-    };
+async fn do_like (cmdstruct: &mut CmdStruct, caps:Vec<Option<String>>) -> Bresult<Outcome> {
+    if cmdstruct.id == cmdstruct.to { return Ok(Outcome::Skip) } // Self plussed
+    let adj = IF!(caps.as_str(1)? == "+", 1, -1);
 
     let (fromname, likes, toname) = {
         let ref mut envstruct = getenvstruct!(cmdstruct);
@@ -1155,12 +1369,10 @@ async fn do_like (cmdstruct: &mut CmdStruct) -> Bresult<String> {
         .push_msg(&format!("{}{}{}", fromname, num2heart(likes), toname))
         .send_msg()
         .await?;
-    Ok("COMPLETED.".into())
+    Ok(Outcome::Done)
 }
 
-async fn do_like_info (cmdstruct: &mut CmdStruct) -> Bresult<&'static str> {
-    if cmdstruct.message != "+?" { return Ok("SKIP"); }
-
+async fn do_like_info (cmdstruct: &mut CmdStruct) -> Bresult<Outcome> {
     let mut likes :Vec<(i64, String)>=
         getenvstruct!(cmdstruct).entitys.iter().map( |(_,e)| (e.likes, e.name.clone()) ).collect();
     likes.sort_by(|a,b| b.0.cmp(&a.0));
@@ -1172,13 +1384,11 @@ async fn do_like_info (cmdstruct: &mut CmdStruct) -> Bresult<&'static str> {
             .map( |(likecount, username)| format!("{}{} ", username, num2heart(*likecount)) )
             .collect::<Vec<String>>().join(""))
         .send_msg().await?;
-    Ok("COMPLETED.")
+    Ok(Outcome::Done)
 }
 
-async fn do_def (cmdstruct:&mut CmdStruct) -> Bresult<&'static str> {
-    let cap = regex_to_vec(r"^([A-Za-z-]+):$", &cmdstruct.message)?;
-    if cap.is_empty() { return Ok("SKIP"); }
-    let word = cap.as_str(1)?;
+async fn do_def (cmdstruct:&mut CmdStruct, caps:Vec<Option<String>>) -> Bresult<Outcome> {
+    let word = caps.as_str(1)?;
 
     info!("looking up {:?}", word);
 
@@ -1186,7 +1396,8 @@ async fn do_def (cmdstruct:&mut CmdStruct) -> Bresult<&'static str> {
 
     // Definitions
 
-    let defs = get_definition(&word).await?;
+    let http = getenvstruct!(cmdstruct).http.clone();
+    let defs = get_definition(&http, &word).await?;
 
     cmdstruct.markdown();
 
@@ -1209,7 +1420,7 @@ async fn do_def (cmdstruct:&mut CmdStruct) -> Bresult<&'static str> {
 
     // Synonyms
 
-    let mut syns = get_syns(&word).await?;
+    let mut syns = get_syns(&http, &word).await?;
 
     if syns.is_empty() {
         cmdstruct
@@ -1230,41 +1441,85 @@ async fn do_def (cmdstruct:&mut CmdStruct) -> Bresult<&'static str> {
     if !msg.is_empty() {
         cmdstruct.push_msg(&msg).send_msg().await?;
     }
-    Ok("COMPLETED.")
+    Ok(Outcome::Done)
 }
 
-async fn do_sql (cmdstruct:&mut CmdStruct) -> Bresult<&'static str> {
-    if cmdstruct.id != 308188500 { return Ok("do_sql invalid user"); }
-    let rev = regex_to_vec("^(.*)ß$", &cmdstruct.message)?;
-    let sqlexpr = if rev.is_empty() { return Ok("SKIP") } else { rev.as_str(1)? };
-    let sqlres = {
+// Handle: /sql query   query ß
+// Read-only query, answered as a table.
+async fn do_sql (cmdstruct:&mut CmdStruct, caps:Vec<Option<String>>) -> Bresult<Outcome> {
+    if Regex::new(SQL_WRITE_PREFIX)?.is_match(&cmdstruct.message) { return Ok(Outcome::Skip) } // For sql_write
+    let sqlexpr = caps.as_str(1).or_else( |_| caps.as_str(2) )?;
+    let table = {
         let envstruct = getenvstruct!(cmdstruct);
-        getsql!(&envstruct.dbconn, sqlexpr)
+        envstruct.dbconn.open_readonly()
+            .and_then( |readonly| sql_query(&readonly, sqlexpr, SQL_ROW_LIMIT, SQL_TIMEOUT) )
     };
-    let results =
-        match sqlres {
-            Err(e) => {
-                cmdstruct.push_msg(&format!("{}", e)).send_msg().await?;
-                Err(e)?
-            }
-            Ok(r) => r
+    let msg =
+        match table {
+            Err(e) => format!("`{}`", e),
+            Ok(table) if table.rows.is_empty() => "`empty results`".to_string(),
+            Ok(table) => format!("```\n{}```", table)
         };
+    cmdstruct.markdown().push_msg(&msg).send_msg().await?;
+    Ok(Outcome::Done)
+}
 
-    let msg = // SQL rows to single string.
-        results.iter().fold(
-            String::new(),
-            |mut b, vv| {
-                vv.iter().for_each( |(k,_v)|
-                    b += &format!(" {}:{}", k,
-                      vv.to_string(k)
-                      .unwrap_or_else( |e| {error!("{:?}", e); "?".into()} ) ) );
-                b+"\n"
-            } );
+const SQL_WRITE_PREFIX :&str = r"(?i)^/sql +(write|confirm|cancel)\b";
 
-    cmdstruct
-        .push_msg(if msg.is_empty() { &"empty results" } else { &msg })
-        .send_msg().await?;
-    Ok("COMPLETED.")
+// Handle: /sql write statement   /sql confirm   /sql cancel
+// Stage a write, then run it once confirmed.  Runs are audit logged.
+async fn do_sql_write (cmdstruct:&mut CmdStruct, caps:Vec<Option<String>>) -> Bresult<Outcome> {
+    let (id, at, now) = (cmdstruct.id, cmdstruct.at, cmdstruct.now);
+    let msg = {
+        let envstruct = getenvstruct!(cmdstruct);
+        match (caps.as_str(1)?.to_lowercase().as_str(), caps.as_str(2)) {
+            ("write", Ok(sqlexpr)) => {
+                envstruct.sql_pending.insert((id, at), (sqlexpr.to_string(), now));
+                format!("`/sql confirm` within {}min to run:\n```\n{}```", SQL_CONFIRM_SECS/60, sqlexpr.replace('`', "'"))
+            },
+            ("confirm", Err(_)) =>
+                match envstruct.sql_pending.remove(&(id, at)) {
+                    Some((sqlexpr, time)) if now - time <= SQL_CONFIRM_SECS => {
+                        envstruct.books.clear(); // The exchange table may change under the cached books
+                        match sql_write(&envstruct.dbconn, id, at, &sqlexpr, now) {
+                            Ok(changes) => format!("`{} changes`", changes),
+                            Err(e) => format!("`{}`", e)
+                        }
+                    },
+                    Some(_) => "`Staged write expired`".to_string(),
+                    None => "`Nothing staged, /sql write first`".to_string()
+                },
+            ("cancel", Err(_)) =>
+                IF!(envstruct.sql_pending.remove(&(id, at)).is_some(), "`Staged write dropped`", "`Nothing staged`").to_string(),
+            _ => return Ok(Outcome::Skip)
+        }
+    };
+    cmdstruct.markdown().push_msg(&msg).send_msg().await?;
+    Ok(Outcome::Done)
+}
+
+// Handle: /corpactions [ticker [split ratio|div amount]]
+// Fetch and apply recent actions, or apply one by hand.
+async fn do_corpactions (cmdstruct:&mut CmdStruct, caps:Vec<Option<String>>) -> Bresult<Outcome> {
+    let ticker = caps.as_str(1).ok().map( str::to_uppercase );
+    let (now, origin) = (cmdstruct.now, cmdstruct.origin());
+    let applied =
+        match (ticker, caps.as_str(2).map( str::to_lowercase )) {
+            (Some(ticker), Ok(kind)) => {
+                let kind =
+                    if kind == "split" { ActionKind::Split(caps.as_f64(3)?) }
+                    else { ActionKind::Dividend(caps.as_str(3)?.parse::<Money>()?) };
+                let action = CorporateAction{ ticker, time: now, kind };
+                let holders = getenvstruct!(cmdstruct).transaction( |envstruct| corporate_action_apply(envstruct, &action, origin) )?;
+                holders.map_or(vec![], |_| vec![action])
+            },
+            (ticker, _) => corporate_actions_run(cmdstruct.env.clone(), ticker.as_deref(), origin).await?
+        };
+    let msg =
+        if applied.is_empty() { "No corporate actions applied".to_string() }
+        else { applied.iter().map( |a| a.to_string() ).collect::<Vec<String>>().join("\n") };
+    cmdstruct.push_msg(&msg).send_msg().await?;
+    Ok(Outcome::Done)
 }
 
 ////////////////////////////////////////
@@ -1294,24 +1549,17 @@ pub fn doquotes_scan_tickers (txt :&str, re:Regex) -> Bresult<HashSet<String>> {
 
 async fn doquotes_pretty (cmdstruct: &CmdStruct, ticker: &str) -> Bresult<String> {
     let (ticker, bidask) = {
-        let dbconn = &getenvstruct!(cmdstruct).dbconn;
-        let ticker = deref_ticker(dbconn, ticker).unwrap_or(ticker.to_string());
+        let envstruct = getenvstruct!(cmdstruct);
+        let ticker = deref_ticker(&envstruct.dbconn, ticker).unwrap_or(ticker.to_string());
         let bidask = if is_self_stonk(&ticker) {
-            let mut asks = format!("*Asks:*");
-            for ask in getsql!(dbconn, "SELECT -qty AS qty, price FROM exchange WHERE qty<0 AND ticker=? order by price;", &*ticker)? {
-                asks.push_str(
-                    &format!(" `{}@{}`",
-                    num_simp( format!("{:.4}", ask.get_f64("qty")?) ),
-                    ask.get_f64("price")?));
-            }
-            let mut bids = format!("*Bids:*");
-            for bid in getsql!(dbconn, "SELECT qty, price FROM exchange WHERE 0<qty AND ticker=? order by price desc;", &*ticker)? {
-                bids.push_str(
-                    &format!(" `{}@{}`",
-                    num_simp( format!("{:.4}", bid.get_f64("qty")?) ),
-                    bid.get_f64("price")?));
-            }
-            format!("\n{}\n{}", asks, bids)
+            let fmt = |qty:Qty, price:Money| format!(" `{}@{}`", num_simp(format!("{:.4}", qty)), price);
+            let book = envstruct.book(&ticker)?;
+            let (bids, asks) = book.depth(5);
+            let tape = book.tape().take(5).map( |fill| fmt(fill.qty, fill.price) ).collect::<String>();
+            format!("\n*Asks:*{}\n*Bids:*{}{}",
+                asks.iter().map( |(price, qty)| fmt(*qty, *price) ).collect::<String>(),
+                bids.iter().map( |(price, qty)| fmt(*qty, *price) ).collect::<String>(),
+                IF!(tape.is_empty(), tape, format!("\n*Tape:*{}", tape)))
         } else {
             "".to_string()
         };
@@ -1321,12 +1569,12 @@ async fn doquotes_pretty (cmdstruct: &CmdStruct, ticker: &str) -> Bresult<String
     Ok(format!("{}{}", quote.format_quote(cmdstruct.id)?, bidask))
 }
 
-async fn do_quotes (cmdstruct :&mut CmdStruct) -> Bresult<&'static str> {
+async fn do_quotes (cmdstruct :&mut CmdStruct) -> Bresult<Outcome> {
     let tickers =
         doquotes_scan_tickers(
             &cmdstruct.message,
             Regex::new(r"^[@^]?[A-Z_a-z][-.0-9=A-Z_a-z]*$")?)?;
-    if tickers.is_empty() { return Ok("SKIP") }
+    if tickers.is_empty() { return Ok(Outcome::Skip) }
 
     cmdstruct.markdown().push_msg("…").send_msg().await?;
     cmdstruct.set_msg("");
@@ -1347,34 +1595,40 @@ async fn do_quotes (cmdstruct :&mut CmdStruct) -> Bresult<&'static str> {
     if !found_tickers {
         cmdstruct.set_msg(&"No quotes found").edit_msg().await?;
     }
-    Ok("COMPLETED.")
+    Ok(Outcome::Done)
 }
 // DO QUOTES
 ////////////////////////////////////////
 
-async fn do_portfolio (cmdstruct: &mut CmdStruct) -> Bresult<&'static str> {
-    let caps = regex_to_vec(r"(?i)/stonks( .+)?", &cmdstruct.message)?;
-    if caps.is_empty() { return Ok("SKIP"); }
-
+async fn do_portfolio (cmdstruct: &mut CmdStruct, caps:Vec<Option<String>>) -> Bresult<Outcome> {
     let dosort = caps[1].is_none();
     let positions = Position::get_users_positions(cmdstruct)?;
 
     cmdstruct.markdown().push_msg("…").send_msg().await?;
     cmdstruct.set_msg("");
 
-    let mut long = 0.0;
-    let mut short = 0.0;
-    let mut positions_table :Vec<(f64,String)> = Vec::new();
+    let mut quotes = { // Positions and currency pairs in one batch
+        let mut tickers = positions.iter().map( |pos| pos.ticker.to_string() ).collect::<Vec<String>>();
+        tickers.extend(fx_tickers(&getenvstruct!(cmdstruct).dbconn)?);
+        Quote::get_market_quotes(cmdstruct, &tickers).await?
+    };
+
+    let mut long = Money::ZERO;
+    let mut short = Money::ZERO;
+    let mut positions_table :Vec<(Money,String)> = Vec::new();
     for mut pos in positions {
         if !is_self_stonk(&pos.ticker) {
-            pos.update_quote(&cmdstruct).await?;
+            match quotes.remove(&pos.ticker) {
+                Some(quote) => pos.quote = Some(quote),
+                None => { pos.update_quote(&cmdstruct).await?; }
+            }
             info!("{} position {:?}", cmdstruct.id, &pos);
             let quote = pos.quote.as_ref().ok_or("quote not acquired")?;
-            let pretty_position = pos.format_position(&getenvstruct!(cmdstruct), cmdstruct.id)?;
+            let pretty_position = pos.format_position(&getenvstruct!(cmdstruct), cmdstruct.id, cmdstruct.now)?;
             if dosort {
                 let gain = pos.qty*(quote.price - pos.price);
                 positions_table.push( (gain, pretty_position) );
-                positions_table.sort_by( |a,b| a.0.partial_cmp(&b.0).unwrap_or(Ordering::Less) );
+                positions_table.sort_by( |a,b| a.0.cmp(&b.0) );
                 cmdstruct.set_msg( // Refresh message with sorted stonks
                     &positions_table.iter()
                         .map( |(_,s)| s.to_string())
@@ -1383,30 +1637,95 @@ async fn do_portfolio (cmdstruct: &mut CmdStruct) -> Bresult<&'static str> {
                 cmdstruct.push_msg(&pretty_position);
             }
 
-            if pos.qty < 0.0 {
-                short += pos.qty * pos.quote.unwrap().price;
+            let quote = pos.quote.unwrap();
+            let value = (pos.qty * quote.price).scale(fx_rate(cmdstruct, &quote.currency).await?);
+            if pos.qty.is_negative() {
+                short += value;
             } else {
-                long += pos.qty * pos.quote.unwrap().price;
+                long += value;
             }
             cmdstruct.edit_msg().await?;
         }
     }
 
-    let cash = getenvstruct!(cmdstruct).entity_balance(cmdstruct.id)?;
-    let bp = long + short*3.0 + cash*2.0;
+    let (mut cash, foreign, requirement, base) = {
+        let envstruct = getenvstruct!(cmdstruct);
+        let dbconn = &envstruct.dbconn;
+        (envstruct.entity_balance(cmdstruct.id)?, balances(dbconn, cmdstruct.id)?,
+         margin(dbconn, cmdstruct.id)?.requirement, base_currency(dbconn, cmdstruct.id)?)
+    };
+    for (currency, balance) in &foreign {
+        cash += balance.scale(fx_rate(cmdstruct, currency).await?);
+        cmdstruct.push_msg(&format!("\n`{:.2}``{}`", balance, currency));
+    }
+    let bp = long + short*3 + cash*2;
+    let rate = 1.0 / fx_rate(cmdstruct, &base).await?; // Totals in the base currency
     cmdstruct
-        .push_msg(&format!("\n`{:.2}``CASH`  `{:.2}``BP`  `{:.2}``YOLO`\n",
-            roundcents(IF!(cash<0.0, 0.0, cash)), roundcents(bp), roundcents(long+short+cash)))
+        .push_msg(&format!("\n`{:.2}``CASH`  `{:.2}``BP`  `{:.2}``YOLO`  `{:.2}``MAINT`{}\n",
+            cash.max(Money::ZERO).scale(rate), bp.scale(rate), (long+short+cash).scale(rate), requirement.scale(rate),
+            IF!(base == USD, String::new(), format!("  _{}_", base))))
         .edit_msg()
         .await?;
-    Ok("COMPLETED.")
+    Ok(Outcome::Done)
 }
 
-// Handle: /yolo
-async fn do_yolo (cmdstruct:&mut CmdStruct) -> Bresult<&'static str> {
+// Handle: /fx eur [-]100
+// Buy or sell a currency with USD cash at the quoted XXXUSD=X rate
+async fn do_fx (cmdstruct:&mut CmdStruct, caps:Vec<Option<String>>) -> Bresult<Outcome> {
+    let currency = caps.as_str(1)?.to_uppercase();
+    cmdstruct.markdown();
+    if currency == USD { cmdstruct.push_msg("USD is the account currency").send_msg().await?; return Ok(Outcome::Done) }
 
-    if regex_to_vec(r"/yolo", &cmdstruct.message)?.is_empty() { return Ok("SKIP"); }
+    let rate = match fx_rate(cmdstruct, &currency).await {
+        Ok(rate) => rate,
+        Err(e) => {
+            cmdstruct.push_msg(&format!("No {} rate", fx_ticker(&currency))).send_msg().await?;
+            Err(e)?
+        }
+    };
+    let amount = match caps.as_str(2) {
+        Ok(amount) => amount.parse::<Money>()?,
+        Err(_) => {
+            cmdstruct.push_msg(&format!("`1 {} = {} USD`", currency, rate)).send_msg().await?;
+            return Ok(Outcome::Done)
+        }
+    };
+    let (id, origin) = (cmdstruct.id, cmdstruct.origin());
+    let usd = amount.scale(rate).cents();
+    let msg = getenvstruct!(cmdstruct).transaction( |envstruct| {
+        let held = balances(&envstruct.dbconn, id)?.iter().find( |(c, _)| *c == currency ).map_or(Money::ZERO, |(_, b)| *b);
+        if amount.is_positive() && envstruct.entity_balance(id)? < usd {
+            return Ok(format!("Need ${} USD cash", money_pretty(usd)))
+        }
+        if amount.is_negative() && held < -amount {
+            return Ok(format!("Only {:.2} {} to sell", held, currency))
+        }
+        cash_inc(envstruct, origin, id, USD, -usd)?;
+        cash_inc(envstruct, origin, id, &currency, amount)?;
+        Ok(format!("*{}:* `{:.2}``{}` _@{}_ `{:.2}``USD`", IF!(amount.is_positive(), "Bought", "Sold"), amount.abs(), currency, rate, usd.abs()))
+    })?;
+    cmdstruct.push_msg(&msg).send_msg().await?;
+    Ok(Outcome::Done)
+}
 
+// Handle: /base [eur]
+// Show or set the currency /stonks totals are shown in
+async fn do_base (cmdstruct:&mut CmdStruct, caps:Vec<Option<String>>) -> Bresult<Outcome> {
+    let id = cmdstruct.id;
+    if let Ok(currency) = caps.as_str(1).map( str::to_uppercase ) {
+        if let Err(e) = fx_rate(cmdstruct, &currency).await { // Must be quotable
+            cmdstruct.push_msg(&format!("No {} rate", fx_ticker(&currency))).send_msg().await?;
+            Err(e)?
+        }
+        base_currency_set(&getenvstruct!(cmdstruct).dbconn, id, &currency)?;
+    }
+    let base = base_currency(&getenvstruct!(cmdstruct).dbconn, id)?;
+    cmdstruct.push_msg(&format!("Base currency {}", base)).send_msg().await?;
+    Ok(Outcome::Done)
+}
+
+// Handle: /yolo
+async fn do_yolo (cmdstruct:&mut CmdStruct) -> Bresult<Outcome> {
     cmdstruct.markdown().push_msg("...").send_msg().await?;
 
     let rows = {
@@ -1429,10 +1748,11 @@ async fn do_yolo (cmdstruct:&mut CmdStruct) -> Bresult<&'static str> {
             GROUP BY ticker")?
     };
 
-    // Update all user-positioned tickers
-    for row in rows {
-        let ticker = row.get_string("ticker")?;
-        info!("Stonk \x1b[33m{:?}", Quote::get_market_quote(cmdstruct, &ticker).await?);
+    // Update all user-positioned tickers and the currency pairs to value them
+    let mut tickers = rows.iter().map( |row| row.get_string("ticker") ).collect::<Bresult<Vec<String>>>()?;
+    tickers.extend(fx_tickers(&getenvstruct!(cmdstruct).dbconn)?);
+    for quote in Quote::get_market_quotes(cmdstruct, &tickers).await?.values() {
+        info!("Stonk \x1b[33m{:?}", quote);
     }
 
     /* Everyone's YOLO including non positioned YOLOers
@@ -1450,10 +1770,11 @@ async fn do_yolo (cmdstruct:&mut CmdStruct) -> Bresult<&'static str> {
     ORDER BY yolo DESC"; */
 
     // Everyone's YOLO including non positioned YOLOers
-    let sql = "SELECT name, ROUND(value + balance, 2) AS yolo \
-               FROM (SELECT positions.id, SUM(qty*stonks.price) AS value \
+    let sql = "SELECT name, value + balance + IFNULL(cash, 0) AS yolo \
+               FROM (SELECT positions.id, SUM(qty*stonks.price/1e6*IFNULL(fx.price, 1000000)/1e6) AS value \
                      FROM positions \
                      LEFT JOIN stonks ON stonks.ticker = positions.ticker \
+                     LEFT JOIN stonks AS fx ON fx.ticker = stonks.currency || 'USD=X' \
                      WHERE positions.ticker NOT LIKE '0%' \
                        AND positions.ticker NOT LIKE '1%' \
                        AND positions.ticker NOT LIKE '2%' \
@@ -1468,6 +1789,10 @@ async fn do_yolo (cmdstruct:&mut CmdStruct) -> Bresult<&'static str> {
                      GROUP BY id) \
                NATURAL JOIN accounts \
                NATURAL JOIN entitys \
+               LEFT JOIN (SELECT balances.id, SUM(balance*IFNULL(fx.price, 1000000)/1e6) AS cash \
+                          FROM balances \
+                          LEFT JOIN stonks AS fx ON fx.ticker = balances.currency || 'USD=X' \
+                          GROUP BY balances.id) USING (id) \
                ORDER BY yolo DESC";
 
     let sql_results = {
@@ -1480,21 +1805,89 @@ async fn do_yolo (cmdstruct:&mut CmdStruct) -> Bresult<&'static str> {
     let mut msg = "*YOLOlians*".to_string();
     for row in sql_results {
         msg.push_str( &format!(" `{:.2}@{}`",
-            row.get_f64("yolo")?,
+            row.get_money("yolo")?,
             row.get_string("name")?) );
     }
     cmdstruct.set_msg(&msg).edit_msg().await?;
-    Ok("COMPLETED.")
+    Ok(Outcome::Done)
+}
+
+// Handle: /perf [1w|1m|ytd]
+async fn do_perf (cmdstruct:&mut CmdStruct, caps:Vec<Option<String>>) -> Bresult<Outcome> {
+    let period = caps.as_str(1).unwrap_or("1m").to_lowercase();
+    let since = history_period_start(&period, cmdstruct.now)?;
+
+    let perf = {
+        let dbconn = &getenvstruct!(cmdstruct).dbconn;
+        performance(dbconn, cmdstruct.id, since)?
+    };
+    let msg = match perf {
+        None => format!("No {} history yet, snapshots are taken daily after the close.", period),
+        Some(perf) => {
+            let fmt_gains = |gains: Vec<&(String, Money)>|
+                gains.iter().map( |(ticker, gain)| format!(" `{}{:+.2}`", ticker, gain) ).collect::<String>();
+            let best = perf.positions.iter().filter( |p| p.1.is_positive() ).take(3).collect();
+            let worst = perf.positions.iter().rev().filter( |p| p.1.is_negative() ).take(3).collect();
+            format!("*Performance {}* `{}..{}`\n`{:.2}` -> `{:.2}` `{:+.2}` `{:+.2}%`\n*Max drawdown* `{:.2}%`\n*Best:*{}\n*Worst:*{}",
+                period,
+                &time2datetimestr(perf.start.time)[..10], &time2datetimestr(perf.end.time)[..10],
+                perf.start.yolo, perf.end.yolo, perf.gain(), perf.percent(),
+                perf.drawdown,
+                fmt_gains(best), fmt_gains(worst))
+        }
+    };
+    cmdstruct.markdown().push_msg(&msg).send_msg().await?;
+    Ok(Outcome::Done)
+}
+
+// Handle: /pnl [ticker|fifo|lifo]
+async fn do_pnl (cmdstruct:&mut CmdStruct, caps:Vec<Option<String>>) -> Bresult<Outcome> {
+    let id = cmdstruct.id;
+    let arg = caps.as_str(1).unwrap_or("").to_uppercase();
+    cmdstruct.markdown();
+
+    if arg == "FIFO" || arg == "LIFO" {
+        let method = IF!(arg == "FIFO", LotMethod::Fifo, LotMethod::Lifo);
+        lot_method_set(&getenvstruct!(cmdstruct).dbconn, id, method)?;
+        cmdstruct.push_msg(&format!("Lots now close {}", method.name())).send_msg().await?;
+        return Ok(Outcome::Done)
+    }
+
+    let (tickers, method) = {
+        let dbconn = &getenvstruct!(cmdstruct).dbconn;
+        let tickers = IF!(arg.is_empty(), pnl_tickers(dbconn, id)?, vec![arg.to_string()]);
+        (tickers, lot_method(dbconn, id)?)
+    };
+
+    let mut msg = format!("*P&L* _{}_  `realized` `unrealized`", method.name());
+    let (mut total_realized, mut total_unrealized) = (Money::ZERO, Money::ZERO);
+    for ticker in tickers {
+        let (realized, open) = {
+            let dbconn = &getenvstruct!(cmdstruct).dbconn;
+            (realized_gain(dbconn, id, Some(&ticker))?, lots(dbconn, id, &ticker)?)
+        };
+        let unrealized =
+            if open.is_empty() || is_self_stonk(&ticker) { Money::ZERO } else {
+                let price = Quote::get_market_quote(cmdstruct, &ticker).await?.price;
+                open.iter().map( |lot| lot.qty * (price - lot.price) ).sum()
+            };
+        total_realized += realized;
+        total_unrealized += unrealized;
+        msg += &format!("\n`{}` `{:.2}` `{:.2}` {} lots", ticker, realized, unrealized, open.len());
+    }
+    msg += &format!("\n*Total* `{:.2}` `{:.2}`", total_realized, total_unrealized);
+    cmdstruct.push_msg(&msg).send_msg().await?;
+    Ok(Outcome::Done)
 }
 
 // Returns qty,newBalance if qty to buy doesn't exceed buying power
-fn verify_qty (mut qty:f64, price:f64, bp:f64) -> Result<(f64,f64), &'static str> {
-    qty = roundqty(qty);
+fn verify_qty (mut qty:Qty, price:Money, bp:Money) -> Result<(Qty,Money), &'static str> {
+    qty = qty.tradeable();
     let basis = qty * price;
     let new_balance = bp - basis;
     info!("\x1b[1mbuy? {} @ {} = {}  BANK {} -> {}", qty, price, basis, bp, new_balance);
-    if qty < 0.0001 { return Err("Amount too low") }
-    if new_balance < 0.0 { return Err("Need more buying power") }
+    if qty < Qty::TICK { return Err("Amount too low") }
+    if new_balance.is_negative() { return Err("Need more buying power") }
     Ok((qty, new_balance))
 }
 
@@ -1504,8 +1897,9 @@ fn verify_qty (mut qty:f64, price:f64, bp:f64) -> Result<(f64,f64), &'static str
 #[derive(Debug)]
 struct TradeBuy<'a> {
     position: Position,
-    qty:f64, // actual qty to trade
-    bp: f64,
+    qty:Qty, // actual qty to trade
+    bp: Money,
+    rate: f64, // USD per unit of the stonk's currency
     trade: Trade<'a>
 }
 
@@ -1517,54 +1911,56 @@ impl<'a> TradeBuy<'a> {
 
         if quote.exchange == "PNK" {
             trade.cmdstruct.push_msg("`OTC/PinkSheet untradeable`").send_msg().await?;
-            Err("OTC/PinkSheet untradeable")?
+            Err(Rejected("OTC/PinkSheet untradeable"))?
         }
 
-        let bp = trade.cmdstruct.buying_power().await?;
+        let rate = fx_rate(trade.cmdstruct, &quote.currency).await?;
+        let bp = trade.cmdstruct.buying_power().await?.scale(1.0 / rate); // In the stonk's currency
         let qty = match trade.amt {
-            Some(amt) => if trade.is_dollars { amt/quote.price } else { amt },
+            Some(Amount::Dollars(amt)) => amt / quote.price,
+            Some(Amount::Shares(qty)) => qty,
             None => {
-                if position.qty < 0.0 {
+                if position.qty.is_negative() {
                     -position.qty // Cover full short position
                 } else {
-                    roundqty(bp / quote.price) // Buy as much as possible
+                    (bp / quote.price).tradeable() // Buy as much as possible
                 }
             }
         };
-        Ok( TradeBuy{position, qty, bp, trade} )
+        Ok( TradeBuy{position, qty, bp, rate, trade} )
     }
 }
 
 #[derive(Debug)]
-struct TradeBuyCalc<'a> { qty:f64, cost:f64, new_qty:f64, new_basis:f64, new_position_p:bool, tradebuy: TradeBuy<'a> }
+struct TradeBuyCalc<'a> { qty:Qty, cost:Money, new_qty:Qty, new_basis:Money, new_position_p:bool, tradebuy: TradeBuy<'a> }
 
 impl<'a> TradeBuyCalc<'a> {
     async fn compute_position (obj: TradeBuy<'a>) -> Bresult<TradeBuyCalc<'a>> {
         let quote = obj.position.quote.as_ref().ok_or("quote not acquired")?;
         // TODO: Adjust for short settling?
-        let bp = obj.bp + IF!(obj.position.qty<0.0, -obj.position.qty*quote.price, 0.0);
+        let bp = obj.bp + IF!(obj.position.qty.is_negative(), -obj.position.qty*quote.price, Money::ZERO);
         // Try and fit quantity to buying power (reduce qty until <=)
         let (qty, _new_balance) =
             match
                 verify_qty(obj.qty, quote.price, bp)
-                .or_else( |_e| verify_qty(obj.qty-0.0001, quote.price, bp) )
-                .or_else( |_e| verify_qty(obj.qty-0.0002, quote.price, bp) )
-                .or_else( |_e| verify_qty(obj.qty-0.0003, quote.price, bp) )
-                .or_else( |_e| verify_qty(obj.qty-0.0004, quote.price, bp) )
-                .or_else( |_e| verify_qty(obj.qty-0.0005, quote.price, bp) ) {
+                .or_else( |_e| verify_qty(obj.qty - Qty::TICK, quote.price, bp) )
+                .or_else( |_e| verify_qty(obj.qty - Qty::TICK*2, quote.price, bp) )
+                .or_else( |_e| verify_qty(obj.qty - Qty::TICK*3, quote.price, bp) )
+                .or_else( |_e| verify_qty(obj.qty - Qty::TICK*4, quote.price, bp) )
+                .or_else( |_e| verify_qty(obj.qty - Qty::TICK*5, quote.price, bp) ) {
                 Err(e) => {  // Message user problem and log
                     obj.trade.cmdstruct.push_msg(&e).send_msg_id().await?;
-                    return Err(e.into())
+                    return Err(Rejected(e).into())
                 },
                 Ok(r) => r
             };
         let qty_old = obj.position.qty;
         let price_old = obj.position.price;
-        let new_position_p = qty_old == 0.0;
+        let new_position_p = qty_old.is_zero();
         let price_new = quote.price;
         let cost = qty * price_new;
         let new_basis = (qty * price_new + qty_old * price_old) / (qty + qty_old);
-        let new_qty = roundqty(qty + qty_old);
+        let new_qty = qty + qty_old;
 
         Ok(Self{qty, cost, new_qty, new_basis, new_position_p, tradebuy:obj})
     }
@@ -1579,8 +1975,7 @@ impl<'a> ExecuteBuy<'a> {
         let quote = obj.tradebuy.position.quote.as_ref().ok_or("quote not acquired")?;
         let now = obj.tradebuy.trade.cmdstruct.now;
 
-        let dst_hours_adjust = getenvstruct!(obj.tradebuy.trade.cmdstruct).dst_hours_adjust;
-        if quote.hours!=24 && !trading_hours_p(dst_hours_adjust, now)? {
+        if quote.hours!=24 && !trading_hours_p(&quote.exchange, now)? {
             return Ok( ExecuteBuy {
                 msg: format!("Unable to buy {} after hours", obj.tradebuy.trade.ticker),
                 tradebuycalc:obj
@@ -1589,52 +1984,59 @@ impl<'a> ExecuteBuy<'a> {
 
         let msg = {
             let envstruct = getenvstruct!(obj.tradebuy.trade.cmdstruct);
-            let dbconn = &envstruct.dbconn;
             let id = obj.tradebuy.trade.cmdstruct.id;
+            let origin = obj.tradebuy.trade.cmdstruct.origin();
+            let fills = obj.tradebuy.trade.cmdstruct.fills.clone();
             let ticker = &obj.tradebuy.trade.ticker;
+            let (price, currency, rate) = (quote.price, quote.currency.to_string(), obj.tradebuy.rate);
+            let position = &mut obj.tradebuy.position;
+            let (qty, cost, new_qty, new_basis, new_position_p) = (obj.qty, obj.cost, obj.new_qty, obj.new_basis, obj.new_position_p);
+
+            envstruct.transaction( |envstruct| {
+                let dbconn = &envstruct.dbconn;
+                sql_table_order_insert(dbconn, id, ticker, qty, price, now)?;
+                let mut msg = format!("*Bought:*");
+                msg += &format!("  `{:.2}``{}` *{}*_@{}_", qty*price, ticker, qty, price);
+
+                if !new_position_p && !new_qty.is_zero() {
+                    info!("\x1b[1madd to existing position:  {} @ {}  ->  {} @ {}", position.qty, price, new_qty, new_basis);
+                }
+                position_set(dbconn, origin, id, ticker, new_qty, new_basis)?;
 
-            let price = quote.price;
-
-            sql_table_order_insert(dbconn, id, ticker, obj.qty, price, now)?;
-            let mut msg = format!("*Bought:*");
-            msg += &format!("  `{:.2}``{}` *{}*_@{}_", obj.qty*price, ticker, obj.qty, price);
-
-            if obj.new_position_p {
-                getsql!(dbconn, "INSERT INTO positions VALUES (?, ?, ?, ?)", id, &**ticker, obj.new_qty, obj.new_basis)?;
-            } else if obj.new_qty == 0.0 {
-                getsql!(dbconn, "DELETE FROM positions WHERE id=? AND ticker=?", id, &**ticker)?;
-            } else {
-                info!("\x1b[1madd to existing position:  {} @ {}  ->  {} @ {}", obj.tradebuy.position.qty, price, obj.new_qty, obj.new_basis);
-                getsql!(dbconn, "UPDATE positions SET qty=?, price=? WHERE id=? AND ticker=?", obj.new_qty, obj.new_basis, id, &**ticker)?;
-            }
-
-            envstruct.entity_balance_inc(id, -obj.cost)?;
+                crash_point("execute_buy")?;
+                cash_spend(envstruct, origin, id, &currency, cost, rate)?;
+                fills_close(dbconn, origin, id, now, &fills)?;
 
-            if obj.new_qty != 0.0 {
-                obj.tradebuy.position.qty = obj.new_qty; // TODO: Mutating previous monadic state
-                obj.tradebuy.position.price = obj.new_basis;
-                msg.push_str(&obj.tradebuy.position.format_position(&envstruct, id)?);
-            }
-            msg
+                if !new_qty.is_zero() {
+                    position.qty = new_qty; // TODO: Mutating previous monadic state
+                    position.price = new_basis;
+                    msg.push_str(&position.format_position(&envstruct, id, now)?);
+                }
+                Ok(msg)
+            })?
         };
 
         Ok(Self{msg, tradebuycalc:obj})
     }
 }
 
-async fn do_trade_buy (cmdstruct:&mut CmdStruct) -> Bresult<&'static str> {
-    let trade = Trade::new_trade(cmdstruct)?;
-    if trade.as_ref().map_or(true, |trade| trade.action != '+') { return Ok("SKIP") }
+// Ticker, +, $ when the amount is dollars, amount
+const BUY_TRIGGER :&str = r"(?i)^([A-Za-z0-9^.-]+)([+])([$])?(\d+\.?|\d*\.\d{1,4})?$";
+
+async fn do_trade_buy (cmdstruct:&mut CmdStruct, caps:Vec<Option<String>>) -> Bresult<Outcome> {
+    trade_buy(Trade::new_trade(cmdstruct, caps)?).await
+}
 
+async fn trade_buy (trade:Trade<'_>) -> Bresult<Outcome> {
     let res =
-        TradeBuy::new_tradebuy(trade.unwrap()).await
+        TradeBuy::new_tradebuy(trade).await
         .map(TradeBuyCalc::compute_position)?.await
         .map(ExecuteBuy::execute)??;
 
     info!("\x1b[1;31mResult {:#?}", &res);
 
     res.tradebuycalc.tradebuy.trade.cmdstruct.push_msg(&res.msg).edit_msg().await?; // Report to group
-    Ok("COMPLETED.")
+    Ok(Outcome::Done)
 }
 
 ////////////////////////////////////////////////////////////////////////////////
@@ -1644,13 +2046,13 @@ async fn do_trade_buy (cmdstruct:&mut CmdStruct) -> Bresult<&'static str> {
 struct TradeSell<'a> {
     position: Position,
     short: bool,
-    qty: f64, // calculated quantity to sell
-    price: f64, // stonk price
-    bank_balance: f64,
-    new_balance: f64, // eventual bank balance
-    new_qty: f64,     // eventual position quantity
+    qty: Qty, // calculated quantity to sell
+    price: Money, // stonk price
+    bank_balance: Money,
+    new_balance: Money, // eventual bank balance
+    new_qty: Qty,       // eventual position quantity
     trade: Trade<'a>,
-    bp: f64
+    bp: Money
 }
 
 impl<'a> TradeSell<'a> {
@@ -1663,41 +2065,47 @@ impl<'a> TradeSell<'a> {
 
         if quote.exchange == "PNK" {
             trade.cmdstruct.push_msg("`OTC / PinkSheet untradeable`").send_msg().await?;
-            Err("OTC / PinkSheet Verboten Stonken")?
+            Err(Rejected("OTC / PinkSheet Verboten Stonken"))?
         }
 
-        let bp = trade.cmdstruct.buying_power().await?;
+        let bp = trade.cmdstruct.buying_power().await?.scale(1.0 / fx_rate(trade.cmdstruct, &quote.currency).await?); // In the stonk's currency
 
         let qty =
-            roundqty(match trade.amt {
-                Some(amt) => IF!(trade.is_dollars, amt/price, amt), // Convert dollars to shares
+            match trade.amt {
+                Some(Amount::Dollars(amt)) => amt / price, // Convert dollars to shares
+                Some(Amount::Shares(qty)) => qty,
                 None =>
-                    if position.qty <= 0.0 {
-                        roundqty(bp / price) // Short entire bying power
+                    if !position.qty.is_positive() {
+                        bp / price // Short entire bying power
                     } else {
                         position.qty // no amount set, so set to entire qty
                     }
-            });
+            }.tradeable();
 
-        if qty == 0.0 {
+        if qty.is_zero() {
             trade.cmdstruct.push_msg("Quantity too low.").send_msg().await?;
-            Err("sell qty too low")?
+            Err(Rejected("sell qty too low"))?
         }
 
-        let short = position.qty <= 0.0;
+        let short = !position.qty.is_positive();
+
+        if short && is_option(&trade.ticker) {
+            trade.cmdstruct.push_msg("Writing options isn't supported").send_msg().await?;
+            Err(Rejected("option write"))?
+        }
 
         let (mut qty, _new_balance) =
-            if !short { (qty, 0.0) } else {
+            if !short { (qty, Money::ZERO) } else {
             match
                 verify_qty(qty, price, bp)
-                .or_else( |_e| verify_qty(qty-0.0001, price, bp) )
-                .or_else( |_e| verify_qty(qty-0.0002, price, bp) )
-                .or_else( |_e| verify_qty(qty-0.0003, price, bp) )
-                .or_else( |_e| verify_qty(qty-0.0004, price, bp) )
-                .or_else( |_e| verify_qty(qty-0.0005, price, bp) ) {
+                .or_else( |_e| verify_qty(qty - Qty::TICK, price, bp) )
+                .or_else( |_e| verify_qty(qty - Qty::TICK*2, price, bp) )
+                .or_else( |_e| verify_qty(qty - Qty::TICK*3, price, bp) )
+                .or_else( |_e| verify_qty(qty - Qty::TICK*4, price, bp) )
+                .or_else( |_e| verify_qty(qty - Qty::TICK*5, price, bp) ) {
                 Err(e) => {  // Message user problem and log
                     trade.cmdstruct.push_msg(&e).send_msg().await?;
-                    (qty, 0.0)
+                    (qty, Money::ZERO)
                 },
                 Ok(r) => r
             } };
@@ -1709,7 +2117,7 @@ impl<'a> TradeSell<'a> {
         info!("\x1b[1msell? {} {}/{} @ {} = {}  CASH {} -> {}", IF!(short, "short", "long"), qty, position.qty, price, gain,  bank_balance, new_balance);
 
         // If equal to the rounded position value, snap qty to exact position
-        if qty != position.qty && roundcents(gain) == roundcents(position.qty*price) {
+        if qty != position.qty && gain.cents() == (position.qty*price).cents() {
             qty = position.qty;
             gain = qty*price;
             new_balance = bank_balance + gain;
@@ -1717,11 +2125,11 @@ impl<'a> TradeSell<'a> {
         }
 
         if !short && position.qty < qty {
-            trade.cmdstruct.push_msg("You can't sell more than you own.").send_msg().await?;
-            return Err("not enough shares to sell".into());
+            trade.cmdstruct.push_msg("You can't sell more than you own.  Sell it all then /short.").send_msg().await?;
+            return Err(Rejected("not enough shares to sell").into());
         }
 
-        let new_qty = roundqty(position.qty-qty);
+        let new_qty = position.qty-qty;
 
         Ok( Self{ position, short, qty, price, bank_balance, new_balance, new_qty, trade, bp} )
     }
@@ -1737,72 +2145,354 @@ impl<'a> ExecuteSell<'a> {
     fn execute (mut obj: TradeSell<'a>) -> Bresult<Self> {
         let msg = {
             let now = obj.trade.cmdstruct.now;
-            if obj.position.quote.as_ref().unwrap().hours != 24
-                && !trading_hours_p(getenvstruct!(obj.trade.cmdstruct).dst_hours_adjust, now)? {
+            let quote = obj.position.quote.as_ref().unwrap();
+            if quote.hours != 24 && !trading_hours_p(&quote.exchange, now)? {
                 return Ok(Self{msg:format!("Unable to sell {} after hours", obj.position.ticker), tradesell:obj});
             }
             let envstruct = getenvstruct!(obj.trade.cmdstruct);
-            let dbconn = &envstruct.dbconn;
             let id = obj.trade.cmdstruct.id;
+            let origin = obj.trade.cmdstruct.origin();
+            let fills = obj.trade.cmdstruct.fills.clone();
             let ticker = &obj.trade.ticker;
-            let qty = obj.qty;
-            let price = obj.price;
-
-            let mut msg = IF!(obj.short, format!("*Short:*"), format!("*Sold:*"));
-
-            let new_qty = obj.new_qty;
-            if new_qty == 0.0 {
-                sql_table_order_insert(dbconn, id, ticker, -qty, price, now)?;
-                getsql!(dbconn, "DELETE FROM positions WHERE id=? AND ticker=?", id, &**ticker)?;
-                msg += &obj.position.format_position(&envstruct, id)?;
-                envstruct.entity_balance_set(id, obj.new_balance)?;
-            } else if obj.position.qty == 0.0 {
-                let amt = qty*price;
-                if obj.bp < amt {
-                    msg = format!("${} of {} exceeds buying power of ${}", money_pretty(amt), ticker, money_pretty(obj.bp));
-                } else {
+            let position = &mut obj.position;
+            let (qty, price, short, bp, new_qty, new_balance) = (obj.qty, obj.price, obj.short, obj.bp, obj.new_qty, obj.new_balance);
+            let gain = new_balance - obj.bank_balance; // Settled in the stonk's currency
+            let currency = position.quote.as_ref().map_or(USD.to_string(), |q| q.currency.to_string());
+
+            envstruct.transaction( |envstruct| {
+                let dbconn = &envstruct.dbconn;
+                let mut msg = IF!(short, format!("*Short:*"), format!("*Sold:*"));
+
+                if new_qty.is_zero() {
                     sql_table_order_insert(dbconn, id, ticker, -qty, price, now)?;
-                    getsql!(dbconn, "INSERT INTO positions VALUES (?, ?, ?, ?)", id, &**ticker, obj.new_qty, obj.price)?;
-                    obj.position.qty = obj.new_qty; // so format_position is up to date
-                    obj.position.price = obj.price; // Update previous monad so position is printed correctly
-                    msg += &format!("  `{:.2}``{}` *{}*_@{}_{}",
-                        amt, ticker, qty, price,
-                        &obj.position.format_position(&envstruct, id)?);
-                    envstruct.entity_balance_set(id, obj.new_balance)?;
-                }
-            } else {
-                let amt = qty*price;
-                if obj.short && obj.bp < amt {
-                    msg = format!("${} of {} exceeds buying power of ${}", money_pretty(amt), ticker, money_pretty(obj.bp));
+                    position_set(dbconn, origin, id, ticker, Qty::ZERO, Money::ZERO)?;
+                    msg += &position.format_position(&envstruct, id, now)?;
+                    crash_point("execute_sell")?;
+                    cash_inc(envstruct, origin, id, &currency, gain)?;
+                    fills_close(dbconn, origin, id, now, &fills)?;
+                } else if position.qty.is_zero() {
+                    let amt = qty*price;
+                    if bp < amt {
+                        msg = format!("${} of {} exceeds buying power of ${}", money_pretty(amt), ticker, money_pretty(bp));
+                    } else {
+                        sql_table_order_insert(dbconn, id, ticker, -qty, price, now)?;
+                        position_set(dbconn, origin, id, ticker, new_qty, price)?;
+                        position.qty = new_qty; // so format_position is up to date
+                        position.price = price; // Update previous monad so position is printed correctly
+                        msg += &format!("  `{:.2}``{}` *{}*_@{}_{}",
+                            amt, ticker, qty, price,
+                            &position.format_position(&envstruct, id, now)?);
+                        crash_point("execute_sell")?;
+                        cash_inc(envstruct, origin, id, &currency, gain)?;
+                        fills_close(dbconn, origin, id, now, &fills)?;
+                    }
                 } else {
-                    sql_table_order_insert(dbconn, id, ticker, -qty, price, now)?;
-                    let new_basis = (amt + -obj.position.qty * obj.position.price) / -new_qty;
-                    getsql!(dbconn, "UPDATE positions SET qty=?, price=? WHERE id=? AND ticker=?", new_qty, new_basis, id, &**ticker)?;
-                    obj.position.qty = obj.new_qty; // so format_position is up to date
-                    obj.position.price = new_basis;
-                    msg += &format!("  `{:.2}``{}` *{}*_@{}_{}",
-                        amt, ticker, qty, price,
-                        &obj.position.format_position(&envstruct, id)?);
-                    envstruct.entity_balance_set(id, obj.new_balance)?;
+                    let amt = qty*price;
+                    if short && bp < amt {
+                        msg = format!("${} of {} exceeds buying power of ${}", money_pretty(amt), ticker, money_pretty(bp));
+                    } else {
+                        sql_table_order_insert(dbconn, id, ticker, -qty, price, now)?;
+                        let new_basis = (amt + -position.qty * position.price) / -new_qty;
+                        position_set(dbconn, origin, id, ticker, new_qty, new_basis)?;
+                        position.qty = new_qty; // so format_position is up to date
+                        position.price = new_basis;
+                        msg += &format!("  `{:.2}``{}` *{}*_@{}_{}",
+                            amt, ticker, qty, price,
+                            &position.format_position(&envstruct, id, now)?);
+                        crash_point("execute_sell")?;
+                        cash_inc(envstruct, origin, id, &currency, gain)?;
+                        fills_close(dbconn, origin, id, now, &fills)?;
+                    }
                 }
-            }
-            msg
+                Ok(msg)
+            })?
         };
         Ok(Self{msg, tradesell:obj})
     }
 }
 
-async fn do_trade_sell (cmdstruct :&mut CmdStruct) -> Bresult<&'static str> {
-    let trade = Trade::new_trade(cmdstruct)?;
-    if trade.as_ref().map_or(true, |trade| trade.action != '-') { return Ok("SKIP") }
+// Ticker, -, $ when the amount is dollars, amount
+const SELL_TRIGGER :&str = r"(?i)^([A-Za-z0-9^.-]+)([-])([$])?(\d+\.?|\d*\.\d{1,4})?$";
 
+async fn do_trade_sell (cmdstruct :&mut CmdStruct, caps:Vec<Option<String>>) -> Bresult<Outcome> {
+    trade_sell(Trade::new_trade(cmdstruct, caps)?).await
+}
+
+async fn trade_sell (trade:Trade<'_>) -> Bresult<Outcome> {
     let res =
-        TradeSell::new_tradesell(trade.unwrap()).await
+        TradeSell::new_tradesell(trade).await
         .map(ExecuteSell::execute)??;
 
     info!("\x1b[1;31mResult {:#?}", res);
     res.tradesell.trade.cmdstruct.push_msg(&res.msg).send_msg().await?; // Report to group
-    Ok("COMPLETED.")
+    Ok(Outcome::Done)
+}
+
+// Handle: /short gme [5|$18]  /cover gme [5]
+// Explicit short sales and covers through the sell and buy paths.
+async fn do_short (cmdstruct :&mut CmdStruct, caps:Vec<Option<String>>) -> Bresult<Outcome> {
+    let cover = caps.as_str(1)?.to_lowercase() == "cover";
+    let ticker = caps.as_str(2)?.to_uppercase();
+    let is_dollars = caps.as_str(3).is_ok();
+    let amt = match caps.as_str(4) {
+        Ok(amt) if is_dollars => Some(Amount::Dollars(amt.parse::<Money>()?)),
+        Ok(amt) => Some(Amount::Shares(amt.parse::<Qty>()?)),
+        Err(_) => None
+    };
+    cmdstruct.markdown();
+
+    if !market_ticker_p(&ticker) || is_option(&ticker) {
+        cmdstruct.push_msg("Only market stonks can be shorted").send_msg().await?;
+        Err("short of a non-market ticker")?
+    }
+    let held = {
+        let dbconn = &getenvstruct!(cmdstruct).dbconn;
+        getsql!(dbconn, "SELECT qty FROM positions WHERE id=? AND ticker=?", cmdstruct.id, &*ticker)?
+            .first().map_or(Qty::ZERO, |row| row.get_qty_or(Qty::ZERO, "qty"))
+    };
+
+    if cover {
+        if !held.is_negative() || is_dollars {
+            cmdstruct.push_msg(&IF!(is_dollars, "Cover by share count".to_string(), format!("No {} short to cover", ticker))).send_msg().await?;
+            Err("nothing to cover")?
+        }
+        let amt = match amt { // Never cover past flat into a long
+            Some(Amount::Shares(qty)) => Some(Amount::Shares(qty.min(-held))),
+            amt => amt
+        };
+        trade_buy(Trade{ cmdstruct, ticker, action: '+', amt }).await
+    } else {
+        if held.is_positive() {
+            cmdstruct.push_msg(&format!("Sell your {} shares before shorting", ticker)).send_msg().await?;
+            Err("short while long")?
+        }
+        trade_sell(Trade{ cmdstruct, ticker, action: '-', amt }).await
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+/// Stonk Limit and Stop Orders
+/*
+    Pending orders on market tickers wait in the limits table until the
+    scheduler sees a quote that triggers them.  The trade is then executed
+    at the market price via trade_buy/trade_sell.
+      kind  qty  triggers when
+       @     +   price <= limit   buy limit
+       @     -   limit <= price   sell limit
+       !     +   stop <= price    buy stop
+       !     -   price <= stop    sell stop
+*/
+
+fn limit_triggered_p (kind: &str, qty: Qty, limit: Money, price: Money) -> bool {
+    match (kind, qty.is_positive()) {
+        ("@", true)  => price <= limit,
+        ("@", false) => limit <= price,
+        (_, true)    => limit <= price,
+        (_, false)   => price <= limit
+    }
+}
+
+const LIMIT_TRIGGER :&str =
+    r"(?xi)^
+        ([A-Za-z0-9^.-]+)             ### ticker symbol
+        (?:
+            ([+-])                    ### + buy, - sell
+            (\d+\.?|\d*\.\d{1,4})        ### float quantity
+            ([@!])                    ### @ limit, ! stop
+            (\d+\.?|\d*\.\d{1,4})        ### float price
+        |
+            (~)                       ### cancel
+            (?: ([@!]) (\d+\.?|\d*\.\d{1,4}) )?
+        )$";
+
+async fn do_trade_limit (cmdstruct: &mut CmdStruct, caps:Vec<Option<String>>) -> Bresult<Outcome> {
+    let id = cmdstruct.id;
+    let at = cmdstruct.at;
+    let ticker = caps.as_str(1)?.to_uppercase();
+    cmdstruct.markdown();
+
+    if caps.as_str(6).is_ok() { // Cancel pending orders, optionally just those of a kind and price
+        let kind = caps.as_str(7).unwrap_or("%");
+        let price = match caps.as_str(8) { Ok(price) => price.parse::<Money>()?, Err(_) => Money(-1) };
+        let rows = {
+            let dbconn = &getenvstruct!(cmdstruct).dbconn;
+            let rows = getsql!(dbconn, "SELECT * FROM limits WHERE id=? AND ticker=? AND kind LIKE ? AND (?<0 OR price=?)",
+                id, &*ticker, kind, price, price)?;
+            getsql!(dbconn, "DELETE FROM limits WHERE id=? AND ticker=? AND kind LIKE ? AND (?<0 OR price=?)",
+                id, &*ticker, kind, price, price)?;
+            rows
+        };
+        let msg =
+            if rows.is_empty() {
+                format!("`No {} orders to cancel`", ticker)
+            } else {
+                rows.iter().fold("*Cancelled:*".to_string(), |msg, row|
+                    msg + &format!(" `{}{:+}{}{}`",
+                        ticker,
+                        row.get_qty_or(Qty::ZERO, "qty"),
+                        row.get_string("kind").unwrap_or_default(),
+                        row.get_money_or(Money::ZERO, "price")))
+            };
+        cmdstruct.push_msg(&msg).send_msg().await?;
+        return Ok(Outcome::Done)
+    }
+
+    let action = caps.as_str(2)?;
+    let qty = caps.as_str(3)?.parse::<Qty>()? * IF!(action == "-", -1, 1);
+    let kind = caps.as_str(4)?;
+    let price = caps.as_str(5)?.parse::<Money>()?.cents();
+
+    if is_self_stonk(&ticker) || qty.abs() < Qty::TICK || !price.is_positive() {
+        cmdstruct.push_msg("`Invalid limit/stop order`").send_msg().await?;
+        Err("invalid limit/stop order")?
+    }
+
+    let quote = Quote::get_market_quote(cmdstruct, &ticker).await?;
+    if quote.exchange == "PNK" {
+        cmdstruct.push_msg("`OTC/PinkSheet untradeable`").send_msg().await?;
+        Err("OTC/PinkSheet untradeable")?
+    }
+
+    {
+        let dbconn = &getenvstruct!(cmdstruct).dbconn;
+        getsql!(dbconn, "INSERT INTO limits VALUES (?, ?, ?, ?, ?, ?, ?)",
+            id, at, &*ticker, qty, price, kind, cmdstruct.now)?;
+    }
+
+    cmdstruct
+        .push_msg(&format!("*{} {}:* `{}{:+}{}{}` {}",
+            IF!(kind == "@", "Limit", "Stop"), IF!(qty.is_positive(), "buy", "sell"),
+            ticker, qty, kind, price,
+            quote.format_quote(id)?))
+        .send_msg().await?;
+    Ok(Outcome::Done)
+}
+
+// Executes every triggered limit/stop order.  Run by the scheduler thread.
+async fn do_limits (env: Env, now: i64) -> Bresult<()> {
+    let limits = {
+        let envstruct = env.lock().unwrap();
+        getsql!(envstruct.dbconn, "SELECT rowid AS rowid, id, at, ticker, qty, price, kind FROM limits ORDER BY time")?
+    };
+    let quotes = {
+        let mut tickers = limits.iter().filter_map( |limit| limit.get_string("ticker").ok() ).collect::<Vec<String>>();
+        tickers.sort();
+        tickers.dedup();
+        Quote::get_market_quotes(&CmdStruct::new_cmdstruct(env.clone(), now, 0, 0, 0, 0, "")?, &tickers).await?
+    };
+    for limit in limits {
+        let id = limit.get_i64("id")?;
+        let at = limit.get_i64("at")?;
+        let ticker = limit.get_string("ticker")?;
+        let qty = limit.get_qty("qty")?;
+        let price = limit.get_money("price")?;
+        let kind = limit.get_string("kind")?;
+
+        let mut cmdstruct =
+            match CmdStruct::new_cmdstruct(env.clone(), now, id, at, at, 0, "") {
+                Ok(cmdstruct) => cmdstruct,
+                e => { glog!(e); continue }
+            };
+        let quote = match quotes.get(&ticker) {
+            Some(quote) => quote,
+            None => { warn!("do_limits no quote for {}", ticker); continue }
+        };
+        match trading_hours_p(&quote.exchange, now) {
+            Ok(open) => if quote.hours != 24 && !open { continue },
+            e => { glogd!("do_limits trading_hours_p =>", e); continue }
+        }
+        if !limit_triggered_p(&kind, qty, price, quote.price) { continue }
+
+        info!("\x1b[1mlimit triggered {}{:+}{}{} at {}", ticker, qty, kind, price, quote.price);
+        let rowid = limit.get_i64("rowid")?;
+        let order = format!("{}{:+}{}{}", ticker, qty, kind, price);
+
+        cmdstruct
+            .markdown()
+            .set_msg(&format!("*{} triggered:* `{}`\n", IF!(kind == "@", "Limit", "Stop"), order));
+        cmdstruct.fills = Some(Fills::Limit(rowid)); // The fill removes the order
+        let trade = Trade{ cmdstruct: &mut cmdstruct, ticker, action: IF!(qty.is_positive(), '+', '-'), amt: Some(Amount::Shares(qty.abs())) };
+        let res = if qty.is_positive() { trade_buy(trade).await } else { trade_sell(trade).await };
+        glogd!("do_limits trade =>", res);
+
+        // Cancel an order the trade refused so it isn't retried every scheduler
+        // tick.  One that failed otherwise waits for the next.
+        let refused = match res { Err(e) if e.is::<Rejected>() => e, _ => continue };
+        let cancelled = {
+            let dbconn = &getenvstruct!(cmdstruct).dbconn;
+            let unfilled = !getsql!(dbconn, "SELECT rowid FROM limits WHERE rowid=?", rowid)?.is_empty();
+            if unfilled { getsql!(dbconn, "DELETE FROM limits WHERE rowid=?", rowid)?; }
+            unfilled
+        };
+        if cancelled {
+            cmdstruct.set_msg(&format!("*Cancelled:* `{}` {}", order, refused));
+            glogd!("do_limits send_msg =>", cmdstruct.send_msg().await);
+        }
+    }
+    Ok(())
+}
+
+
+const ALERT_TRIGGER :&str =
+    r"(?xi)^/alert \s+
+        ([A-Za-z0-9^.=-]+) \s*           ### ticker symbol
+        (?:
+            ([<>]) \s* (\d+\.?|\d*\.\d{1,6})  ### price at or above/below
+        |
+            ([+-])? (\d+\.?|\d*\.\d+) %    ### change since the close, either way if unsigned
+        )$";
+
+async fn do_alert (cmdstruct: &mut CmdStruct, caps:Vec<Option<String>>) -> Bresult<Outcome> {
+    let ticker = caps.as_str(1)?.to_uppercase();
+    let (kind, price, percent) = match caps.as_str(2) {
+        Ok(kind) => (kind, caps.as_str(3)?.parse::<Money>()?, 0.0),
+        Err(_) => (
+            match caps.as_str(4) { Ok("+") => ">%", Ok(_) => "<%", Err(_) => "%" },
+            Money::ZERO,
+            caps.as_str(5)?.parse::<f64>()?)
+    };
+    cmdstruct.markdown();
+    if !price.is_positive() && percent <= 0.0 {
+        cmdstruct.push_msg("`Invalid alert`").send_msg().await?;
+        Err("invalid alert")?
+    }
+
+    let quote = Quote::get_market_quote(cmdstruct, &ticker).await?;
+    let alert = Alert{ rowid: 0, id: cmdstruct.id, at: cmdstruct.at, ticker, kind: kind.to_string(), price, percent, name: String::new() };
+    {
+        let dbconn = &getenvstruct!(cmdstruct).dbconn;
+        getsql!(dbconn, "INSERT INTO alerts VALUES (?, ?, ?, ?, ?, ?, ?)",
+            alert.id, alert.at, &*alert.ticker, kind, price, percent, cmdstruct.now)?;
+    }
+    cmdstruct.push_msg(&format!("*Alert set:* `{}` {}", alert, quote.format_quote(alert.id)?)).send_msg().await?;
+    Ok(Outcome::Done)
+}
+
+async fn do_alerts (cmdstruct: &mut CmdStruct, caps:Vec<Option<String>>) -> Bresult<Outcome> {
+    cmdstruct.markdown();
+
+    let mut alerts = alerts(&getenvstruct!(cmdstruct).dbconn, cmdstruct.id)?;
+    let msg =
+        if let Ok(which) = caps.as_str(1) { // Delete by number or ticker
+            alerts.retain( |alert| alert.rowid.to_string() == which || alert.ticker == which.to_uppercase() );
+            let dbconn = &getenvstruct!(cmdstruct).dbconn;
+            for alert in &alerts { getsql!(dbconn, "DELETE FROM alerts WHERE rowid=?", alert.rowid)?; }
+            if alerts.is_empty() {
+                format!("`No {} alerts to delete`", which.to_uppercase())
+            } else {
+                alerts.iter().fold("*Deleted:*".to_string(), |msg, alert| msg + &format!(" `{}`", alert))
+            }
+        } else if alerts.is_empty() {
+            "No Alerts".to_string()
+        } else {
+            "Alerts:\n".to_string() +
+            &alerts.iter()
+                .map( |alert| format!("`#{}` `{}` `{}`", alert.rowid, alert, alert.name) )
+                .collect::<Vec<String>>()
+                .join("\n")
+        };
+    cmdstruct.push_msg(&msg).send_msg().await?;
+    Ok(Outcome::Done)
 }
 
 
@@ -1820,355 +2510,126 @@ struct ExQuote<'a> { // Local Exchange Quote Structure
     cmdstruct: &'a mut CmdStruct,
     id: i64,
     thing: String,
-    qty: f64,
-    price: f64,
+    qty: Qty,             // Positive bid, negative ask, zero cancel
+    price: Option<Money>, // Cancels without a price remove every order
     ticker: String,
     now: i64,
 }
 
 impl<'a> ExQuote<'a> {
-    fn scan (cmdstruct: &'a mut CmdStruct) -> Bresult<Option<ExQuote<'a>>> {
-         //                         ____ticker____  _____________qty____________________  $@  ___________price______________
-        let caps = regex_to_vec(r"^(@[A-Za-z^.-_]+)([+-]([0-9]+[.]?|[0-9]*[.][0-9]{1,4}))[$@]([0-9]+[.]?|[0-9]*[.][0-9]{1,2})$", &cmdstruct.message)?;
-        if caps.is_empty() { return Ok(None) }
-
+    // From the captures of an EXCHANGE_TRIGGER match
+    fn scan (cmdstruct: &'a mut CmdStruct, caps: Vec<Option<String>>) -> Bresult<ExQuote<'a>> {
         let thing = caps.as_string(1)?;
         let ticker = {
             let dbconn = &getenvstruct!(cmdstruct).dbconn;
             deref_ticker(dbconn, &thing)?
         };
-        let qty   = roundqty(caps.as_f64(2)?);
-        let price = caps.as_f64(4)?;
+        let (qty, price) =
+            if caps.as_str(2).is_ok() {
+                (caps.as_str(2)?.parse::<Qty>()?, Some(caps.as_str(4)?.parse::<Money>()?))
+            } else {
+                (Qty::ZERO, caps.as_str(5).ok().map( str::parse::<Money> ).transpose()?)
+            };
         let now   = Instant::now().seconds();
         let id    = cmdstruct.id;
-        Ok(Some(ExQuote {cmdstruct, id, thing, qty, price, ticker, now } ))
+        Ok(ExQuote {cmdstruct, id, thing, qty, price, ticker, now } )
     }
 }
 
 #[derive(Debug)]
-struct QuoteCancelMine<'a> {
-    qty: f64,
-    myasks: Vec<HashMap<String, ::sqlite::Value>>,
-    myasksqty: f64,
-    mybids: Vec<HashMap<String, ::sqlite::Value>>,
+struct ExchangeExecute<'a> {
     exquote: ExQuote<'a>,
+    execution: Execution,
     msg: String
 }
 
-impl<'a> QuoteCancelMine<'a> {
-    fn doit (exquote :ExQuote<'a>) -> Bresult<QuoteCancelMine<'a>> {
+impl<'a> ExchangeExecute<'a> {
+    fn fmt_order (thing: &str, side: Side, order: &Order) -> String {
+        format!("`{}{:+}@{}`", thing, IF!(side == Side::Ask, -order.qty, order.qty), order.price)
+    }
+
+    async fn doit (exquote: ExQuote<'a>) -> Bresult<ExchangeExecute<'a>> {
         let id = exquote.id;
-        let ticker = &exquote.ticker;
-        let mut qty = exquote.qty;
-        let price = exquote.price;
+        let ticker = exquote.ticker.to_string();
+        let mut execution = Execution::default();
         let mut msg = String::new();
 
-        let (myasks, myasksqty, mybids) = {
-            let cmdstruct = &exquote.cmdstruct;
-            let dbconn = & getenvstruct!(cmdstruct).dbconn;
-
-            let mut myasks = getsql!(dbconn, "SELECT * FROM exchange WHERE id=? AND ticker=? AND qty<0.0 ORDER BY price", id, &**ticker)?;
-            let myasksqty = -roundqty(myasks.iter().map( |ask| ask.get_f64("qty").unwrap() ).sum::<f64>());
-            let mut mybids = getsql!(dbconn, "SELECT * FROM exchange WHERE id=? AND ticker=? AND 0.0<=qty ORDER BY price DESC", id, &**ticker)?;
-
-            if qty < 0.0 { // This is an ask exquote
-                // Remove/decrement a matching bid in the current settled market
-                if let Some(mybid) = mybids.iter_mut().find( |b| price == b.get_f64("price").unwrap() ) {
-                    let bidqty = roundqty(mybid.get_f64("qty")?);
-                    if bidqty <= -qty {
-                        getsql!(dbconn, "DELETE FROM exchange WHERE id=? AND ticker=? AND qty=? AND price=?", id, &**ticker, bidqty, price)?;
-                        mybid.insert("*UPDATED*".into(), ::sqlite::Value::String("*REMOVED*".into()));
-                        qty = roundqty(qty + bidqty);
-                        msg += &format!("\n*Removed bid:* `{}+{}@{}`", exquote.thing, bidqty, price);
-                    } else {
-                        let newbidqty = roundqty(bidqty+qty);
-                        getsql!(dbconn, "UPDATE exchange SET qty=? WHERE id=? AND ticker=? AND qty=? AND price=?", newbidqty, id, &**ticker, bidqty, price)?;
-                        mybid.insert("*UPDATED*".into(), ::sqlite::Value::String(format!("*QTY={:.}*", newbidqty)));
-                        qty = 0.0;
-                        msg += &format!("\n*Updated bid:* `{}+{}@{}` -> `{}@{}`", exquote.thing, bidqty, price, newbidqty, price);
-                    }
-                }
-            } else if 0.0 < qty { // This is a bid exquote
-                // Remove/decrement a matching ask in the current settled market
-                if let Some(myask) = myasks.iter_mut().find( |b| price == b.get_f64("price").unwrap() ) {
-                    let askqty = roundqty(myask.get_f64("qty")?);
-                    if -askqty <= qty {
-                        getsql!(dbconn, "DELETE FROM exchange WHERE id=? AND ticker=? AND qty=? AND price=?", id, &**ticker, askqty, price)?;
-                        myask.insert("*UPDATED*".into(), ::sqlite::Value::String("*REMOVED*".into()));
-                        qty = roundqty(qty + askqty);
-                        msg += &format!("\n*Removed ask:* `{}{}@{}`", exquote.thing, askqty, price);
-                    } else {
-                        let newaskqty = roundqty(askqty+qty);
-                        getsql!(dbconn, "UPDATE exchange SET qty=? WHERE id=? AND ticker=? AND qty=? AND price=?", newaskqty, id, &**ticker, askqty, price)?;
-                        myask.insert("*UPDATED*".into(), ::sqlite::Value::String(format!("*QTY={:.}*", newaskqty)));
-                        qty = 0.0;
-                        msg += &format!("\n*Updated ask:* `{}{}@{}` -> `{}@{}`", exquote.thing, askqty, price, newaskqty, price);
-                    }
+        if exquote.qty.is_zero() { // Cancel my orders
+            let cancel_price = exquote.price;
+            let cancelled = getenvstruct!(exquote.cmdstruct).transaction( |envstruct| {
+                let book = envstruct.book(&ticker)?;
+                let cancelled = (book.cancel(id, Some(Side::Bid), cancel_price), book.cancel(id, Some(Side::Ask), cancel_price));
+                envstruct.book_save(&ticker)?;
+                Ok(cancelled)
+            })?;
+            for (side, orders) in vec![(Side::Bid, cancelled.0), (Side::Ask, cancelled.1)] {
+                for order in orders {
+                    msg += &format!("\n*Removed {}:* {}", IF!(side == Side::Bid, "bid", "ask"), Self::fmt_order(&exquote.thing, side, &order));
+                    execution.cancelled.push(order);
                 }
             }
-            (myasks, myasksqty, mybids)
-        };
-
-        Ok(Self{qty, myasks, myasksqty, mybids, exquote, msg})
-    }
-}
+            if msg.is_empty() { msg += "\nNo orders to remove." }
+            return Ok(Self{exquote, execution, msg})
+        }
 
-#[derive(Debug)]
-struct QuoteExecute<'a> {
-    qty: f64,
-    bids: Vec<HashMap<String, ::sqlite::Value>>,
-    quotecancelmine: QuoteCancelMine<'a>,
-    msg: String
-}
+        let side = IF!(exquote.qty.is_negative(), Side::Ask, Side::Bid);
+        let qty = exquote.qty.abs();
+        let price = exquote.price.ok_or("exchange order missing price")?;
+        let position = Position::query_position(&exquote.cmdstruct, id, &ticker).await?;
 
-impl<'a> QuoteExecute<'a> {
-    async fn doit (mut obj: QuoteCancelMine<'a>) -> Bresult<QuoteExecute<'a>> {
-        let (qty, bids, msg) = {
-            let id = obj.exquote.id;
-            let ticker = &obj.exquote.ticker;
-            let price = obj.exquote.price;
-            let mut qty = obj.qty;
-            let now = obj.exquote.now;
-            let myasks = &mut obj.myasks;
-            let mybids = &mut obj.mybids;
-            let asksqty = obj.myasksqty;
-
-            let (mut bids, mut asks) = {
-                let dbconn = &getenvstruct!(obj.exquote.cmdstruct).dbconn;
-                // Other bids / asks (not mine) global since it's being updated and returned for logging
-                (
-                    getsql!(dbconn,
-                        "SELECT * FROM exchange WHERE id!=? AND ticker=? AND 0.0<qty ORDER BY price DESC",
-                        id, &**ticker)?,
-                    getsql!(dbconn,
-                        "SELECT * FROM exchange WHERE id!=? AND ticker=? AND qty<0.0 ORDER BY price",
-                        id, &**ticker)?
-                )
+        {
+            let envstruct = getenvstruct!(exquote.cmdstruct);
+            let (open_asks, open_bids_value) = {
+                let book = envstruct.book(&ticker)?;
+                (book.open_qty(id, Side::Ask), book.open_bid_value(id))
             };
-
-            let mut msg = obj.msg.to_string();
-            let position = Position::query_position( &obj.exquote.cmdstruct, id, ticker).await?;
-            let posqty = position.qty;
-            let posprice = position.price;
-
-            if qty < 0.0 { // This is an ask exquote
-                if posqty < -qty + asksqty { // does it exceed my current ask qty?
-                    msg += "\nYou lack that available quantity to sell.";
-                } else {
-                    for abid in bids.iter_mut().filter( |b| price <= b.get_f64("price").unwrap() ) {
-                        let aid = abid.get_i64("id")?;
-                        let aqty = roundqty(abid.get_f64("qty")?);
-                        let aprice = abid.get_f64("price")?;
-                        let xqty = aqty.min(-qty); // quantity exchanged is the smaller of the two (could be equal)
-
-                        let envstruct = getenvstruct!(obj.exquote.cmdstruct);
-
-                        {
-                            let dbconn = &envstruct.dbconn;
-                            if xqty == aqty { // bid to be entirely settled
-                                getsql!(dbconn, "DELETE FROM exchange WHERE id=? AND ticker=? AND qty=? AND price=?", aid, &**ticker, aqty, aprice)?;
-                                abid.insert("*UPDATED*".into(), sqlite::Value::String("*REMOVED*".into()));
-                            } else {
-                                getsql!(dbconn, "UPDATE exchange set qty=? WHERE id=? AND ticker=? AND qty=? AND price=?", aqty-xqty, aid, &**ticker, aqty, aprice)?;
-                                abid.insert("*UPDATED*".into(), sqlite::Value::String(format!("*qty={}*", aqty-xqty)));
-                            }
-
-                            // Update order table with the purchase and buy info
-                            sql_table_order_insert(dbconn, id, ticker, -xqty, aprice, now)?;
-                            sql_table_order_insert(dbconn, aid, ticker, xqty, aprice, now)?;
-                        }
-
-                        // Update each user's account
-                        let value = xqty * aprice;
-                        envstruct.entity_balance_inc(id, value)?;
-                        envstruct.entity_balance_inc(aid, -value)?;
-
-                        let name = envstruct.entity_id2name(id);
-                        let namea = envstruct.entity_id2name(aid);
-                        msg += &format!("\n*Settled:*\n{} `{}{:+}@{}` <-> `${}` {}",
-                                name.unwrap_or(&id.to_string()),
-                                obj.exquote.thing, xqty, aprice,
-                                value,
-                                namea.unwrap_or(&aid.to_string()));
-
-                        // Update my position
-                        let newposqty = roundqty(posqty - xqty);
-                        let dbconn = &envstruct.dbconn;
-                        if 0.0 == newposqty {
-                            getsql!(dbconn, "DELETE FROM positions WHERE id=? AND ticker=? AND qty=?", id, &**ticker, posqty)?;
-                        } else {
-                            getsql!(dbconn, "UPDATE positions SET qty=? WHERE id=? AND ticker=? AND qty=?", newposqty, id, &**ticker, posqty)?;
-                        }
-
-                        // Update buyer's position
-    /**/                let aposition = Position::query_position( &obj.exquote.cmdstruct, aid, ticker).await?;
-                        let aposqty = aposition.qty;
-                        let aposprice = aposition.price;
-
-                        if 0.0 == aposqty {
-                            getsql!(dbconn, "INSERT INTO positions values(?, ?, ?, ?)", aid, &**ticker, xqty, value)?;
-                        } else {
-                            let newaposqty = roundqty(aposqty+aqty);
-                            let newaposcost = (aposprice + value) / (aposqty + xqty);
-                            getsql!(dbconn, "UPDATE positions SET qty=?, price=? WHERE id=? AND ticker=? AND qty=?", newaposqty, newaposcost, aid, &**ticker, aposqty)?;
-                        }
-
-                        // Update stonk exquote value
-    /**/                let last_price = Quote::get_market_quote(obj.exquote.cmdstruct, ticker).await?.price;
-                        getsql!(dbconn, "UPDATE stonks SET price=?, last=?, time=? WHERE ticker=?", aprice, last_price, now, &**ticker)?;
-
-                        qty = roundqty(qty+xqty);
-                        if 0.0 <= qty { break }
-                    } // for
-
-                    // create or increment in exchange table my ask.  This could also be the case if no bids were executed.
-                    if qty < 0.0 {
-                        let dbconn = &getenvstruct!(obj.exquote.cmdstruct).dbconn;
-                        if let Some(myask) = myasks.iter_mut().find( |a| price == a.get_f64("price").unwrap() ) {
-                            let oldqty = myask.get_f64("qty").unwrap();
-                            let newqty = roundqty(oldqty + qty); // both negative, so "increased" ask qty
-                            let mytime = myask.get_i64("time").unwrap();
-                            getsql!(dbconn, "UPDATE exchange set qty=? WHERE id=? AND ticker=? AND price=? AND time=?", newqty, id, &**ticker, price, mytime)?;
-                            myask.insert("*UPDATED*".into(), ::sqlite::Value::String(format!("qty={}", newqty)));
-                            msg += &format!("\n*Updated ask:* `{}{}@{}` -> `{}@{}`", obj.exquote.thing, oldqty, price, newqty, price);
-                        } else {
-                            getsql!(dbconn, "INSERT INTO exchange VALUES (?, ?, ?, ?, ?)", id, &**ticker, &*format!("{:.4}", qty), &*format!("{:.4}", price), now)?;
-                            // Add a fake entry to local myasks vector for logging's sake
-                            let mut hm = HashMap::<String, ::sqlite::Value>::new();
-                            hm.insert("*UPDATED*".to_string(), ::sqlite::Value::String(format!("*INSERTED* {} {} {} {} {}", id, ticker, qty, price, now)));
-                            myasks.push(hm);
-                            msg += &format!("\n*Created ask:* `{}{}@{}`", obj.exquote.thing, qty, price);
-                        }
+            if side == Side::Ask && position.qty < qty + open_asks {
+                msg += "\nYou lack that available quantity to sell.";
+            } else if side == Side::Bid && envstruct.entity_balance(id)? < open_bids_value + qty * price {
+                msg += "\nAvailable cash lacking for this bid.";
+            } else {
+                let now = exquote.now;
+                let origin = exquote.cmdstruct.origin();
+                execution = envstruct.transaction( |envstruct| {
+                    let execution = envstruct.book(&ticker)?.submit(id, side, qty, price, now);
+                    for fill in &execution.fills {
+                        exchange_fill_settle(envstruct, origin, &ticker, fill)?;
                     }
-                }
-            } else if 0.0 < qty { // This is a bid exquote (want to buy someone)
-                // Limited by available cash but that's up to the current best ask price and sum of ask costs.
-                let basis = qty * price;
-                let bank_balance = getenvstruct!(obj.exquote.cmdstruct).entity_balance(obj.exquote.id)?;
-                let mybidsprice =
-                    mybids.iter().map( |bid|
-                        bid.get_f64("qty").unwrap()
-                        * bid.get_f64("price").unwrap() )
-                    .sum::<f64>();
-
-                if bank_balance < mybidsprice + basis { // Verify not over spending as this order could become a bid
-                    msg += "Available cash lacking for this bid.";
-                } else {
-                    for aask in asks.iter_mut().filter( |a| a.get_f64("price").unwrap() <= price ) {
-                        //error!("{:?}", mybid);
-                        let aid = aask.get_i64("id")?;
-                        let aqty = roundqty(aask.get_f64("qty")?); // This is a negative value
-                        let aprice = aask.get_f64("price")?;
-                        //error!("my ask qty {}  asksqty sum {}  bid qty {}", qty, asksqty, aqty);
-
-
-                        let xqty = roundqty(qty.min(-aqty)); // quantity exchanged is the smaller of the two (could be equal)
-
-                        {
-                            let envstruct = getenvstruct!(obj.exquote.cmdstruct);
-
-                            {
-                                let dbconn = &envstruct.dbconn;
-
-                                if xqty == -aqty { // ask to be entirely settled
-                                    getsql!(dbconn, "DELETE FROM exchange WHERE id=? AND ticker=? AND qty=? AND price=?", aid, &**ticker, aqty, aprice)?;
-                                    aask.insert("*UPDATED*".into(), ::sqlite::Value::String("*REMOVED*".into()));
-                                } else {
-                                    let newqty = aqty+xqty;
-                                    getsql!(dbconn, "UPDATE exchange set qty=? WHERE id=? AND ticker=? AND qty=? AND price=?", newqty, aid, &**ticker, aqty, aprice)?;
-                                    aask.insert("*UPDATED*".into(), ::sqlite::Value::String(format!("*qty={}*", newqty)));
-                                }
-
-                                //error!("new order {} {} {} {} {}", id, ticker, aqty, price, now);
-                                // Update order table with the purchase and buy info
-                                sql_table_order_insert(dbconn, id, ticker, xqty, aprice, now)?;
-                                sql_table_order_insert(dbconn, aid, ticker, -xqty, aprice, now)?;
-                            }
-
-                            // Update each user's account
-                            let value = xqty * aprice;
-                            envstruct.entity_balance_inc(id, -value)?;
-                            envstruct.entity_balance_inc(aid, value)?;
-
-                            msg += &format!("\n*Settled:*\n{} `${}` <-> `{}{:+}@{}` {}",
-                                    envstruct.entity_id2name(id).unwrap_or(&id.to_string()),
-                                    value,
-                                    obj.exquote.thing, xqty, aprice,
-                                    envstruct.entity_id2name(aid).unwrap_or(&aid.to_string()));
-
-                            // Update my position
-                            let dbconn = &envstruct.dbconn;
-                            if 0.0 == posqty {
-                                getsql!(dbconn, "INSERT INTO positions values(?, ?, ?, ?)", id, &**ticker, xqty, value)?;
-                            } else {
-                                let newposqty = roundqty(posqty+xqty);
-                                let newposcost = (posprice + value) / (posqty + xqty);
-                                getsql!(dbconn, "UPDATE positions SET qty=?, price=? WHERE id=? AND ticker=? AND qty=?",
-                                    newposqty, newposcost, id, &**ticker, posqty)?;
-                            }
-                        }
-
-                        {
-                            // Update their position
-        /**/                let aposition = Position::query_position( &obj.exquote.cmdstruct, aid, ticker).await?;
-                            let aposqty = aposition.qty;
-                            let newaposqty = roundqty(aposqty - xqty);
-                            let dbconn = &getenvstruct!(obj.exquote.cmdstruct).dbconn;
-                            if 0.0 == newaposqty {
-                                getsql!(dbconn, "DELETE FROM positions WHERE id=? AND ticker=? AND qty=?", aid, &**ticker, aposqty)?;
-                            } else {
-                                getsql!(dbconn, "UPDATE positions SET qty=? WHERE id=? AND ticker=? AND qty=?", newaposqty, aid, &**ticker, aposqty)?;
-                            }
-                        }
-
-                        // Update self-stonk exquote value
-                        let last_price = Quote::get_market_quote(obj.exquote.cmdstruct, ticker).await?.price;
-                        let dbconn = &getenvstruct!(obj.exquote.cmdstruct).dbconn;
-                        getsql!(dbconn, "UPDATE stonks SET price=?, last=?, time=? WHERE ticker=?",
-                            aprice, last_price, now, &**ticker)?;
-
-                        qty = roundqty(qty-xqty);
-                        if qty <= 0.0 { break }
-                    } // for
-
-                    // create or increment in exchange table my bid.  This could also be the case if no asks were executed.
-                    if 0.0 < qty{
-                        let dbconn = &getenvstruct!(obj.exquote.cmdstruct).dbconn;
-                        if let Some(mybid) = mybids.iter_mut().find( |b| price == b.get_f64("price").unwrap() ) {
-                            let oldqty = mybid.get_f64("qty").unwrap();
-                            let newqty = roundqty(oldqty + qty);
-                            let mytime = mybid.get_i64("time").unwrap();
-                            getsql!(dbconn, "UPDATE exchange set qty=? WHERE id=? AND ticker=? AND price=? AND time=?", newqty, id, &**ticker, price, mytime)?;
-                            mybid.insert("*UPDATED*".into(), ::sqlite::Value::String(format!("qty={}", newqty)));
-                            msg += &format!("\n*Updated bid:* `{}+{}@{}` -> `{}@{}`", obj.exquote.thing, oldqty, price, newqty, price);
-                        } else {
-                            getsql!(dbconn, "INSERT INTO exchange VALUES (?, ?, ?, ?, ?)", id, &**ticker, &*format!("{:.4}",qty), &*format!("{:.4}",price), now)?;
-                            // Add a fake entry to local mybids vector for logging's sake
-                            let mut hm = HashMap::<String, ::sqlite::Value>::new();
-                            hm.insert("*UPDATED*".to_string(), ::sqlite::Value::String(format!("*INSERTED* {} {} {} {} {}", id, ticker, qty, price, now)));
-                            mybids.push(hm);
-                            msg += &format!("\n*Created bid:* `{}+{}@{}`", obj.exquote.thing, qty, price);
-                        }
+                    if let Some(fill) = execution.fills.last() {
+                        stonk_trade_price_set(&envstruct.dbconn, &ticker, fill.price, now)?;
                     }
-                } // else
-            } // if
-            (qty, bids, msg)
-        };
-        Ok(Self{qty, bids, quotecancelmine: obj, msg})
+                    envstruct.book_save(&ticker)?;
+                    Ok(execution)
+                })?;
+                let contra = IF!(side == Side::Bid, Side::Ask, Side::Bid);
+                for order in &execution.cancelled {
+                    msg += &format!("\n*Removed {}:* {}", IF!(contra == Side::Bid, "bid", "ask"), Self::fmt_order(&exquote.thing, contra, order));
+                }
+                for fill in &execution.fills {
+                    msg += &format!("\n*Settled:*\n{} `${}` <-> `{}{:+}@{}` {}",
+                        envstruct.entity_id2name(fill.buyer).unwrap_or(&fill.buyer.to_string()),
+                        (fill.qty * fill.price).cents(),
+                        exquote.thing, fill.qty, fill.price,
+                        envstruct.entity_id2name(fill.seller).unwrap_or(&fill.seller.to_string()));
+                }
+                if let Some(order) = &execution.rested {
+                    msg += &format!("\n*Created {}:* {}", IF!(side == Side::Bid, "bid", "ask"), Self::fmt_order(&exquote.thing, side, order));
+                }
+            }
+        }
+        Ok(Self{exquote, execution, msg})
     }
 }
 
-async fn do_exchange_bidask (cmdstruct :&mut CmdStruct) -> Bresult<&'static str> {
-
-    let exquote = ExQuote::scan(cmdstruct)?;
-    let exquote = if exquote.is_none() { return Ok("SKIP") } else { exquote.unwrap() };
+//                                  ____ticker____     _____________qty____________________  $@  ___________price______________        ~  $@  ___________price______________
+const EXCHANGE_TRIGGER :&str = r"^(@[A-Za-z^.-_]+)(?:([+-]([0-9]+[.]?|[0-9]*[.][0-9]{1,4}))[$@]([0-9]+[.]?|[0-9]*[.][0-9]{1,2})|~(?:[$@]([0-9]+[.]?|[0-9]*[.][0-9]{1,2}))?)$";
 
-    let ret =
-        QuoteCancelMine::doit(exquote)
-        .map(|obj| QuoteExecute::doit(obj) )?.await?;
-    info!("\x1b[1;31mResult {:#?}", ret);
+async fn do_exchange_bidask (cmdstruct :&mut CmdStruct, caps:Vec<Option<String>>) -> Bresult<Outcome> {
+    let exquote = ExQuote::scan(cmdstruct, caps)?;
+    let ret = ExchangeExecute::doit(exquote).await?;
+    info!("\x1b[1;31mResult {:#?}", ret.execution);
     if 0 != ret.msg.len() {
-        ret.quotecancelmine.exquote.cmdstruct
+        ret.exquote.cmdstruct
             .markdown()
             .push_msg(
                 &ret.msg
@@ -2177,33 +2638,40 @@ async fn do_exchange_bidask (cmdstruct :&mut CmdStruct) -> Bresult<&'static str>
             .send_msg()
             .await?;
     } // Report to group
-    Ok("COMPLETED.")
+    Ok(Outcome::Done)
 }
 
 
-async fn do_orders (cmdstruct: &mut CmdStruct) -> Bresult<&'static str> {
+async fn do_orders (cmdstruct: &mut CmdStruct) -> Bresult<Outcome> {
     let id = cmdstruct.id;
     let mut asks = String::from("");
     let mut bids = String::from("");
+    let mut limits = String::from("");
     let rows = {
         let envstruct = getenvstruct!(cmdstruct);
         let dbconn = &envstruct.dbconn;
-        if Regex::new(r"(?i)/orders").unwrap().find(&cmdstruct.message).is_none() { return Ok("SKIP"); }
         for order in getsql!(dbconn, "SELECT * FROM exchange WHERE id=?", id)? {
             let ticker = order.get_string("ticker")?;
             let stonk = reference_ticker(envstruct, &ticker).replacen("_", "\\_", 10000);
-            let qty = order.get_f64("qty")?;
-            let price = order.get_f64("price")?;
-            if qty < 0.0 {
+            let qty = order.get_qty("qty")?;
+            let price = order.get_money("price")?;
+            if qty.is_negative() {
                 asks += &format!("\n{}{:+}@{}", stonk, qty, price);
             } else {
                 bids += &format!("\n{}{:+}@{}", stonk, qty, price);
             }
         }
+        for order in getsql!(dbconn, "SELECT * FROM limits WHERE id=? ORDER BY ticker, price", id)? {
+            limits += &format!("\n{}{:+}{}{}",
+                order.get_string("ticker")?.replacen("_", "\\_", 10000),
+                order.get_qty("qty")?,
+                order.get_string("kind")?,
+                order.get_money("price")?);
+        }
 
         // Include all self-stonks positions (mine and others)
         let sql = format!(r#"
-            SELECT id||'' AS ticker, 0.0 AS qty, 0.0 AS price
+            SELECT id||'' AS ticker, 0 AS qty, 0 AS price
             FROM entitys
             WHERE 0<id AND id NOT IN (SELECT ticker FROM positions WHERE id={})
         UNION
@@ -2214,27 +2682,27 @@ async fn do_orders (cmdstruct: &mut CmdStruct) -> Bresult<&'static str> {
     };
 
     let mut msg = String::new();
-    let mut total = 0.0;
+    let mut total = Money::ZERO;
     for pos in rows {
         if is_self_stonk(&pos.get_string("ticker")?) {
             let mut pos = {
                 Position {
                     ticker: pos.get_string("ticker")?,
-                    qty: pos.get_f64("qty")?,
-                    price: pos.get_f64("price")?,
+                    qty: pos.get_qty("qty")?,
+                    price: pos.get_money("price")?,
                     quote: None
                 }
             };
             pos.update_quote(&cmdstruct).await?;
-            msg += &pos.format_position(getenvstruct!(cmdstruct), id)?;
+            msg += &pos.format_position(getenvstruct!(cmdstruct), id, cmdstruct.now)?;
             total += pos.qty * pos.quote.unwrap().price;
         }
     }
 
     let cash = getenvstruct!(cmdstruct).entity_balance(id)?;
     msg += &format!("\n`{:7.2}``Cash`    `YOLO``{:.2}`",
-        roundcents(cash),
-        roundcents(total+cash));
+        cash,
+        total+cash);
 
     if 0 < msg.len() { msg += "\n" }
     if bids.len() == 0 && asks.len() == 0 {
@@ -2254,9 +2722,13 @@ async fn do_orders (cmdstruct: &mut CmdStruct) -> Bresult<&'static str> {
             msg += "*BIDS* none";
         }
     }
+    if limits.len() != 0 {
+        msg += "\n*LIMITS/STOPS:*";
+        msg += &limits;
+    }
 
     cmdstruct.markdown().push_msg(&msg).send_msg().await?;
-    Ok("COMPLETED.")
+    Ok(Outcome::Done)
 }
 
 async fn send_format_strings_help (cmdstruct: &mut CmdStruct) -> Bresult<()> {
@@ -2295,14 +2767,18 @@ const FORMAT_STRINGS_HELP: &str =
 `%K` `Day Gain`
 `%L` `Day Arrow`
 `%M` `Day Gain %`
+`%N` `Realized Gain`
+`%O` `Realized Color`
+`%P` `Short Borrow Fees`
+`%Q` `Short Borrow Rate %`
+`%R` `Option Kind Strike/Expiry`
+`%S` `Option Strike`
+`%T` `Option Days to Expiry`
 `%[%nbiusq]` `% newline bold italics underline strikeout quote`
 ";
 
-async fn do_fmt (cmdstruct :&mut CmdStruct) -> Bresult<&'static str> {
-
-    let caps = regex_to_vec(r"^/fmt( ([qp?])[ ]?(.*)?)?$", &cmdstruct.message)?;
+async fn do_fmt (cmdstruct :&mut CmdStruct, caps:Vec<Option<String>>) -> Bresult<Outcome> {
     //caps.iter().for_each( |c| println!("\x1b[1;35m{:?}", c));
-    if caps.is_empty() { return Ok("SKIP"); }
 
     let id = cmdstruct.id;
 
@@ -2318,7 +2794,7 @@ async fn do_fmt (cmdstruct :&mut CmdStruct) -> Bresult<&'static str> {
                 IF!(fmt_quote==FORMAT_STRING_QUOTE, "*", ""), fmt_quote,
                 IF!(fmt_position==FORMAT_STRING_POSITION, "*", ""), fmt_position))
             .send_msg().await
-            .and(Ok("COMPLETED."))
+            .and(Ok(Outcome::Done))
     }
 
     // "/fmt [qp?] [*]" show current format strings
@@ -2333,7 +2809,7 @@ async fn do_fmt (cmdstruct :&mut CmdStruct) -> Bresult<&'static str> {
             getenvstruct!(cmdstruct).entitys.get_mut(&id).unwrap().position = new_format_str.to_string();
             "position"
         },
-          _ => return send_format_strings_help(cmdstruct).await.and(Ok("COMPLETED."))
+          _ => return send_format_strings_help(cmdstruct).await.and(Ok(Outcome::Done))
     };
 
     // notify user the change
@@ -2352,18 +2828,15 @@ async fn do_fmt (cmdstruct :&mut CmdStruct) -> Bresult<&'static str> {
     getsql!(dbconn, format!("UPDATE formats SET {}=? WHERE id=?", format_type),
         &*new_format_str.replacen("\"", "\"\"", 10000), id)?;
 
-    Ok("COMPLETED.")
+    Ok(Outcome::Done)
 }
 
 
-async fn do_rebalance (cmdstruct: &mut CmdStruct) -> Bresult<&'static str> {
-
-    let caps = regex_to_vec(
-        //             _______float to 2 decimal places______                                    ____________float_____________
-        r"(?i)^/rebalance( (-?[0-9]+[.]?|(-?[0-9]*[.][0-9]{1,2})))?(( [@^]?[A-Z_a-z][-.0-9=A-Z_a-z]* (([0-9]*[.][0-9]+)|([0-9]+[.]?)))+)",
-        &cmdstruct.message)?;
-    if caps.is_empty() { return Ok("SKIP") }
+const REBALANCE_TRIGGER :&str =
+    //             _______float to 2 decimal places______                                    ____________float_____________
+    r"(?i)^/rebalance( (-?[0-9]+[.]?|(-?[0-9]*[.][0-9]{1,2})))?(( [@^]?[A-Z_a-z][-.0-9=A-Z_a-z]* (([0-9]*[.][0-9]+)|([0-9]+[.]?)))+)";
 
+async fn do_rebalance (cmdstruct: &mut CmdStruct, caps:Vec<Option<String>>) -> Bresult<Outcome> {
 
     cmdstruct
         .markdown()
@@ -2379,23 +2852,29 @@ async fn do_rebalance (cmdstruct: &mut CmdStruct) -> Bresult<&'static str> {
         .collect::<HashMap<String, f64>>();
 
 
-    if { // Check/exit if after hours rebalancing
+    let closed = { // Check/exit if after hours rebalancing on any exchange
         let envstruct = &getenvstruct!(cmdstruct);
-        !trading_hours_p(envstruct.dst_hours_adjust, cmdstruct.now)?
-        &&
-        getsql!(
+        let mut closed = Vec::new();
+        for row in getsql!(
             envstruct.dbconn,
-            format!("SELECT hours FROM stonks WHERE hours!=24 AND ticker IN ('{}')",
-                percents.keys().map(String::to_string).collect::<Vec<String>>().join("','")))?
-        .len() != 0
-    } {
-        cmdstruct.push_msg(&"\nRebalance During Trading Hours Mon..Fri 1AM..5PM").edit_msg().await?;
-        return Ok("COMPLETED.");
+            format!("SELECT DISTINCT exchange FROM stonks WHERE hours!=24 AND ticker IN ('{}')",
+                percents.keys().map(String::to_string).collect::<Vec<String>>().join("','")))? {
+            let exchange = exchange_hours(&row.get_string("exchange")?);
+            if !exchange.trading_p(cmdstruct.now)? && !closed.contains(&exchange.name) { closed.push(exchange.name) }
+        }
+        closed
+    };
+    if !closed.is_empty() {
+        cmdstruct.push_msg(&format!("\nRebalance During {} Trading Hours", closed.join(" and "))).edit_msg().await?;
+        return Ok(Outcome::Done);
     }
 
-    for ticker in percents.keys() { // Refresh stonk quotes
+    let quotes = Quote::get_market_quotes(cmdstruct, // Refresh stonk quotes
+        &percents.keys().filter( |ticker| !is_self_stonk(ticker) ).cloned().collect::<Vec<String>>()).await?;
+    for ticker in percents.keys() {
         if !is_self_stonk(&ticker) {
-            if Quote::get_market_quote(cmdstruct, &ticker).await.map_err( |e|warn!("ticker {} invalid {}", ticker, e) ).is_err() {
+            if !quotes.contains_key(ticker) {
+                warn!("ticker {} invalid", ticker);
                 cmdstruct.push_msg(&format!(" ~{}~", ticker)).edit_msg().await?;
             } else {
                 // Update feedback message with ticker symbol
@@ -2411,7 +2890,7 @@ async fn do_rebalance (cmdstruct: &mut CmdStruct) -> Bresult<&'static str> {
                 positions.ticker,
                 positions.qty,
                 stonks.price,
-                positions.qty*stonks.price AS value
+                positions.qty*stonks.price/1e6 AS value
             FROM positions
             LEFT JOIN stonks ON positions.ticker=stonks.ticker
             WHERE id={} AND positions.ticker IN ('{}')",
@@ -2421,8 +2900,8 @@ async fn do_rebalance (cmdstruct: &mut CmdStruct) -> Bresult<&'static str> {
     };
 
     // Sum the optional offset amount and stonk values
-    let mut total :f64 = caps.as_f64(2).unwrap_or(0.0);
-    positions.iter().for_each(|hm| total += hm.get_f64("value").unwrap());
+    let mut total = match caps.as_str(2) { Ok(offset) => offset.parse::<Money>()?, Err(_) => Money::ZERO };
+    for hm in &positions { total += hm.get_money("value")? }
     info!("rebalance total {}", total);
 
     if 0==positions.len() {
@@ -2430,9 +2909,8 @@ async fn do_rebalance (cmdstruct: &mut CmdStruct) -> Bresult<&'static str> {
     } else {
         for i in 0..positions.len() {
             let ticker = positions[i].get_string("ticker")?;
-            let value = positions[i].get_f64("value")?;
-            let mut diff = roundfloat(percents.get(&ticker).unwrap() * total - value, 2);
-            if -0.01 < diff && diff < 0.01 { diff = 0.0; } // under 1¢ diffs will be skipped
+            let value = positions[i].get_money("value")?;
+            let diff = (total.scale(*percents.get(&ticker).unwrap()) - value).cents(); // under 1¢ diffs will be skipped
             positions[i].insert("diff".to_string(), ::sqlite::Value::String(diff.to_string())); // Add new key/val to Position HashMap
         }
         for i in 0..positions.len() {
@@ -2444,9 +2922,8 @@ async fn do_rebalance (cmdstruct: &mut CmdStruct) -> Bresult<&'static str> {
                 if "0" == diffstr {
                     cmdstruct.push_msg(&format!("{} is balanced", ticker)).edit_msg().await?;
                 } else {
-                    let message = format!("{}-${}", ticker, &diffstr);
-                    cmdstruct.message = message;
-                    glogd!(" do_trade_sell =>", do_trade_sell(cmdstruct).await); // Recurse on same cmsstruct but mutated message
+                    let trade = Trade{ cmdstruct: &mut *cmdstruct, ticker: ticker.to_string(), action: '-', amt: Some(Amount::Dollars(diffstr.parse::<Money>()?)) };
+                    glogd!(" trade_sell =>", trade_sell(trade).await);
                 }
                 //cmdstruct.edit_msg().await?;
             }
@@ -2459,9 +2936,9 @@ async fn do_rebalance (cmdstruct: &mut CmdStruct) -> Bresult<&'static str> {
                 if diffstr != "0" {
                     let bp = cmdstruct.buying_power().await?;
                     // The last buy might be so off, so skip or adjust to account value
-                    if bp < diffstr.parse::<f64>()? {
-                        if 0.01 <= bp {
-                            diffstr = format!("{}", (bp*100.0) as i64 as f64 / 100.0);
+                    if bp < diffstr.parse::<Money>()? {
+                        if Money::CENT <= bp {
+                            diffstr = Money(bp.0 - bp.0 % Money::CENT.0).to_string();
                         } else {
                             diffstr = format!("0");
                         }
@@ -2472,70 +2949,73 @@ async fn do_rebalance (cmdstruct: &mut CmdStruct) -> Bresult<&'static str> {
                 if "0" == diffstr {
                     cmdstruct.push_msg(&format!("{} is balanced", ticker)).edit_msg().await?;
                 } else {
-                    let message = format!("{}+${}", ticker, &diffstr);
-                    //cmdstruct.push_msg(&format!("\n{}\n", message));
-                    cmdstruct.message = message;
-                    glogd!(" do_trade_buy =>", do_trade_buy(cmdstruct).await); // Recurse on same cmsstruct but mutated message
+                    let trade = Trade{ cmdstruct: &mut *cmdstruct, ticker: ticker.to_string(), action: '+', amt: Some(Amount::Dollars(diffstr.parse::<Money>()?)) };
+                    glogd!(" trade_buy =>", trade_buy(trade).await);
                 }
                 //cmdstruct.send_msg().await?;
             }
         }
     }
-    Ok("COMPLETED.")
-}
-
-async fn do_schedule (cmdstruct: &mut CmdStruct) -> Bresult<&'static str> {
-    let caps =
-        regex_to_vec(
-            r"(?sxi)^/schedule
-            (
-                (?: ### Fixed GMT
-                    \ +
-                    ( \d+ - \d{1,2} - \d{1,2} T )?
-                    ( \d{1,2} : \d{1,2} : \d{1,2} )
-                    (?: Z | [-+]0{1,4})
-                )?
-                (?: ### Duration
-                    \ + (-?  \d+ (?: h (?: \d+ m)? | m)? \d*)
-                )?
-                (?: ### days
-                    \ + ([*]|[mtwhfsu]+)
-                )?
-                #### Command
-                (?: \ + (.+))?
-            )",
-            &cmdstruct.message )?;
-    //caps.iter().for_each( |c| println!("\x1b[1;35m{:?}", c));
-    if caps.is_empty() { return Ok("SKIP") }
+    Ok(Outcome::Done)
+}
 
+#[derive(Debug, FromRow)]
+struct Schedule {
+    id:   i64,
+    at:   i64,
+    time: i64,    // Daily seconds after midnight, otherwise a one-time epoch
+    days: String, // Empty for one-time jobs
+    cmd:  String,
+    #[row(default)]
+    name: String  // Name of the at entity, when joined
+}
+
+// The /schedule or /unschedule trigger, both take the same arguments
+macro_rules! schedule_trigger {
+    ($cmd:literal) => { concat!(r"(?sxi)^/", $cmd, r"
+        (
+            (?: ### Fixed GMT
+                \ +
+                ( \d+ - \d{1,2} - \d{1,2} T )?
+                ( \d{1,2} : \d{1,2} : \d{1,2} )
+                (?: Z | [-+]0{1,4})
+            )?
+            (?: ### Duration
+                \ + (-?  \d+ (?: h (?: \d+ m)? | m)? \d*)
+            )?
+            (?: ### days
+                \ + ([*]|[mtwhfsu]+)
+            )?
+            #### Command
+            (?: \ + (.+))?
+        )") }
+}
+
+async fn do_schedule (cmdstruct: &mut CmdStruct, caps:Vec<Option<String>>, unschedule:bool) -> Bresult<Outcome> {
     cmdstruct.markdown();
 
     // "/schedule" Show all jobs
     if caps.as_str(1)?.is_empty() {
         let mut res = {
             let dbconn = &getenvstruct!(cmdstruct).dbconn;
-            getsql!(dbconn, "SELECT name, time, days, cmd FROM schedules LEFT JOIN entitys ON schedules.at = entitys.id WHERE schedules.id=?", cmdstruct.id)?
+            dbconn.query_as::<Schedule>("SELECT schedules.*, name FROM schedules LEFT JOIN entitys ON schedules.at = entitys.id WHERE schedules.id=?", &[&cmdstruct.id])?
         };
         if res.is_empty() {
             cmdstruct.push_msg("No Scheduled Jobs").send_msg().await?;
-            return Ok("COMPLETED.")
+            return Ok(Outcome::Done)
         }
-        res.sort_by( |a,b| a.get_i64("time").unwrap().cmp(&b.get_i64("time").unwrap()) );
+        res.sort_by_key( |job| job.time );
         let buff =
             "Scheduled Jobs:\n".to_string() +
             &res.iter()
-            .map( |row| {
-                let time = row.get_i64("time").unwrap();
+            .map( |job|
                 format!("`{}Z {}` `{}` `{}`",
-                    if time < 86400 { time2timestr } else { time2datetimestr }(time),
-                    row.get_string("days").unwrap(),
-                    row.get_string("name").unwrap(),
-                    row.get_string("cmd").unwrap())
-            } )
+                    if job.time < 86400 { time2timestr } else { time2datetimestr }(job.time),
+                    job.days, job.name, job.cmd) )
             .collect::<Vec<String>>()
             .join("\n");
         cmdstruct.push_msg(&buff).send_msg().await?;
-        return Ok("COMPLETED.")
+        return Ok(Outcome::Done)
     }
 
     let msg = {
@@ -2567,7 +3047,7 @@ async fn do_schedule (cmdstruct: &mut CmdStruct) -> Bresult<&'static str> {
                 now
             }
             + neg*(hours*60*60 + mins*60 + secs)
-            + 60 * 60 * envstruct.dst_hours_adjust as i64;
+            + 60 * 60 * dst_hours_adjust(now);
         //error!("n{} h{} m{} s{} {}", neg, hours, mins, secs, time);
 
         let daily = caps.as_str(5);
@@ -2584,8 +3064,8 @@ async fn do_schedule (cmdstruct: &mut CmdStruct) -> Bresult<&'static str> {
             + days.find("u").map_or(0, |_| 1)
         { days = ""; }
 
-        // Save job
-        if let Ok(command) = caps.as_str(6) {
+        match (unschedule, caps.as_str(6)) {
+            (false, Ok(command)) => { // Save job
                 info!("now:{} [id:{} at:{} time:{} days:{} cmd:{:?}]",
                     now, id, at, time, days, command);
                 glog!(getsql!(dbconn, "INSERT INTO schedules VALUES (?, ?, ?, ?, ?)",
@@ -2594,31 +3074,32 @@ async fn do_schedule (cmdstruct: &mut CmdStruct) -> Bresult<&'static str> {
                     if time < 86400 { time2timestr } else { time2datetimestr }(time),
                     days,
                     command)
-        } else { // Delete job
-            if getsql!(dbconn, "SELECT * FROM schedules WHERE time=? AND days=?", time, days)?.len()
-                != getsql!(dbconn, "DELETE FROM schedules WHERE time=? AND days=?", time, days)?.len()
-            {
-                format!("`{}Z` `{}` removed",
-                    if time < 86400 { time2timestr } else { time2datetimestr }(time),
-                    days)
-            } else {
-                format!("`{}Z` `{}` not found",
-                    if time < 86400 { time2timestr } else { time2datetimestr }(time),
-                    days)
+            },
+            (false, Err(_)) => "`Nothing to schedule, /unschedule removes jobs`".to_string(),
+            (true, _) => { // Delete job
+                if getsql!(dbconn, "SELECT * FROM schedules WHERE time=? AND days=?", time, days)?.len()
+                    != getsql!(dbconn, "DELETE FROM schedules WHERE time=? AND days=?", time, days)?.len()
+                {
+                    format!("`{}Z` `{}` removed",
+                        if time < 86400 { time2timestr } else { time2datetimestr }(time),
+                        days)
+                } else {
+                    format!("`{}Z` `{}` not found",
+                        if time < 86400 { time2timestr } else { time2datetimestr }(time),
+                        days)
+                }
             }
         }
     };
 
     cmdstruct.push_msg(&msg).send_msg().await?;
 
-    Ok("COMPLETED.")
+    Ok(Outcome::Done)
 }
 
-async fn do_rpn (cmdstruct: &mut CmdStruct) -> Bresult<&'static str> {
-    let expr = regex_to_vec(r"(?i)^=([-+*/0-9. ]+)", &cmdstruct.message)?;
-    if expr.is_empty() { return Ok("SKIP") }
+async fn do_rpn (cmdstruct: &mut CmdStruct, caps:Vec<Option<String>>) -> Bresult<Outcome> {
     let mut stack = Vec::new();
-    for toks in Regex::new(r" *((-?[0-9]*[.][0-9]+)|(-?[0-9]+[.]?))|([-+*/])")?.captures_iter(&expr.as_string(1)?) {
+    for toks in Regex::new(r" *((-?[0-9]*[.][0-9]+)|(-?[0-9]+[.]?))|([-+*/])")?.captures_iter(&caps.as_string(1)?) {
         if let Some(num) = toks.get(1) { // Push a number
             stack.push(num.as_str().parse::<f64>()?)
         } else if let Some(op) = toks.get(4) { // Compute a mathematical operation on top most numbers in stack
@@ -2642,69 +3123,181 @@ async fn do_rpn (cmdstruct: &mut CmdStruct) -> Bresult<&'static str> {
         cmdstruct.set_msg( &stack.iter().map( |f| f.to_string() ).collect::<Vec<String>>().join(" ") ).edit_msg().await?;
     }
 
-    Ok("COMPLETED.")
+    Ok(Outcome::Done)
 }
 
 ////////////////////////////////////////////////////////////////////////////////
 
+/// The chat commands in the order each message is offered to them.
+fn builtin_commands () -> Bresult<CommandRegistry> {
+    use Role::*;
+    let mut registry = CommandRegistry::default();
+    registry
+    .register(Builtin{ name: "schedule", trigger: schedule_trigger!("schedule"), role: Trader, exclusive: true,
+        help: &[("/schedule", "List jobs"),
+                ("/schedule [ISO-8601] | [1h][2m][3][mtwhfsu*] CMD", "schedule CMD now or ISO-8601 GMT o'clock, offset 1h 2m 3s, repeat on day(s)")],
+        handler: |c, caps| do_schedule(c, caps, false).boxed_local() })?
+    .register(Builtin{ name: "unschedule", trigger: schedule_trigger!("unschedule"), role: Trader, exclusive: true,
+        help: &[("/unschedule [ISO-8601] | [1h][2m][3][mtwhfsu*]", "Delete jobs at time")],
+        handler: |c, caps| do_schedule(c, caps, true).boxed_local() })?
+    .register(Builtin{ name: "echo", trigger: r"^/echo ?([0-9]+)?", role: Trader, exclusive: false,
+        help: &[("/echo 2", "Echo level (verbose 2…0 quiet)")],
+        handler: |c, caps| do_echo_lvl(c, caps).boxed_local() })?
+    .register(Builtin{ name: "help", trigger: r"/help", role: Viewer, exclusive: false,
+        help: &[],
+        handler: |c, _| do_help(c).boxed_local() })?
+    .register(Builtin{ name: "commands", trigger: r"(?i)^/commands(?: +([a-z_]+) +(on|off))?$", role: Admin, exclusive: false,
+        help: &[("/commands [cmd on|off]", "List or switch commands in this chat")],
+        handler: |c, caps| do_commands(c, caps).boxed_local() })?
+    .register(Builtin{ name: "roles", trigger: r"(?i)^/roles$", role: Viewer, exclusive: false,
+        help: &[("/roles", "Roles in this chat")],
+        handler: |c, _| do_roles(c).boxed_local() })?
+    .register(Builtin{ name: "grant", trigger: r"(?i)^/(grant|revoke) +(@[^ ]+)(?: +(viewer|trader|admin|owner))?( +[*])?$", role: Admin, exclusive: false,
+        help: &[("/grant @usr role [*]", "Give viewer/trader/admin/owner here or everywhere"),
+                ("/revoke @usr [*]", "Take away a role")],
+        handler: |c, caps| do_grant(c, caps).boxed_local() })?
+    .register(Builtin{ name: "role", trigger: r"(?i)^/role +([a-z_]+)(?: +(viewer|trader|admin|owner|default))?$", role: Owner, exclusive: false,
+        help: &[("/role cmd [role|default]", "Lowest role a command needs")],
+        handler: |c, caps| do_role(c, caps).boxed_local() })?
+    .register(Builtin{ name: "curse", trigger: r"/curse", role: Trader, exclusive: false,
+        help: &[],
+        handler: |c, _| do_curse(c).boxed_local() })?
+    .register(Builtin{ name: "say", trigger: r"^/say (.*)$", role: Trader, exclusive: false,
+        help: &[("/say hi", "™Bot will say \"hi\"")],
+        handler: |c, caps| do_say(c, caps).boxed_local() })?
+    .register(Builtin{ name: "like", trigger: r"^([+-])1", role: Trader, exclusive: false,
+        help: &[("+1    ", "Like someone's post (via reply)")],
+        handler: |c, caps| do_like(c, caps).boxed_local() })?
+    .register(Builtin{ name: "like_info", trigger: r"^\+\?$", role: Viewer, exclusive: false,
+        help: &[("+?    ", "Like leaderboard")],
+        handler: |c, _| do_like_info(c).boxed_local() })?
+    .register(Builtin{ name: "def", trigger: r"^([A-Za-z-]+):$", role: Viewer, exclusive: false,
+        help: &[("word: ", "Definition lookup")],
+        handler: |c, caps| do_def(c, caps).boxed_local() })?
+    .register(Builtin{ name: "sql", trigger: r"(?is)^(?:/sql +(.+)|(.+)ß)$", role: Owner, exclusive: false,
+        help: &[("/sql SELECT ...", "Read-only query (first 50 rows)")],
+        handler: |c, caps| do_sql(c, caps).boxed_local() })?
+    .register(Builtin{ name: "sql_write", trigger: r"(?is)^/sql +(write|confirm|cancel)(?: +(.+))?$", role: Owner, exclusive: false,
+        help: &[("/sql write UPDATE ...", "Stage a write, audit logged"),
+                ("/sql confirm|cancel", "Run or drop the staged write")],
+        handler: |c, caps| do_sql_write(c, caps).boxed_local() })?
+    .register(Builtin{ name: "corpactions", trigger: r"(?i)^/corpactions(?: +([A-Za-z0-9^.=-]+)(?: +(split|div) +([0-9]*\.?[0-9]+))?)?$", role: Owner, exclusive: false,
+        help: &[("/corpactions [gme [split 4|div .5]]", "Apply splits/dividends")],
+        handler: |c, caps| do_corpactions(c, caps).boxed_local() })?
+    .register(Builtin{ name: "balance", trigger: r"(?i)^/balance +(@[^ ]+) +([+-]?(?:\d+\.?|\d*\.\d{1,2}))$", role: Owner, exclusive: false,
+        help: &[("/balance @usr [+-]100", "Adjust cash")],
+        handler: |c, caps| do_balance(c, caps).boxed_local() })?
+    .register(Builtin{ name: "ledger", trigger: r"(?i)^/ledger(?: +(check|rebuild))?$", role: Owner, exclusive: false,
+        help: &[("/ledger [check|rebuild]", "Compare or rebuild cash and positions from the ledger")],
+        handler: |c, caps| do_ledger(c, caps).boxed_local() })?
+    .register(Builtin{ name: "import", trigger: r"(?is)^/import(?: +(@[^ \n]+))?\s+(\{.*\}|id,section,.*)$", role: Owner, exclusive: false,
+        help: &[("/import [@usr] {json}|csv", "Replay an /export into an empty account")],
+        handler: |c, caps| do_import(c, caps).boxed_local() })?
+    .register(Builtin{ name: "quotes", trigger: r"\$", role: Viewer, exclusive: false,
+        help: &[("gme$   ", "Quote ({delay}min delay)")],
+        handler: |c, _| do_quotes(c).boxed_local() })?
+    .register(Builtin{ name: "yolo", trigger: r"/yolo", role: Viewer, exclusive: false,
+        help: &[("/yolo  ", "Stonks leaderboard")],
+        handler: |c, _| do_yolo(c).boxed_local() })?
+    .register(Builtin{ name: "fx", trigger: r"(?i)^/fx +([A-Za-z]{3})(?: +([+-]?(?:\d+\.?|\d*\.\d{1,2})))?$", role: Trader, exclusive: false,
+        help: &[("/fx eur [-]100", "Buy/sell 100 EUR with USD, rate")],
+        handler: |c, caps| do_fx(c, caps).boxed_local() })?
+    .register(Builtin{ name: "base", trigger: r"(?i)^/base(?: +([A-Za-z]{3}))?$", role: Trader, exclusive: false,
+        help: &[("/base [eur]", "Currency for /stonks totals")],
+        handler: |c, caps| do_base(c, caps).boxed_local() })?
+    .register(Builtin{ name: "perf", trigger: r"(?i)^/perf(?: +(1w|1m|ytd))?$", role: Viewer, exclusive: false,
+        help: &[("/perf [1w|1m|ytd]", "Your return, drawdown, best/worst")],
+        handler: |c, caps| do_perf(c, caps).boxed_local() })?
+    .register(Builtin{ name: "history", trigger: r"(?i)^/history(?: +(\d+))?$", role: Viewer, exclusive: false,
+        help: &[("/history [10]", "Your last cash and position changes")],
+        handler: |c, caps| do_history(c, caps).boxed_local() })?
+    .register(Builtin{ name: "export", trigger: r"(?i)^/export(?: +(json|csv))?$", role: Viewer, exclusive: false,
+        help: &[("/export [json|csv]", "Your positions, orders and schedules as a file")],
+        handler: |c, caps| do_export(c, caps).boxed_local() })?
+    .register(Builtin{ name: "pnl", trigger: r"(?i)^/pnl(?: +([A-Za-z0-9^.=-]+))?$", role: Viewer, exclusive: false,
+        help: &[("/pnl [gme]", "Realized/unrealized gains by tax lot"),
+                ("/pnl fifo|lifo", "Lot closing order")],
+        handler: |c, caps| do_pnl(c, caps).boxed_local() })?
+    .register(Builtin{ name: "stonks", trigger: r"(?i)/stonks( .+)?", role: Viewer, exclusive: false,
+        help: &[("/stonks", "Your Stonkfolio")],
+        handler: |c, caps| do_portfolio(c, caps).boxed_local() })?
+    .register(Builtin{ name: "buy", trigger: BUY_TRIGGER, role: Trader, exclusive: false,
+        help: &[("gme+   ", "Buy max GME shares"),
+                ("gme+3  ", "Buy 3 shares (min qty 0.0001)"),
+                ("gme+$18", "Buy $18 worth (min $0.01)"),
+                ("gme240119c00020000+1", "Buy 1 GME $20 call (100sh) expiring 2024-01-19")],
+        handler: |c, caps| do_trade_buy(c, caps).boxed_local() })?
+    .register(Builtin{ name: "sell", trigger: SELL_TRIGGER, role: Trader, exclusive: false,
+        help: &[("gme-   ", "Sell all GME shares"),
+                ("gme-5  ", "Sell 5 share"),
+                ("gme-$.9", "Sell 90¢ worth")],
+        handler: |c, caps| do_trade_sell(c, caps).boxed_local() })?
+    .register(Builtin{ name: "short", trigger: r"(?i)^/(short|cover) +([A-Za-z0-9^.-]+)(?: +([$])?(\d+\.?|\d*\.\d{1,4}))?$", role: Trader, exclusive: false,
+        help: &[("/short gme [5|$18]", "Sell short (max if no amount)"),
+                ("/cover gme [5]", "Buy to cover (all if no amount)")],
+        handler: |c, caps| do_short(c, caps).boxed_local() })?
+    .register(Builtin{ name: "limit", trigger: LIMIT_TRIGGER, role: Trader, exclusive: false,
+        help: &[("gme+3@150", "Limit buy 3 at $150 or less"),
+                ("gme-3@200", "Limit sell 3 at $200 or more"),
+                ("gme-3!140", "Stop sell 3 once $140 or less"),
+                ("gme+3!200", "Stop buy 3 once $200 or more"),
+                ("gme~[@150]", "Cancel GME limit/stop orders")],
+        handler: |c, caps| do_trade_limit(c, caps).boxed_local() })?
+    .register(Builtin{ name: "alert", trigger: ALERT_TRIGGER, role: Trader, exclusive: false,
+        help: &[("/alert gme >200|<150", "Alert when GME reaches a price"),
+                ("/alert gme [+|-]5%", "Alert on a 5% move since the close")],
+        handler: |c, caps| do_alert(c, caps).boxed_local() })?
+    .register(Builtin{ name: "alerts", trigger: r"(?i)^/alerts(?: +~([A-Za-z0-9^.=-]+))?$", role: Trader, exclusive: false,
+        help: &[("/alerts [~gme|~3]", "List alerts, delete GME's or #3")],
+        handler: |c, caps| do_alerts(c, caps).boxed_local() })?
+    .register(Builtin{ name: "exchange", trigger: EXCHANGE_TRIGGER, role: Trader, exclusive: false,
+        help: &[("@usr+2@3", "Bid/buy 2sh of '@usr' at $3"),
+                ("@usr-5@4", "Ask/sell 5sh of '@usr' at $4"),
+                ("@usr~", "Cancel your bids/asks on '@usr'"),
+                ("@usr~@4", "Cancel your bids/asks at $4")],
+        handler: |c, caps| do_exchange_bidask(c, caps).boxed_local() })?
+    .register(Builtin{ name: "orders", trigger: r"(?i)/orders", role: Viewer, exclusive: false,
+        help: &[("/orders", "Your @shares, bid/ask and limit orders")],
+        handler: |c, _| do_orders(c).boxed_local() })?
+    .register(Builtin{ name: "fmt", trigger: r"^/fmt( ([qp?])[ ]?(.*)?)?$", role: Trader, exclusive: false,
+        help: &[("/fmt [?]     ", "Show format strings, help"),
+                ("/fmt [qp] ...", "Set quote/position fmt str")],
+        handler: |c, caps| do_fmt(c, caps).boxed_local() })?
+    .register(Builtin{ name: "rebalance", trigger: REBALANCE_TRIGGER, role: Trader, exclusive: false,
+        help: &[("/rebalance -9.99 AMC 40 QQQ 60 ...", "Rebalance to AMC/40% QQQ/60%, opt adj -$9.99")],
+        handler: |c, caps| do_rebalance(c, caps).boxed_local() })?
+    .register(Builtin{ name: "rpn", trigger: r"(?i)^=([-+*/0-9. ]+)", role: Viewer, exclusive: false,
+        help: &[],
+        handler: |c, caps| do_rpn(c, caps).boxed_local() })?;
+    Ok(registry)
+}
+
 async fn do_all (cmdstruct:&mut CmdStruct) -> Bresult<()> {
     info!("\x1b[33m{:?}", cmdstruct);
-
-    let res = do_schedule(cmdstruct).await;
-    glogd!("do_schedule =>", res);
-    match res {
-        Err(e) => {
-            cmdstruct.push_msg(&format!("Scheduler {}", e)).send_msg_id().await?; }
-        Ok(o) =>  {
-            // Stop evaluating if this is a successfull scheduled job
-            if o == "COMPLETED." { return Ok(()) }
-        }
-    }
-
-    glogd!("do_echo_lvl =>",   do_echo_lvl(cmdstruct).await);
-    glogd!("do_help =>",       do_help(cmdstruct).await);
-    glogd!("do_curse =>",      do_curse(cmdstruct).await);
-    glogd!("do_say =>",        do_say(cmdstruct).await);
-    glogd!("do_like =>",       do_like(cmdstruct).await);
-    glogd!("do_like_info =>",  do_like_info(cmdstruct).await);
-    glogd!("do_def =>",        do_def(cmdstruct).await);
-    glogd!("do_sql =>",        do_sql(cmdstruct).await);
-    glogd!("do_quotes => ",    do_quotes(cmdstruct).await);
-    glogd!("do_yolo =>",       do_yolo(cmdstruct).await);
-    glogd!("do_portfolio =>",  do_portfolio(cmdstruct).await);
-    glogd!("do_trade_buy =>",  do_trade_buy(cmdstruct).await);
-    glogd!("do_trade_sell =>", do_trade_sell(cmdstruct).await);
-    glogd!("do_exchange_bidask =>", do_exchange_bidask(cmdstruct).await);
-    glogd!("do_orders =>",     do_orders(cmdstruct).await);
-    glogd!("do_fmt =>",        do_fmt(cmdstruct).await);
-    glogd!("do_rebalance =>",  do_rebalance(cmdstruct).await);
-    glogd!("do_rpn =>",        do_rpn(cmdstruct).await);
-    Ok(())
+    let commands = getenvstruct!(cmdstruct).commands.clone();
+    commands.dispatch(cmdstruct).await
 }
 
 async fn do_each_scheduled_job (
-    jobs: Vec<HashMap<String, sqlite::Value>>,
+    jobs: Vec<Schedule>,
     env:  Env,
     now:  i64
 ) -> Bresult<()> {
     for job in jobs {
         let env = env.clone();
-        let id = job.get_i64("id")?;
-        let at = job.get_i64("at")?;
-        let command = job.get_str("cmd")?;
+        let Schedule{id, at, time, days, cmd: command, ..} = job;
+        let command = command.as_str();
         let mut cmdstruct =
             match CmdStruct::new_cmdstruct(env, now, id, at, at, 0, command) {
                 Ok(cmdstruct) => cmdstruct,
                 e => { glog!(e); continue }
             };
-        let days = job.get_str("days")?;
         let day_now = match LocalDateTime::at(now).weekday() {
             Monday => "m", Tuesday => "t", Wednesday => "w", Thursday => "h",
             Friday => "f" , Saturday => "s", Sunday => "u" };
         if days.is_empty() || days.find(day_now).is_some() {
             glog!(do_all(&mut cmdstruct).await);
         }
-        let time = job.get_i64("time")?;
         if 86400 <= time { // Delete the non-daily job
             let cmdstruct = &getenvstruct!(cmdstruct).dbconn;
             getsql!(
@@ -2726,11 +3319,11 @@ pub fn launch_scheduler(env:Env) -> Bresult<()> {
         let jobs = {
             let env = env.clone();
             let envstruct = env.lock().unwrap();
-            let dstsecs = 60 * 60 * envstruct.dst_hours_adjust as i64;
-            let res = getsqlquiet!(&envstruct.dbconn,
+            let dstsecs = 60 * 60 * dst_hours_adjust(now);
+            let res = envstruct.dbconn.query_as::<Schedule>(
                 "SELECT id, at, time, days, cmd FROM schedules WHERE (?<=time AND time<?) or (?<=time AND time<?) ORDER BY time",
-                envstruct.time_scheduler+dstsecs, now+dstsecs, // one-time jobs
-                (envstruct.time_scheduler+dstsecs) % 86400, (now+dstsecs) % 86400); // daily jobs
+                &[&(envstruct.time_scheduler+dstsecs), &(now+dstsecs), // one-time jobs
+                  &((envstruct.time_scheduler+dstsecs) % 86400), &((now+dstsecs) % 86400)]); // daily jobs
             if res.is_err() { glog!(res); continue }
             res.unwrap()
         };
@@ -2740,6 +3333,51 @@ pub fn launch_scheduler(env:Env) -> Bresult<()> {
             let res = actix_web::rt::System::new("tmbot").block_on( async move { do_each_scheduled_job(jobs, env, now).await } ); // for rows, async, block_on
             info!("\x1b[1mSchedules end. {:?}", res);
         }
+        let limits_pending = {
+            let envstruct = env.lock().unwrap();
+            getsqlquiet!(&envstruct.dbconn, "SELECT rowid FROM limits LIMIT ?", 1)
+                .map_or(false, |rows| !rows.is_empty())
+        };
+        if limits_pending {
+            let env = env.clone();
+            let res = actix_web::rt::System::new("tmbot").block_on( async move { do_limits(env, now).await } );
+            glogd!("do_limits =>", res);
+        }
+        let alerts_pending = {
+            let envstruct = env.lock().unwrap();
+            getsqlquiet!(&envstruct.dbconn, "SELECT rowid FROM alerts LIMIT ?", 1)
+                .map_or(false, |rows| !rows.is_empty())
+        };
+        if alerts_pending {
+            let env = env.clone();
+            let res = actix_web::rt::System::new("tmbot").block_on( async move { alerts_check(env, now).await } );
+            glogd!("alerts_check =>", res);
+        }
+        if now / 600 != env.lock().unwrap().time_scheduler / 600 { // Every 10 minutes
+            let res = env.lock().unwrap().transaction( |envstruct| margin_interest_accrue(envstruct, now) );
+            glogd!("margin_interest_accrue =>", res);
+            let res = env.lock().unwrap().transaction( |envstruct| borrow_fees_accrue(envstruct, now) );
+            glogd!("borrow_fees_accrue =>", res);
+            if trading_hours_p(NYSE, now).unwrap_or(false) {
+                let env = env.clone();
+                let res = actix_web::rt::System::new("tmbot").block_on( async move {
+                    glogd!("options_settle =>", options_settle(env.clone(), now).await);
+                    margin_check(env, now).await
+                } );
+                glogd!("margin_check =>", res);
+            }
+        }
+        let snapshot_due =
+            history_snapshot_due(&env.lock().unwrap(), now)
+            .unwrap_or_else( |e| { error!("history_snapshot_due => {:?}", e); false } );
+        if snapshot_due { // Splits and dividends first so the snapshot sees them
+            let env = env.clone();
+            let res = actix_web::rt::System::new("tmbot").block_on( async move {
+                glogd!("corporate_actions_run =>", corporate_actions_run(env.clone(), None, Origin::system(now)).await);
+                history_snapshot(env, now).await
+            } );
+            glogd!("history_snapshot =>", res);
+        }
         env.lock().unwrap().time_scheduler = now;
     } ); // loop, ||, thread
     Ok(())
@@ -2858,10 +3496,11 @@ fn web_login (env: Env, name: &str) -> Bresult<i64> {
 }
 
 fn web_yolo (envstruct: &mut EnvStruct) -> Bresult<String> {
-    let sql = "SELECT name, ROUND(value + balance, 2) AS yolo \
-               FROM (SELECT positions.id, SUM(qty*stonks.price) AS value \
+    let sql = "SELECT name, value + balance + IFNULL(cash, 0) AS yolo \
+               FROM (SELECT positions.id, SUM(qty*stonks.price/1e6*IFNULL(fx.price, 1000000)/1e6) AS value \
                      FROM positions \
                      LEFT JOIN stonks ON stonks.ticker = positions.ticker \
+                     LEFT JOIN stonks AS fx ON fx.ticker = stonks.currency || 'USD=X' \
                      WHERE positions.ticker NOT LIKE '0%' \
                        AND positions.ticker NOT LIKE '1%' \
                        AND positions.ticker NOT LIKE '2%' \
@@ -2876,6 +3515,10 @@ fn web_yolo (envstruct: &mut EnvStruct) -> Bresult<String> {
                      GROUP BY id) \
                NATURAL JOIN accounts \
                NATURAL JOIN entitys \
+               LEFT JOIN (SELECT balances.id, SUM(balance*IFNULL(fx.price, 1000000)/1e6) AS cash \
+                          FROM balances \
+                          LEFT JOIN stonks AS fx ON fx.ticker = balances.currency || 'USD=X' \
+                          GROUP BY balances.id) USING (id) \
                ORDER BY yolo DESC";
 
     let mut yololians =
@@ -2883,21 +3526,34 @@ fn web_yolo (envstruct: &mut EnvStruct) -> Bresult<String> {
             .iter()
             .map( |row| (
                 row.get_string("name").unwrap_or("?".to_string()),
-                row.get_f64("yolo").unwrap_or(0.0) ) )
+                row.get_money_or(Money::ZERO, "yolo").cents().to_f64() ) )
             .collect::<HashMap<String, f64>>();
 
-    yololians.insert("hitcounter".to_string(), envstruct.entity_likes_inc(1544486685, 1)? as f64);
+    if let Some(hitcounter) = envstruct.setting("hitcounter")? { // Entity whose likes count page views
+        yololians.insert("hitcounter".to_string(), envstruct.entity_likes_inc(hitcounter.parse()?, 1)? as f64);
+    }
 
     let json = serde_json::to_string(&yololians)?;
     info!("created json: {:?}", &json);
     Ok(json)
 }
 
+// [[time, cash, long, short, yolo], ...] oldest first
+fn web_history (envstruct: &EnvStruct, id: i64, period: &str, now: i64) -> Bresult<String> {
+    let since = history_period_start(period, now)?;
+    let rows =
+        history(&envstruct.dbconn, id, since)?
+        .iter()
+        .map( |s| serde_json::json!([s.time, s.cash.to_f64(), s.long.to_f64(), s.short.to_f64(), s.yolo.to_f64()]) )
+        .collect::<Vec<Value>>();
+    Ok(serde_json::to_string(&rows)?)
+}
+
 async fn web_stonks (cmdstruct: &mut CmdStruct) -> Bresult<String> {
     let positions = Position::get_users_positions(cmdstruct)?;
     let mut quotes :Vec<Vec<String>> = Vec::new();
-    let mut long = 0.0;
-    let mut short = 0.0;
+    let mut long = Money::ZERO;
+    let mut short = Money::ZERO;
     for mut pos in positions {
         if !is_self_stonk(&pos.ticker) {
             pos.update_quote(&cmdstruct).await?;
@@ -2909,7 +3565,7 @@ async fn web_stonks (cmdstruct: &mut CmdStruct) -> Bresult<String> {
                     pos.qty.to_string(),
                     pos.price.to_string(),
                     quote.price.to_string() ) );
-            if pos.qty < 0.0 {
+            if pos.qty.is_negative() {
                 short += pos.qty * pos.quote.unwrap().price;
             } else {
                 long += pos.qty * pos.quote.unwrap().price;
@@ -2917,9 +3573,9 @@ async fn web_stonks (cmdstruct: &mut CmdStruct) -> Bresult<String> {
         }
     }
 
-    let cash = roundcents(getenvstruct!(cmdstruct).entity_balance(cmdstruct.id)?);
-    let bp = roundcents(long + short*3.0 + cash*2.0);
-    let yolo = roundcents(long+short+cash);
+    let cash = getenvstruct!(cmdstruct).entity_balance(cmdstruct.id)?.cents();
+    let bp = (long + short*3 + cash*2).cents();
+    let yolo = (long+short+cash).cents();
     quotes.push( vec!(cash.to_string(), bp.to_string(), yolo.to_string()) );
 
     let json = serde_json::to_string(&quotes)?;
@@ -2977,7 +3633,7 @@ async fn ws_handle_request (cmdstruct: &mut CmdStruct) -> String {
             match envstruct.entitys.get(&cmdstruct.id) {
                 Some(entity) => {
                     hm.insert("name", entity.name.to_string());
-                    hm.insert("balance", roundcents(entity.balance).to_string());
+                    hm.insert("balance", entity.balance.cents().to_string());
                     hm.insert("likes", entity.likes.to_string());
                     serde_json::to_string(&hm).unwrap_or("{}".to_string())
                 },
@@ -2995,6 +3651,11 @@ async fn ws_handle_request (cmdstruct: &mut CmdStruct) -> String {
         "stonks" => {
             web_stonks(cmdstruct).await.unwrap_or_else( |e| { error!("{:?}", e); "error".into() } )
         },
+        "history" => { // history [1w|1m|ytd]
+            let envstruct = cmdstruct.env.lock().unwrap();
+            web_history(&envstruct, cmdstruct.id, words.get(1).copied().unwrap_or("1m"), Instant::now().seconds())
+                .unwrap_or_else( |e| { error!("{:?}", e); "error".into() } )
+        },
         "login" => {
             if 2 == words.len() { // Message user privately their login code
                 web_login(cmdstruct.env.clone(), words[1])
@@ -3081,13 +3742,60 @@ pub fn main_websocket_ssl(env: Env) -> Bresult<()> {
     Ok(())
 }
 
+// Terminal REPL.  Each stdin line is an incoming message handled by do_all
+// with responses printed to stdout.
+pub fn main_console(env: Env) -> Bresult<()> {
+    env.lock().unwrap().chat = ChatPlatform::Console;
+    let mut system = actix_web::rt::System::new("console");
+    let stdin = std::io::stdin();
+    let mut line = String::new();
+    while 0 < stdin.read_line(&mut line)? {
+        let envc = env.clone();
+        let body = line.clone();
+        system.block_on(async move {
+            match CmdStruct::newcmdstruct(envc, body.as_bytes()) {
+                Ok(mut cmdstruct) => do_all(&mut cmdstruct).await.unwrap_or_else(|r| error!("{:?}", r)),
+                e => glog!(e)
+            }
+        });
+        line.clear();
+    }
+    Ok(())
+}
+
 ////////////////////////////////////////
 pub fn main_launch() -> Bresult<()> {
     let argv = env::args();
-    if 4 != argv.len() { Err(format!("Arguments: {:?}  USAGE:: tmbot  {{API_TOKEN_VAR}}  {{SQLITE.FILENAME}}  {{DST 0|1}}", argv))?  }
-    if !true { glogd!("create_schema => ", create_schema()) } // Create DB
+    if argv.len() < 3 || 5 < argv.len() { Err(format!("Arguments: {:?}  USAGE:: tmbot  {{API_TOKEN_VAR}}  {{SQLITE.FILENAME}}  [--console|--migrate-only|--export [FILE]|--import FILE]", argv))?  }
+    let mode = env::args().nth(3).unwrap_or_default();
     if !true { fun(argv) } // Hacks and other test code
-    else {
+    else if mode == "--console" {
+        let env = EnvStruct::new(argv)?;
+        env.lock().unwrap().chat = ChatPlatform::Console; // Before the scheduler creates any CmdStructs
+        glogd!("scheduler() =>", launch_scheduler(env.clone()));
+        main_console(env)
+    } else if mode == "--migrate-only" {
+        let dbconn = Connection::new(env::args().nth(2).ok_or("args[2] missing")?)?;
+        info!("schema version {}", migrate(&dbconn)?);
+        Ok(())
+    } else if mode == "--export" { // Every account to FILE or stdout
+        let dbconn = Connection::new(env::args().nth(2).ok_or("args[2] missing")?)?;
+        migrate(&dbconn)?;
+        let json = serde_json::to_string_pretty(&export_document(&dbconn, None, Instant::now().seconds())?)?;
+        match env::args().nth(4) {
+            Some(filename) => ::std::fs::write(filename, json)?,
+            None => println!("{}", json)
+        }
+        Ok(())
+    } else if mode == "--import" { // Every account in FILE, all or nothing
+        let dbconn = Connection::new(env::args().nth(2).ok_or("args[2] missing")?)?;
+        migrate(&dbconn)?;
+        let doc = read_document(&::std::fs::read_to_string(env::args().nth(4).ok_or("--import FILE missing")?)?)?;
+        info!("imported {} accounts", import_document(&dbconn, Origin::system(Instant::now().seconds()), &doc)?);
+        Ok(())
+    } else if !mode.is_empty() {
+        Err(format!("Unknown mode {:?}", mode).into())
+    } else {
         let env = EnvStruct::new(argv)?;
         glogd!("websocket() =>", main_websocket(env.clone()));
         glogd!("websocketssl() =>", main_websocket_ssl(env.clone()));
@@ -3136,4 +3844,236 @@ fn fun (_argv: std::env::Args) -> Bresult<()>  {
 */
 
     Ok(())
-}
\ No newline at end of file
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Run a chat message from id 1 through a command with its trigger's captures
+    fn handle (env:&Env, name:&str, message:&str) -> Bresult<Outcome> {
+        let commands = env.lock().unwrap().commands.clone();
+        let command = commands.get(name).ok_or("no such command")?;
+        let caps = regex_to_vec(command.trigger(), message)?;
+        if caps.is_empty() { return Ok(Outcome::Skip) }
+        let mut cmdstruct = CmdStruct::new_cmdstruct(env.clone(), Instant::now().seconds(), 1, 1, 1, 1, message)?;
+        futures::executor::block_on(command.run(&mut cmdstruct, caps))
+    }
+
+    // FixtureQuotes counting batch requests, failing them when broken
+    #[derive(Debug)]
+    struct BatchQuotes { fixture: FixtureQuotes, batches: ::std::sync::atomic::AtomicUsize, broken: bool }
+
+    impl QuoteProvider for BatchQuotes {
+        fn get_ticker_raw<'a> (&'a self, ticker: &'a str) -> futures::future::LocalBoxFuture<'a, Bresult<Value>> {
+            self.fixture.get_ticker_raw(ticker)
+        }
+        fn get_tickers_raw<'a> (&'a self, tickers: &'a [String]) -> futures::future::LocalBoxFuture<'a, Bresult<Value>> {
+            self.batches.fetch_add(1, ::std::sync::atomic::Ordering::SeqCst);
+            if self.broken { futures::future::ready(Err("batch failed".into())).boxed_local() } else { self.fixture.get_tickers_raw(tickers) }
+        }
+        fn get_option_chain_raw<'a> (&'a self, ticker: &'a str, expiry: i64) -> futures::future::LocalBoxFuture<'a, Bresult<Value>> {
+            self.fixture.get_option_chain_raw(ticker, expiry)
+        }
+        fn get_closes_raw<'a> (&'a self, ticker: &'a str, since: i64, until: i64) -> futures::future::LocalBoxFuture<'a, Bresult<Value>> {
+            self.fixture.get_closes_raw(ticker, since, until)
+        }
+    }
+
+    // Env quoting GME and AMC through a BatchQuotes
+    fn batch_env (broken:bool) -> (Env, Arc<BatchQuotes>) {
+        let mut envstruct = test_envstruct();
+        let provider = Arc::new(BatchQuotes{
+            fixture: FixtureQuotes::from_value(serde_json::json!({
+                "gme": {"regularMarketPrice": 10.0, "regularMarketPreviousClose": 9.0, "volume24Hr": 1, "currency": "USD"},
+                "amc": {"regularMarketPrice": 5.0, "regularMarketPreviousClose": 5.0, "volume24Hr": 1, "currency": "USD"}})).unwrap(),
+            batches: ::std::sync::atomic::AtomicUsize::new(0),
+            broken });
+        envstruct.quotes = provider.clone();
+        (envstruct.into(), provider)
+    }
+
+    // Prices get_market_quotes finds for GME, AMC and the unknown BBBY
+    fn market_prices (env:&Env) -> Vec<(String, Money)> {
+        let cmdstruct = CmdStruct::new_cmdstruct(env.clone(), Instant::now().seconds(), 1, 1, 1, 1, "").unwrap();
+        let tickers = ["GME", "AMC", "BBBY"].iter().map( |t| t.to_string() ).collect::<Vec<String>>();
+        let mut prices =
+            futures::executor::block_on(Quote::get_market_quotes(&cmdstruct, &tickers)).unwrap()
+            .into_iter().map( |(ticker, quote)| (ticker, quote.price) ).collect::<Vec<_>>();
+        prices.sort();
+        prices
+    }
+
+    #[test]
+    fn market_quotes_batched() {
+        let (env, provider) = batch_env(false);
+        assert_eq!(market_prices(&env), vec![("AMC".to_string(), Money::from_int(5)), ("GME".to_string(), Money::from_int(10))]);
+        assert_eq!(provider.batches.load(::std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn market_quotes_one_at_a_time_after_batch_fails() {
+        let (env, provider) = batch_env(true);
+        assert_eq!(market_prices(&env), vec![("AMC".to_string(), Money::from_int(5)), ("GME".to_string(), Money::from_int(10))]);
+        assert_eq!(provider.batches.load(::std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn from_row_defaults() {
+        let dbconn = test_dbconn();
+        getsql!(dbconn, "INSERT INTO entitys VALUES (7, 'seven')").unwrap();
+        let entitys = dbconn.query_as::<Entity>(
+            "SELECT entitys.id, entitys.name, accounts.balance, modes.echo FROM entitys
+             LEFT JOIN accounts ON entitys.id = accounts.id
+             LEFT JOIN modes    ON entitys.id = modes.id WHERE entitys.id=?", &[&7i64]).unwrap();
+        assert_eq!((entitys[0].id, entitys[0].name.as_str(), entitys[0].balance, entitys[0].echo, entitys[0].likes), (7, "seven", Money::ZERO, 2, 0));
+        assert!(dbconn.query_as::<Entity>("SELECT name FROM entitys", &[]).is_err()); // id is required
+    }
+
+    #[test]
+    fn settlement_crash_rolls_back() {
+        let mut envstruct = test_envstruct();
+        let (q, m) = (Qty::from_int, Money::from_int);
+        let fill = Fill{ buyer: 1, seller: 2, qty: q(4), price: m(10), time: 0 };
+        let before = test_ledger(&envstruct);
+
+        assert!(crashing_at("exchange_fill_settle", ||
+            envstruct.transaction( |envstruct| exchange_fill_settle(envstruct, Origin::system(0), "2", &fill) )).is_err());
+        assert_eq!(test_ledger(&envstruct), before);
+
+        envstruct.transaction( |envstruct| exchange_fill_settle(envstruct, Origin::system(0), "2", &fill) ).unwrap();
+        assert_eq!(test_ledger(&envstruct), (vec![m(960), m(1040)], vec![m(960), m(1040)], vec![q(4), q(6)], 2));
+    }
+
+    // Env where id 1 holds GME qty@8
+    fn holding_env (qty:i64) -> Env {
+        let envstruct = test_envstruct();
+        getsql!(envstruct.dbconn, "INSERT INTO positions VALUES (1, 'GME', ?, 8e6)", Qty::from_int(qty)).unwrap();
+        lots_apply(&envstruct.dbconn, 1, "GME", Qty::from_int(qty), Money::from_int(8), 0).unwrap();
+        envstruct.into()
+    }
+
+    #[test]
+    fn buy_crash_rolls_back() {
+        let (q, m) = (Qty::from_int, Money::from_int);
+        let env :Env = test_envstruct().into();
+        let before = test_ledger(&env.lock().unwrap());
+        assert!(crashing_at("execute_buy", || handle(&env, "buy", "gme+2")).is_err());
+        assert_eq!(test_ledger(&env.lock().unwrap()), before);
+        assert_eq!(handle(&env, "buy", "gme+2").unwrap(), Outcome::Done);
+        assert_eq!(test_ledger(&env.lock().unwrap()), (vec![m(980), m(1000)], vec![m(980), m(1000)], vec![q(2), q(10)], 1));
+    }
+
+    #[test]
+    fn sell_all_crash_rolls_back() {
+        let (q, m) = (Qty::from_int, Money::from_int);
+        let env = holding_env(3);
+        let before = test_ledger(&env.lock().unwrap());
+        assert!(crashing_at("execute_sell", || handle(&env, "sell", "gme-3")).is_err());
+        assert_eq!(test_ledger(&env.lock().unwrap()), before);
+        assert_eq!(handle(&env, "sell", "gme-3").unwrap(), Outcome::Done);
+        assert_eq!(test_ledger(&env.lock().unwrap()), (vec![m(1030), m(1000)], vec![m(1030), m(1000)], vec![q(10)], 1));
+    }
+
+    #[test]
+    fn sell_part_crash_rolls_back() {
+        let (q, m) = (Qty::from_int, Money::from_int);
+        let env = holding_env(3);
+        let before = test_ledger(&env.lock().unwrap());
+        assert!(crashing_at("execute_sell", || handle(&env, "sell", "gme-1")).is_err());
+        assert_eq!(test_ledger(&env.lock().unwrap()), before);
+        assert_eq!(handle(&env, "sell", "gme-1").unwrap(), Outcome::Done);
+        assert_eq!(test_ledger(&env.lock().unwrap()), (vec![m(1010), m(1000)], vec![m(1010), m(1000)], vec![q(2), q(10)], 1));
+    }
+
+    #[test]
+    fn short_crash_rolls_back() {
+        let (q, m) = (Qty::from_int, Money::from_int);
+        let env :Env = test_envstruct().into();
+        let before = test_ledger(&env.lock().unwrap());
+        assert!(crashing_at("execute_sell", || handle(&env, "sell", "gme-2")).is_err());
+        assert_eq!(test_ledger(&env.lock().unwrap()), before);
+        assert_eq!(handle(&env, "sell", "gme-2").unwrap(), Outcome::Done);
+        assert_eq!(test_ledger(&env.lock().unwrap()), (vec![m(1020), m(1000)], vec![m(1020), m(1000)], vec![q(-2), q(10)], 1));
+    }
+
+    #[test]
+    fn limit_trigger_boundaries() {
+        let (q, m) = (Qty::from_int, Money::from_int);
+        assert_eq!(
+            [limit_triggered_p("@", q(1), m(10), m(10)), limit_triggered_p("@", q(1), m(10), m(11)),
+             limit_triggered_p("@", q(-1), m(10), m(10)), limit_triggered_p("@", q(-1), m(10), m(9)),
+             limit_triggered_p("!", q(1), m(10), m(10)), limit_triggered_p("!", q(1), m(10), m(9)),
+             limit_triggered_p("!", q(-1), m(10), m(10)), limit_triggered_p("!", q(-1), m(10), m(11))],
+            [true, false, true, false, true, false, true, false]);
+    }
+
+    // Env with id 1's GME buy limit of qty at price, GME quoted at 10
+    fn limit_env (qty:i64, price:i64) -> Env {
+        let envstruct = test_envstruct();
+        getsql!(envstruct.dbconn, "INSERT INTO limits VALUES (1, 1, 'GME', ?, ?, '@', 0)", Qty::from_int(qty), Money::from_int(price)).unwrap();
+        envstruct.into()
+    }
+
+    #[test]
+    fn limit_waits_for_price() {
+        let env = limit_env(2, 9);
+        futures::executor::block_on(do_limits(env.clone(), Instant::now().seconds())).unwrap();
+        let envstruct = env.lock().unwrap();
+        assert_eq!(getsql!(envstruct.dbconn, "SELECT * FROM limits").unwrap().len(), 1);
+        assert!(test_positions(&envstruct.dbconn, 1).is_empty());
+    }
+
+    #[test]
+    fn limit_removed_with_fill() {
+        let env = limit_env(2, 10);
+        futures::executor::block_on(do_limits(env.clone(), Instant::now().seconds())).unwrap();
+        let envstruct = env.lock().unwrap();
+        assert!(getsql!(envstruct.dbconn, "SELECT * FROM limits").unwrap().is_empty());
+        assert_eq!(test_positions(&envstruct.dbconn, 1), vec![("GME".to_string(), Qty::from_int(2))]);
+    }
+
+    #[test]
+    fn failed_limit_kept() {
+        let env = limit_env(2, 10);
+        crashing_at("execute_buy", || futures::executor::block_on(do_limits(env.clone(), Instant::now().seconds()))).unwrap();
+        let envstruct = env.lock().unwrap();
+        assert_eq!(getsql!(envstruct.dbconn, "SELECT * FROM limits").unwrap().len(), 1); // Retried next tick
+        assert!(test_positions(&envstruct.dbconn, 1).is_empty());
+    }
+
+    #[test]
+    fn rejected_limit_cancelled() {
+        let env = limit_env(100000, 10); // Past buying power
+        futures::executor::block_on(do_limits(env.clone(), Instant::now().seconds())).unwrap();
+        let envstruct = env.lock().unwrap();
+        assert!(getsql!(envstruct.dbconn, "SELECT * FROM limits").unwrap().is_empty());
+        assert!(test_positions(&envstruct.dbconn, 1).is_empty());
+    }
+
+    #[test]
+    fn nested_transaction_failure_keeps_outer() {
+        let dbconn = test_dbconn();
+        crashing_at("inner", || dbconn.transaction( |tx| {
+            getsql!(tx, "INSERT INTO accounts VALUES (1, 1e6)")?;
+            assert!(tx.transaction( |tx| {
+                getsql!(tx, "INSERT INTO accounts VALUES (2, 2e6)")?;
+                crash_point("inner")
+            }).is_err());
+            Ok(())
+        })).unwrap();
+        assert_eq!(getsql!(dbconn, "SELECT id FROM accounts").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn buy_through_handler() {
+        let (q, m) = (Qty::from_int, Money::from_int);
+        let env :Env = test_envstruct().into();
+        assert_eq!(handle(&env, "buy", "gme+2").unwrap(), Outcome::Done);
+        let envstruct = env.lock().unwrap();
+        assert_eq!(test_ledger(&envstruct), (vec![m(980), m(1000)], vec![m(980), m(1000)], vec![q(2), q(10)], 1));
+        assert!(ledger_check(&envstruct.dbconn).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,127 @@
+//! # Tax Lots and Realized Gains
+use crate::*;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LotMethod { Fifo, Lifo }
+
+impl LotMethod {
+    pub fn name (&self) -> &'static str {
+        match self { LotMethod::Fifo => "FIFO", LotMethod::Lifo => "LIFO" }
+    }
+}
+
+#[derive(Debug, FromRow)]
+pub struct Lot {
+    pub rowid: i64,
+    pub qty:   Qty, // Signed, negative for short lots
+    pub price: Money
+}
+
+pub fn lot_method (dbconn:&Connection, id:i64) -> Bresult<LotMethod> {
+    let rows = getsql!(dbconn, "SELECT method FROM lotmethods WHERE id=?", id)?;
+    Ok(IF!(rows.first().map_or(false, |row| row.get_string_or("", "method") == "lifo"), LotMethod::Lifo, LotMethod::Fifo))
+}
+
+pub fn lot_method_set (dbconn:&Connection, id:i64, method:LotMethod) -> Bresult<()> {
+    getsql!(dbconn, "INSERT OR REPLACE INTO lotmethods VALUES (?, ?)", id, &*method.name().to_lowercase())?;
+    Ok(())
+}
+
+pub fn lots (dbconn:&Connection, id:i64, ticker:&str) -> Bresult<Vec<Lot>> {
+    dbconn.query_as::<Lot>(
+        "SELECT rowid AS rowid, qty, price FROM lots WHERE id=? AND ticker=? ORDER BY time, rowid",
+        &[&id, &ticker])
+}
+
+/// Apply a signed fill to id's lots.  Opposite lots are closed in lot method
+/// order and the remainder opens a new lot.  Returns the realized gain.
+pub fn lots_apply (dbconn:&Connection, id:i64, ticker:&str, qty:Qty, price:Money, time:i64) -> Bresult<Money> {
+    let mut open = lots(dbconn, id, ticker)?;
+    if LotMethod::Lifo == lot_method(dbconn, id)? { open.reverse() }
+    let mut remaining = qty;
+    let mut realized = Money::ZERO;
+    for lot in open.iter().filter( |lot| lot.qty.signum() * qty.signum() < 0 ) {
+        if remaining.is_zero() { break }
+        let closed = remaining.abs().min(lot.qty.abs()) * lot.qty.signum(); // Lot's sign
+        let gain = closed * (price - lot.price);
+        getsql!(dbconn, "INSERT INTO realized VALUES (?, ?, ?, ?, ?, ?, ?)",
+            id, ticker, closed, lot.price, price, gain, time)?;
+        let left = lot.qty - closed;
+        if left.is_zero() {
+            getsql!(dbconn, "DELETE FROM lots WHERE rowid=?", lot.rowid)?;
+        } else {
+            getsql!(dbconn, "UPDATE lots SET qty=? WHERE rowid=?", left, lot.rowid)?;
+        }
+        remaining += closed;
+        realized += gain;
+    }
+    if !remaining.is_zero() {
+        getsql!(dbconn, "INSERT INTO lots VALUES (?, ?, ?, ?, ?)", id, ticker, remaining, price, time)?;
+    }
+    Ok(realized)
+}
+
+/// Scale id's lots by a split ratio rounded like the position, qty after
+/// the split.  The newest lot takes the rounding so the lots still sum to it.
+pub fn lots_split (dbconn:&Connection, id:i64, ticker:&str, ratio:f64, qty:Qty) -> Bresult<()> {
+    let open = lots(dbconn, id, ticker)?;
+    let mut total = Qty::ZERO;
+    for (i, lot) in open.iter().enumerate() {
+        let lot_qty = IF!(i + 1 == open.len(), qty - total, lot.qty.scale(ratio).tradeable());
+        total += lot_qty;
+        getsql!(dbconn, "UPDATE lots SET qty=?, price=? WHERE rowid=?", lot_qty, lot.price.scale(1.0 / ratio), lot.rowid)?;
+    }
+    Ok(())
+}
+
+/// Realized gain for one ticker or all of them
+pub fn realized_gain (dbconn:&Connection, id:i64, ticker:Option<&str>) -> Bresult<Money> {
+    Ok(getsql!(dbconn,
+        "SELECT SUM(gain) AS gain FROM realized WHERE id=? AND (?='' OR ticker=?)",
+        id, ticker.unwrap_or(""), ticker.unwrap_or(""))?[0]
+        .get_money_or(Money::ZERO, "gain"))
+}
+
+/// Tickers with open lots or realized gains
+pub fn pnl_tickers (dbconn:&Connection, id:i64) -> Bresult<Vec<String>> {
+    getsql!(dbconn, "SELECT ticker FROM lots WHERE id=? UNION SELECT ticker FROM realized WHERE id=? ORDER BY ticker", id, id)?
+    .iter()
+    .map( |row| row.get_string("ticker") )
+    .collect()
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fifo_and_lifo() {
+        let dbconn = test_dbconn();
+        lot_method_set(&dbconn, 2, LotMethod::Lifo).unwrap();
+        let (q, m) = (Qty::from_int, Money::from_int);
+        for id in 1..=2 {
+            lots_apply(&dbconn, id, "GME", q(2), m(10), 1).unwrap();
+            lots_apply(&dbconn, id, "GME", q(2), m(20), 2).unwrap();
+        }
+        assert_eq!(lots_apply(&dbconn, 1, "GME", q(-3), m(30), 3).unwrap(), m(40 + 10));
+        assert_eq!(lots_apply(&dbconn, 2, "GME", q(-3), m(30), 3).unwrap(), m(20 + 20));
+        assert_eq!(lots(&dbconn, 1, "GME").unwrap()[0].price, m(20));
+        assert_eq!(lots(&dbconn, 2, "GME").unwrap()[0].price, m(10));
+        assert_eq!(realized_gain(&dbconn, 1, None).unwrap(), m(50));
+    }
+
+    #[test]
+    fn flip_long_to_short() {
+        let dbconn = test_dbconn();
+        let (q, m) = (Qty::from_int, Money::from_int);
+        lots_apply(&dbconn, 1, "AMC", q(1), m(10), 1).unwrap();
+        assert_eq!(lots_apply(&dbconn, 1, "AMC", q(-3), m(8), 2).unwrap(), m(-2));
+        let open = lots(&dbconn, 1, "AMC").unwrap();
+        assert_eq!((open.len(), open[0].qty, open[0].price), (1, q(-2), m(8)));
+        assert_eq!(lots_apply(&dbconn, 1, "AMC", q(2), m(5), 3).unwrap(), m(6)); // Short covered lower
+        assert!(lots(&dbconn, 1, "AMC").unwrap().is_empty());
+        assert_eq!(realized_gain(&dbconn, 1, Some("AMC")).unwrap(), m(4));
+    }
+}
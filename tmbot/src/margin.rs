@@ -0,0 +1,170 @@
+//! # Margin
+use crate::*;
+
+pub const MARGIN_LONG     :f64 = 0.25; // Maintenance, fraction of long market value
+pub const MARGIN_SHORT    :f64 = 0.30; // Maintenance, fraction of short market value
+pub const MARGIN_WARN     :f64 = 1.10; // Warn when equity is within 10% of maintenance
+pub const MARGIN_INTEREST :f64 = 0.08; // Yearly rate charged on negative cash
+pub const YEAR_DAYS       :f64 = 365.0; // Day count of every yearly rate, interest and borrow fees alike
+
+pub fn maintenance_requirement (qty:Qty, price:Money) -> Money {
+    IF!(qty.is_negative(), (-qty * price).scale(MARGIN_SHORT), (qty * price).scale(MARGIN_LONG))
+}
+
+#[derive(Debug, Default)]
+pub struct Margin {
+    pub cash: Money,
+    pub long: Money,
+    pub short: Money, // Negative
+    pub requirement: Money,
+    pub positions: Vec<(String, Qty, Money)> // Ticker, qty and requirement, largest requirement first
+}
+
+impl Margin {
+    pub fn equity (&self) -> Money { self.cash + self.long + self.short }
+    pub fn call_p (&self) -> bool { self.equity() < self.requirement }
+    pub fn warn_p (&self) -> bool { self.equity() < self.requirement.scale(MARGIN_WARN) }
+}
+
+/// An entity's margin at the cached market prices
+pub fn margin (dbconn:&Connection, id:i64) -> Bresult<Margin> {
+    let mut margin = Margin::default();
+    margin.cash = getsql!(dbconn, "SELECT balance FROM accounts WHERE id=?", id)?
+        .first().map_or(Money::ZERO, |row| row.get_money_or(Money::ZERO, "balance"))
+        + balances_usd(dbconn, id)?;
+    for row in getsql!(dbconn, &format!(
+        "SELECT positions.ticker, qty, stonks.price*{} AS price FROM positions
+         LEFT JOIN stonks ON stonks.ticker = positions.ticker
+         LEFT JOIN stonks AS fx ON fx.ticker = stonks.currency || 'USD=X' WHERE id=?", SQL_FX_RATE), id)? {
+        let (ticker, qty) = (row.get_string("ticker")?, row.get_qty("qty")?);
+        if !market_ticker_p(&ticker) { continue }
+        let price = row.get_money_or(Money::ZERO, "price");
+        if qty.is_negative() { margin.short += qty * price } else { margin.long += qty * price }
+        let requirement = IF!(is_option(&ticker), qty * price, maintenance_requirement(qty, price)); // Options aren't marginable
+        margin.requirement += requirement;
+        margin.positions.push((ticker, qty, requirement));
+    }
+    margin.positions.sort_by( |a, b| b.2.cmp(&a.2) );
+    Ok(margin)
+}
+
+/// Charge a day's interest to every negative balance not yet charged today.
+/// Returns the ids and amounts charged.
+pub fn margin_interest_accrue (envstruct:&mut EnvStruct, now:i64) -> Bresult<Vec<(i64, Money)>> {
+    let day = now - now % 86400;
+    let accounts = getsql!(envstruct.dbconn,
+        "SELECT id, balance FROM accounts WHERE balance<0 AND id NOT IN (SELECT id FROM interest WHERE time=?)", day)?;
+    let mut charged = Vec::new();
+    for row in accounts {
+        let id = row.get_i64("id")?;
+        let interest = row.get_money("balance")?.scale(MARGIN_INTEREST / YEAR_DAYS).cents();
+        getsql!(envstruct.dbconn, "INSERT INTO interest VALUES (?, ?, ?)", id, day, interest)?;
+        cash_inc(envstruct, Origin::system(now), id, USD, interest)?;
+        charged.push((id, interest));
+    }
+    Ok(charged)
+}
+
+/// Apply splits and dividends, refresh quotes, then warn or liquidate every
+/// entity short of maintenance.
+pub async fn margin_check (env:Env, now:i64) -> Bresult<()> {
+    // Quotes are post-split from the ex-date on, so positions must be too
+    glogd!("margin_check corporate_actions_run =>", corporate_actions_run(env.clone(), None, Origin::system(now)).await);
+    let (ids, tickers) = {
+        let envstruct = env.lock().unwrap();
+        (   getsql!(envstruct.dbconn, "SELECT DISTINCT id FROM positions")?,
+            getsql!(envstruct.dbconn, "SELECT DISTINCT ticker FROM positions")? )
+    };
+    let cmdstruct = CmdStruct::new_cmdstruct(env.clone(), now, 0, 0, 0, 0, "")?;
+    let tickers = tickers.iter()
+        .filter_map( |row| row.get_string("ticker").ok() )
+        .filter( |ticker| market_ticker_p(ticker) )
+        .collect::<Vec<String>>();
+    glogd!("margin_check quotes =>", Quote::get_market_quotes(&cmdstruct, &tickers).await.map( |quotes| quotes.len() ));
+    let fx = fx_tickers(&env.lock().unwrap().dbconn)?; // After the positions so new currencies are known
+    glogd!("margin_check fx =>", Quote::get_market_quotes(&cmdstruct, &fx).await.map( |quotes| quotes.len() ));
+
+    for row in ids {
+        let id = row.get_i64("id")?;
+        let status = margin(&env.lock().unwrap().dbconn, id)?;
+        if !status.warn_p() { continue }
+        let kind = IF!(status.call_p(), "call", "warn");
+        { // Warn once a day, but log every call
+            let envstruct = env.lock().unwrap();
+            let day = now - now % 86400;
+            if kind == "warn" && !getsql!(envstruct.dbconn, "SELECT id FROM margin_calls WHERE id=? AND ?<=time LIMIT 1", id, day)?.is_empty() { continue }
+            getsql!(envstruct.dbconn, "INSERT INTO margin_calls VALUES (?, ?, ?, ?, ?)", id, now, status.equity().cents(), status.requirement.cents(), kind)?;
+        }
+        warn!("margin {} id {} equity {:.2} requirement {:.2}", kind, id, status.equity(), status.requirement);
+        let mut cmdstruct =
+            match CmdStruct::new_cmdstruct(env.clone(), now, id, id, id, 0, "") {
+                Ok(cmdstruct) => cmdstruct,
+                e => { glog!(e); continue }
+            };
+        let header = format!("*Margin {}:* equity `{:.2}` maintenance `{:.2}`",
+            IF!(kind == "call", "call", "warning"), status.equity(), status.requirement);
+        if kind == "warn" {
+            glogd!("margin_check warn =>", cmdstruct.markdown().push_msg(&header).send_msg().await);
+            continue
+        }
+        for (ticker, qty, _) in status.positions {
+            cmdstruct.markdown().set_msg(&format!("{}\n", header));
+            let trade = Trade{ cmdstruct: &mut cmdstruct, ticker, action: IF!(qty.is_negative(), '+', '-'), amt: None }; // Cover or sell it all
+            if qty.is_negative() {
+                glogd!("margin_check trade_buy =>", trade_buy(trade).await);
+            } else {
+                glogd!("margin_check trade_sell =>", trade_sell(trade).await);
+            }
+            if !margin(&getenvstruct!(cmdstruct).dbconn, id)?.call_p() { break }
+        }
+    }
+    Ok(())
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn margin_requirements() {
+        let dbconn = test_dbconn();
+        getsql!(dbconn, "INSERT INTO accounts VALUES (1, -500e6)").unwrap();
+        getsql!(dbconn, "INSERT INTO stonks VALUES ('GME', 100e6, 90e6, 'r', 16, 'NYQ', 0, 'GameStop', 'USD'), ('AMC', 10e6, 9e6, 'r', 16, 'NYQ', 0, 'AMC', 'USD')").unwrap();
+        getsql!(dbconn, "INSERT INTO positions VALUES (1, 'GME', 10e6, 50e6), (1, 'AMC', -20e6, 5e6), (1, '2', 5e6, 1e6)").unwrap();
+        let status = margin(&dbconn, 1).unwrap();
+        assert_eq!((status.long, status.short, status.equity()), (Money::from_int(1000), Money::from_int(-200), Money::from_int(300)));
+        assert_eq!(status.requirement, Money::from_int(250 + 60));
+        assert!(status.call_p() && status.warn_p());
+        assert_eq!(status.positions.iter().map( |p| p.0.as_str() ).collect::<Vec<_>>(), vec!["GME", "AMC"]);
+    }
+
+    #[test]
+    fn margin_interest_once_a_day() {
+        let mut envstruct = test_envstruct();
+        cash_inc(&mut envstruct, Origin::system(0), 1, USD, Money::from_int(-1365)).unwrap();
+        let day = 86400 * 100;
+        assert_eq!(envstruct.transaction( |envstruct| margin_interest_accrue(envstruct, day + 10) ).unwrap(), vec![(1, Money::from_f64(-0.08))]);
+        assert!(envstruct.transaction( |envstruct| margin_interest_accrue(envstruct, day + 20) ).unwrap().is_empty());
+        assert_eq!(envstruct.transaction( |envstruct| margin_interest_accrue(envstruct, day + 86400) ).unwrap().len(), 1);
+        assert_eq!(test_ledger(&envstruct).0[0], test_ledger(&envstruct).1[0]);
+        assert_eq!(envstruct.entity_balance(1).unwrap(), Money::from_f64(-365.16));
+    }
+
+    #[test]
+    fn split_on_ex_date_no_margin_call() {
+        let mut envstruct = test_envstruct();
+        let now = Instant::now().seconds();
+        cash_inc(&mut envstruct, Origin::system(0), 1, USD, Money::from_int(-3000)).unwrap();
+        getsql!(envstruct.dbconn, "INSERT INTO positions VALUES (1, 'AAPL', 10e6, 400e6)").unwrap();
+        envstruct.quotes = Arc::new(FixtureQuotes::from_value(serde_json::json!(
+            {"aapl": {"regularMarketPrice": 100.0, "regularMarketPreviousClose": 400.0, "volume24Hr": 1, "currency": "USD"}})).unwrap());
+        envstruct.actions = Arc::new(FixtureActions::from_value(serde_json::json!({"aapl": [{"date": now - 3600, "split": 4}]})).unwrap());
+        let env :Env = envstruct.into();
+        futures::executor::block_on(margin_check(env.clone(), now)).unwrap();
+        let envstruct = env.lock().unwrap();
+        assert!(getsql!(envstruct.dbconn, "SELECT * FROM margin_calls").unwrap().is_empty());
+        assert_eq!(test_positions(&envstruct.dbconn, 1), vec![("AAPL".to_string(), Qty::from_int(40))]);
+    }
+}
@@ -0,0 +1,506 @@
+//! # Schema Migrations
+use crate::*;
+
+/// (version, description, SQL batch).  Append new entries, never edit one
+/// that has been deployed.
+const MIGRATIONS :&[(i64, &str, &str)] = &[
+    (1, "baseline tables", "
+        CREATE TABLE IF NOT EXISTS entitys (
+            id INTEGER NOT NULL UNIQUE,
+            name  TEXT NOT NULL);
+
+        CREATE TABLE IF NOT EXISTS modes (
+            id   INTEGER NOT NULL UNIQUE,
+            echo INTEGER NOT NULL);
+
+        CREATE TABLE IF NOT EXISTS formats (
+            id    INTEGER NOT NULL UNIQUE,
+            quote    TEXT NOT NULL,
+            position TEXT NOT NULL);
+
+        CREATE TABLE IF NOT EXISTS accounts (
+            id    INTEGER  NOT NULL UNIQUE,
+            balance FLOAT  NOT NULL);
+
+        CREATE TABLE IF NOT EXISTS stonks (
+            ticker   TEXT NOT NULL UNIQUE,
+            price   FLOAT NOT NULL,
+            last    FLOAT NOT NULL,
+            market   TEXT NOT NULL,
+            hours INTEGER NOT NULL,
+            exchange TEXT NOT NULL,
+            time  INTEGER NOT NULL,
+            title    TEXT NOT NULL);
+
+        CREATE TABLE IF NOT EXISTS orders (
+            id   INTEGER  NOT NULL,
+            ticker  TEXT  NOT NULL,
+            qty    FLOAT  NOT NULL,
+            price  FLOAT  NOT NULL,
+            time INTEGER  NOT NULL);
+
+        CREATE TABLE IF NOT EXISTS positions (
+            id   INTEGER NOT NULL,
+            ticker  TEXT NOT NULL,
+            qty    FLOAT NOT NULL,
+            price  FLOAT NOT NULL);
+
+        CREATE TABLE IF NOT EXISTS exchange (
+            id   INTEGER NOT NULL,
+            ticker  TEXT NOT NULL,
+            qty    FLOAT NOT NULL,
+            price  FLOAT NOT NULL,
+            time INTEGER NOT NULL);
+
+        CREATE TABLE IF NOT EXISTS limits (
+            id   INTEGER NOT NULL,
+            at   INTEGER NOT NULL,
+            ticker  TEXT NOT NULL,
+            qty    FLOAT NOT NULL,
+            price  FLOAT NOT NULL,
+            kind    TEXT NOT NULL,
+            time INTEGER NOT NULL);
+
+        CREATE TABLE IF NOT EXISTS schedules (
+            id   INTEGER NOT NULL,
+            at   INTEGER NOT NULL,
+            time INTEGER NOT NULL,
+            days    TEXT NOT NULL,
+            cmd     TEXT NOT NULL);
+
+        CREATE TABLE IF NOT EXISTS likes (
+            id    INTEGER NOT NULL UNIQUE,
+            likes INTEGER NOT NULL);"),
+
+    // SQLite can't add a primary key in place so positions is rebuilt.
+    // Duplicate rows left by older code are merged at their average cost.
+    (2, "positions primary key, exchange book index", "
+        CREATE TABLE positions_new (
+            id   INTEGER NOT NULL,
+            ticker  TEXT NOT NULL,
+            qty    FLOAT NOT NULL,
+            price  FLOAT NOT NULL,
+            PRIMARY KEY (id, ticker));
+        INSERT INTO positions_new
+            SELECT id, ticker, SUM(qty), SUM(qty*price)/SUM(qty) FROM positions
+            GROUP BY id, ticker HAVING SUM(qty) != 0;
+        DROP TABLE positions;
+        ALTER TABLE positions_new RENAME TO positions;
+
+        CREATE INDEX IF NOT EXISTS exchange_ticker_price ON exchange (ticker, price);"),
+
+    (3, "daily portfolio history", "
+        CREATE TABLE IF NOT EXISTS history (
+            id   INTEGER NOT NULL,
+            time INTEGER NOT NULL,
+            cash   FLOAT NOT NULL,
+            long   FLOAT NOT NULL,
+            short  FLOAT NOT NULL,
+            yolo   FLOAT NOT NULL,
+            PRIMARY KEY (id, time));
+
+        CREATE TABLE IF NOT EXISTS history_positions (
+            id   INTEGER NOT NULL,
+            time INTEGER NOT NULL,
+            ticker  TEXT NOT NULL,
+            qty    FLOAT NOT NULL,
+            price  FLOAT NOT NULL,
+            PRIMARY KEY (id, time, ticker));"),
+
+    // Existing positions become one lot each at their blended cost
+    (4, "tax lots and realized gains", "
+        CREATE TABLE IF NOT EXISTS lots (
+            id   INTEGER NOT NULL,
+            ticker  TEXT NOT NULL,
+            qty    FLOAT NOT NULL,
+            price  FLOAT NOT NULL,
+            time INTEGER NOT NULL);
+        CREATE INDEX IF NOT EXISTS lots_id_ticker ON lots (id, ticker);
+
+        CREATE TABLE IF NOT EXISTS realized (
+            id   INTEGER NOT NULL,
+            ticker  TEXT NOT NULL,
+            qty    FLOAT NOT NULL,
+            basis  FLOAT NOT NULL,
+            price  FLOAT NOT NULL,
+            gain   FLOAT NOT NULL,
+            time INTEGER NOT NULL);
+        CREATE INDEX IF NOT EXISTS realized_id_ticker ON realized (id, ticker);
+
+        CREATE TABLE IF NOT EXISTS lotmethods (
+            id   INTEGER NOT NULL UNIQUE,
+            method  TEXT NOT NULL);
+
+        INSERT INTO lots SELECT id, ticker, qty, price, 0 FROM positions;"),
+
+    (5, "applied corporate actions", "
+        CREATE TABLE IF NOT EXISTS corpactions (
+            ticker  TEXT NOT NULL,
+            time INTEGER NOT NULL,
+            kind    TEXT NOT NULL,
+            value  FLOAT NOT NULL,
+            applied INTEGER NOT NULL,
+            PRIMARY KEY (ticker, time, kind));"),
+
+    (6, "margin interest and calls", "
+        CREATE TABLE IF NOT EXISTS interest (
+            id   INTEGER NOT NULL,
+            time INTEGER NOT NULL,
+            amount FLOAT NOT NULL,
+            PRIMARY KEY (id, time));
+
+        CREATE TABLE IF NOT EXISTS margin_calls (
+            id   INTEGER NOT NULL,
+            time INTEGER NOT NULL,
+            equity      FLOAT NOT NULL,
+            requirement FLOAT NOT NULL,
+            kind         TEXT NOT NULL);"),
+
+    // Existing shorts borrow at the default rate from the migration on
+    (7, "short borrows and fees", "
+        CREATE TABLE IF NOT EXISTS borrows (
+            id   INTEGER NOT NULL,
+            ticker  TEXT NOT NULL,
+            qty    FLOAT NOT NULL,
+            rate   FLOAT NOT NULL,
+            time INTEGER NOT NULL,
+            PRIMARY KEY (id, ticker));
+
+        CREATE TABLE IF NOT EXISTS borrowrates (
+            ticker TEXT NOT NULL UNIQUE,
+            rate  FLOAT NOT NULL);
+
+        CREATE TABLE IF NOT EXISTS borrowfees (
+            id   INTEGER NOT NULL,
+            ticker  TEXT NOT NULL,
+            time INTEGER NOT NULL,
+            qty    FLOAT NOT NULL,
+            price  FLOAT NOT NULL,
+            fee    FLOAT NOT NULL,
+            PRIMARY KEY (id, ticker, time));
+
+        INSERT INTO borrows SELECT id, ticker, -qty, 0.03, strftime('%s','now') FROM positions
+            WHERE qty < 0 AND ticker NOT GLOB '[0-9]*' AND ticker NOT LIKE '@%';"),
+
+    // Cached quotes so far were all taken as USD
+    (8, "currency balances", "
+        ALTER TABLE stonks ADD COLUMN currency TEXT NOT NULL DEFAULT 'USD';
+
+        CREATE TABLE IF NOT EXISTS balances (
+            id   INTEGER NOT NULL,
+            currency TEXT NOT NULL,
+            balance FLOAT NOT NULL,
+            PRIMARY KEY (id, currency));
+
+        CREATE TABLE IF NOT EXISTS basecurrencies (
+            id   INTEGER NOT NULL UNIQUE,
+            currency TEXT NOT NULL);"),
+
+    // REAL affinity would turn stored integers back into floats so every
+    // money and quantity column is rebuilt as INTEGER millionths, see
+    // money.rs.  Rowids are kept since lots and the exchange book order by
+    // them.  Rates and corpactions values stay FLOAT.
+    (9, "fixed point micro-units", "
+        CREATE TABLE accounts_new (
+            id    INTEGER NOT NULL UNIQUE,
+            balance INTEGER NOT NULL);
+        INSERT INTO accounts_new SELECT id, CAST(ROUND(balance*1000000) AS INTEGER) FROM accounts;
+        DROP TABLE accounts;
+        ALTER TABLE accounts_new RENAME TO accounts;
+
+        CREATE TABLE stonks_new (
+            ticker   TEXT NOT NULL UNIQUE,
+            price INTEGER NOT NULL,
+            last  INTEGER NOT NULL,
+            market   TEXT NOT NULL,
+            hours INTEGER NOT NULL,
+            exchange TEXT NOT NULL,
+            time  INTEGER NOT NULL,
+            title    TEXT NOT NULL,
+            currency TEXT NOT NULL DEFAULT 'USD');
+        INSERT INTO stonks_new SELECT ticker, CAST(ROUND(price*1000000) AS INTEGER), CAST(ROUND(last*1000000) AS INTEGER),
+            market, hours, exchange, time, title, currency FROM stonks;
+        DROP TABLE stonks;
+        ALTER TABLE stonks_new RENAME TO stonks;
+
+        CREATE TABLE orders_new (
+            id   INTEGER NOT NULL,
+            ticker  TEXT NOT NULL,
+            qty  INTEGER NOT NULL,
+            price INTEGER NOT NULL,
+            time INTEGER NOT NULL);
+        INSERT INTO orders_new (rowid, id, ticker, qty, price, time)
+            SELECT rowid, id, ticker, CAST(ROUND(qty*1000000) AS INTEGER), CAST(ROUND(price*1000000) AS INTEGER), time FROM orders;
+        DROP TABLE orders;
+        ALTER TABLE orders_new RENAME TO orders;
+
+        CREATE TABLE positions_new (
+            id   INTEGER NOT NULL,
+            ticker  TEXT NOT NULL,
+            qty  INTEGER NOT NULL,
+            price INTEGER NOT NULL,
+            PRIMARY KEY (id, ticker));
+        INSERT INTO positions_new
+            SELECT id, ticker, CAST(ROUND(qty*1000000) AS INTEGER), CAST(ROUND(price*1000000) AS INTEGER) FROM positions;
+        DROP TABLE positions;
+        ALTER TABLE positions_new RENAME TO positions;
+
+        CREATE TABLE exchange_new (
+            id   INTEGER NOT NULL,
+            ticker  TEXT NOT NULL,
+            qty  INTEGER NOT NULL,
+            price INTEGER NOT NULL,
+            time INTEGER NOT NULL);
+        INSERT INTO exchange_new (rowid, id, ticker, qty, price, time)
+            SELECT rowid, id, ticker, CAST(ROUND(qty*1000000) AS INTEGER), CAST(ROUND(price*1000000) AS INTEGER), time FROM exchange;
+        DROP TABLE exchange;
+        ALTER TABLE exchange_new RENAME TO exchange;
+        CREATE INDEX IF NOT EXISTS exchange_ticker_price ON exchange (ticker, price);
+
+        CREATE TABLE limits_new (
+            id   INTEGER NOT NULL,
+            at   INTEGER NOT NULL,
+            ticker  TEXT NOT NULL,
+            qty  INTEGER NOT NULL,
+            price INTEGER NOT NULL,
+            kind    TEXT NOT NULL,
+            time INTEGER NOT NULL);
+        INSERT INTO limits_new (rowid, id, at, ticker, qty, price, kind, time)
+            SELECT rowid, id, at, ticker, CAST(ROUND(qty*1000000) AS INTEGER), CAST(ROUND(price*1000000) AS INTEGER), kind, time FROM limits;
+        DROP TABLE limits;
+        ALTER TABLE limits_new RENAME TO limits;
+
+        CREATE TABLE history_new (
+            id   INTEGER NOT NULL,
+            time INTEGER NOT NULL,
+            cash  INTEGER NOT NULL,
+            long  INTEGER NOT NULL,
+            short INTEGER NOT NULL,
+            yolo  INTEGER NOT NULL,
+            PRIMARY KEY (id, time));
+        INSERT INTO history_new SELECT id, time, CAST(ROUND(cash*1000000) AS INTEGER), CAST(ROUND(long*1000000) AS INTEGER),
+            CAST(ROUND(short*1000000) AS INTEGER), CAST(ROUND(yolo*1000000) AS INTEGER) FROM history;
+        DROP TABLE history;
+        ALTER TABLE history_new RENAME TO history;
+
+        CREATE TABLE history_positions_new (
+            id   INTEGER NOT NULL,
+            time INTEGER NOT NULL,
+            ticker  TEXT NOT NULL,
+            qty  INTEGER NOT NULL,
+            price INTEGER NOT NULL,
+            PRIMARY KEY (id, time, ticker));
+        INSERT INTO history_positions_new
+            SELECT id, time, ticker, CAST(ROUND(qty*1000000) AS INTEGER), CAST(ROUND(price*1000000) AS INTEGER) FROM history_positions;
+        DROP TABLE history_positions;
+        ALTER TABLE history_positions_new RENAME TO history_positions;
+
+        CREATE TABLE lots_new (
+            id   INTEGER NOT NULL,
+            ticker  TEXT NOT NULL,
+            qty  INTEGER NOT NULL,
+            price INTEGER NOT NULL,
+            time INTEGER NOT NULL);
+        INSERT INTO lots_new (rowid, id, ticker, qty, price, time)
+            SELECT rowid, id, ticker, CAST(ROUND(qty*1000000) AS INTEGER), CAST(ROUND(price*1000000) AS INTEGER), time FROM lots;
+        DROP TABLE lots;
+        ALTER TABLE lots_new RENAME TO lots;
+        CREATE INDEX IF NOT EXISTS lots_id_ticker ON lots (id, ticker);
+
+        CREATE TABLE realized_new (
+            id   INTEGER NOT NULL,
+            ticker  TEXT NOT NULL,
+            qty   INTEGER NOT NULL,
+            basis INTEGER NOT NULL,
+            price INTEGER NOT NULL,
+            gain  INTEGER NOT NULL,
+            time  INTEGER NOT NULL);
+        INSERT INTO realized_new (rowid, id, ticker, qty, basis, price, gain, time)
+            SELECT rowid, id, ticker, CAST(ROUND(qty*1000000) AS INTEGER), CAST(ROUND(basis*1000000) AS INTEGER),
+                CAST(ROUND(price*1000000) AS INTEGER), CAST(ROUND(gain*1000000) AS INTEGER), time FROM realized;
+        DROP TABLE realized;
+        ALTER TABLE realized_new RENAME TO realized;
+        CREATE INDEX IF NOT EXISTS realized_id_ticker ON realized (id, ticker);
+
+        CREATE TABLE interest_new (
+            id   INTEGER NOT NULL,
+            time INTEGER NOT NULL,
+            amount INTEGER NOT NULL,
+            PRIMARY KEY (id, time));
+        INSERT INTO interest_new SELECT id, time, CAST(ROUND(amount*1000000) AS INTEGER) FROM interest;
+        DROP TABLE interest;
+        ALTER TABLE interest_new RENAME TO interest;
+
+        CREATE TABLE margin_calls_new (
+            id   INTEGER NOT NULL,
+            time INTEGER NOT NULL,
+            equity      INTEGER NOT NULL,
+            requirement INTEGER NOT NULL,
+            kind         TEXT NOT NULL);
+        INSERT INTO margin_calls_new SELECT id, time, CAST(ROUND(equity*1000000) AS INTEGER),
+            CAST(ROUND(requirement*1000000) AS INTEGER), kind FROM margin_calls;
+        DROP TABLE margin_calls;
+        ALTER TABLE margin_calls_new RENAME TO margin_calls;
+
+        CREATE TABLE borrows_new (
+            id   INTEGER NOT NULL,
+            ticker  TEXT NOT NULL,
+            qty  INTEGER NOT NULL,
+            rate   FLOAT NOT NULL,
+            time INTEGER NOT NULL,
+            PRIMARY KEY (id, ticker));
+        INSERT INTO borrows_new SELECT id, ticker, CAST(ROUND(qty*1000000) AS INTEGER), rate, time FROM borrows;
+        DROP TABLE borrows;
+        ALTER TABLE borrows_new RENAME TO borrows;
+
+        CREATE TABLE borrowfees_new (
+            id   INTEGER NOT NULL,
+            ticker  TEXT NOT NULL,
+            time INTEGER NOT NULL,
+            qty  INTEGER NOT NULL,
+            price INTEGER NOT NULL,
+            fee  INTEGER NOT NULL,
+            PRIMARY KEY (id, ticker, time));
+        INSERT INTO borrowfees_new SELECT id, ticker, time, CAST(ROUND(qty*1000000) AS INTEGER),
+            CAST(ROUND(price*1000000) AS INTEGER), CAST(ROUND(fee*1000000) AS INTEGER) FROM borrowfees;
+        DROP TABLE borrowfees;
+        ALTER TABLE borrowfees_new RENAME TO borrowfees;
+
+        CREATE TABLE balances_new (
+            id   INTEGER NOT NULL,
+            currency TEXT NOT NULL,
+            balance INTEGER NOT NULL,
+            PRIMARY KEY (id, currency));
+        INSERT INTO balances_new SELECT id, currency, CAST(ROUND(balance*1000000) AS INTEGER) FROM balances;
+        DROP TABLE balances;
+        ALTER TABLE balances_new RENAME TO balances;"),
+
+    (10, "price alerts", "
+        CREATE TABLE IF NOT EXISTS alerts (
+            id   INTEGER NOT NULL,
+            at   INTEGER NOT NULL,
+            ticker  TEXT NOT NULL,
+            kind    TEXT NOT NULL,
+            price INTEGER NOT NULL,
+            percent FLOAT NOT NULL,
+            time INTEGER NOT NULL);"),
+    (11, "per chat command switches", "
+        CREATE TABLE IF NOT EXISTS commands_disabled (
+            at   INTEGER NOT NULL,
+            name    TEXT NOT NULL,
+            UNIQUE(at, name));"),
+    (12, "roles and settings", "
+        CREATE TABLE IF NOT EXISTS roles (
+            id   INTEGER NOT NULL,
+            at   INTEGER NOT NULL,
+            role    TEXT NOT NULL,
+            UNIQUE(id, at));
+
+        CREATE TABLE IF NOT EXISTS command_roles (
+            name TEXT NOT NULL UNIQUE,
+            role TEXT NOT NULL);
+
+        CREATE TABLE IF NOT EXISTS settings (
+            key   TEXT NOT NULL UNIQUE,
+            value TEXT NOT NULL);"),
+
+    (13, "sql console audit log", "
+        CREATE TABLE IF NOT EXISTS sql_audit (
+            time   INTEGER NOT NULL,
+            id     INTEGER NOT NULL,
+            at     INTEGER NOT NULL,
+            sql    TEXT NOT NULL,
+            result TEXT NOT NULL);"),
+
+    (14, "ledger events opened from current cash and positions", "
+        CREATE TABLE IF NOT EXISTS ledger_events (
+            time         INTEGER NOT NULL,
+            id           INTEGER NOT NULL,
+            kind         TEXT NOT NULL,
+            key          TEXT NOT NULL,
+            before       INTEGER NOT NULL,
+            after        INTEGER NOT NULL,
+            basis_before INTEGER NOT NULL,
+            basis_after  INTEGER NOT NULL,
+            actor        INTEGER NOT NULL,
+            at           INTEGER NOT NULL,
+            message_id   INTEGER NOT NULL);
+
+        CREATE TRIGGER IF NOT EXISTS ledger_events_no_update BEFORE UPDATE ON ledger_events
+            BEGIN SELECT RAISE(ABORT, 'ledger_events is append-only'); END;
+        CREATE TRIGGER IF NOT EXISTS ledger_events_no_delete BEFORE DELETE ON ledger_events
+            BEGIN SELECT RAISE(ABORT, 'ledger_events is append-only'); END;
+
+        INSERT INTO ledger_events SELECT 0, id, 'cash', 'USD', 0, balance, 0, 0, 0, 0, 0 FROM accounts WHERE balance<>0;
+        INSERT INTO ledger_events SELECT 0, id, 'cash', currency, 0, balance, 0, 0, 0, 0, 0 FROM balances WHERE balance<>0;
+        INSERT INTO ledger_events SELECT 0, id, 'position', ticker, 0, qty, 0, price, 0, 0, 0 FROM positions;"),
+];
+
+pub fn schema_version (dbconn:&Connection) -> Bresult<i64> {
+    getsql!(dbconn, "
+        CREATE TABLE IF NOT EXISTS schema_version (
+            version  INTEGER NOT NULL UNIQUE,
+            time     INTEGER NOT NULL,
+            description TEXT NOT NULL);")?;
+    Ok(getsql!(dbconn, "SELECT MAX(version) AS version FROM schema_version")?[0].get_i64_or(0, "version"))
+}
+
+/// Bring the database up to the latest schema, returning its version.
+pub fn migrate (dbconn:&Connection) -> Bresult<i64> {
+    let mut version = schema_version(dbconn)?;
+    let latest = MIGRATIONS.last().map_or(0, |m| m.0);
+    if latest < version { Err(format!("database schema version {} is newer than this tmbot's {}", version, latest))? }
+    for (number, description, sql) in MIGRATIONS.iter().filter( |m| version < m.0 ) {
+        warn!("schema migration {} {}", number, description);
+        dbconn.transaction( |tx| {
+            tx.conn.execute(sql)?;
+            getsql!(tx, "INSERT INTO schema_version VALUES (?, ?, ?)", *number, Instant::now().seconds(), *description)?;
+            Ok(())
+        })?;
+        version = *number;
+    }
+    Ok(version)
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn legacy () -> Connection {
+        let dbconn = Connection::new(":memory:".into()).unwrap();
+        dbconn.conn.execute("
+            CREATE TABLE positions (id INTEGER NOT NULL, ticker TEXT NOT NULL, qty FLOAT NOT NULL, price FLOAT NOT NULL);
+            INSERT INTO positions VALUES (1, 'GME', 1.0, 10.0), (1, 'GME', 3.0, 30.0), (2, 'AMC', 1.0, 5.0), (2, 'AMC', -1.0, 6.0);").unwrap();
+        assert_eq!(migrate(&dbconn).unwrap(), MIGRATIONS.last().unwrap().0);
+        dbconn
+    }
+
+    #[test]
+    fn merges_legacy_positions() {
+        let dbconn = legacy();
+        let rows = getsql!(dbconn, "SELECT * FROM positions").unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!((rows[0].get_qty("qty").unwrap(), rows[0].get_money("price").unwrap()), (Qty::from_int(4), Money::from_int(25)));
+        assert_eq!(getsql!(dbconn, "SELECT qty FROM lots").unwrap()[0].get("qty"), Some(&::sqlite::Value::Integer(4_000_000)));
+    }
+
+    #[test]
+    fn positions_keyed_by_id_and_ticker() {
+        assert!(getsql!(legacy(), "INSERT INTO positions VALUES (1, 'GME', 1e6, 1e6)").is_err());
+    }
+
+    #[test]
+    fn migrate_twice_is_a_no_op() {
+        let dbconn = legacy();
+        assert_eq!(migrate(&dbconn).unwrap(), schema_version(&dbconn).unwrap());
+    }
+
+    #[test]
+    fn seeds_ledger_with_opening_positions() {
+        let dbconn = legacy();
+        assert_eq!(getsql!(dbconn, "SELECT * FROM ledger_events").unwrap().len(), 1);
+        assert!(ledger_check(&dbconn).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,170 @@
+//! # Fixed Point Money and Quantities
+use crate::*;
+use ::std::{
+    fmt,
+    iter::Sum,
+    ops::{Add, AddAssign, Div, Mul, Neg, Sub, SubAssign},
+    str::FromStr};
+
+pub const MICROS :i64 = 1_000_000;
+
+// Round half away from zero
+fn div_round (n:i128, d:i128) -> i128 {
+    if 0 == d { return 0 }
+    let half = IF!((n < 0) == (d < 0), d.abs() / 2, -(d.abs() / 2));
+    (n + half * d.signum()) / d
+}
+
+macro_rules! fixed_point {
+    ($name:ident) => {
+        #[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+        pub struct $name(pub i64); // Millionths
+
+        impl $name {
+            pub const ZERO :$name = $name(0);
+
+            pub fn from_f64 (f:f64) -> Self { $name((f * MICROS as f64).round() as i64) }
+            pub fn to_f64 (self) -> f64 { self.0 as f64 / MICROS as f64 }
+
+            /// Round half away from zero to a number of decimal places, 0..=6
+            pub fn round (self, places:u32) -> Self {
+                $name(div_round(self.0 as i128, 10i128.pow(6 - places.min(6))) as i64 * 10i64.pow(6 - places.min(6)))
+            }
+
+            /// Multiply by a rate or ratio, to the nearest micro-unit
+            pub fn scale (self, ratio:f64) -> Self { $name((self.0 as f64 * ratio).round() as i64) }
+
+            pub fn abs (self) -> Self { $name(self.0.abs()) }
+            pub fn is_zero (self) -> bool { 0 == self.0 }
+            pub fn is_positive (self) -> bool { 0 < self.0 }
+            pub fn is_negative (self) -> bool { self.0 < 0 }
+            pub fn signum (self) -> i64 { self.0.signum() }
+        }
+
+        impl Add for $name { type Output = $name; fn add (self, b:$name) -> $name { $name(self.0 + b.0) } }
+        impl Sub for $name { type Output = $name; fn sub (self, b:$name) -> $name { $name(self.0 - b.0) } }
+        impl Neg for $name { type Output = $name; fn neg (self) -> $name { $name(-self.0) } }
+        impl AddAssign for $name { fn add_assign (&mut self, b:$name) { self.0 += b.0 } }
+        impl SubAssign for $name { fn sub_assign (&mut self, b:$name) { self.0 -= b.0 } }
+        impl Mul<i64> for $name { type Output = $name; fn mul (self, n:i64) -> $name { $name(self.0 * n) } }
+
+        impl Sum for $name {
+            fn sum<I: Iterator<Item=$name>> (iter:I) -> $name { $name(iter.map( |n| n.0 ).sum()) }
+        }
+
+        impl<'a> Sum<&'a $name> for $name {
+            fn sum<I: Iterator<Item=&'a $name>> (iter:I) -> $name { $name(iter.map( |n| n.0 ).sum()) }
+        }
+
+        /// Exact decimal with trailing zeros trimmed.  A precision rounds
+        /// and pads to that many places, width and + work as for integers.
+        impl fmt::Display for $name {
+            fn fmt (&self, f:&mut fmt::Formatter) -> fmt::Result {
+                let places = f.precision().map_or(6, |p| p.min(6));
+                let micros = self.round(places as u32).0;
+                let frac = format!("{:06}", (micros % MICROS).abs());
+                let frac = match f.precision() {
+                    Some(p) => format!("{:0<width$}", &frac[..places], width=p),
+                    None => frac.trim_end_matches('0').to_string()
+                };
+                let whole = (micros / MICROS).abs();
+                let digits = IF!(frac.is_empty(), whole.to_string(), format!("{}.{}", whole, frac));
+                f.pad_integral(0 <= micros, "", &digits)
+            }
+        }
+
+        /// Parse a decimal with up to 6 places exactly:  "12"  "-0.5"  ".0001"
+        impl FromStr for $name {
+            type Err = String;
+            fn from_str (s:&str) -> Result<Self, String> {
+                let err = || format!("Not a decimal {:?}", s);
+                let (neg, digits) = match s.strip_prefix('-') {
+                    Some(digits) => (true, digits),
+                    None => (false, s.strip_prefix('+').unwrap_or(s))
+                };
+                let (whole, frac) = match digits.find('.') {
+                    Some(i) => (&digits[..i], &digits[i+1..]),
+                    None => (digits, "")
+                };
+                if (whole.is_empty() && frac.is_empty()) || 6 < frac.len()
+                    || !whole.chars().chain(frac.chars()).all( |c| c.is_ascii_digit() ) {
+                    return Err(err())
+                }
+                let whole = IF!(whole.is_empty(), 0, whole.parse::<i64>().map_err( |_| err() )?);
+                let frac = format!("{:0<6}", frac).parse::<i64>().map_err( |_| err() )?;
+                let micros = whole.checked_mul(MICROS).and_then( |w| w.checked_add(frac) ).ok_or_else(err)?;
+                Ok($name(IF!(neg, -micros, micros)))
+            }
+        }
+    }
+}
+
+fixed_point!(Money);
+fixed_point!(Qty);
+
+impl Money {
+    pub const CENT :Money = Money(10_000);
+    pub fn cents (self) -> Self { self.round(2) }
+    pub fn from_int (dollars:i64) -> Self { Money(dollars * MICROS) }
+}
+
+impl Qty {
+    pub const TICK :Qty = Qty(100); // Smallest tradeable quantity, .0001
+    pub fn from_int (shares:i64) -> Self { Qty(shares * MICROS) }
+    /// Trades are in ten thousandths of a share
+    pub fn tradeable (self) -> Self { self.round(4) }
+}
+
+// Value of a quantity at a price, to the nearest micro-unit
+impl Mul<Money> for Qty {
+    type Output = Money;
+    fn mul (self, price:Money) -> Money { Money(div_round(self.0 as i128 * price.0 as i128, MICROS as i128) as i64) }
+}
+
+impl Mul<Qty> for Money {
+    type Output = Money;
+    fn mul (self, qty:Qty) -> Money { qty * self }
+}
+
+// Price per share, to the nearest micro-unit.  Zero when qty is zero.
+impl Div<Qty> for Money {
+    type Output = Money;
+    fn div (self, qty:Qty) -> Money { Money(div_round(self.0 as i128 * MICROS as i128, qty.0 as i128) as i64) }
+}
+
+// Shares an amount buys at a price, truncated so it never costs more than
+// the amount.  Zero when price is zero.
+impl Div<Money> for Money {
+    type Output = Qty;
+    fn div (self, price:Money) -> Qty {
+        if 0 == price.0 { return Qty::ZERO }
+        Qty((self.0 as i128 * MICROS as i128 / price.0 as i128) as i64)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exact_arithmetic() {
+        let (a, b) = ("0.1".parse::<Money>().unwrap(), "0.2".parse::<Money>().unwrap());
+        assert_eq!(a + b, "0.3".parse::<Money>().unwrap());
+        assert_eq!(Money(-9_999).cents(), Money(-10_000));
+        assert_eq!(Money(1_344_999).cents(), Money(1_340_000));
+        assert_eq!(Qty::from_int(3) * Money::from_f64(33.333333), Money(99_999_999));
+        assert_eq!(Money::from_int(100) / Qty::from_int(3), Money(33_333_333));
+        assert_eq!(Money::from_int(100) / Money::from_int(3), Qty(33_333_333));
+        assert_eq!(Money::from_int(1) / Money::ZERO, Qty::ZERO);
+        assert!("1.2345678".parse::<Qty>().is_err() && "".parse::<Qty>().is_err() && "1e3".parse::<Qty>().is_err());
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(format!("{} {} {}", Money(1_500_000), Qty(-250_000), Qty::ZERO), "1.5 -0.25 0");
+        assert_eq!(format!("{:.2} {:+} {:7.2}", Money(-1_005_000), Qty(2_000_000), Money(3_000_000)), "-1.01 +2    3.00");
+        assert_eq!(format!("{:.0}", Money(-400_000)), "0");
+    }
+}
@@ -0,0 +1,252 @@
+//! # Options Contracts
+use crate::*;
+use ::datetime::Month;
+
+pub const OPTION_MULTIPLIER :i64 = 100; // Shares per contract
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OptionKind { Call, Put }
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OptionContract {
+    pub underlying: String,
+    pub expiry: i64, // Midnight UTC of the expiration date
+    pub kind: OptionKind,
+    pub strike: Money
+}
+
+impl OptionContract {
+    pub fn parse (ticker:&str) -> Option<Self> {
+        let caps = regex_to_vec(r"^([A-Z^.]{1,6})(\d\d)(\d\d)(\d\d)([CP])(\d{8})$", ticker).ok()?;
+        if caps.is_empty() { return None }
+        let month = Month::from_one(caps.as_i64(3).ok()? as i8).ok()?;
+        let date = LocalDate::ymd(2000 + caps.as_i64(2).ok()?, month, caps.as_i64(4).ok()? as i8).ok()?;
+        Some(OptionContract{
+            underlying: caps.as_string(1).ok()?,
+            expiry: LocalDateTime::new(date, LocalTime::midnight()).to_instant().seconds(),
+            kind: IF!(caps.as_str(5).ok()? == "C", OptionKind::Call, OptionKind::Put),
+            strike: Money(caps.as_i64(6).ok()? * 1000)
+        })
+    }
+
+    pub fn expiry_str (&self) -> String {
+        time2datetimestr(self.expiry)[0..10].to_string()
+    }
+
+    /// Per share value when the underlying trades at price
+    pub fn intrinsic (&self, price:Money) -> Money {
+        match self.kind {
+            OptionKind::Call => (price - self.strike).max(Money::ZERO),
+            OptionKind::Put => (self.strike - price).max(Money::ZERO)
+        }
+    }
+
+    pub fn title (&self) -> String {
+        format!("{} {} {} {}", self.underlying, self.expiry_str(), self.strike, IF!(self.kind == OptionKind::Call, "Call", "Put"))
+    }
+}
+
+pub fn is_option (ticker:&str) -> bool {
+    OptionContract::parse(ticker).is_some()
+}
+
+/// Find a contract in a Yahoo style option chain:
+///   {"optionChain":{"result":[{"options":[{"calls":[{"contractSymbol":"GME240119C00020000","lastPrice":1.5,"change":0.1,"lastTradeDate":1705611600}],"puts":[]}]}]}}
+/// and restate it as a v7 quote so Quote::new_market_quote parses it like
+/// any other.
+pub fn option_quote_raw (chain:&Value, ticker:&str) -> Bresult<Value> {
+    let contract = OptionContract::parse(ticker).ok_or_else( || format!("{} is not an option symbol", ticker) )?;
+    let options = &chain["optionChain"]["result"][0]["options"][0];
+    let details =
+        ["calls", "puts"].iter()
+        .filter_map( |side| options[*side].as_array() )
+        .flatten()
+        .find( |o| o["contractSymbol"].as_str() == Some(ticker) )
+        .ok_or_else( || format!("option chain has no {}", ticker) )?;
+    let price = Money::from_f64(getin_f64(details, &["lastPrice"])?);
+    let change = Money::from_f64(getin_f64(details, &["change"]).unwrap_or(0.0));
+    Ok(serde_json::json!({"quoteResponse": {"result": [{
+        "symbol": ticker,
+        "shortName": contract.title(),
+        "exchange": "OPR",
+        "regularMarketPrice": (price * OPTION_MULTIPLIER).cents().to_f64(),
+        "regularMarketPreviousClose": ((price - change) * OPTION_MULTIPLIER).cents().to_f64(),
+        "regularMarketTime": getin_i64_or(Instant::now().seconds(), details, &["lastTradeDate"])
+    }]}}))
+}
+
+// Exercise qty contracts at the strike through the regular trade path,
+// closing the option position in the trade's transaction
+async fn option_exercise (cmdstruct:&mut CmdStruct, ticker:&str, contract:&OptionContract, qty:Qty) -> Bresult<String> {
+    let strike = contract.strike;
+    cmdstruct.fills = Some(Fills::Exercise(ticker.to_string(), qty));
+    let trade = Trade{
+        cmdstruct,
+        ticker: contract.underlying.to_string(),
+        action: IF!(contract.kind == OptionKind::Call, '+', '-'),
+        amt: Some(Amount::Shares(qty * OPTION_MULTIPLIER)) };
+    let msg = match contract.kind {
+        OptionKind::Call => {
+            let mut tradebuy = TradeBuy::new_tradebuy(trade).await?;
+            if let Some(quote) = tradebuy.position.quote.as_mut() { quote.price = strike }
+            ExecuteBuy::execute(TradeBuyCalc::compute_position(tradebuy).await?)?.msg
+        },
+        OptionKind::Put => {
+            let mut tradesell = TradeSell::new_tradesell(trade).await?;
+            if let Some(quote) = tradesell.position.quote.as_mut() { quote.price = strike }
+            tradesell.price = strike;
+            tradesell.new_balance = tradesell.bank_balance + tradesell.qty * strike;
+            ExecuteSell::execute(tradesell)?.msg
+        }
+    };
+    if msg.starts_with("Unable") || msg.contains("exceeds buying power") { Err(msg)? }
+    Ok(msg)
+}
+
+// The underlying's close on the contract's expiration date
+async fn expiry_close (cmdstruct:&CmdStruct, contract:&OptionContract) -> Bresult<Money> {
+    let provider = getenvstruct!(cmdstruct).quotes.clone();
+    let chart = provider.get_closes_raw(&contract.underlying, contract.expiry - 7*86400, contract.expiry + 86400).await?;
+    chart_close(&chart, contract.expiry + 86400)
+}
+
+/// Settle every option position whose expiration date has passed against
+/// the underlying's close that day.  In the money contracts are exercised,
+/// or closed at their intrinsic value when the account can't take the
+/// shares.  The rest expire worthless.  A position that fails to settle is
+/// logged and retried next time.
+pub async fn options_settle (env:Env, now:i64) -> Bresult<()> {
+    let positions = {
+        let envstruct = env.lock().unwrap();
+        getsql!(envstruct.dbconn, "SELECT id, ticker, qty FROM positions")?
+    };
+    for row in positions {
+        let (id, ticker, qty) = (row.get_i64("id")?, row.get_string("ticker")?, row.get_qty("qty")?);
+        let contract = match OptionContract::parse(&ticker) {
+            Some(contract) if contract.expiry + 86400 <= now => contract,
+            _ => continue
+        };
+        let mut cmdstruct =
+            match CmdStruct::new_cmdstruct(env.clone(), now, id, id, id, 0, "") {
+                Ok(cmdstruct) => cmdstruct,
+                e => { glog!(e); continue }
+            };
+        let price = match expiry_close(&cmdstruct, &contract).await {
+            Ok(price) => price,
+            e => { glogd!("options_settle close =>", e); continue }
+        };
+        let intrinsic = contract.intrinsic(price);
+        info!("\x1b[1moption expired {} {} underlying {} intrinsic {}", id, ticker, price, intrinsic);
+
+        let mut msg = format!("*Expired:* `{}` {}", ticker, qty);
+        let mut settle_price = Money::ZERO; // Per contract cash paid out
+        let mut exercised = false; // The exercise closed the position
+        if intrinsic.is_positive() {
+            cmdstruct.markdown();
+            match option_exercise(&mut cmdstruct, &ticker, &contract, qty).await {
+                Ok(trade) => {
+                    exercised = true;
+                    msg = format!("*Exercised:* `{}` {}\n{}", ticker, qty, trade)
+                },
+                Err(e) => {
+                    warn!("options_settle exercise {} {} => {:?}", id, ticker, e);
+                    settle_price = (intrinsic * OPTION_MULTIPLIER).cents();
+                    msg = format!("*Cash settled:* `{}` {}@{}", ticker, qty, settle_price);
+                }
+            }
+        }
+        if !exercised {
+            let closed = getenvstruct!(cmdstruct).transaction( |envstruct| {
+                sql_table_order_insert(&envstruct.dbconn, id, &ticker, -qty, settle_price, now)?;
+                position_set(&envstruct.dbconn, Origin::system(now), id, &ticker, Qty::ZERO, Money::ZERO)?;
+                crash_point("options_settle")?;
+                if settle_price.is_positive() { cash_inc(envstruct, Origin::system(now), id, USD, qty * settle_price)? }
+                Ok(())
+            });
+            if let Err(e) = closed { warn!("options_settle close {} {} => {:?}", id, ticker, e); continue }
+        }
+        cmdstruct.markdown().set_msg(&msg);
+        glogd!("options_settle send_msg =>", cmdstruct.send_msg().await);
+    }
+    Ok(())
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn occ_symbols() {
+        let call = OptionContract::parse("GME240119C00020000").unwrap();
+        assert_eq!((call.underlying.as_str(), call.kind, call.strike, call.expiry_str().as_str()), ("GME", OptionKind::Call, Money::from_int(20), "2024-01-19"));
+        assert_eq!(OptionContract::parse("SPY241220P00412500").unwrap().strike, Money::from_f64(412.5));
+        assert!(OptionContract::parse("GME").is_none() && OptionContract::parse("GME241320C00020000").is_none());
+        assert_eq!((call.intrinsic(Money::from_int(25)), call.intrinsic(Money::from_int(15))), (Money::from_int(5), Money::ZERO));
+
+        let chain = serde_json::json!({"optionChain": {"result": [{"options": [{"calls": [], "puts": [
+            {"contractSymbol": "GME240119P00020000", "lastPrice": 1.25, "change": 0.25, "lastTradeDate": 7}]}]}]}});
+        let quote = option_quote_raw(&chain, "GME240119P00020000").unwrap();
+        let details = &quote["quoteResponse"]["result"][0];
+        assert_eq!((details["regularMarketPrice"].as_f64(), details["regularMarketPreviousClose"].as_f64()), (Some(125.0), Some(100.0)));
+        assert!(option_quote_raw(&chain, "GME240119C00020000").is_err());
+    }
+
+    // Env with id 1 holding one contract, GME having closed at close on its
+    // expiration date, and the time two days after
+    fn expired_env (ticker:&str, close:f64) -> (Env, i64) {
+        let mut envstruct = test_envstruct();
+        let expiry = OptionContract::parse(ticker).unwrap().expiry;
+        getsql!(envstruct.dbconn, "INSERT INTO positions VALUES (1, ?, ?, ?)", ticker, Qty::from_int(1), Money::from_int(100)).unwrap();
+        envstruct.quotes = Arc::new(FixtureQuotes::from_value(serde_json::json!(
+            {"gme": {"regularMarketPrice": 10.0, "regularMarketPreviousClose": 9.0, "volume24Hr": 1, "currency": "USD",
+                     "closes": [[expiry - 86400 + 52200, 1.0], [expiry + 52200, close]]}})).unwrap());
+        (envstruct.into(), expiry + 2*86400)
+    }
+
+    #[test]
+    fn call_exercised_at_expiry_close() {
+        let (env, now) = expired_env("GME240119C00005000", 8.0);
+        futures::executor::block_on(options_settle(env.clone(), now)).unwrap();
+        let envstruct = env.lock().unwrap();
+        assert_eq!(envstruct.entity_balance(1).unwrap(), Money::from_int(500));
+        assert_eq!(test_positions(&envstruct.dbconn, 1), vec![("GME".to_string(), Qty::from_int(100))]);
+    }
+
+    #[test]
+    fn put_exercised_at_expiry_close() {
+        let (env, now) = expired_env("GME240119P00010000", 8.0);
+        futures::executor::block_on(options_settle(env.clone(), now)).unwrap();
+        let envstruct = env.lock().unwrap();
+        assert_eq!(envstruct.entity_balance(1).unwrap(), Money::from_int(2000));
+        assert_eq!(test_positions(&envstruct.dbconn, 1), vec![("GME".to_string(), Qty::from_int(-100))]);
+    }
+
+    #[test]
+    fn worthless_at_expiry_close() {
+        let (env, now) = expired_env("GME240119C00005000", 4.0); // Though GME now trades at 10
+        futures::executor::block_on(options_settle(env.clone(), now)).unwrap();
+        let envstruct = env.lock().unwrap();
+        assert_eq!(envstruct.entity_balance(1).unwrap(), Money::from_int(1000));
+        assert!(test_positions(&envstruct.dbconn, 1).is_empty());
+    }
+
+    #[test]
+    fn failed_exercise_settled_in_cash() {
+        let (env, now) = expired_env("GME240119C00005000", 8.0);
+        crashing_at("execute_buy", || futures::executor::block_on(options_settle(env.clone(), now))).unwrap();
+        let envstruct = env.lock().unwrap();
+        assert_eq!(envstruct.entity_balance(1).unwrap(), Money::from_int(1300)); // The intrinsic value
+        assert!(test_positions(&envstruct.dbconn, 1).is_empty());
+    }
+
+    #[test]
+    fn failed_close_kept_for_next_time() {
+        let (env, now) = expired_env("GME240119C00005000", 4.0);
+        crashing_at("options_settle", || futures::executor::block_on(options_settle(env.clone(), now))).unwrap();
+        let envstruct = env.lock().unwrap();
+        assert_eq!(envstruct.entity_balance(1).unwrap(), Money::from_int(1000));
+        assert_eq!(test_positions(&envstruct.dbconn, 1), vec![("GME240119C00005000".to_string(), Qty::from_int(1))]);
+    }
+}
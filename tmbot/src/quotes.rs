@@ -0,0 +1,238 @@
+//! # Market Quote Providers
+use crate::*;
+use ::std::{fmt, fs, sync::MutexGuard};
+use ::futures::future::{LocalBoxFuture, FutureExt};
+
+/// A source of raw ticker details.  Every provider answers in the shape of
+/// Yahoo's v7 quote endpoint, {"quoteResponse":{"result":[{...}]}}, so
+/// Quote::new_market_quote parses all of them the same way.
+/// Batches answer with a result for each ticker found, in any order.
+/// Option chains answer in the shape of Yahoo's v7 options endpoint, see
+/// option_quote_raw.  Daily closes answer in the shape of Yahoo's v8 chart
+/// endpoint, see chart_close.
+pub trait QuoteProvider: fmt::Debug + Send + Sync {
+    fn get_ticker_raw<'a> (&'a self, ticker: &'a str) -> LocalBoxFuture<'a, Bresult<Value>>;
+    fn get_tickers_raw<'a> (&'a self, tickers: &'a [String]) -> LocalBoxFuture<'a, Bresult<Value>>;
+    fn get_option_chain_raw<'a> (&'a self, ticker: &'a str, expiry: i64) -> LocalBoxFuture<'a, Bresult<Value>>;
+    fn get_closes_raw<'a> (&'a self, ticker: &'a str, since: i64, until: i64) -> LocalBoxFuture<'a, Bresult<Value>>;
+}
+
+/// The last daily close before time in a Yahoo style chart:
+///   {"chart":{"result":[{"timestamp":[1705588200],"indicators":{"quote":[{"close":[25.5]}]}}]}}
+pub fn chart_close (chart:&Value, before:i64) -> Bresult<Money> {
+    let result = &chart["chart"]["result"][0];
+    let times = result["timestamp"].as_array().ok_or("chart has no timestamps")?;
+    let closes = result["indicators"]["quote"][0]["close"].as_array().ok_or("chart has no closes")?;
+    times.iter().zip(closes)
+        .filter_map( |(time, close)| Some((time.as_i64()?, close.as_f64()?)) ) // Closes are null on days without trades
+        .filter( |(time, _)| *time < before )
+        .last()
+        .map( |(_, close)| Money::from_f64(close) )
+        .ok_or_else( || format!("chart has no close before {}", before).into() )
+}
+
+////////////////////////////////////////
+
+/// The live Yahoo Finance quote service.
+#[derive(Debug)]
+pub struct YahooQuotes {
+    http: Arc<HttpService>
+}
+
+impl QuoteProvider for YahooQuotes {
+    fn get_ticker_raw<'a> (&'a self, ticker: &'a str) -> LocalBoxFuture<'a, Bresult<Value>> {
+        srvs::get_ticker_raw(&self.http, ticker).boxed_local()
+    }
+    fn get_tickers_raw<'a> (&'a self, tickers: &'a [String]) -> LocalBoxFuture<'a, Bresult<Value>> {
+        srvs::get_tickers_raw(&self.http, tickers).boxed_local()
+    }
+    fn get_option_chain_raw<'a> (&'a self, ticker: &'a str, expiry: i64) -> LocalBoxFuture<'a, Bresult<Value>> {
+        srvs::get_option_chain_raw(&self.http, ticker, expiry).boxed_local()
+    }
+    fn get_closes_raw<'a> (&'a self, ticker: &'a str, since: i64, until: i64) -> LocalBoxFuture<'a, Bresult<Value>> {
+        srvs::get_closes_raw(&self.http, ticker, since, until).boxed_local()
+    }
+}
+
+////////////////////////////////////////
+
+/// A provider's JSON fixture parsed into T.  One read from a file is re-read
+/// on every lookup so it can be edited while the bot is running.
+pub struct FixtureFile<T> {
+    filename: Option<String>,
+    parse: fn(Value) -> Bresult<T>,
+    data: Mutex<T>
+}
+
+impl<T> FixtureFile<T> {
+    pub fn new (filename: &str, parse: fn(Value) -> Bresult<T>) -> Bresult<Self> {
+        let data = parse(bytes2json(fs::read_to_string(filename)?.as_bytes())?)?;
+        Ok(FixtureFile{ filename: Some(filename.to_string()), parse, data: Mutex::new(data) })
+    }
+
+    pub fn from_value (json: Value, parse: fn(Value) -> Bresult<T>) -> Bresult<Self> {
+        Ok(FixtureFile{ filename: None, parse, data: Mutex::new(parse(json)?) })
+    }
+
+    pub fn filename (&self) -> Option<&str> { self.filename.as_deref() }
+
+    pub fn reload (&self) -> Bresult<()> {
+        if let Some(filename) = &self.filename {
+            let fresh = (self.parse)(bytes2json(fs::read_to_string(filename)?.as_bytes())?)?;
+            *self.data.lock().unwrap() = fresh;
+        }
+        Ok(())
+    }
+
+    // The data as last read
+    pub fn cached (&self) -> MutexGuard<T> { self.data.lock().unwrap() }
+
+    // The data re-read from the file
+    pub fn fresh (&self) -> MutexGuard<T> {
+        glogd!("Fixture reload =>", self.reload());
+        self.cached()
+    }
+}
+
+// The fixture file an environment variable names, if it's set
+pub fn fixture_filename (var: &str) -> Option<String> {
+    let filename = env::var(var).ok()?;
+    info!("{} fixture {:?}", var, filename);
+    Some(filename)
+}
+
+////////////////////////////////////////
+
+/// Offline quotes from a JSON fixture of Yahoo style quote objects keyed by
+/// ticker symbol:
+///   {"GME": {"longName":"GameStop", "exchange":"NYQ", "regularMarketPrice":150.0, "regularMarketPreviousClose":140.0}}
+/// Missing "exchange" and "regularMarketTime" fields are filled in so the
+/// quote is always current.  Daily closes are [time, price] pairs, and
+/// without them the current price stands in for every close:
+///   {"GME": {"regularMarketPrice":150.0, "closes":[[1705588200, 145.0]]}}
+/// Option contracts are keyed by OCC symbol with Yahoo option chain fields:
+///   {"GME240119C00020000": {"lastPrice":1.5, "change":0.1}}
+pub struct FixtureQuotes {
+    quotes: FixtureFile<HashMap<String, Value>>
+}
+
+impl FixtureQuotes {
+    fn parse (json: Value) -> Bresult<HashMap<String, Value>> {
+        Ok(json.as_object().ok_or("fixture quotes must be a JSON object")?
+            .iter()
+            .map( |(ticker, details)| (ticker.to_uppercase(), details.clone()) )
+            .collect())
+    }
+
+    pub fn new (filename: &str) -> Bresult<Self> {
+        Ok(FixtureQuotes{ quotes: FixtureFile::new(filename, FixtureQuotes::parse)? })
+    }
+
+    // Fixture that only lives in memory.  Tests populate it with set_quote().
+    pub fn from_value (json: Value) -> Bresult<Self> {
+        Ok(FixtureQuotes{ quotes: FixtureFile::from_value(json, FixtureQuotes::parse)? })
+    }
+
+    pub fn set_quote (&self, ticker: &str, details: Value) {
+        self.quotes.cached().insert(ticker.to_uppercase(), details);
+    }
+
+    fn lookup (&self, ticker: &str) -> Bresult<Value> {
+        let mut details =
+            self.quotes.fresh()
+            .get(&ticker.to_uppercase())
+            .ok_or_else( || format!("fixture has no quote for {}", ticker) )?
+            .clone();
+        let obj = details.as_object_mut().ok_or("fixture quote must be a JSON object")?;
+        obj.entry("symbol").or_insert_with( || Value::from(ticker) );
+        obj.entry("shortName").or_insert_with( || Value::from(ticker) );
+        obj.entry("exchange").or_insert_with( || Value::from("FIX") );
+        obj.entry("regularMarketTime").or_insert_with( || Value::from(Instant::now().seconds()) );
+        Ok(serde_json::json!({"quoteResponse": {"result": [details]}}))
+    }
+
+    // Every ticker the fixture has a quote for
+    fn lookup_all (&self, tickers: &[String]) -> Bresult<Value> {
+        let mut results = Vec::new();
+        for ticker in tickers {
+            match self.lookup(ticker) {
+                Ok(json) => results.push(json["quoteResponse"]["result"][0].clone()),
+                e => glogd!("FixtureQuotes lookup =>", e)
+            }
+        }
+        Ok(serde_json::json!({"quoteResponse": {"result": results}}))
+    }
+
+    // Gather the fixture's contracts on one underlying and expiry
+    fn chain (&self, ticker: &str, expiry: i64) -> Bresult<Value> {
+        let (mut calls, mut puts) = (Vec::new(), Vec::new());
+        for (symbol, details) in self.quotes.fresh().iter() {
+            let contract = match OptionContract::parse(symbol) {
+                Some(contract) if contract.underlying == ticker.to_uppercase() && contract.expiry == expiry => contract,
+                _ => continue
+            };
+            let mut details = details.clone();
+            let obj = details.as_object_mut().ok_or("fixture option must be a JSON object")?;
+            obj.insert("contractSymbol".into(), Value::from(symbol.as_str()));
+            obj.insert("strike".into(), Value::from(contract.strike));
+            obj.insert("expiration".into(), Value::from(expiry));
+            if contract.kind == OptionKind::Call { calls.push(details) } else { puts.push(details) }
+        }
+        Ok(serde_json::json!({"optionChain": {"result": [{"options": [{"calls": calls, "puts": puts}]}]}}))
+    }
+
+    // A ticker's daily closes from since until until as a chart
+    fn closes (&self, ticker: &str, since: i64, until: i64) -> Bresult<Value> {
+        let details = self.lookup(ticker)?["quoteResponse"]["result"][0].clone();
+        let closes :Vec<(i64, f64)> = match details["closes"].as_array() {
+            Some(closes) => closes.iter()
+                .filter_map( |close| Some((close[0].as_i64()?, close[1].as_f64()?)) )
+                .filter( |(time, _)| since <= *time && *time < until )
+                .collect(),
+            None => vec![(since, getin_f64(&details, &["regularMarketPrice"])?)]
+        };
+        Ok(serde_json::json!({"chart": {"result": [{
+            "timestamp": closes.iter().map( |(time, _)| *time ).collect::<Vec<i64>>(),
+            "indicators": {"quote": [{"close": closes.iter().map( |(_, close)| *close ).collect::<Vec<f64>>()}]}
+        }]}}))
+    }
+}
+
+impl QuoteProvider for FixtureQuotes {
+    fn get_ticker_raw<'a> (&'a self, ticker: &'a str) -> LocalBoxFuture<'a, Bresult<Value>> {
+        info!("FixtureQuotes <- {}", ticker);
+        futures::future::ready(self.lookup(ticker)).boxed_local()
+    }
+    fn get_tickers_raw<'a> (&'a self, tickers: &'a [String]) -> LocalBoxFuture<'a, Bresult<Value>> {
+        info!("FixtureQuotes <- {}", tickers.join(","));
+        futures::future::ready(self.lookup_all(tickers)).boxed_local()
+    }
+    fn get_option_chain_raw<'a> (&'a self, ticker: &'a str, expiry: i64) -> LocalBoxFuture<'a, Bresult<Value>> {
+        info!("FixtureQuotes chain <- {} {}", ticker, expiry);
+        futures::future::ready(self.chain(ticker, expiry)).boxed_local()
+    }
+    fn get_closes_raw<'a> (&'a self, ticker: &'a str, since: i64, until: i64) -> LocalBoxFuture<'a, Bresult<Value>> {
+        info!("FixtureQuotes closes <- {} {} {}", ticker, since, until);
+        futures::future::ready(self.closes(ticker, since, until)).boxed_local()
+    }
+}
+
+impl fmt::Debug for FixtureQuotes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FixtureQuotes")
+         .field("filename", &self.quotes.filename())
+         .field("tickers", &self.quotes.cached().len())
+         .finish()
+    }
+}
+
+////////////////////////////////////////
+
+// Select the fixture provider when TMBOT_QUOTES_FIXTURE names a JSON file,
+// otherwise go live to Yahoo.
+pub fn new_quote_provider (http: &Arc<HttpService>) -> Bresult<Arc<dyn QuoteProvider>> {
+    Ok(match fixture_filename("TMBOT_QUOTES_FIXTURE") {
+        Some(filename) => Arc::new(FixtureQuotes::new(&filename)?),
+        None => Arc::new(YahooQuotes{ http: http.clone() })
+    })
+}
@@ -0,0 +1,168 @@
+//! # Roles
+use crate::*;
+use ::std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    Viewer, // Quotes and leaderboards
+    Trader, // Trades with their own account
+    Admin,  // Runs the chat
+    Owner   // Runs the bot
+}
+
+pub const DEFAULT_ROLE :Role = Role::Trader;
+pub const EVERY_CHAT :i64 = 0;
+
+impl FromStr for Role {
+    type Err = String;
+    fn from_str (s:&str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "viewer" => Ok(Role::Viewer),
+            "trader" => Ok(Role::Trader),
+            "admin"  => Ok(Role::Admin),
+            "owner"  => Ok(Role::Owner),
+            _ => Err(format!("no role {:?}", s))
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", match self {
+            Role::Viewer => "viewer",
+            Role::Trader => "trader",
+            Role::Admin  => "admin",
+            Role::Owner  => "owner"
+        })
+    }
+}
+
+#[derive(Debug, FromRow)]
+pub struct Grant {
+    pub id: i64,
+    pub at: i64,
+    pub role: String,
+    #[row(default)]
+    pub name: String // Name of the id entity, when joined
+}
+
+/// id's role in chat at
+pub fn entity_role (dbconn:&Connection, id:i64, at:i64) -> Bresult<Role> {
+    let mut role = None;
+    for row in getsql!(dbconn, "SELECT role FROM roles WHERE id=? AND at IN (?, ?)", id, EVERY_CHAT, at)? {
+        role = role.max(Some(row.get_string("role")?.parse::<Role>()?));
+    }
+    Ok(role.unwrap_or(DEFAULT_ROLE))
+}
+
+/// May granter give (or take away) role from an entity whose role is
+/// target?  The owner can do anything, others only in a chat and below
+/// themselves.
+pub fn role_assignable (granter:Role, target:Role, role:Role, every_chat:bool) -> bool {
+    Role::Owner == granter || (!every_chat && role < granter && target < granter)
+}
+
+/// Grants in a chat and in every chat
+pub fn grants (dbconn:&Connection, at:i64) -> Bresult<Vec<Grant>> {
+    dbconn.query_as::<Grant>(
+        "SELECT roles.id, at, role, name FROM roles
+         LEFT JOIN entitys ON roles.id = entitys.id WHERE at IN (?, ?) ORDER BY at, name", &[&EVERY_CHAT, &at])
+}
+
+pub fn role_grant (dbconn:&Connection, id:i64, at:i64, role:Role) -> Bresult<()> {
+    getsql!(dbconn, "INSERT OR REPLACE INTO roles VALUES (?, ?, ?)", id, at, &*role.to_string())?;
+    Ok(())
+}
+
+/// Grant TMBOT_OWNER_ID the owner role in every chat.  The configured id
+/// if there is one.
+pub fn owner_bootstrap (dbconn:&Connection) -> Bresult<Option<i64>> {
+    let id = match env::var("TMBOT_OWNER_ID") {
+        Ok(id) => id.parse::<i64>().map_err( |e| format!("TMBOT_OWNER_ID {:?}: {}", id, e) )?,
+        Err(_) => return Ok(None)
+    };
+    role_grant(dbconn, id, EVERY_CHAT, Role::Owner)?;
+    Ok(Some(id))
+}
+
+// The revoked role if there was one
+pub fn role_revoke (dbconn:&Connection, id:i64, at:i64) -> Bresult<Option<Role>> {
+    let rows = getsql!(dbconn, "SELECT role FROM roles WHERE id=? AND at=?", id, at)?;
+    getsql!(dbconn, "DELETE FROM roles WHERE id=? AND at=?", id, at)?;
+    Ok(match rows.first() {
+        Some(row) => Some(row.get_string("role")?.parse::<Role>()?),
+        None => None
+    })
+}
+
+/// Owner overrides of commands' declared roles
+pub fn command_roles (dbconn:&Connection) -> Bresult<HashMap<String, Role>> {
+    let mut roles = HashMap::new();
+    for row in getsql!(dbconn, "SELECT name, role FROM command_roles")? {
+        roles.insert(row.get_string("name")?, row.get_string("role")?.parse::<Role>()?);
+    }
+    Ok(roles)
+}
+
+// None restores the command's declared role
+pub fn command_role_set (dbconn:&Connection, name:&str, role:Option<Role>) -> Bresult<()> {
+    match role {
+        Some(role) => getsql!(dbconn, "INSERT OR REPLACE INTO command_roles VALUES (?, ?)", name, &*role.to_string())?,
+        None => getsql!(dbconn, "DELETE FROM command_roles WHERE name=?", name)?
+    };
+    Ok(())
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roles_per_chat() {
+        let dbconn = test_dbconn();
+        assert_eq!(entity_role(&dbconn, 1, 5).unwrap(), DEFAULT_ROLE);
+        role_grant(&dbconn, 1, 5, Role::Viewer).unwrap();
+        assert_eq!((entity_role(&dbconn, 1, 5).unwrap(), entity_role(&dbconn, 1, 6).unwrap()), (Role::Viewer, Role::Trader));
+    }
+
+    #[test]
+    fn every_chat_grant_outranks() {
+        let dbconn = test_dbconn();
+        role_grant(&dbconn, 1, 5, Role::Viewer).unwrap();
+        role_grant(&dbconn, 1, EVERY_CHAT, Role::Admin).unwrap();
+        role_grant(&dbconn, 1, EVERY_CHAT, Role::Admin).unwrap();
+        assert_eq!((entity_role(&dbconn, 1, 5).unwrap(), entity_role(&dbconn, 1, 6).unwrap()), (Role::Admin, Role::Admin));
+        assert_eq!(grants(&dbconn, 5).unwrap().iter().filter( |g| 1 == g.id ).count(), 2);
+        assert_eq!(role_revoke(&dbconn, 1, EVERY_CHAT).unwrap(), Some(Role::Admin));
+        assert_eq!(role_revoke(&dbconn, 1, EVERY_CHAT).unwrap(), None);
+        assert_eq!(entity_role(&dbconn, 1, 5).unwrap(), Role::Viewer);
+    }
+
+    #[test]
+    fn no_seeded_owner() {
+        let dbconn = test_dbconn();
+        assert!(grants(&dbconn, EVERY_CHAT).unwrap().is_empty());
+        assert!(getsql!(dbconn, "SELECT * FROM settings").unwrap().is_empty());
+    }
+
+    #[test]
+    fn command_role_overrides() {
+        let dbconn = test_dbconn();
+        command_role_set(&dbconn, "sql", Some(Role::Admin)).unwrap();
+        assert_eq!(command_roles(&dbconn).unwrap().get("sql"), Some(&Role::Admin));
+        command_role_set(&dbconn, "sql", None).unwrap();
+        assert!(command_roles(&dbconn).unwrap().is_empty());
+    }
+
+    #[test]
+    fn assignable_roles() {
+        assert_eq!(
+            [role_assignable(Role::Admin, Role::Trader, Role::Viewer, false), role_assignable(Role::Admin, Role::Trader, Role::Admin, false),
+             role_assignable(Role::Admin, Role::Admin, Role::Viewer, false), role_assignable(Role::Admin, Role::Trader, Role::Viewer, true),
+             role_assignable(Role::Owner, Role::Owner, Role::Viewer, true)],
+            [true, false, false, false, true]);
+        assert!("Admin".parse::<Role>().map_or(false, |r| Role::Trader < r && r < Role::Owner) && "root".parse::<Role>().is_err());
+    }
+}
@@ -0,0 +1,108 @@
+//! # Short Borrows
+use crate::*;
+
+pub const BORROW_RATE :f64 = 0.03; // Yearly fee when the ticker has no borrowrates entry
+
+#[derive(Debug, FromRow)]
+pub struct Borrow {
+    pub qty:  Qty, // Shares borrowed, positive
+    pub rate: f64
+}
+
+pub fn borrow_rate (dbconn:&Connection, ticker:&str) -> Bresult<f64> {
+    Ok(getsql!(dbconn, "SELECT rate FROM borrowrates WHERE ticker=?", ticker)?
+        .first().map_or(BORROW_RATE, |row| row.get_f64_or(BORROW_RATE, "rate")))
+}
+
+pub fn borrow (dbconn:&Connection, id:i64, ticker:&str) -> Bresult<Option<Borrow>> {
+    Ok(dbconn.query_as::<Borrow>("SELECT qty, rate FROM borrows WHERE id=? AND ticker=?", &[&id, &ticker])?.pop())
+}
+
+/// Match id's borrow to its lots after a fill.  Opens a borrow at the
+/// ticker's current rate, resizes it, or returns it once covered.
+pub fn borrow_sync (dbconn:&Connection, id:i64, ticker:&str, time:i64) -> Bresult<()> {
+    if !market_ticker_p(ticker) { return Ok(()) }
+    let short = -lots(dbconn, id, ticker)?.iter().map( |lot| lot.qty ).filter( |qty| qty.is_negative() ).sum::<Qty>();
+    if short.is_zero() {
+        getsql!(dbconn, "DELETE FROM borrows WHERE id=? AND ticker=?", id, ticker)?;
+    } else if borrow(dbconn, id, ticker)?.is_some() {
+        getsql!(dbconn, "UPDATE borrows SET qty=? WHERE id=? AND ticker=?", short, id, ticker)?;
+    } else {
+        getsql!(dbconn, "INSERT INTO borrows VALUES (?, ?, ?, ?, ?)", id, ticker, short, borrow_rate(dbconn, ticker)?, time)?;
+    }
+    Ok(())
+}
+
+/// Fees paid on id's open borrow of a ticker
+pub fn borrow_fees (dbconn:&Connection, id:i64, ticker:&str) -> Bresult<Money> {
+    Ok(getsql!(dbconn,
+        "SELECT SUM(fee) AS fee FROM borrowfees JOIN borrows USING (id, ticker) WHERE id=? AND ticker=? AND borrows.time<=borrowfees.time",
+        id, ticker)?[0]
+        .get_money_or(Money::ZERO, "fee"))
+}
+
+/// Charge a day's fee on every borrow not yet charged today at the cached
+/// market price.  Returns the ids, tickers and fees charged.
+pub fn borrow_fees_accrue (envstruct:&mut EnvStruct, now:i64) -> Bresult<Vec<(i64, String, Money)>> {
+    let day = now - now % 86400;
+    let borrows = getsql!(envstruct.dbconn,
+        "SELECT borrows.id, borrows.ticker, borrows.qty, borrows.rate, stonks.price, stonks.currency FROM borrows
+         LEFT JOIN stonks ON stonks.ticker = borrows.ticker
+         WHERE NOT EXISTS (SELECT id FROM borrowfees WHERE borrowfees.id=borrows.id AND borrowfees.ticker=borrows.ticker AND borrowfees.time=?)", day)?;
+    let mut charged = Vec::new();
+    for row in borrows {
+        let (id, ticker, qty, rate) = (row.get_i64("id")?, row.get_string("ticker")?, row.get_qty("qty")?, row.get_f64("rate")?);
+        let price = row.get_money_or(Money::ZERO, "price");
+        let fee = (qty * price).scale(rate / YEAR_DAYS).cents(); // In the stonk's currency
+        getsql!(envstruct.dbconn, "INSERT INTO borrowfees VALUES (?, ?, ?, ?, ?, ?)", id, &*ticker, day, qty, price, fee)?;
+        cash_inc(envstruct, Origin::system(now), id, &row.get_string_or(USD, "currency"), -fee)?;
+        charged.push((id, ticker, fee));
+    }
+    Ok(charged)
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fills (dbconn:&Connection, ticker:&str, fills:&[(i64, i64)]) {
+        for (qty, time) in fills {
+            sql_table_order_insert(dbconn, 1, ticker, Qty::from_int(*qty), Money::from_int(10), *time).unwrap();
+        }
+    }
+
+    #[test]
+    fn borrow_follows_lots() {
+        let dbconn = test_dbconn();
+        getsql!(dbconn, "INSERT INTO borrowrates VALUES ('GME', 0.5)").unwrap();
+        fills(&dbconn, "GME", &[(-4, 1), (-2, 2), (5, 3)]);
+        let open = borrow(&dbconn, 1, "GME").unwrap().unwrap();
+        assert_eq!((open.qty, open.rate), (Qty::from_int(1), 0.5));
+    }
+
+    #[test]
+    fn borrow_returned_when_covered() {
+        let dbconn = test_dbconn();
+        fills(&dbconn, "GME", &[(-1, 1), (3, 2)]); // Covered and long 2
+        assert!(borrow(&dbconn, 1, "GME").unwrap().is_none());
+    }
+
+    #[test]
+    fn borrow_default_rate() {
+        let dbconn = test_dbconn();
+        fills(&dbconn, "AMC", &[(-1, 1)]);
+        assert_eq!(borrow(&dbconn, 1, "AMC").unwrap().unwrap().rate, BORROW_RATE);
+    }
+
+    #[test]
+    fn borrow_fee_same_day_count_as_interest() {
+        let mut envstruct = test_envstruct();
+        getsql!(envstruct.dbconn, "INSERT INTO stonks VALUES ('GME', 10e6, 10e6, 'r', 24, 'FIX', 0, 'GME', 'USD')").unwrap();
+        sql_table_order_insert(&envstruct.dbconn, 1, "GME", Qty::from_int(-3650), Money::from_int(10), 0).unwrap();
+        let fees = envstruct.transaction( |envstruct| borrow_fees_accrue(envstruct, 86400 * 100) ).unwrap();
+        assert_eq!(fees, vec![(1, "GME".to_string(), Money::from_int(36500).scale(BORROW_RATE / YEAR_DAYS).cents())]);
+        assert_eq!(fees[0].2, Money::from_int(3));
+    }
+}
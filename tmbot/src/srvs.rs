@@ -1,20 +1,10 @@
 use crate::*;
 
-pub async fn get_definition (word: &str) -> Bresult<Vec<String>> {
+const YAHOO_USER_AGENT :&str = "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/95.0.4638.69 Safari/537.36";
+
+pub async fn get_definition (http: &HttpService, word: &str) -> Bresult<Vec<String>> {
     let body =
-        Client::builder()
-        .connector( Connector::new()
-                    .ssl( SslConnector::builder(SslMethod::tls())?.build() )
-                    .timeout( std::time::Duration::new(90,0) )
-                    .finish() )
-        .finish() // -> Client
-        .get("https://www.onelook.com/")
-        .header("User-Agent", "Actix-web")
-        .timeout(std::time::Duration::new(90,0))
-        .query(&[["q",word]])?
-        .send()
-        .await?
-        .body().limit(1_000_000).await
+        http.get("https://www.onelook.com/", &[["q",word]], &[("User-Agent", "Actix-web")]).await
         .or_else( |e| {
             error!(r#"get_definition http body {:?} for {:?}"#, e, word);
             Err(e)
@@ -48,21 +38,8 @@ pub async fn get_definition (word: &str) -> Bresult<Vec<String>> {
 }
 
 
-pub async fn get_syns (word: &str) -> Bresult<Vec<String>> {
-    let body =
-        Client::builder()
-        .connector( Connector::new()
-                    .ssl( SslConnector::builder(SslMethod::tls()).unwrap().build() )
-                    .timeout( std::time::Duration::new(90,0) )
-                    .finish() )
-        .finish() // -> Client
-        .get("https://onelook.com/")
-        .header("User-Agent", "Actix-web")
-        .timeout(std::time::Duration::new(90,0))
-        .query(&[["clue", word]]).unwrap()
-        .send()
-        .await.unwrap()
-        .body().limit(1_000_000).await;
+pub async fn get_syns (http: &HttpService, word: &str) -> Bresult<Vec<String>> {
+    let body = http.get("https://onelook.com/", &[["clue", word]], &[("User-Agent", "Actix-web")]).await;
 
     if body.is_err() {
          error!(r#"get_syns http body {:?} for {:?}"#, body, word);
@@ -84,21 +61,9 @@ pub async fn get_syns (word: &str) -> Bresult<Vec<String>> {
 
 ////////////////////////////////////////////////////////////////////////////////
 
-pub async fn _get_ticker_raw_1 (ticker: &str) -> Bresult<Value> {
+pub async fn _get_ticker_raw_1 (http: &HttpService, ticker: &str) -> Bresult<Value> {
     info!("get_ticker_quote <- {}", ticker);
-    let body =
-        Client::builder()
-        .connector( Connector::new()
-                    .ssl( SslConnector::builder(SslMethod::tls())?.build() )
-                    .timeout( std::time::Duration::new(90,0) )
-                    .finish() )
-        .finish() // -> Client
-        .get("https://finance.yahoo.com/chart/".to_string() + ticker)
-        .header("User-Agent", "Actix-web")
-        .timeout(std::time::Duration::new(90,0))
-        .send()
-        .await?
-        .body().limit(1_000_000).await;
+    let body = http.get(&("https://finance.yahoo.com/chart/".to_string() + ticker), &[], &[("User-Agent", "Actix-web")]).await;
 
     let body = body.or_else( |r| Err(format!("get_ticker_quote for {:?}  {:?}", ticker, r)) )?;
     let domstr = from_utf8(&body).or_else( |r| Err(format!(r#"get_ticker_quote http body2str {:?} {:?}"#, ticker, r)) )?;
@@ -110,26 +75,68 @@ pub async fn _get_ticker_raw_1 (ticker: &str) -> Bresult<Value> {
     bytes2json(&cap.unwrap()[1].as_bytes())
 } // _get_ticker_raw_1
 
-pub async fn get_ticker_raw (ticker: &str) -> Bresult<Value> {
+pub async fn get_ticker_raw (http: &HttpService, ticker: &str) -> Bresult<Value> {
     info!("get_ticker_quote <- {}", ticker);
-    let body =
-        Client::builder()
-        .connector( Connector::new()
-                    .ssl( SslConnector::builder(SslMethod::tls())?.build() )
-                    .timeout( std::time::Duration::new(90,0) )
-                    .finish() )
-        .finish() // -> Client
-        .get("https://query1.finance.yahoo.com/v7/finance/quote".to_string())
-        .header("User-Agent", "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/95.0.4638.69 Safari/537.36")
-        .header("Referer", "https://finance.yahoo.com/__finStreamer-worker.js")
-        .timeout(std::time::Duration::new(90,0))
-        .query(&[["symbols", ticker]]).unwrap()
-        .send()
-        .await?
-        .body().limit(1_000_000).await;
-
-    let body = body.or_else( |r| Err(format!("get_ticker_raw for {:?}  {:?}", ticker, r)) )?;
-    let jsonstr = from_utf8(&body).or_else( |r| Err(format!(r#"get_ticker_raw http body2str {:?} {:?}"#, ticker, r)) )?;
-
-    bytes2json(jsonstr.as_bytes())
-} // get_ticker_raw
\ No newline at end of file
+    http.get_json(
+        "https://query1.finance.yahoo.com/v7/finance/quote",
+        &[["symbols", ticker]],
+        &[("User-Agent", YAHOO_USER_AGENT), ("Referer", "https://finance.yahoo.com/__finStreamer-worker.js")]).await
+        .or_else( |r| Err(format!("get_ticker_raw for {:?}  {:?}", ticker, r).into()) )
+} // get_ticker_raw
+
+// Yahoo's quote endpoint takes this many comma separated symbols
+const QUOTE_BATCH_SIZE :usize = 50;
+
+// Many tickers' quotes, QUOTE_BATCH_SIZE symbols a request, merged into
+// one quoteResponse
+pub async fn get_tickers_raw (http: &HttpService, tickers: &[String]) -> Bresult<Value> {
+    let mut results = Vec::new();
+    for chunk in tickers.chunks(QUOTE_BATCH_SIZE) {
+        let symbols = chunk.join(",");
+        info!("get_tickers_raw <- {}", symbols);
+        let json = http.get_json(
+            "https://query1.finance.yahoo.com/v7/finance/quote",
+            &[["symbols", &symbols]],
+            &[("User-Agent", YAHOO_USER_AGENT), ("Referer", "https://finance.yahoo.com/__finStreamer-worker.js")]).await
+            .or_else( |r| Err(format!("get_tickers_raw for {:?}  {:?}", symbols, r)) )?;
+        results.extend(getin(&json, &["quoteResponse", "result"]).as_array().cloned().unwrap_or_default());
+    }
+    Ok(serde_json::json!({"quoteResponse": {"result": results}}))
+} // get_tickers_raw
+
+// Dividend and split events from Yahoo's chart endpoint
+pub async fn get_ticker_events_raw (http: &HttpService, ticker: &str, since: i64) -> Bresult<Value> {
+    info!("get_ticker_events_raw <- {} {}", ticker, since);
+    http.get_json(
+        &("https://query1.finance.yahoo.com/v8/finance/chart/".to_string() + ticker),
+        &[
+            ["period1", &since.to_string()],
+            ["period2", &Instant::now().seconds().to_string()],
+            ["interval", "1d"],
+            ["events", "div,split"]],
+        &[("User-Agent", YAHOO_USER_AGENT)]).await
+        .or_else( |r| Err(format!("get_ticker_events_raw for {:?}  {:?}", ticker, r).into()) )
+} // get_ticker_events_raw
+
+// Daily closes from since until until from Yahoo's chart endpoint
+pub async fn get_closes_raw (http: &HttpService, ticker: &str, since: i64, until: i64) -> Bresult<Value> {
+    info!("get_closes_raw <- {} {} {}", ticker, since, until);
+    http.get_json(
+        &("https://query1.finance.yahoo.com/v8/finance/chart/".to_string() + ticker),
+        &[
+            ["period1", &since.to_string()],
+            ["period2", &until.to_string()],
+            ["interval", "1d"]],
+        &[("User-Agent", YAHOO_USER_AGENT)]).await
+        .or_else( |r| Err(format!("get_closes_raw for {:?}  {:?}", ticker, r).into()) )
+} // get_closes_raw
+
+// One expiry's option chain, calls and puts, for an underlying ticker
+pub async fn get_option_chain_raw (http: &HttpService, ticker: &str, expiry: i64) -> Bresult<Value> {
+    info!("get_option_chain_raw <- {} {}", ticker, expiry);
+    http.get_json(
+        &("https://query1.finance.yahoo.com/v7/finance/options/".to_string() + ticker),
+        &[["date", &expiry.to_string()]],
+        &[("User-Agent", YAHOO_USER_AGENT)]).await
+        .or_else( |r| Err(format!("get_option_chain_raw for {:?}  {:?}", ticker, r).into()) )
+} // get_option_chain_raw
@@ -7,7 +7,7 @@ use ::std::{
         //hash_map::Entry
     }
 };
-use ::regex::{Regex};
+use ::regex::{Regex, Captures};
 pub use ::serde_json::{Value};
 
 ////////////////////////////////////////////////////////////////////////////////
@@ -15,6 +15,17 @@ pub use ::serde_json::{Value};
 
 pub type Bresult<T> = Result<T, Box<dyn Error>>;
 
+/// A request refused for good, unlike a failure that may pass when retried.
+/// The user has been told why.
+#[derive(Debug)]
+pub struct Rejected(pub &'static str);
+
+impl ::std::fmt::Display for Rejected {
+    fn fmt (&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result { f.write_str(self.0) }
+}
+
+impl Error for Rejected {}
+
 ////////////////////////////////////////////////////////////////////////////////
 /// Logging
 
@@ -205,14 +216,17 @@ pub fn regex_to_hashmap (re: &str, msg: &str) -> Bresult<HashMap<String, String>
 
 // Return vector of the regex capture groups, if any.
 pub fn regex_to_vec (re: &str, msg: &str) -> Bresult<Vec<Option<String>>> {
-    Regex::new(re)?
-    .captures(msg) // An Option<Captures>
-    .map_or(Ok(Vec::new()), // Return Ok empty vec if None...
-        |captures|          // ...or return Ok Vec of Option<Vec>
-        Ok(captures.iter() // Iterator over Option<Match>
-            .map( |o_match| // None or Some<String>
-                    o_match.map( |mtch| mtch.as_str().into() ) )
-            .collect()))
+    Ok(Regex::new(re)?
+        .captures(msg) // An Option<Captures>
+        .map_or(Vec::new(), |captures| captures_to_vec(&captures))) // Empty vec if None
+}
+
+// Vector of a match's capture groups
+pub fn captures_to_vec (captures: &Captures) -> Vec<Option<String>> {
+    captures.iter() // Iterator over Option<Match>
+        .map( |o_match| // None or Some<String>
+                o_match.map( |mtch| mtch.as_str().into() ) )
+        .collect()
 }
 
 pub trait AsI64 { fn as_i64 (&self, i:usize) -> Bresult<i64>; }